@@ -0,0 +1,390 @@
+use assert_matches::assert_matches;
+use diesel::prelude::*;
+use failure::Fallible;
+use serde_json::json;
+use swirl::admin::{self, PreviewOptions};
+use swirl::{AdminError, EnqueueOptions, JobsFailed, PerformError};
+
+use crate::dummy_jobs::*;
+use crate::test_guard::TestGuard;
+
+#[swirl::background_job]
+fn send_email(to: String, body: String) -> Result<(), PerformError> {
+    let _ = (to, body);
+    Ok(())
+}
+
+#[swirl::background_job]
+fn expect_foo(arg: String) -> Result<(), PerformError> {
+    if arg == "foo" {
+        Ok(())
+    } else {
+        Err("arg wasn't foo!".into())
+    }
+}
+
+#[test]
+fn list_jobs_truncates_and_redacts_previews() -> Fallible<()> {
+    let runner = TestGuard::dummy_runner();
+    let conn = runner.connection_pool().get()?;
+    send_email("me@example.com".into(), "a".repeat(1000)).enqueue(&conn)?;
+
+    let preview = PreviewOptions {
+        max_length: 50,
+        redacted_keys: vec!["to".into()],
+    };
+    let jobs = admin::list_jobs(&conn, 0, 10, &preview)?;
+
+    assert_eq!(1, jobs.len());
+    assert_eq!("send_email", jobs[0].job_type);
+    assert_eq!(50, jobs[0].data_preview.chars().count());
+    assert!(jobs[0].data_preview.contains("[REDACTED]"));
+    assert!(!jobs[0].data_preview.contains("me@example.com"));
+    assert!(jobs[0].data_size > 1000);
+    Ok(())
+}
+
+#[test]
+fn jobs_can_be_inspected_and_counted_by_type() -> Fallible<()> {
+    let runner = TestGuard::dummy_runner();
+    let conn = runner.connection_pool().get()?;
+    let handle = send_email("me@example.com".into(), "hi".into()).enqueue(&conn)?;
+    expect_foo("bar".into()).enqueue(&conn)?;
+    expect_foo("baz".into()).enqueue(&conn)?;
+
+    let job = admin::get_job(&conn, handle.id())?.expect("job should exist");
+    assert_eq!("send_email", job.job_type);
+    assert_eq!(json!({ "to": "me@example.com", "body": "hi" }), job.data);
+    assert_eq!(0, job.retries);
+    assert!(admin::get_job(&conn, handle.id() + 100)?.is_none());
+
+    runner.run_all_pending_jobs()?;
+    assert_eq!(Err(JobsFailed(2)), runner.check_for_failed_jobs());
+
+    let counts = admin::count_jobs_by_type(&conn)?;
+    assert_eq!(1, counts.len());
+    assert_eq!("expect_foo", counts[0].job_type);
+    assert_eq!(2, counts[0].jobs);
+    assert_eq!(2, counts[0].failing_jobs);
+    Ok(())
+}
+
+#[test]
+fn retry_with_replaces_arguments_of_failed_jobs() -> Fallible<()> {
+    let runner = TestGuard::dummy_runner();
+    let conn = runner.connection_pool().get()?;
+    expect_foo("bar".into()).enqueue(&conn)?;
+
+    runner.run_all_pending_jobs()?;
+    assert_eq!(Err(JobsFailed(1)), runner.check_for_failed_jobs());
+
+    let job_id = admin::list_jobs(&conn, 0, 1, &PreviewOptions::default())?[0].id;
+    assert_matches!(
+        admin::retry_with(&conn, job_id, json!({ "arg": 1 })),
+        Err(AdminError::InvalidArguments(_))
+    );
+    assert_matches!(
+        admin::retry_with(&conn, job_id + 1, json!({ "arg": "foo" })),
+        Err(AdminError::JobNotFound)
+    );
+    admin::retry_with(&conn, job_id, json!({ "arg": "foo" }))?;
+
+    runner.run_all_pending_jobs()?;
+    runner.check_for_failed_jobs()?;
+    Ok(())
+}
+
+#[test]
+fn retry_job_makes_failed_jobs_run_immediately() -> Fallible<()> {
+    let runner = TestGuard::dummy_runner();
+    let conn = runner.connection_pool().get()?;
+    let handle = expect_foo("bar".into()).enqueue(&conn)?;
+    expect_foo("baz".into()).enqueue(&conn)?;
+
+    runner.run_all_pending_jobs()?;
+    assert_eq!(Err(JobsFailed(2)), runner.check_for_failed_jobs());
+
+    admin::retry_job(&conn, handle.id())?;
+    let job = admin::get_job(&conn, handle.id())?.expect("job should exist");
+    assert_eq!(0, job.retries);
+    assert_matches!(
+        admin::retry_job(&conn, handle.id() + 100),
+        Err(AdminError::JobNotFound)
+    );
+
+    // Only the job which was reset is due
+    runner.run_all_pending_jobs()?;
+    assert_eq!(Err(JobsFailed(2)), runner.check_for_failed_jobs());
+    let job = admin::get_job(&conn, handle.id())?.expect("job should exist");
+    assert_eq!(1, job.retries);
+
+    assert_eq!(2, admin::retry_all_failed(&conn)?);
+    assert_eq!(0, admin::count_jobs_by_type(&conn)?[0].failing_jobs);
+    Ok(())
+}
+
+#[test]
+fn retry_history_records_each_failure() -> Fallible<()> {
+    let runner = TestGuard::builder(()).max_retries(0).build();
+    let conn = runner.connection_pool().get()?;
+    let handle = expect_foo("bar".into()).enqueue(&conn)?;
+    assert_eq!(Some(vec![]), admin::retry_history(&conn, handle.id())?);
+
+    runner.run_all_pending_jobs()?;
+    runner.check_for_failed_jobs()?;
+    // The history is kept when the job moves in and out of swirl_failed_jobs
+    admin::retry_failed_job(&conn, handle.id(), None)?;
+    runner.run_all_pending_jobs()?;
+    runner.check_for_failed_jobs()?;
+
+    let history = admin::retry_history(&conn, handle.id())?.expect("job should exist");
+    assert_eq!(2, history.len());
+    assert_eq!(history[0].fingerprint, history[1].fingerprint);
+    assert!(history[0].failed_at <= history[1].failed_at);
+    assert_eq!(None, admin::retry_history(&conn, handle.id() + 100)?);
+    Ok(())
+}
+
+#[test]
+fn retried_failed_jobs_keep_their_locality_and_unique_key() -> Fallible<()> {
+    use swirl::schema::background_jobs;
+
+    let runner = TestGuard::builder(()).max_retries(0).build();
+    let conn = runner.connection_pool().get()?;
+    let options = EnqueueOptions {
+        unique_key: Some("bar".into()),
+        locality: Some("eu".into()),
+        ..EnqueueOptions::for_job::<expect_foo::Job>()
+    };
+    let handle = expect_foo("bar".into()).enqueue_with(&conn, options.clone())?;
+
+    runner.run_all_pending_jobs()?;
+    runner.check_for_failed_jobs()?;
+    admin::retry_failed_job(&conn, handle.id(), None)?;
+
+    let retried = background_jobs::table
+        .find(handle.id())
+        .select((background_jobs::unique_key, background_jobs::locality))
+        .first::<(Option<String>, Option<String>)>(&conn);
+    assert_eq!(Ok((Some("bar".into()), Some("eu".into()))), retried);
+    // The retried job still holds its unique key
+    assert_matches!(
+        expect_foo("bar".into()).enqueue_with(&conn, options),
+        Err(swirl::EnqueueError::Duplicate)
+    );
+    Ok(())
+}
+
+#[test]
+fn rename_job_type_updates_queued_jobs() -> Fallible<()> {
+    let runner = TestGuard::dummy_runner();
+    let conn = runner.connection_pool().get()?;
+    expect_foo("foo".into()).enqueue(&conn)?;
+    expect_foo("foo".into()).enqueue(&conn)?;
+    diesel::sql_query("UPDATE background_jobs SET job_type = 'old_expect_foo'").execute(&conn)?;
+
+    assert_eq!(
+        2,
+        admin::rename_job_type(&conn, "old_expect_foo", "expect_foo")?
+    );
+    assert_eq!(
+        0,
+        admin::rename_job_type(&conn, "old_expect_foo", "expect_foo")?
+    );
+
+    runner.run_all_pending_jobs()?;
+    runner.check_for_failed_jobs()?;
+    Ok(())
+}
+
+#[test]
+fn boost_raises_the_priority_of_matching_jobs() -> Fallible<()> {
+    use swirl::schema::background_jobs;
+
+    let runner = TestGuard::dummy_runner();
+    let conn = runner.connection_pool().get()?;
+    let mut acme = EnqueueOptions::for_job::<send_email::Job>();
+    acme.metadata.insert("tenant".into(), json!("acme"));
+    send_email("a@acme.com".into(), "".into()).enqueue_with(&conn, acme.clone())?;
+    send_email("b@acme.com".into(), "".into()).enqueue_with(&conn, acme)?;
+    send_email("me@example.com".into(), "".into()).enqueue(&conn)?;
+    expect_foo("foo".into()).enqueue(&conn)?;
+
+    let filter = admin::JobFilter {
+        job_type: Some("send_email".into()),
+        metadata: Some(json!({ "tenant": "acme" })),
+        ..Default::default()
+    };
+    assert_eq!(2, admin::boost(&conn, &filter, 10)?);
+    // Boosting never lowers a job's priority
+    assert_eq!(0, admin::boost(&conn, &filter, 5)?);
+
+    let priorities = background_jobs::table
+        .select(background_jobs::priority)
+        .order(background_jobs::id)
+        .load::<i16>(&conn);
+    assert_eq!(Ok(vec![10, 10, 0, 0]), priorities);
+    Ok(())
+}
+
+#[test]
+fn jobs_can_be_searched_by_their_arguments() -> Fallible<()> {
+    let runner = TestGuard::dummy_runner();
+    let conn = runner.connection_pool().get()?;
+    send_email("a@acme.com".into(), "hello".into()).enqueue(&conn)?;
+    send_email("a@acme.com".into(), "goodbye".into()).enqueue(&conn)?;
+    send_email("b@acme.com".into(), "hello".into()).enqueue(&conn)?;
+    expect_foo("foo".into()).enqueue(&conn)?;
+
+    let search = |filter: admin::JobFilter| -> Fallible<Vec<String>> {
+        let jobs = admin::search_jobs(&conn, &filter, 0, 10, &PreviewOptions::default())?;
+        Ok(jobs.into_iter().map(|j| j.data_preview).collect())
+    };
+
+    let filter = admin::JobFilter::new().data_at(&["to"], "a@acme.com");
+    assert_eq!(2, search(filter)?.len());
+
+    let filter = admin::JobFilter::new()
+        .data_at(&["to"], "a@acme.com")
+        .data_contains(json!({ "body": "hello" }));
+    assert_eq!(
+        vec![r#"{"to": "a@acme.com", "body": "hello"}"#],
+        search(filter)?
+    );
+
+    let filter = admin::JobFilter::new()
+        .job_type("send_email")
+        .data_at(&["arg"], "foo");
+    assert!(search(filter)?.is_empty());
+    Ok(())
+}
+
+#[test]
+fn the_first_failure_of_a_job_is_kept_when_it_is_edited() -> Fallible<()> {
+    let runner = TestGuard::builder(()).worker_version("1.2.3").build();
+    let conn = runner.connection_pool().get()?;
+    expect_foo("bar".into()).enqueue(&conn)?;
+    let job_id = admin::list_jobs(&conn, 0, 1, &PreviewOptions::default())?[0].id;
+    assert_eq!(None, admin::first_failure(&conn, job_id)?);
+
+    runner.run_all_pending_jobs()?;
+    assert_eq!(Err(JobsFailed(1)), runner.check_for_failed_jobs());
+    admin::retry_with(&conn, job_id, json!({ "arg": "baz" }))?;
+    runner.run_all_pending_jobs()?;
+    assert_eq!(Err(JobsFailed(1)), runner.check_for_failed_jobs());
+
+    let snapshot = admin::first_failure(&conn, job_id)?.expect("no snapshot was taken");
+    let worker_id = admin::list_workers(&conn)?[0].id;
+    assert_eq!(json!({ "arg": "bar" }), snapshot["data"]);
+    assert_eq!(json!("arg wasn't foo!"), snapshot["error"]);
+    assert_eq!(json!(worker_id), snapshot["worker_id"]);
+    assert_eq!(json!("1.2.3"), snapshot["worker_version"]);
+    Ok(())
+}
+
+#[test]
+fn failed_jobs_can_be_listed_retried_and_purged() -> Fallible<()> {
+    use std::time::Duration;
+
+    let runner = TestGuard::builder(()).max_retries(0).build();
+    let conn = runner.connection_pool().get()?;
+    expect_foo("bar".into()).enqueue(&conn)?;
+    expect_foo("baz".into()).enqueue(&conn)?;
+
+    runner.run_all_pending_jobs()?;
+    // Jobs which have been given up on aren't counted as failures
+    runner.check_for_failed_jobs()?;
+
+    let failed = admin::list_failed_jobs(&conn, 0, 10)?;
+    assert_eq!(2, failed.len());
+    assert_eq!("arg wasn't foo!", failed[0].error);
+    assert_eq!(1, failed[0].retries);
+
+    assert_matches!(
+        admin::retry_failed_job(&conn, failed[0].id, Some(json!({ "arg": 1 }))),
+        Err(AdminError::InvalidArguments(_))
+    );
+    admin::retry_failed_job(&conn, failed[0].id, Some(json!({ "arg": "foo" })))?;
+    assert_matches!(
+        admin::retry_failed_job(&conn, failed[0].id, None),
+        Err(AdminError::JobNotFound)
+    );
+    runner.run_all_pending_jobs()?;
+    runner.check_for_failed_jobs()?;
+
+    assert_eq!(0, admin::purge_failed_jobs(&conn, Duration::from_secs(60))?);
+    assert_eq!(1, admin::purge_failed_jobs(&conn, Duration::from_secs(0))?);
+    assert!(admin::list_failed_jobs(&conn, 0, 10)?.is_empty());
+    Ok(())
+}
+
+#[test]
+fn runners_register_themselves_as_workers() -> Fallible<()> {
+    let runner = TestGuard::builder(())
+        .thread_count(2)
+        .worker_version("1.2.3")
+        .build();
+    let conn = runner.connection_pool().get()?;
+    assert!(admin::list_workers(&conn)?.is_empty());
+
+    runner.run_all_pending_jobs()?;
+
+    let workers = admin::list_workers(&conn)?;
+    assert_eq!(1, workers.len());
+    assert_eq!(std::process::id() as i32, workers[0].pid);
+    assert_eq!(Some("1.2.3".into()), workers[0].version);
+    assert_eq!(None, workers[0].queues);
+    assert_eq!(2, workers[0].thread_count);
+    Ok(())
+}
+
+#[test]
+fn running_jobs_are_listed_with_the_worker_running_them() -> Fallible<()> {
+    use swirl::testing::sync::Barrier;
+
+    let barrier = Barrier::new(2);
+    let runner = TestGuard::runner(barrier.clone());
+    let conn = runner.connection_pool().get()?;
+    let job = barrier_job().enqueue(&conn)?;
+    runner.run_all_pending_jobs()?;
+
+    let worker_id = admin::list_workers(&conn)?[0].id;
+    // The job is labelled just after the runner reports that it has started.
+    // Other tests may be running jobs with the same id in their own schema.
+    let mut running = None;
+    for _ in 0..100 {
+        running = admin::running_jobs(&conn)?
+            .into_iter()
+            .find(|r| r.job_id == job.id() && r.worker_id == Some(worker_id));
+        if running.is_some() {
+            break;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+    barrier.wait();
+    runner.check_for_failed_jobs()?;
+
+    let running = running.expect("the job wasn't listed as running");
+    assert_eq!(Some(std::process::id() as i32), running.pid);
+    assert!(running.hostname.is_some());
+    Ok(())
+}
+
+#[test]
+fn failures_are_sampled_up_to_the_limit() -> Fallible<()> {
+    let runner = TestGuard::builder(()).failure_samples(2).build();
+    let conn = runner.connection_pool().get()?;
+    failure_job().enqueue(&conn)?;
+
+    for _ in 0..3 {
+        runner.run_all_pending_jobs()?;
+        assert_eq!(Err(JobsFailed(1)), runner.check_for_failed_jobs());
+        diesel::sql_query("UPDATE background_jobs SET last_retry = '1970-01-01'").execute(&conn)?;
+    }
+
+    let samples = admin::list_failure_samples(&conn, Some("failure_job"), 10)?;
+    assert_eq!(2, samples.len());
+    assert_eq!("failed", samples[0].error);
+    assert_eq!(json!({}), samples[0].data);
+    Ok(())
+}
@@ -0,0 +1,74 @@
+use diesel::prelude::*;
+use failure::Fallible;
+use serde_json::{json, Value};
+use swirl::backfill::{self, Backfill};
+use swirl::schema::background_jobs;
+use swirl::PerformError;
+
+use crate::test_guard::TestGuard;
+
+#[swirl::background_job]
+fn reindex_record(record_id: i32) -> Result<(), PerformError> {
+    let _ = record_id;
+    Ok(())
+}
+
+/// Pages through the records with ids from 1 to `count`, like a query
+/// against a table of them would
+fn records_after(count: i32, after: Option<&i32>, limit: i64) -> QueryResult<Vec<(i32, i32)>> {
+    let after = after.copied().unwrap_or(0);
+    Ok((after + 1..=count)
+        .take(limit as usize)
+        .map(|id| (id, id))
+        .collect())
+}
+
+#[test]
+fn a_job_is_enqueued_for_every_row() -> Fallible<()> {
+    let runner = TestGuard::dummy_runner();
+    let conn = runner.connection_pool().get()?;
+
+    let mut progress = Vec::new();
+    let enqueued = Backfill::new()
+        .batch_size(10)
+        .on_progress(|p| progress.push((p.batches, p.jobs, *p.last_key)))
+        .run(
+            &conn,
+            |after, limit| records_after(25, after, limit),
+            reindex_record,
+        )?;
+
+    assert_eq!(25, enqueued);
+    assert_eq!(vec![(1, 10, 10), (2, 20, 20), (3, 25, 25)], progress);
+    let args = background_jobs::table
+        .select(background_jobs::data)
+        .order(background_jobs::id)
+        .load::<Value>(&conn)?;
+    assert_eq!(25, args.len());
+    assert_eq!(json!({ "record_id": 25 }), args[24]);
+
+    runner.run_all_pending_jobs()?;
+    runner.check_for_failed_jobs()?;
+    Ok(())
+}
+
+#[test]
+fn backfills_can_be_resumed() -> Fallible<()> {
+    let runner = TestGuard::dummy_runner();
+    let conn = runner.connection_pool().get()?;
+
+    let enqueued = Backfill::new().after(20).run(
+        &conn,
+        |after, limit| records_after(25, after, limit),
+        reindex_record,
+    )?;
+    assert_eq!(5, enqueued);
+
+    let enqueued = backfill::enqueue_from_query(
+        &conn,
+        |after, limit| records_after(0, after, limit),
+        reindex_record,
+    )?;
+    assert_eq!(0, enqueued);
+    Ok(())
+}
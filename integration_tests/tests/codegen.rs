@@ -3,7 +3,7 @@ use crate::test_guard::TestGuard;
 use diesel::prelude::*;
 use failure::Fallible;
 use swirl::db::DieselPoolObj;
-use swirl::{JobsFailed, PerformError};
+use swirl::{JobContext, JobsFailed, PerformError};
 
 #[test]
 fn generated_jobs_serialize_all_arguments_except_first() -> Fallible<()> {
@@ -143,3 +143,309 @@ fn jobs_can_take_a_connection_as_an_argument() -> Fallible<()> {
     runner.check_for_failed_jobs()?;
     Ok(())
 }
+
+#[test]
+fn jobs_can_resume_from_a_checkpoint() -> Fallible<()> {
+    #[swirl::background_job]
+    fn resumable(ctx: &JobContext) -> Result<(), PerformError> {
+        match ctx.load_checkpoint::<i32>()? {
+            Some(1) => Ok(()),
+            Some(step) => Err(format!("unexpected checkpoint {}", step).into()),
+            None => {
+                ctx.save_checkpoint(&1)?;
+                Err("failed after saving a checkpoint".into())
+            }
+        }
+    }
+
+    let runner = TestGuard::dummy_runner();
+    let conn = runner.connection_pool().get()?;
+    resumable().enqueue(&conn)?;
+
+    runner.run_all_pending_jobs()?;
+    assert_eq!(Err(JobsFailed(1)), runner.check_for_failed_jobs());
+
+    diesel::sql_query("UPDATE background_jobs SET last_retry = '1970-01-01'").execute(&conn)?;
+    runner.run_all_pending_jobs()?;
+    runner.check_for_failed_jobs()?;
+    Ok(())
+}
+
+#[test]
+fn jobs_only_resume_from_their_own_checkpoint() -> Fallible<()> {
+    #[swirl::background_job]
+    fn resumable_step(ctx: &JobContext, step: i32) -> Result<(), PerformError> {
+        match ctx.load_checkpoint::<i32>()? {
+            Some(checkpoint) if checkpoint == step => Ok(()),
+            Some(checkpoint) => Err(format!("loaded the checkpoint of step {}", checkpoint).into()),
+            None => {
+                ctx.save_checkpoint(&step)?;
+                Err("failed after saving a checkpoint".into())
+            }
+        }
+    }
+
+    let runner = TestGuard::dummy_runner();
+    let conn = runner.connection_pool().get()?;
+    resumable_step(1).enqueue(&conn)?;
+    resumable_step(2).enqueue(&conn)?;
+
+    runner.run_all_pending_jobs()?;
+    assert_eq!(Err(JobsFailed(2)), runner.check_for_failed_jobs());
+
+    diesel::sql_query("UPDATE background_jobs SET last_retry = '1970-01-01'").execute(&conn)?;
+    runner.run_all_pending_jobs()?;
+    runner.check_for_failed_jobs()?;
+    Ok(())
+}
+
+#[test]
+fn jobs_can_tell_which_attempt_they_are_on() -> Fallible<()> {
+    use std::time::Duration;
+
+    #[swirl::background_job(max_retries = 1)]
+    fn fails_until_final_attempt(ctx: &JobContext) -> Result<(), PerformError> {
+        let enqueued_at = ctx.enqueued_at().ok_or("enqueued_at was missing")?;
+        if enqueued_at.elapsed().unwrap_or_default() > Duration::from_secs(60) {
+            return Err(format!("enqueued_at was {:?}", enqueued_at).into());
+        }
+        if ctx.queue() != "default" {
+            return Err(format!("unexpected queue {}", ctx.queue()).into());
+        }
+        match (ctx.retries(), ctx.is_final_attempt()) {
+            (0, false) => Err("failed on the first attempt".into()),
+            (1, true) => Ok(()),
+            other => Err(format!("unexpected attempt {:?}", other).into()),
+        }
+    }
+
+    let runner = TestGuard::dummy_runner();
+    let conn = runner.connection_pool().get()?;
+    fails_until_final_attempt().enqueue(&conn)?;
+
+    runner.run_all_pending_jobs()?;
+    assert_eq!(Err(JobsFailed(1)), runner.check_for_failed_jobs());
+
+    diesel::sql_query("UPDATE background_jobs SET last_retry = '1970-01-01'").execute(&conn)?;
+    runner.run_all_pending_jobs()?;
+    runner.check_for_failed_jobs()?;
+    Ok(())
+}
+
+#[test]
+fn job_errors_can_be_sent_between_threads() -> Fallible<()> {
+    #[swirl::background_job]
+    fn fails_on_another_thread() -> Result<(), PerformError> {
+        let worker = std::thread::spawn(|| -> Result<(), PerformError> {
+            Err("failed on another thread".into())
+        });
+        worker.join().map_err(|_| "the thread panicked")??;
+        Ok(())
+    }
+
+    let runner = TestGuard::dummy_runner();
+    let conn = runner.connection_pool().get()?;
+    fails_on_another_thread().enqueue(&conn)?;
+
+    runner.run_all_pending_jobs()?;
+    assert_eq!(Err(JobsFailed(1)), runner.check_for_failed_jobs());
+    Ok(())
+}
+
+#[test]
+fn jobs_can_stream_large_objects() -> Fallible<()> {
+    use diesel::QueryableByName;
+    use std::io::Read;
+
+    #[swirl::background_job]
+    fn read_large_object(ctx: &JobContext, oid: u32, len: usize) -> Result<(), PerformError> {
+        let mut contents = Vec::new();
+        ctx.large_object_reader(oid)?.read_to_end(&mut contents)?;
+        if contents.len() == len && contents.iter().all(|&b| b == 7) {
+            Ok(())
+        } else {
+            Err(format!("read {} unexpected bytes", contents.len()).into())
+        }
+    }
+
+    #[derive(QueryableByName)]
+    struct LargeObject {
+        #[sql_type = "diesel::sql_types::Oid"]
+        oid: u32,
+    }
+
+    let runner = TestGuard::dummy_runner();
+    let conn = runner.connection_pool().get()?;
+    // Larger than a single chunk, so the reader has to fetch more than once
+    let len = 600 * 1024;
+    let object = diesel::sql_query("SELECT lo_from_bytea(0, $1) AS oid")
+        .bind::<diesel::sql_types::Binary, _>(vec![7u8; len])
+        .get_result::<LargeObject>(&conn)?;
+    read_large_object(object.oid, len).enqueue(&conn)?;
+
+    runner.run_all_pending_jobs()?;
+    let result = runner.check_for_failed_jobs();
+    diesel::sql_query("SELECT lo_unlink($1)")
+        .bind::<diesel::sql_types::Oid, _>(object.oid)
+        .execute(&conn)?;
+    result?;
+    Ok(())
+}
+
+#[test]
+fn jobs_can_configure_how_they_are_retried() -> Fallible<()> {
+    use swirl::schema::{background_jobs, swirl_failed_jobs};
+
+    #[swirl::background_job(
+        max_retries = 1,
+        retry_policy = swirl::RetryPolicy::Fixed(std::time::Duration::from_secs(0)),
+    )]
+    fn gives_up_after_one_retry() -> Result<(), PerformError> {
+        Err("failed".into())
+    }
+
+    let runner = TestGuard::dummy_runner();
+    let conn = runner.connection_pool().get()?;
+    gives_up_after_one_retry().enqueue(&conn)?;
+
+    // The job is due again as soon as it fails, so it may be retried by the
+    // first run already
+    for _ in 0..2 {
+        runner.run_all_pending_jobs()?;
+        let _ = runner.check_for_failed_jobs();
+    }
+    runner.run_all_pending_jobs()?;
+    runner.check_for_failed_jobs()?;
+
+    let queued = background_jobs::table.count().get_result(&conn);
+    assert_eq!(Ok(0), queued);
+    let retries = swirl_failed_jobs::table
+        .select(swirl_failed_jobs::retries)
+        .first::<i32>(&conn);
+    assert_eq!(Ok(2), retries);
+    Ok(())
+}
+
+#[test]
+fn job_owners_are_recorded_with_failures() -> Fallible<()> {
+    use swirl::admin;
+
+    #[swirl::background_job(owner = "team-billing", max_retries = 0)]
+    fn owned_job() -> Result<(), PerformError> {
+        Err("failed".into())
+    }
+
+    assert_eq!(Some("team-billing"), <owned_job::Job as Job>::OWNER);
+
+    let runner = TestGuard::builder(()).failure_samples(1).build();
+    let conn = runner.connection_pool().get()?;
+    owned_job().enqueue(&conn)?;
+    runner.run_all_pending_jobs()?;
+    runner.check_for_failed_jobs()?;
+
+    let failed = admin::list_failed_jobs(&conn, 0, 1)?;
+    assert_eq!(Some("team-billing"), failed[0].owner.as_deref());
+    let samples = admin::list_failure_samples(&conn, Some("owned_job"), 1)?;
+    assert_eq!(Some("team-billing"), samples[0].owner.as_deref());
+    Ok(())
+}
+
+#[test]
+fn jobs_which_exceed_their_timeout_are_failed() -> Fallible<()> {
+    use std::time::Duration;
+
+    #[swirl::background_job(timeout = "100ms")]
+    fn slow_job() -> Result<(), PerformError> {
+        std::thread::sleep(Duration::from_secs(1));
+        Ok(())
+    }
+
+    assert_eq!(
+        Some(Duration::from_millis(100)),
+        <slow_job::Job as Job>::TIMEOUT
+    );
+
+    let runner = TestGuard::dummy_runner();
+    let conn = runner.connection_pool().get()?;
+    slow_job().enqueue(&conn)?;
+    runner.run_all_pending_jobs()?;
+    assert_eq!(Err(JobsFailed(1)), runner.check_for_failed_jobs());
+    Ok(())
+}
+
+#[test]
+fn jobs_can_be_async() -> Fallible<()> {
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    /// Returns `Pending` the first time it is polled
+    struct YieldOnce(bool);
+
+    impl Future for YieldOnce {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+            if self.0 {
+                Poll::Ready(())
+            } else {
+                self.0 = true;
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+        }
+    }
+
+    #[swirl::background_job]
+    async fn async_job(env: &String, ctx: &JobContext, arg: String) -> Result<(), PerformError> {
+        YieldOnce(false).await;
+        diesel::select(diesel::dsl::now).execute(ctx.connection())?;
+        if *env == arg {
+            Ok(())
+        } else {
+            Err("arg wasn't env!".into())
+        }
+    }
+
+    let runner = TestGuard::runner("a".to_string());
+    let conn = runner.connection_pool().get()?;
+    async_job("a".into()).enqueue(&conn)?;
+    async_job("b".into()).enqueue(&conn)?;
+
+    runner.run_all_pending_jobs()?;
+    assert_eq!(Err(JobsFailed(1)), runner.check_for_failed_jobs());
+    Ok(())
+}
+
+#[test]
+fn values_returned_by_jobs_can_be_looked_up() -> Fallible<()> {
+    #[swirl::background_job]
+    fn generate_report(name: String) -> Result<Vec<String>, PerformError> {
+        if name.is_empty() {
+            return Err("no name given".into());
+        }
+        Ok(vec![format!("report for {}", name)])
+    }
+
+    #[swirl::background_job(result_retention = "0s")]
+    async fn generate_expired_report(ctx: &JobContext) -> Result<i64, PerformError> {
+        Ok(ctx.job_id())
+    }
+
+    let runner = TestGuard::dummy_runner();
+    let conn = runner.connection_pool().get()?;
+    let report = generate_report("accounts".into()).enqueue(&conn)?;
+    let failed = generate_report(String::new()).enqueue(&conn)?;
+    let expired = generate_expired_report().enqueue(&conn)?;
+    assert_eq!(None, report.result::<Vec<String>>(&conn)?);
+
+    runner.run_all_pending_jobs()?;
+    assert_eq!(Err(JobsFailed(1)), runner.check_for_failed_jobs());
+    let expected = vec!["report for accounts".to_string()];
+    assert_eq!(Some(expected), report.result(&conn)?);
+    assert_eq!(None, swirl::job_result::<Vec<String>>(&conn, failed.id())?);
+    assert_eq!(None, expired.result::<i64>(&conn)?);
+    assert!(report.result::<u32>(&conn).is_err());
+    assert_eq!(1, swirl::admin::purge_job_results(&conn)?);
+    Ok(())
+}
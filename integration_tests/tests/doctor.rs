@@ -0,0 +1,27 @@
+use failure::Fallible;
+
+use crate::dummy_jobs::*;
+use crate::test_guard::TestGuard;
+
+#[test]
+fn doctor_reports_no_problems_for_a_fresh_database() -> Fallible<()> {
+    let runner = TestGuard::dummy_runner();
+    let conn = runner.connection_pool().get()?;
+    failure_job().enqueue(&conn)?;
+
+    let report = swirl::doctor(&conn)?;
+    assert!(report.is_healthy(), "{}", report);
+    Ok(())
+}
+
+#[test]
+fn running_migrations_again_does_nothing() -> Fallible<()> {
+    let runner = TestGuard::dummy_runner();
+    let conn = runner.connection_pool().get()?;
+
+    swirl::run_migrations(&conn)?;
+
+    let report = swirl::doctor(&conn)?;
+    assert!(report.is_healthy(), "{}", report);
+    Ok(())
+}
@@ -1,8 +1,7 @@
 pub use swirl::Job;
 
 use swirl::errors::PerformError;
-
-use crate::sync::Barrier;
+use swirl::testing::sync::{Barrier, Sequence};
 
 /// A job which takes a barrier as its environment and calls wait on it before
 /// succeeding
@@ -12,6 +11,14 @@ pub fn barrier_job(env: &Barrier) -> Result<(), PerformError> {
     Ok(())
 }
 
+/// A job which takes a sequence as its environment and runs the given step of
+/// it before succeeding
+#[swirl::background_job]
+pub fn sequenced_job(env: &Sequence, step: usize) -> Result<(), PerformError> {
+    env.step(step, || ());
+    Ok(())
+}
+
 /// A job which always fails
 #[swirl::background_job]
 pub fn failure_job() -> Result<(), PerformError> {
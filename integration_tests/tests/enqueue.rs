@@ -0,0 +1,435 @@
+use assert_matches::assert_matches;
+use diesel::prelude::*;
+use failure::Fallible;
+use serde_json::{json, Value};
+use swirl::admin::{self, PreviewOptions};
+use swirl::schema::background_jobs;
+use swirl::{
+    enqueue_superseding, insert_raw_job, EnqueueError, EnqueueMiddleware, EnqueueOptions,
+    PerformError,
+};
+
+use crate::dummy_jobs::*;
+use crate::test_guard::TestGuard;
+
+struct TestMiddleware;
+
+impl EnqueueMiddleware for TestMiddleware {
+    fn before_enqueue(
+        &self,
+        job_type: &str,
+        _data: &Value,
+        options: &mut EnqueueOptions,
+    ) -> Result<(), EnqueueError> {
+        match job_type {
+            "vetoed_job" => Err(EnqueueError::Vetoed("vetoed by middleware".into())),
+            "traced_job" => {
+                options.priority = 10;
+                options.metadata.insert("trace_id".into(), "abc123".into());
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+swirl::register_enqueue_middleware!(TestMiddleware);
+
+#[swirl::background_job]
+fn vetoed_job() -> Result<(), PerformError> {
+    Ok(())
+}
+
+#[swirl::background_job]
+fn traced_job() -> Result<(), PerformError> {
+    Ok(())
+}
+
+#[swirl::background_job]
+fn failing_job() -> Result<(), PerformError> {
+    Err("failed".into())
+}
+
+#[swirl::background_job(json_schema = r#"{"properties": {"count": {"minimum": 1}}}"#)]
+fn counted_job(count: i32) -> Result<(), PerformError> {
+    let _ = count;
+    Ok(())
+}
+
+#[test]
+fn middleware_can_veto_jobs() -> Fallible<()> {
+    let runner = TestGuard::dummy_runner();
+    let conn = runner.connection_pool().get()?;
+
+    assert_matches!(vetoed_job().enqueue(&conn), Err(EnqueueError::Vetoed(_)));
+    let job_count = background_jobs::table.count().get_result(&conn);
+    assert_eq!(Ok(0), job_count);
+    Ok(())
+}
+
+#[test]
+fn middleware_can_change_options_and_add_metadata() -> Fallible<()> {
+    let runner = TestGuard::dummy_runner();
+    let conn = runner.connection_pool().get()?;
+    traced_job().enqueue(&conn)?;
+
+    let jobs = admin::list_jobs(&conn, 0, 10, &PreviewOptions::default())?;
+    assert_eq!(10, jobs[0].priority);
+    let metadata = background_jobs::table
+        .select(background_jobs::metadata)
+        .first::<Value>(&conn)?;
+    assert_eq!(json!("abc123"), metadata["trace_id"]);
+
+    runner.run_all_pending_jobs()?;
+    runner.check_for_failed_jobs()?;
+    Ok(())
+}
+
+#[test]
+fn runners_only_run_jobs_from_their_queues() -> Fallible<()> {
+    let runner = TestGuard::builder(()).queues(vec!["other"]).build();
+    let conn = runner.connection_pool().get()?;
+    let options = EnqueueOptions {
+        queue: "other".into(),
+        ..EnqueueOptions::for_job::<failing_job::Job>()
+    };
+    failing_job().enqueue_with(&conn, options)?;
+    failing_job().enqueue(&conn)?;
+
+    runner.run_all_pending_jobs()?;
+    assert_eq!(Err(swirl::JobsFailed(1)), runner.check_for_failed_jobs());
+    Ok(())
+}
+
+#[test]
+fn runners_run_jobs_from_aliases_of_their_queues() -> Fallible<()> {
+    let runner = TestGuard::builder(()).queues(vec!["new"]).build();
+    let conn = runner.connection_pool().get()?;
+    admin::set_queue_alias(&conn, "old", "new")?;
+    for queue in &["old", "new", "unrelated"] {
+        let options = EnqueueOptions {
+            queue: queue.to_string(),
+            ..EnqueueOptions::for_job::<failing_job::Job>()
+        };
+        failing_job().enqueue_with(&conn, options)?;
+    }
+
+    runner.run_all_pending_jobs()?;
+    assert_eq!(Err(swirl::JobsFailed(2)), runner.check_for_failed_jobs());
+
+    // Aliasing the queue back reverses the alias
+    admin::set_queue_alias(&conn, "new", "old")?;
+    let aliases = admin::list_queue_aliases(&conn)?;
+    assert_eq!(1, aliases.len());
+    assert_eq!(("new", "old"), (&*aliases[0].alias, &*aliases[0].queue));
+    assert!(admin::remove_queue_alias(&conn, "new")?);
+    assert!(!admin::remove_queue_alias(&conn, "new")?);
+    Ok(())
+}
+
+#[test]
+fn removing_a_queue_alias_leaves_other_aliases() -> Fallible<()> {
+    let runner = TestGuard::dummy_runner();
+    let conn = runner.connection_pool().get()?;
+    admin::set_queue_alias(&conn, "old", "new")?;
+    admin::set_queue_alias(&conn, "older", "new")?;
+
+    assert!(admin::remove_queue_alias(&conn, "old")?);
+    let aliases = admin::list_queue_aliases(&conn)?;
+    assert_eq!(1, aliases.len());
+    assert_eq!(("older", "new"), (&*aliases[0].alias, &*aliases[0].queue));
+    Ok(())
+}
+
+#[test]
+fn runners_only_run_the_job_types_they_are_given() -> Fallible<()> {
+    let only = TestGuard::builder(())
+        .only_job_types(vec!["failing_job"])
+        .build();
+    let conn = only.connection_pool().get()?;
+    failing_job().enqueue(&conn)?;
+    failure_job().enqueue(&conn)?;
+    failure_job().enqueue(&conn)?;
+
+    only.run_all_pending_jobs()?;
+    assert_eq!(Err(swirl::JobsFailed(1)), only.check_for_failed_jobs());
+
+    let except = TestGuard::builder(())
+        .except_job_types(vec!["failing_job"])
+        .build();
+    let conn = except.connection_pool().get()?;
+    failing_job().enqueue(&conn)?;
+    failure_job().enqueue(&conn)?;
+    failure_job().enqueue(&conn)?;
+
+    except.run_all_pending_jobs()?;
+    assert_eq!(Err(swirl::JobsFailed(2)), except.check_for_failed_jobs());
+    Ok(())
+}
+
+#[test]
+fn enqueue_time_and_location_are_recorded_in_metadata() -> Fallible<()> {
+    let runner = TestGuard::dummy_runner();
+    let conn = runner.connection_pool().get()?;
+    let line = line!() + 1;
+    failure_job().enqueue(&conn)?;
+
+    let metadata = background_jobs::table
+        .select(background_jobs::metadata)
+        .first::<Value>(&conn)?;
+    assert!(metadata["enqueued_at"].is_f64());
+    let location = metadata["enqueued_from"].as_str().unwrap();
+    assert!(location.contains(&format!("enqueue.rs:{}:", line)));
+    Ok(())
+}
+
+#[test]
+fn runners_skip_jobs_requiring_a_newer_version() -> Fallible<()> {
+    let runner = TestGuard::builder(()).worker_version("1.5").build();
+    let conn = runner.connection_pool().get()?;
+    for version in &["1.4", "1.10"] {
+        let options = EnqueueOptions {
+            min_worker_version: Some(version.to_string()),
+            ..EnqueueOptions::for_job::<failing_job::Job>()
+        };
+        failing_job().enqueue_with(&conn, options)?;
+    }
+
+    runner.run_all_pending_jobs()?;
+    assert_eq!(Err(swirl::JobsFailed(1)), runner.check_for_failed_jobs());
+
+    let options = EnqueueOptions {
+        min_worker_version: Some("latest".into()),
+        ..EnqueueOptions::for_job::<failing_job::Job>()
+    };
+    assert_matches!(
+        failing_job().enqueue_with(&conn, options),
+        Err(EnqueueError::InvalidVersion(_))
+    );
+    Ok(())
+}
+
+#[test]
+fn scheduled_jobs_are_not_run_until_they_are_due() -> Fallible<()> {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    let runner = TestGuard::dummy_runner();
+    let conn = runner.connection_pool().get()?;
+    failing_job().enqueue_in(&conn, Duration::from_secs(60 * 60))?;
+    failing_job().enqueue_at(&conn, UNIX_EPOCH + Duration::from_secs(60 * 60))?;
+
+    runner.run_all_pending_jobs()?;
+    assert_eq!(Err(swirl::JobsFailed(1)), runner.check_for_failed_jobs());
+    Ok(())
+}
+
+#[test]
+fn tenants_cannot_enqueue_more_jobs_than_their_quota() -> Fallible<()> {
+    let runner = TestGuard::dummy_runner();
+    let conn = runner.connection_pool().get()?;
+    admin::set_tenant_quota(&conn, "acme", Some(2))?;
+    let enqueue_for = |tenant: &str| {
+        let mut options = EnqueueOptions::for_job::<traced_job::Job>();
+        options.metadata.insert("tenant".into(), tenant.into());
+        traced_job().enqueue_with(&conn, options)
+    };
+
+    enqueue_for("acme")?;
+    enqueue_for("acme")?;
+    assert_matches!(
+        enqueue_for("acme"),
+        Err(EnqueueError::QuotaExceeded(ref tenant)) if tenant == "acme"
+    );
+    enqueue_for("globex")?;
+
+    let tenants = admin::list_tenants(&conn)?;
+    let counts = tenants
+        .iter()
+        .map(|t| (t.tenant.as_str(), t.pending_jobs, t.max_pending_jobs))
+        .collect::<Vec<_>>();
+    assert_eq!(vec![("acme", 2, Some(2)), ("globex", 1, None)], counts);
+
+    runner.run_all_pending_jobs()?;
+    runner.check_for_failed_jobs()?;
+    let pending = admin::list_tenants(&conn)?
+        .iter()
+        .map(|t| t.pending_jobs)
+        .collect::<Vec<_>>();
+    assert_eq!(vec![0, 0], pending);
+    enqueue_for("acme")?;
+    Ok(())
+}
+
+#[test]
+fn jobs_with_the_same_unique_key_are_only_enqueued_once() -> Fallible<()> {
+    let runner = TestGuard::dummy_runner();
+    let conn = runner.connection_pool().get()?;
+
+    assert!(failure_job().enqueue_unique(&conn, "a")?.is_some());
+    assert!(failure_job().enqueue_unique(&conn, "a")?.is_none());
+    assert!(failure_job().enqueue_unique(&conn, "b")?.is_some());
+    assert!(failing_job().enqueue_unique(&conn, "a")?.is_some());
+    failure_job().enqueue(&conn)?;
+    failure_job().enqueue(&conn)?;
+
+    let queued_jobs = background_jobs::table.count().get_result::<i64>(&conn)?;
+    assert_eq!(5, queued_jobs);
+    Ok(())
+}
+
+#[test]
+fn superseding_jobs_cancel_the_pending_job_with_the_same_key() -> Fallible<()> {
+    let runner = TestGuard::dummy_runner();
+    let conn = runner.connection_pool().get()?;
+
+    enqueue_superseding(&conn, "a", counted_job(1))?;
+    enqueue_superseding(&conn, "b", counted_job(2))?;
+    let latest = enqueue_superseding(&conn, "a", counted_job(3))?;
+    enqueue_superseding(&conn, "a", failing_job())?;
+
+    let queued = background_jobs::table
+        .filter(background_jobs::job_type.eq("counted_job"))
+        .order(background_jobs::id)
+        .select((background_jobs::id, background_jobs::data))
+        .load::<(i64, Value)>(&conn)?;
+    assert_eq!(2, queued.len());
+    assert_eq!(json!({"count": 2}), queued[0].1);
+    assert_eq!((latest.id(), json!({"count": 3})), queued[1]);
+
+    let queued = background_jobs::table.count().get_result::<i64>(&conn)?;
+    assert_eq!(3, queued);
+    Ok(())
+}
+
+#[test]
+fn superseding_jobs_do_not_cancel_running_jobs() -> Fallible<()> {
+    use swirl::testing::sync::Barrier;
+
+    let barrier = Barrier::new(2);
+    let runner = TestGuard::runner(barrier.clone());
+    let conn = runner.connection_pool().get()?;
+
+    enqueue_superseding(&conn, "a", barrier_job())?;
+    // Waits for the job to start, where it stays until the barrier is passed
+    runner.run_all_pending_jobs()?;
+    // Threads which found no job may still be fetching, and must not start
+    // the jobs enqueued next
+    swirl::queue("default").pause(&conn)?;
+    enqueue_superseding(&conn, "a", barrier_job())?;
+    enqueue_superseding(&conn, "a", barrier_job())?;
+
+    let queued = background_jobs::table.count().get_result(&conn);
+    let pending = background_jobs::table
+        .select(background_jobs::id)
+        .for_update()
+        .skip_locked()
+        .load::<i64>(&conn)
+        .map(|v| v.len());
+    barrier.wait();
+
+    assert_eq!(Ok(2), queued);
+    assert_eq!(Ok(1), pending);
+    Ok(())
+}
+
+#[test]
+fn enqueue_returning_returns_the_stored_job() -> Fallible<()> {
+    let runner = TestGuard::dummy_runner();
+    let conn = runner.connection_pool().get()?;
+
+    let job = traced_job().enqueue_returning(&conn)?;
+
+    let (id, created_at) = background_jobs::table
+        .select((background_jobs::id, background_jobs::created_at))
+        .first::<(i64, std::time::SystemTime)>(&conn)?;
+    assert_eq!(id, job.id);
+    assert_eq!("traced_job", job.job_type);
+    assert_eq!("default", job.queue);
+    assert_eq!(10, job.priority);
+    assert_eq!(created_at, job.enqueued_at);
+    assert!(job.run_at >= job.enqueued_at);
+    Ok(())
+}
+
+#[test]
+fn enqueue_returns_a_handle_to_the_new_job() -> Fallible<()> {
+    let runner = TestGuard::dummy_runner();
+    let conn = runner.connection_pool().get()?;
+
+    let first = failure_job().enqueue(&conn)?;
+    let second = failure_job().enqueue_in(&conn, std::time::Duration::from_secs(60))?;
+
+    let ids = background_jobs::table
+        .select(background_jobs::id)
+        .order(background_jobs::id)
+        .load::<i64>(&conn)?;
+    assert_eq!(vec![first.id(), second.id()], ids);
+    Ok(())
+}
+
+#[test]
+fn raw_jobs_are_validated_against_their_job_type() -> Fallible<()> {
+    let runner = TestGuard::dummy_runner();
+    let conn = runner.connection_pool().get()?;
+    let options = EnqueueOptions::for_job::<counted_job::Job>;
+
+    insert_raw_job(&conn, "counted_job", json!({ "count": 2 }), options())?;
+    assert_matches!(
+        insert_raw_job(&conn, "counted_job", json!({ "count": "two" }), options()),
+        Err(EnqueueError::InvalidArguments(_))
+    );
+    assert_matches!(
+        insert_raw_job(&conn, "counted_job", json!({ "count": 0 }), options()),
+        Err(EnqueueError::InvalidArguments(_))
+    );
+    insert_raw_job(&conn, "unknown_job", json!("anything"), options())?;
+
+    let job_types = background_jobs::table
+        .select(background_jobs::job_type)
+        .order(background_jobs::id)
+        .load::<String>(&conn)?;
+    assert_eq!(vec!["counted_job", "unknown_job"], job_types);
+    Ok(())
+}
+
+#[test]
+fn enqueue_metrics_count_inserts_and_measure_payloads() -> Fallible<()> {
+    use std::collections::HashMap;
+
+    #[swirl::background_job]
+    fn measured_job(_payload: String) -> Result<(), PerformError> {
+        Ok(())
+    }
+
+    #[swirl::background_job]
+    fn unserializable_job(
+        _map: std::collections::HashMap<Vec<u8>, i32>,
+    ) -> Result<(), PerformError> {
+        Ok(())
+    }
+
+    let runner = TestGuard::dummy_runner();
+    let conn = runner.connection_pool().get()?;
+    measured_job("a".repeat(1000)).enqueue_unique(&conn, "a")?;
+    measured_job("a".repeat(1000)).enqueue_unique(&conn, "a")?;
+    let mut map = HashMap::new();
+    map.insert(vec![1], 1);
+    assert_matches!(
+        unserializable_job(map).enqueue(&conn),
+        Err(EnqueueError::SerializationError(_))
+    );
+
+    let snapshot = swirl::enqueue_metrics_snapshot();
+    let metrics = &snapshot.job_types["measured_job"];
+    assert_eq!(1, metrics.enqueued);
+    assert_eq!(1, metrics.duplicates);
+    assert_eq!(2, metrics.duration.count());
+    assert_eq!(Some((1024, 2)), metrics.payload_bytes.buckets().nth(1));
+    let metrics = &snapshot.job_types["unserializable_job"];
+    assert_eq!(1, metrics.serialization_failures);
+    assert_eq!(0, metrics.payload_bytes.count());
+
+    let rendered = snapshot.to_string();
+    assert!(rendered.contains("swirl_jobs_enqueued_total{job_type=\"measured_job\"} 1"));
+    Ok(())
+}
@@ -0,0 +1,91 @@
+use assert_matches::assert_matches;
+use diesel::prelude::*;
+use failure::Fallible;
+use serde_json::{json, Value};
+use swirl::interop::{Format, Importer};
+use swirl::schema::background_jobs;
+use swirl::{ImportError, PerformError};
+
+use crate::test_guard::TestGuard;
+
+#[swirl::background_job]
+fn resize_image(path: String, width: u32) -> Result<(), PerformError> {
+    if path.is_empty() || width == 0 {
+        return Err("nothing to resize".into());
+    }
+    Ok(())
+}
+
+fn importer() -> Importer {
+    Importer::new()
+        .job::<resize_image::Job>("ResizeWorker", &["path", "width"])
+        .job::<resize_image::Job>("app::jobs::ResizeImage", &[])
+}
+
+#[test]
+fn foreign_jobs_are_imported_and_run() -> Fallible<()> {
+    let runner = TestGuard::dummy_runner();
+    let conn = runner.connection_pool().get()?;
+    let faktory = json!({
+        "jid": "b4a1c3d2",
+        "jobtype": "ResizeWorker",
+        "args": ["cat.png", 200],
+        "queue": "default",
+    });
+    let background_jobs = json!({
+        "id": "5e3c8a4e-9c8e-4b3e-8f4c-1f0e2d3c4b5a",
+        "name": "app::jobs::ResizeImage",
+        "queue": "images",
+        "args": { "path": "dog.png", "width": 100 },
+    });
+
+    let importer = importer();
+    importer.import(&conn, Format::Faktory, &faktory)?;
+    importer.import(&conn, Format::BackgroundJobs, &background_jobs)?;
+
+    let imported = background_jobs::table
+        .select((background_jobs::data, background_jobs::metadata))
+        .order(background_jobs::id)
+        .load::<(Value, Value)>(&conn)?;
+    assert_eq!(json!({ "path": "cat.png", "width": 200 }), imported[0].0);
+    assert_eq!(
+        json!({ "format": "faktory", "id": "b4a1c3d2" }),
+        imported[0].1["imported_from"]
+    );
+    assert_eq!(json!({ "path": "dog.png", "width": 100 }), imported[1].0);
+    assert_eq!(
+        json!("background_jobs"),
+        imported[1].1["imported_from"]["format"]
+    );
+
+    runner.run_all_pending_jobs()?;
+    runner.check_for_failed_jobs()?;
+    Ok(())
+}
+
+#[test]
+fn foreign_jobs_which_cant_be_mapped_are_rejected() -> Fallible<()> {
+    let runner = TestGuard::dummy_runner();
+    let conn = runner.connection_pool().get()?;
+    let importer = importer();
+
+    let unmapped = json!({ "jid": "1", "jobtype": "SendEmail", "args": [] });
+    assert_matches!(
+        importer.import(&conn, Format::Faktory, &unmapped),
+        Err(ImportError::UnmappedJobType(_))
+    );
+    let too_few_args = json!({ "jid": "2", "jobtype": "ResizeWorker", "args": ["cat.png"] });
+    assert_matches!(
+        importer.import(&conn, Format::Faktory, &too_few_args),
+        Err(ImportError::MalformedPayload(_))
+    );
+    let wrong_type = json!({ "jid": "3", "jobtype": "ResizeWorker", "args": ["cat.png", "big"] });
+    assert_matches!(
+        importer.import(&conn, Format::Faktory, &wrong_type),
+        Err(ImportError::InvalidArguments(_))
+    );
+
+    let job_count = background_jobs::table.count().get_result(&conn);
+    assert_eq!(Ok(0), job_count);
+    Ok(())
+}
@@ -2,9 +2,13 @@
 
 mod db;
 mod dummy_jobs;
-mod sync;
 mod test_guard;
-mod util;
 
+mod admin;
+mod backfill;
 mod codegen;
+mod doctor;
+mod enqueue;
+mod interop;
+mod maintenance;
 mod runner;
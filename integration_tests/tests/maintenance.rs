@@ -0,0 +1,80 @@
+use diesel::prelude::*;
+use failure::Fallible;
+use std::time::Duration;
+use swirl::admin;
+use swirl::heartbeat::{last_heartbeat, HeartbeatJob};
+use swirl::maintenance::{PruneFailedJobs, ReapStuckJobs, RefreshQueueStats, RefreshStats};
+use swirl::schema::background_jobs;
+use swirl::{EnqueueOptions, Job};
+
+use crate::test_guard::TestGuard;
+
+#[test]
+fn maintenance_jobs_can_be_run_by_runners_with_any_environment() -> Fallible<()> {
+    let runner = TestGuard::runner(String::from("some environment"));
+    let conn = runner.connection_pool().get()?;
+    RefreshStats.enqueue(&conn)?;
+    ReapStuckJobs {
+        max_duration: Duration::from_secs(60 * 60),
+    }
+    .enqueue(&conn)?;
+    PruneFailedJobs {
+        max_age: Duration::from_secs(7 * 24 * 60 * 60),
+    }
+    .enqueue(&conn)?;
+
+    runner.run_all_pending_jobs()?;
+    runner.check_for_failed_jobs()?;
+
+    let job_count = background_jobs::table.count().get_result(&conn);
+    assert_eq!(Ok(0), job_count);
+    Ok(())
+}
+
+#[test]
+fn queue_stats_are_counted_when_refreshed() -> Fallible<()> {
+    let runner = TestGuard::builder(()).queues(vec!["maintenance"]).build();
+    let conn = runner.connection_pool().get()?;
+    RefreshStats.enqueue(&conn)?;
+    RefreshStats.enqueue(&conn)?;
+    let options = EnqueueOptions {
+        queue: "maintenance".into(),
+        ..EnqueueOptions::for_job::<RefreshQueueStats>()
+    };
+    RefreshQueueStats.enqueue_with(&conn, options)?;
+    assert!(admin::queue_stats(&conn)?.is_empty());
+
+    runner.run_all_pending_jobs()?;
+    runner.check_for_failed_jobs()?;
+
+    let stats = admin::queue_stats(&conn)?;
+    let counts = stats
+        .iter()
+        .map(|s| (s.job_type.as_str(), s.queue.as_str(), s.jobs))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        vec![
+            ("swirl_refresh_queue_stats", "maintenance", 1),
+            ("swirl_refresh_stats", "default", 2),
+        ],
+        counts
+    );
+    Ok(())
+}
+
+#[test]
+fn heartbeats_record_the_last_job_which_got_through_each_queue() -> Fallible<()> {
+    let runner = TestGuard::runner(String::from("some environment"));
+    let conn = runner.connection_pool().get()?;
+    assert_eq!(None, last_heartbeat(&conn)?);
+    swirl::queue("critical").enqueue(&conn, HeartbeatJob)?;
+
+    runner.run_all_pending_jobs()?;
+    runner.check_for_failed_jobs()?;
+
+    let heartbeat = last_heartbeat(&conn)?.expect("no heartbeat was recorded");
+    assert_eq!("critical", heartbeat.queue);
+    assert!(heartbeat.age < Duration::from_secs(60));
+    assert!(heartbeat.latency() < Duration::from_secs(60));
+    Ok(())
+}
@@ -1,14 +1,21 @@
 use assert_matches::assert_matches;
 use diesel::prelude::*;
 use failure::Fallible;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::sync_channel;
+use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::Duration;
+use swirl::admin;
 use swirl::schema::*;
-use swirl::JobsFailed;
+use swirl::testing::sync::{Barrier, Sequence};
+use swirl::{
+    BackgroundJob, Cancellation, DefaultFetchQuery, EnqueueOptions, Failure, FailureKind,
+    FairFetchQuery, FetchQuery, FetchRequest, JobContext, JobOutcome, JobStatus, JobsFailed, Queue,
+    RetryPolicy, SubTask, WaitError,
+};
 
 use crate::dummy_jobs::*;
-use crate::sync::Barrier;
 use crate::test_guard::TestGuard;
 
 #[test]
@@ -36,6 +43,32 @@ fn run_all_pending_jobs_returns_when_all_jobs_enqueued() -> Fallible<()> {
     Ok(())
 }
 
+#[test]
+fn sequences_interleave_jobs_with_the_test() -> Fallible<()> {
+    let sequence = Sequence::new();
+    let runner = TestGuard::runner(sequence.clone());
+    let conn = runner.connection_pool().get()?;
+    sequenced_job(1).enqueue(&conn)?;
+
+    runner.run_all_pending_jobs()?;
+
+    sequence.step(0, || {
+        let unlocked_job_count = background_jobs::table
+            .select(background_jobs::id)
+            .for_update()
+            .skip_locked()
+            .load::<i64>(&conn)
+            .map(|v| v.len());
+        assert_eq!(Ok(0), unlocked_job_count);
+    });
+    sequence.step(2, || ());
+
+    runner.check_for_failed_jobs()?;
+    let remaining_jobs = background_jobs::table.count().get_result(&conn);
+    assert_eq!(Ok(0), remaining_jobs);
+    Ok(())
+}
+
 #[test]
 fn check_for_failed_jobs_blocks_until_all_queued_jobs_are_finished() -> Fallible<()> {
     let barrier = Barrier::new(3);
@@ -104,7 +137,10 @@ fn run_all_pending_jobs_errs_if_jobs_dont_start_in_timeout() -> Fallible<()> {
     barrier_job().enqueue(&conn)?;
 
     let run_result = runner.run_all_pending_jobs();
-    assert_matches!(run_result, Err(swirl::FetchError::NoMessageReceived));
+    assert_matches!(run_result, Err(swirl::FetchError::NoMessageReceived(_)));
+    let context = run_result.unwrap_err().context().clone();
+    assert_eq!(1, context.thread_count);
+    assert_eq!(1, context.busy_threads);
 
     // Make sure the jobs actually run so we don't panic on drop
     barrier.wait();
@@ -135,7 +171,824 @@ fn jobs_failing_to_load_doesnt_panic_threads() -> Fallible<()> {
         diesel::sql_query("SET default_transaction_read_only = 'f'").execute(&conn)?;
     }
 
-    assert_matches!(run_result, Err(swirl::FetchError::FailedLoadingJob(_)));
+    assert_matches!(run_result, Err(swirl::FetchError::FailedLoadingJob(..)));
+    runner.check_for_failed_jobs()?;
+    Ok(())
+}
+
+#[test]
+fn jobs_can_be_registered_with_their_own_environment() -> Fallible<()> {
+    #[swirl::background_job]
+    fn needs_string_env(env: &String) -> Result<(), swirl::PerformError> {
+        assert_eq!("job environment", env);
+        Ok(())
+    }
+
+    let runner = TestGuard::builder(())
+        .register_with::<needs_string_env::Job>("job environment".into())
+        .build();
+    let conn = runner.connection_pool().get()?;
+    needs_string_env().enqueue(&conn)?;
+    failure_job().enqueue(&conn)?;
+
+    runner.run_all_pending_jobs()?;
+    assert_eq!(Err(JobsFailed(1)), runner.check_for_failed_jobs());
+    Ok(())
+}
+
+#[test]
+fn job_type_concurrency_limits_are_respected() -> Fallible<()> {
+    let barrier = Barrier::new(2);
+    let runner = TestGuard::builder(barrier.clone())
+        .thread_count(2)
+        .job_type_concurrency_limit("barrier_job", 1)
+        .build();
+    let conn = runner.connection_pool().get()?;
+    barrier_job().enqueue(&conn)?;
+    barrier_job().enqueue(&conn)?;
+
+    runner.run_all_pending_jobs()?;
+
+    let unlocked_job_count = background_jobs::table
+        .select(background_jobs::id)
+        .for_update()
+        .skip_locked()
+        .load::<i64>(&conn)
+        .map(|v| v.len());
+    assert_eq!(Ok(1), unlocked_job_count);
+
+    barrier.wait();
+    Ok(())
+}
+
+#[test]
+fn jobs_with_the_same_concurrency_key_do_not_run_at_once() -> Fallible<()> {
+    let barrier = Barrier::new(3);
+    let runner = TestGuard::builder(barrier.clone()).thread_count(3).build();
+    let conn = runner.connection_pool().get()?;
+    for key in &["account-1", "account-1", "account-2"] {
+        let options = EnqueueOptions {
+            concurrency_key: Some(key.to_string()),
+            ..EnqueueOptions::default()
+        };
+        barrier_job().enqueue_with(&conn, options)?;
+    }
+
+    runner.run_all_pending_jobs()?;
+
+    let unlocked_job_count = background_jobs::table
+        .select(background_jobs::id)
+        .for_update()
+        .skip_locked()
+        .load::<i64>(&conn)
+        .map(|v| v.len());
+    assert_eq!(Ok(1), unlocked_job_count);
+
+    barrier.wait();
+    Ok(())
+}
+
+#[test]
+fn fair_fetch_query_takes_turns_between_job_types() -> Fallible<()> {
+    #[swirl::background_job]
+    fn bulk_job(env: &Arc<Mutex<Vec<String>>>) -> Result<(), swirl::PerformError> {
+        env.lock().unwrap().push("bulk".into());
+        Ok(())
+    }
+
+    #[swirl::background_job]
+    fn urgent_job(env: &Arc<Mutex<Vec<String>>>) -> Result<(), swirl::PerformError> {
+        env.lock().unwrap().push("urgent".into());
+        Ok(())
+    }
+
+    let ran = Arc::new(Mutex::new(Vec::<String>::new()));
+    let runner = TestGuard::builder(ran.clone())
+        .thread_count(1)
+        .fetcher(FairFetchQuery::by_job_type())
+        .build();
+    let conn = runner.connection_pool().get()?;
+    for _ in 0..3 {
+        bulk_job().enqueue(&conn)?;
+    }
+    urgent_job().enqueue(&conn)?;
+
+    runner.run_all_pending_jobs()?;
+    runner.check_for_failed_jobs()?;
+
+    assert_eq!(vec!["bulk", "urgent", "bulk", "bulk"], *ran.lock().unwrap());
+    Ok(())
+}
+
+#[test]
+fn waiting_for_completion_returns_once_the_job_finishes() -> Fallible<()> {
+    let barrier = Barrier::new(2);
+    let runner = TestGuard::builder(barrier.clone())
+        .notify_on_completion(true)
+        .build();
+    let conn = runner.connection_pool().get()?;
+    let handle = barrier_job().enqueue(&conn)?;
+    runner.run_all_pending_jobs()?;
+
+    let waiter = {
+        let database_url = runner.database_url().to_string();
+        thread::spawn(move || handle.wait_for_completion(&database_url, Duration::from_secs(10)))
+    };
+    barrier.wait();
+    assert_eq!(JobOutcome::Succeeded, waiter.join().unwrap()?);
+
+    let never_run = barrier_job().enqueue(&conn)?;
+    assert_matches!(
+        never_run.wait_for_completion(runner.database_url(), Duration::from_millis(100)),
+        Err(WaitError::Timeout)
+    );
+    Ok(())
+}
+
+#[test]
+fn metrics_count_jobs_and_report_the_queue_depth() -> Fallible<()> {
+    let runner = TestGuard::dummy_runner();
+    let conn = runner.connection_pool().get()?;
+    failure_job().enqueue(&conn)?;
+    runner.run_all_pending_jobs()?;
+    assert_eq!(Err(JobsFailed(1)), runner.check_for_failed_jobs());
+    failure_job().enqueue(&conn)?;
+
+    let snapshot = runner.metrics_snapshot().unwrap();
+    let metrics = &snapshot.job_types["failure_job"];
+    assert_eq!(1, metrics.started);
+    assert_eq!(0, metrics.succeeded);
+    assert_eq!(1, metrics.failed);
+    assert_eq!(1, metrics.duration.count());
+    assert_eq!(2, metrics.queue_depth);
+    assert!(metrics.oldest_job_age.is_some());
+
+    let rendered = snapshot.to_string();
+    assert!(rendered.contains("swirl_jobs_failed_total{job_type=\"failure_job\"} 1"));
+    assert!(rendered.contains("swirl_queue_depth{job_type=\"failure_job\"} 2"));
+    Ok(())
+}
+
+#[test]
+fn jobs_which_are_almost_due_are_run_with_early_execution_slack() -> Fallible<()> {
+    let runner = TestGuard::builder(())
+        .early_execution_slack(Duration::from_secs(60))
+        .build();
+    let conn = runner.connection_pool().get()?;
+    failure_job().enqueue(&conn)?;
+    // A job which has failed once is due two minutes after it last failed
+    diesel::sql_query(
+        "UPDATE background_jobs SET retries = 1, last_retry = NOW() - interval '90 seconds'",
+    )
+    .execute(&conn)?;
+
+    runner.run_all_pending_jobs()?;
+    assert_eq!(Err(JobsFailed(1)), runner.check_for_failed_jobs());
+
+    let retries = background_jobs::table
+        .select(background_jobs::retries)
+        .first::<i32>(&conn);
+    assert_eq!(Ok(2), retries);
+    Ok(())
+}
+
+#[test]
+fn failed_jobs_can_be_recorded_and_replayed() -> Fallible<()> {
+    let dir = std::env::temp_dir().join(format!("swirl-recordings-{}", std::process::id()));
+    std::fs::create_dir_all(&dir)?;
+    let runner = TestGuard::builder(()).record_failures_to(&dir).build();
+    let conn = runner.connection_pool().get()?;
+    failure_job().enqueue(&conn)?;
+
+    runner.run_all_pending_jobs()?;
+    assert_eq!(Err(JobsFailed(1)), runner.check_for_failed_jobs());
+
+    let recordings = std::fs::read_dir(&dir)?.collect::<Result<Vec<_>, _>>()?;
+    assert_eq!(1, recordings.len());
+    let recording = swirl::replay::Recording::load(recordings[0].path())?;
+    assert_eq!("failure_job", recording.job_type);
+    assert_eq!("failed", recording.error);
+
+    let replayed = runner.replay(recordings[0].path());
+    std::fs::remove_dir_all(&dir)?;
+    assert_eq!("failed", replayed.unwrap_err().to_string());
+    Ok(())
+}
+
+#[test]
+fn jobs_are_completed_with_asynchronous_completions() -> Fallible<()> {
+    #[swirl::background_job]
+    fn succeeding_job() -> Result<(), swirl::PerformError> {
+        Ok(())
+    }
+
+    let runner = TestGuard::builder(())
+        .asynchronous_completions(true)
+        .build();
+    let conn = runner.connection_pool().get()?;
+    succeeding_job().enqueue(&conn)?;
+    failure_job().enqueue(&conn)?;
+
+    runner.run_all_pending_jobs()?;
+    assert_eq!(Err(JobsFailed(1)), runner.check_for_failed_jobs());
+
+    let job_count = background_jobs::table.count().get_result(&conn);
+    assert_eq!(Ok(1), job_count);
+    Ok(())
+}
+
+#[test]
+fn sub_tasks_run_inline_when_the_runner_has_no_threads_to_spare() -> Fallible<()> {
+    #[swirl::background_job]
+    fn inline_sub_task_job(ctx: &JobContext) -> Result<(), swirl::PerformError> {
+        let job_thread = thread::current().id();
+        let threads = ctx.scope(|s| {
+            let tasks = (0..3)
+                .map(|_| s.spawn(|| thread::current().id()))
+                .collect::<Vec<_>>();
+            tasks.into_iter().map(SubTask::join).collect::<Vec<_>>()
+        });
+        if threads.iter().all(|&t| t == job_thread) {
+            Ok(())
+        } else {
+            Err("sub-task was given a thread of its own".into())
+        }
+    }
+
+    let runner = TestGuard::builder(()).thread_count(1).build();
+    let conn = runner.connection_pool().get()?;
+    inline_sub_task_job().enqueue(&conn)?;
+
+    runner.run_all_pending_jobs()?;
+    runner.check_for_failed_jobs()?;
+    Ok(())
+}
+
+#[test]
+fn sub_tasks_use_the_threads_the_runner_has_to_spare() -> Fallible<()> {
+    #[swirl::background_job]
+    fn parallel_sub_task_job(ctx: &JobContext) -> Result<(), swirl::PerformError> {
+        // Both sub-tasks must run at once for either to get past the barrier
+        let barrier = Barrier::new(2);
+        ctx.scope(|s| {
+            let first = s.spawn(|| barrier.wait());
+            let second = s.spawn(|| barrier.wait());
+            first.join();
+            second.join();
+        });
+        Ok(())
+    }
+
+    let runner = TestGuard::builder(()).thread_count(3).build();
+    let conn = runner.connection_pool().get()?;
+    parallel_sub_task_job().enqueue(&conn)?;
+
+    runner.run_all_pending_jobs()?;
+    runner.check_for_failed_jobs()?;
+    Ok(())
+}
+
+#[test]
+fn run_until_empty_summarizes_and_records_the_run() -> Fallible<()> {
+    #[swirl::background_job]
+    fn succeeding_job() -> Result<(), swirl::PerformError> {
+        Ok(())
+    }
+
+    let runner = TestGuard::builder(())
+        .thread_count(2)
+        .record_runs(true)
+        .build();
+    let conn = runner.connection_pool().get()?;
+    for _ in 0..4 {
+        succeeding_job().enqueue(&conn)?;
+    }
+    failure_job().enqueue(&conn)?;
+
+    let summary = runner.run_until_empty()?;
+    assert_eq!((5, 1), (summary.jobs_run, summary.failures));
+    // The failed job isn't due to be retried yet
+    let job_count = background_jobs::table.count().get_result(&conn);
+    assert_eq!(Ok(1), job_count);
+
+    let runs = admin::list_runs(&conn, 10)?;
+    assert_eq!(1, runs.len());
+    assert_eq!((5, 1), (runs[0].jobs_run, runs[0].failures));
+    assert_eq!(None, runs[0].error);
+    Ok(())
+}
+
+#[test]
+fn follow_up_jobs_are_only_enqueued_if_the_job_succeeds() -> Fallible<()> {
+    #[swirl::background_job]
+    fn enqueue_follow_ups(ctx: &JobContext, fail: bool) -> Result<(), swirl::PerformError> {
+        ctx.enqueue(failure_job())?;
+        ctx.enqueue_on_commit(failure_job());
+        if fail {
+            Err("failed".into())
+        } else {
+            Ok(())
+        }
+    }
+
+    let runner = TestGuard::dummy_runner();
+    let conn = runner.connection_pool().get()?;
+    enqueue_follow_ups(false).enqueue(&conn)?;
+    enqueue_follow_ups(true).enqueue(&conn)?;
+
+    runner.run_all_pending_jobs()?;
+    // Whether the follow-up jobs were run as well depends on timing, so the
+    // number of failed jobs isn't checked
+    let _ = runner.check_for_failed_jobs();
+
+    let follow_up_count = background_jobs::table
+        .filter(background_jobs::job_type.eq("failure_job"))
+        .count()
+        .get_result(&conn);
+    assert_eq!(Ok(2), follow_up_count);
+    let remaining_count = background_jobs::table
+        .filter(background_jobs::job_type.eq("enqueue_follow_ups"))
+        .count()
+        .get_result(&conn);
+    assert_eq!(Ok(1), remaining_count);
+    Ok(())
+}
+
+#[test]
+fn follow_up_jobs_record_the_job_which_enqueued_them() -> Fallible<()> {
+    #[swirl::background_job]
+    fn enqueue_follow_up(ctx: &JobContext) -> Result<(), swirl::PerformError> {
+        ctx.enqueue(failure_job())?;
+        Ok(())
+    }
+
+    let runner = TestGuard::dummy_runner();
+    let conn = runner.connection_pool().get()?;
+    let parent = enqueue_follow_up().enqueue(&conn)?;
+
+    runner.run_all_pending_jobs()?;
+    let _ = runner.check_for_failed_jobs();
+
+    let parent_job_id = background_jobs::table
+        .filter(background_jobs::job_type.eq("failure_job"))
+        .select(background_jobs::metadata)
+        .first::<serde_json::Value>(&conn)?["parent_job_id"]
+        .as_i64();
+    assert_eq!(Some(parent.id()), parent_job_id);
+    Ok(())
+}
+
+#[test]
+fn jobs_in_paused_queues_are_not_run() -> Fallible<()> {
+    const EMAILS: Queue<'static> = Queue::new("emails");
+
+    let runner = TestGuard::dummy_runner();
+    let conn = runner.connection_pool().get()?;
+    EMAILS.pause(&conn)?;
+    assert!(EMAILS.is_paused(&conn)?);
+    EMAILS.enqueue(&conn, failure_job())?;
+    EMAILS.enqueue(&conn, failure_job())?;
+    swirl::queue("other").enqueue(&conn, failure_job())?;
+
+    runner.run_all_pending_jobs()?;
+    assert_eq!(Err(JobsFailed(1)), runner.check_for_failed_jobs());
+    assert_eq!(2, EMAILS.depth(&conn)?);
+    let failed_queues = background_jobs::table
+        .filter(background_jobs::retries.gt(0))
+        .select(background_jobs::queue)
+        .load::<String>(&conn);
+    assert_eq!(Ok(vec!["other".to_string()]), failed_queues);
+
+    assert!(EMAILS.resume(&conn)?);
+    assert!(!EMAILS.resume(&conn)?);
+    runner.run_all_pending_jobs()?;
+    assert_eq!(Err(JobsFailed(3)), runner.check_for_failed_jobs());
+    Ok(())
+}
+
+#[test]
+fn jobs_wait_for_the_jobs_they_depend_on() -> Fallible<()> {
+    #[swirl::background_job]
+    fn succeeding_job() -> Result<(), swirl::PerformError> {
+        Ok(())
+    }
+
+    let runner = TestGuard::builder(())
+        .default_retry_policy(RetryPolicy::Never)
+        .build();
+    let conn = runner.connection_pool().get()?;
+    let failed = failure_job().enqueue(&conn)?;
+    let blocked = failure_job().enqueue_after(&conn, &[failed])?;
+    let succeeded = succeeding_job().enqueue(&conn)?;
+    let unblocked = failure_job().enqueue_after(&conn, &[succeeded])?;
+
+    // The dependent job may not have been unblocked in time for the first run
+    runner.run_all_pending_jobs()?;
+    runner.check_for_failed_jobs()?;
+    runner.run_all_pending_jobs()?;
+    runner.check_for_failed_jobs()?;
+
+    let failed_ids = swirl_failed_jobs::table
+        .select(swirl_failed_jobs::id)
+        .order(swirl_failed_jobs::id)
+        .load::<i64>(&conn);
+    assert_eq!(Ok(vec![failed.id(), unblocked.id()]), failed_ids);
+    let pending_ids = background_jobs::table
+        .select(background_jobs::id)
+        .load::<i64>(&conn);
+    assert_eq!(Ok(vec![blocked.id()]), pending_ids);
+    Ok(())
+}
+
+#[test]
+fn retried_jobs_still_wait_for_the_jobs_they_depend_on() -> Fallible<()> {
+    #[swirl::background_job]
+    fn parent_job() -> Result<(), swirl::PerformError> {
+        Ok(())
+    }
+
+    /// Runs `failure_job`s whether or not the jobs they depend on have
+    /// finished, until `respect_dependencies` is set
+    struct IgnoringDependencies {
+        respect_dependencies: Arc<AtomicBool>,
+    }
+
+    impl FetchQuery for IgnoringDependencies {
+        fn fetch(
+            &self,
+            conn: &PgConnection,
+            request: &FetchRequest<'_>,
+        ) -> QueryResult<Option<BackgroundJob>> {
+            use swirl::schema::background_jobs::dsl::*;
+
+            if self.respect_dependencies.load(Ordering::SeqCst) {
+                return DefaultFetchQuery.fetch(conn, request);
+            }
+            background_jobs
+                .select((id, job_type, data, priority, queue, metadata, retries))
+                .filter(job_type.eq("failure_job"))
+                .for_update()
+                .skip_locked()
+                .first::<BackgroundJob>(conn)
+                .optional()
+        }
+    }
+
+    let respect_dependencies = Arc::new(AtomicBool::new(false));
+    let runner = TestGuard::builder(())
+        .default_retry_policy(RetryPolicy::Never)
+        .only_job_types(vec!["failure_job"])
+        .fetcher(IgnoringDependencies {
+            respect_dependencies: respect_dependencies.clone(),
+        })
+        .build();
+    let conn = runner.connection_pool().get()?;
+    let parent = parent_job().enqueue(&conn)?;
+    let child = failure_job().enqueue_after(&conn, &[parent])?;
+
+    runner.run_all_pending_jobs()?;
+    runner.check_for_failed_jobs()?;
+    let failed_ids = swirl_failed_jobs::table
+        .select(swirl_failed_jobs::id)
+        .load::<i64>(&conn);
+    assert_eq!(Ok(vec![child.id()]), failed_ids);
+
+    respect_dependencies.store(true, Ordering::SeqCst);
+    admin::retry_failed_job(&conn, child.id(), None)?;
+    runner.run_all_pending_jobs()?;
+    runner.check_for_failed_jobs()?;
+
+    let pending_ids = background_jobs::table
+        .select(background_jobs::id)
+        .order(background_jobs::id)
+        .load::<i64>(&conn);
+    assert_eq!(Ok(vec![parent.id(), child.id()]), pending_ids);
+    Ok(())
+}
+
+#[test]
+fn batches_are_completed_once_every_job_in_them_has_finished() -> Fallible<()> {
+    #[swirl::background_job]
+    fn succeeding_job() -> Result<(), swirl::PerformError> {
+        Ok(())
+    }
+
+    #[swirl::background_job]
+    fn report_failures(ctx: &JobContext) -> Result<(), swirl::PerformError> {
+        Err(format!("{:?}", ctx.failed_batch_jobs()?).into())
+    }
+
+    let runner = TestGuard::builder(())
+        .default_retry_policy(RetryPolicy::Never)
+        .build();
+    let conn = runner.connection_pool().get()?;
+    let mut failing = None;
+    let on_complete = swirl::enqueue_batch(&conn, report_failures(), |batch| {
+        batch.enqueue(succeeding_job())?;
+        failing = Some(batch.enqueue(failure_job())?);
+        batch.enqueue(succeeding_job())?;
+        Ok(())
+    })?;
+    let failing = failing.unwrap();
+
+    // The completion job may not have been unblocked in time for the first run
+    runner.run_all_pending_jobs()?;
+    runner.check_for_failed_jobs()?;
+    runner.run_all_pending_jobs()?;
+    runner.check_for_failed_jobs()?;
+
+    let failures = swirl_failed_jobs::table
+        .select((swirl_failed_jobs::id, swirl_failed_jobs::error))
+        .order(swirl_failed_jobs::id)
+        .load::<(i64, String)>(&conn);
+    assert_eq!(
+        Ok(vec![
+            (on_complete.id(), format!("[{}]", failing.id())),
+            (failing.id(), "failed".to_string()),
+        ]),
+        failures
+    );
+    Ok(())
+}
+
+#[test]
+fn retry_policies_are_chosen_by_the_kind_of_failure() -> Fallible<()> {
+    use diesel::dsl::{now, IntervalDsl};
+
+    #[swirl::background_job]
+    fn takes_a_number(number: i32) -> Result<(), swirl::PerformError> {
+        assert_eq!(1, number);
+        Ok(())
+    }
+
+    let runner = TestGuard::builder(())
+        .retry_policy(FailureKind::Deserialization, RetryPolicy::Never)
+        .retry_policy(
+            FailureKind::Other,
+            RetryPolicy::Fixed(Duration::from_secs(10)),
+        )
+        .build();
+    let conn = runner.connection_pool().get()?;
+    takes_a_number(1).enqueue(&conn)?;
+    failure_job().enqueue(&conn)?;
+    diesel::sql_query(
+        "UPDATE background_jobs SET data = '\"one\"' WHERE job_type = 'takes_a_number'",
+    )
+    .execute(&conn)?;
+
+    runner.run_all_pending_jobs()?;
+    assert_eq!(Err(JobsFailed(1)), runner.check_for_failed_jobs());
+
+    let never_retried = swirl_failed_jobs::table
+        .select(swirl_failed_jobs::job_type)
+        .load::<String>(&conn);
+    assert_eq!(Ok(vec!["takes_a_number".to_string()]), never_retried);
+    let retried_soon = background_jobs::table
+        .select(background_jobs::job_type)
+        .filter(background_jobs::retry_at.lt((now + 1.minutes()).nullable()))
+        .load::<String>(&conn);
+    assert_eq!(Ok(vec!["failure_job".to_string()]), retried_soon);
+    Ok(())
+}
+
+#[test]
+fn jobs_which_fail_fatally_are_not_retried() -> Fallible<()> {
+    #[swirl::background_job]
+    fn validates_input(input: String) -> Result<(), swirl::PerformError> {
+        match input.as_str() {
+            "invalid" => Err(Failure::Fatal("the input is invalid".into()).into()),
+            _ => Err(Failure::Retryable("the service is unavailable".into()).into()),
+        }
+    }
+
+    let runner = TestGuard::builder(())
+        .default_retry_policy(RetryPolicy::Fixed(Duration::from_secs(10)))
+        .build();
+    let conn = runner.connection_pool().get()?;
+    validates_input("invalid".into()).enqueue(&conn)?;
+    validates_input("valid".into()).enqueue(&conn)?;
+
+    // Only the job which will be retried is still in the queue
+    runner.run_all_pending_jobs()?;
+    assert_eq!(Err(JobsFailed(1)), runner.check_for_failed_jobs());
+
+    let never_retried = swirl_failed_jobs::table
+        .select(swirl_failed_jobs::data)
+        .load::<serde_json::Value>(&conn);
+    assert_eq!(
+        Ok(vec![serde_json::json!({"input": "invalid"})]),
+        never_retried
+    );
+    let retried = background_jobs::table
+        .select(background_jobs::data)
+        .load::<serde_json::Value>(&conn);
+    assert_eq!(Ok(vec![serde_json::json!({"input": "valid"})]), retried);
+    Ok(())
+}
+
+#[test]
+fn job_types_in_the_queue_which_are_not_registered_are_reported() -> Fallible<()> {
+    let runner = TestGuard::dummy_runner();
+    let conn = runner.connection_pool().get()?;
+    failure_job().enqueue(&conn)?;
+    diesel::insert_into(background_jobs::table)
+        .values((
+            background_jobs::job_type.eq("not_a_registered_job"),
+            background_jobs::data.eq(serde_json::json!(null)),
+        ))
+        .execute(&conn)?;
+
+    assert_eq!(
+        vec!["not_a_registered_job".to_string()],
+        runner.unregistered_job_types().unwrap()
+    );
+    Ok(())
+}
+
+#[test]
+fn job_status_follows_a_job_through_the_queue() -> Fallible<()> {
+    let barrier = Barrier::new(2);
+    let runner = TestGuard::runner(barrier.clone());
+    let conn = runner.connection_pool().get()?;
+    let job = barrier_job().enqueue(&conn)?;
+    assert_eq!(JobStatus::Pending, job.status(&conn)?);
+
+    runner.run_all_pending_jobs()?;
+    assert_eq!(JobStatus::Running, job.status(&conn)?);
+
+    barrier.wait();
+    runner.check_for_failed_jobs()?;
+    assert_eq!(JobStatus::NotFound, swirl::job_status(&conn, job.id())?);
+    Ok(())
+}
+
+#[test]
+fn running_jobs_report_their_progress() -> Fallible<()> {
+    #[swirl::background_job]
+    fn import_rows(env: &Barrier, ctx: &JobContext) -> Result<(), swirl::PerformError> {
+        ctx.set_progress(3, 4);
+        env.wait();
+        env.wait();
+        Ok(())
+    }
+
+    let barrier = Barrier::new(2);
+    let runner = TestGuard::runner(barrier.clone());
+    let conn = runner.connection_pool().get()?;
+    let job = import_rows().enqueue(&conn)?;
+    assert_eq!(None, admin::job_progress(&conn, job.id())?);
+
+    runner.run_all_pending_jobs()?;
+    barrier.wait();
+    // Progress is written in the background, so wait for it to show up
+    let mut progress = None;
+    for _ in 0..50 {
+        progress = admin::job_progress(&conn, job.id())?;
+        if progress.is_some() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    let progress = progress.expect("progress was never written");
+    assert_eq!((3, 4), (progress.current, progress.total));
+    assert_eq!(0.75, progress.fraction());
+
+    barrier.wait();
+    runner.check_for_failed_jobs()?;
+    assert_eq!(None, admin::job_progress(&conn, job.id())?);
+    Ok(())
+}
+
+#[test]
+fn job_status_reports_retries() -> Fallible<()> {
+    let runner = TestGuard::dummy_runner();
+    let conn = runner.connection_pool().get()?;
+    let job = failure_job().enqueue(&conn)?;
+
+    runner.run_all_pending_jobs()?;
+    runner.check_for_failed_jobs().ok();
+
+    assert_matches!(job.status(&conn)?, JobStatus::Retrying { retries: 1, .. });
+    Ok(())
+}
+
+#[test]
+fn only_jobs_which_are_not_running_can_be_cancelled() -> Fallible<()> {
+    let barrier = Barrier::new(2);
+    let runner = TestGuard::runner(barrier.clone());
+    let conn = runner.connection_pool().get()?;
+    let running = barrier_job().enqueue(&conn)?;
+    runner.run_all_pending_jobs()?;
+    // Threads which found no job may still be fetching, and must not start
+    // the job enqueued next
+    swirl::queue("default").pause(&conn)?;
+    let pending = barrier_job().enqueue(&conn)?;
+
+    assert_eq!(Cancellation::Running, running.cancel(&conn)?);
+    assert_eq!(
+        Cancellation::Cancelled,
+        swirl::cancel_job(&conn, pending.id())?
+    );
+    assert_eq!(Cancellation::NotFound, pending.cancel(&conn)?);
+
+    barrier.wait();
+    runner.check_for_failed_jobs()?;
+    assert_eq!(JobStatus::NotFound, running.status(&conn)?);
+    Ok(())
+}
+
+#[test]
+fn jobs_whose_lease_expires_are_reclaimed() -> Fallible<()> {
+    use diesel::dsl::{now, IntervalDsl};
+
+    let barrier = Barrier::new(2);
+    let runner = TestGuard::builder(barrier.clone())
+        .lease_duration(Duration::from_secs(60))
+        .build();
+    let conn = runner.connection_pool().get()?;
+    let job = barrier_job().enqueue(&conn)?;
+    runner.run_all_pending_jobs()?;
+    // Threads which found no job may still be fetching, and must not start
+    // the job again once its lease has been reclaimed
+    swirl::queue("default").pause(&conn)?;
+
+    let leased_jobs = || {
+        swirl_job_leases::table
+            .select(swirl_job_leases::job_id)
+            .load::<i64>(&*conn)
+    };
+    while leased_jobs()?.is_empty() {
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(vec![job.id()], leased_jobs()?);
+
+    // Act as if the runner had stopped renewing its lease
+    diesel::update(swirl_job_leases::table)
+        .set(swirl_job_leases::locked_until.eq(now - 1.second()))
+        .execute(&*conn)?;
+    assert_eq!(1, admin::reclaim_expired_leases(&conn)?);
+
+    let unlocked_job = || {
+        background_jobs::table
+            .find(job.id())
+            .select(background_jobs::id)
+            .for_update()
+            .skip_locked()
+            .load::<i64>(&*conn)
+    };
+    while unlocked_job()?.is_empty() {
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(JobStatus::Pending, job.status(&conn)?);
+
+    // The job's connection is gone, so it finishes without updating the job
+    barrier.wait();
+    runner.check_for_failed_jobs()?;
+    assert_eq!(JobStatus::Pending, job.status(&conn)?);
+    assert!(leased_jobs()?.is_empty());
+    Ok(())
+}
+
+#[test]
+fn leases_outlive_the_workers_holding_them() -> Fallible<()> {
+    use diesel::dsl::{now, IntervalDsl};
+
+    let barrier = Barrier::new(2);
+    let runner = TestGuard::builder(barrier.clone())
+        .lease_duration(Duration::from_secs(60))
+        .build();
+    let conn = runner.connection_pool().get()?;
+    let job = barrier_job().enqueue(&conn)?;
+    runner.run_all_pending_jobs()?;
+    // Threads which found no job may still be fetching, and must not start
+    // the job again once its lease has been reclaimed
+    swirl::queue("default").pause(&conn)?;
+    while swirl_job_leases::table.count().get_result::<i64>(&*conn)? == 0 {
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    // Act as if another runner purged this one as stale, and the lease then
+    // expired
+    diesel::delete(swirl_workers::table).execute(&*conn)?;
+    diesel::update(swirl_job_leases::table)
+        .set(swirl_job_leases::locked_until.eq(now - 1.second()))
+        .execute(&*conn)?;
+    assert_eq!(1, admin::reclaim_expired_leases(&conn)?);
+
+    let unlocked_job = || {
+        background_jobs::table
+            .find(job.id())
+            .select(background_jobs::id)
+            .for_update()
+            .skip_locked()
+            .load::<i64>(&*conn)
+    };
+    while unlocked_job()?.is_empty() {
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    barrier.wait();
     runner.check_for_failed_jobs()?;
+    assert_eq!(JobStatus::Pending, job.status(&conn)?);
     Ok(())
 }
@@ -1,42 +1,43 @@
-use antidote::{Mutex, MutexGuard};
-use diesel::prelude::*;
 use std::ops::{Deref, DerefMut};
+use std::panic::RefUnwindSafe;
+use std::path::Path;
 use std::time::Duration;
-use swirl::{Builder, Runner};
+use swirl::testing::TestSchema;
+use swirl::{Builder, FailureKind, FetchQuery, Job, RetryPolicy, Runner};
 
 use crate::db::*;
-use crate::util::*;
-
-lazy_static::lazy_static! {
-    // Since these tests deal with behavior concerning multiple connections
-    // running concurrently, they have to run outside of a transaction.
-    // Therefore we can't run more than one at a time.
-    //
-    // Rather than forcing the whole suite to be run with `--test-threads 1`,
-    // we just lock these tests instead.
-    static ref TEST_MUTEX: Mutex<()> = Mutex::new(());
-}
 
-pub struct TestGuard<'a, Env: 'static> {
+// Since these tests deal with behavior concerning multiple connections
+// running concurrently, they have to run outside of a transaction. Each test
+// gets its own schema instead, so they can still run in parallel.
+pub struct TestGuard<Env: 'static> {
+    // Declared first so the pool's connections are closed before the schema
+    // is dropped
     runner: Runner<Env, DieselPool>,
-    _lock: MutexGuard<'a, ()>,
+    schema: TestSchema,
 }
 
-impl<'a, Env> TestGuard<'a, Env> {
+impl<Env> TestGuard<Env> {
     pub fn builder(env: Env) -> GuardBuilder<Env> {
         let database_url =
             dotenv::var("TEST_DATABASE_URL").expect("TEST_DATABASE_URL must be set to run tests");
-        let builder = Runner::builder(env).connection_pool_builder(database_url, pool_builder());
+        let schema = TestSchema::new(&database_url).expect("Failed to create a test schema");
+        let builder =
+            Runner::builder(env).connection_pool_builder(schema.database_url(), pool_builder());
 
-        GuardBuilder { builder }
+        GuardBuilder { builder, schema }
     }
 
     pub fn runner(env: Env) -> Self {
         Self::builder(env).build()
     }
+
+    pub fn database_url(&self) -> &str {
+        self.schema.database_url()
+    }
 }
 
-impl<'a> TestGuard<'a, ()> {
+impl TestGuard<()> {
     pub fn dummy_runner() -> Self {
         Self::builder(()).build()
     }
@@ -44,6 +45,7 @@ impl<'a> TestGuard<'a, ()> {
 
 pub struct GuardBuilder<Env: 'static> {
     builder: Builder<Env, PoolBuilder>,
+    schema: TestSchema,
 }
 
 impl<Env> GuardBuilder<Env> {
@@ -62,15 +64,104 @@ impl<Env> GuardBuilder<Env> {
         self
     }
 
-    pub fn build<'a>(self) -> TestGuard<'a, Env> {
+    pub fn lease_duration(mut self, duration: Duration) -> Self {
+        self.builder = self.builder.lease_duration(duration);
+        self
+    }
+
+    pub fn queues(mut self, queues: Vec<&str>) -> Self {
+        self.builder = self.builder.queues(queues);
+        self
+    }
+
+    pub fn only_job_types(mut self, job_types: Vec<&str>) -> Self {
+        self.builder = self.builder.only_job_types(job_types);
+        self
+    }
+
+    pub fn except_job_types(mut self, job_types: Vec<&str>) -> Self {
+        self.builder = self.builder.except_job_types(job_types);
+        self
+    }
+
+    pub fn early_execution_slack(mut self, slack: Duration) -> Self {
+        self.builder = self.builder.early_execution_slack(slack);
+        self
+    }
+
+    pub fn worker_version(mut self, version: &str) -> Self {
+        self.builder = self.builder.worker_version(version);
+        self
+    }
+
+    pub fn failure_samples(mut self, per_fingerprint: u32) -> Self {
+        self.builder = self.builder.failure_samples(per_fingerprint);
+        self
+    }
+
+    pub fn record_failures_to(mut self, dir: &Path) -> Self {
+        self.builder = self.builder.record_failures_to(dir);
+        self
+    }
+
+    pub fn asynchronous_completions(mut self, enabled: bool) -> Self {
+        self.builder = self.builder.asynchronous_completions(enabled);
+        self
+    }
+
+    pub fn notify_on_completion(mut self, enabled: bool) -> Self {
+        self.builder = self.builder.notify_on_completion(enabled);
+        self
+    }
+
+    pub fn record_runs(mut self, enabled: bool) -> Self {
+        self.builder = self.builder.record_runs(enabled);
+        self
+    }
+
+    pub fn retry_policy(mut self, kind: FailureKind, policy: RetryPolicy) -> Self {
+        self.builder = self.builder.retry_policy(kind, policy);
+        self
+    }
+
+    pub fn default_retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.builder = self.builder.default_retry_policy(policy);
+        self
+    }
+
+    pub fn max_retries(mut self, max_retries: u32) -> Self {
+        self.builder = self.builder.max_retries(max_retries);
+        self
+    }
+
+    pub fn job_type_concurrency_limit(mut self, job_type: &str, limit: u32) -> Self {
+        self.builder = self.builder.job_type_concurrency_limit(job_type, limit);
+        self
+    }
+
+    pub fn fetcher<F: FetchQuery>(mut self, fetcher: F) -> Self {
+        self.builder = self.builder.fetcher(fetcher);
+        self
+    }
+
+    pub fn register_with<J>(mut self, env: J::Environment) -> Self
+    where
+        J: Job,
+        J::Environment: Send + Sync + RefUnwindSafe,
+    {
+        self.builder = self.builder.register_with::<J>(env);
+        self
+    }
+
+    pub fn build(self) -> TestGuard<Env> {
         TestGuard {
-            _lock: TEST_MUTEX.lock(),
             runner: self.builder.build(),
+            schema: self.schema,
         }
     }
 }
 
-impl<'a, Env> Deref for TestGuard<'a, Env> {
+impl<Env> Deref for TestGuard<Env> {
     type Target = Runner<Env, DieselPool>;
 
     fn deref(&self) -> &Self::Target {
@@ -78,17 +169,8 @@ impl<'a, Env> Deref for TestGuard<'a, Env> {
     }
 }
 
-impl<'a, Env> DerefMut for TestGuard<'a, Env> {
+impl<Env> DerefMut for TestGuard<Env> {
     fn deref_mut(&mut self) -> &mut Self::Target {
         &mut self.runner
     }
 }
-
-impl<'a, Env> Drop for TestGuard<'a, Env> {
-    fn drop(&mut self) {
-        let conn = self.runner.connection_pool().get().unwrap();
-        ::diesel::sql_query("TRUNCATE TABLE background_jobs")
-            .execute(&conn)
-            .unwrap_from_drop();
-    }
-}
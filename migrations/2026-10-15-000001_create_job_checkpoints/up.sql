@@ -0,0 +1,7 @@
+-- There is intentionally no foreign key to background_jobs. Checking it would
+-- require a lock which conflicts with the lock held on the job while it runs.
+CREATE TABLE background_job_checkpoints (
+  job_id BIGINT PRIMARY KEY,
+  state JSONB NOT NULL,
+  updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
@@ -0,0 +1,2 @@
+DROP INDEX background_jobs_priority_id;
+ALTER TABLE background_jobs DROP COLUMN priority;
@@ -0,0 +1,3 @@
+DROP INDEX background_jobs_queue_priority_id;
+ALTER TABLE background_jobs DROP COLUMN metadata;
+ALTER TABLE background_jobs DROP COLUMN queue;
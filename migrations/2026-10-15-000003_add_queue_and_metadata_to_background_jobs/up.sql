@@ -0,0 +1,3 @@
+ALTER TABLE background_jobs ADD COLUMN queue TEXT NOT NULL DEFAULT 'default';
+ALTER TABLE background_jobs ADD COLUMN metadata JSONB NOT NULL DEFAULT '{}';
+CREATE INDEX background_jobs_queue_priority_id ON background_jobs (queue, priority DESC, id);
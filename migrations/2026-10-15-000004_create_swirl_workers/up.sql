@@ -0,0 +1,10 @@
+CREATE TABLE swirl_workers (
+  id BIGSERIAL PRIMARY KEY,
+  hostname TEXT NOT NULL,
+  pid INTEGER NOT NULL,
+  version TEXT,
+  queues TEXT[],
+  thread_count INTEGER NOT NULL,
+  started_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  last_heartbeat_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
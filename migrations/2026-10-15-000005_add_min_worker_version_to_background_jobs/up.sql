@@ -0,0 +1 @@
+ALTER TABLE background_jobs ADD COLUMN min_worker_version INTEGER[];
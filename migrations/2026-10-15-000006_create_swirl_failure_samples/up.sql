@@ -0,0 +1,10 @@
+CREATE TABLE swirl_failure_samples (
+  id BIGSERIAL PRIMARY KEY,
+  job_id BIGINT NOT NULL,
+  job_type TEXT NOT NULL,
+  fingerprint TEXT NOT NULL,
+  data JSONB NOT NULL,
+  error TEXT NOT NULL,
+  created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+CREATE INDEX swirl_failure_samples_fingerprint_id ON swirl_failure_samples (fingerprint, id);
@@ -0,0 +1 @@
+ALTER TABLE background_jobs ADD COLUMN scheduled_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP;
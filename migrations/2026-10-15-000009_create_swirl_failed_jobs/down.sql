@@ -0,0 +1 @@
+DROP TABLE swirl_failed_jobs;
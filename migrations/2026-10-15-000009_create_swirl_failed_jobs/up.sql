@@ -0,0 +1,16 @@
+-- Jobs which have failed for the last time. Rows keep the id the job had in
+-- background_jobs, so they can be moved back with the same id.
+CREATE TABLE swirl_failed_jobs (
+  id BIGINT PRIMARY KEY,
+  job_type TEXT NOT NULL,
+  data JSONB NOT NULL,
+  priority SMALLINT NOT NULL,
+  queue TEXT NOT NULL,
+  metadata JSONB NOT NULL,
+  min_worker_version INTEGER[],
+  retries INTEGER NOT NULL,
+  created_at TIMESTAMP NOT NULL,
+  error TEXT NOT NULL,
+  failed_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+CREATE INDEX swirl_failed_jobs_failed_at ON swirl_failed_jobs (failed_at);
@@ -0,0 +1,4 @@
+DROP TRIGGER swirl_count_tenant_jobs_on_delete ON background_jobs;
+DROP TRIGGER swirl_count_tenant_jobs_on_insert ON background_jobs;
+DROP FUNCTION swirl_count_tenant_jobs();
+DROP TABLE swirl_tenants;
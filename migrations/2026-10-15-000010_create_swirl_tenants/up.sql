@@ -0,0 +1,37 @@
+-- Counts the pending jobs of each tenant, so quotas can be checked without
+-- counting rows in background_jobs. A job's tenant is the string stored under
+-- the "tenant" key of its metadata.
+CREATE TABLE swirl_tenants (
+  tenant TEXT PRIMARY KEY,
+  pending_jobs BIGINT NOT NULL DEFAULT 0,
+  max_pending_jobs BIGINT
+);
+
+INSERT INTO swirl_tenants (tenant, pending_jobs)
+  SELECT metadata->>'tenant', COUNT(*) FROM background_jobs
+  WHERE jsonb_typeof(metadata->'tenant') = 'string'
+  GROUP BY metadata->>'tenant';
+
+CREATE FUNCTION swirl_count_tenant_jobs() RETURNS trigger AS $$
+BEGIN
+  IF TG_OP = 'INSERT' THEN
+    INSERT INTO swirl_tenants (tenant, pending_jobs) VALUES (NEW.metadata->>'tenant', 1)
+      ON CONFLICT (tenant) DO UPDATE SET pending_jobs = swirl_tenants.pending_jobs + 1;
+    RETURN NEW;
+  ELSE
+    UPDATE swirl_tenants SET pending_jobs = pending_jobs - 1
+      WHERE tenant = OLD.metadata->>'tenant';
+    RETURN OLD;
+  END IF;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER swirl_count_tenant_jobs_on_insert
+  AFTER INSERT ON background_jobs FOR EACH ROW
+  WHEN (jsonb_typeof(NEW.metadata->'tenant') = 'string')
+  EXECUTE PROCEDURE swirl_count_tenant_jobs();
+
+CREATE TRIGGER swirl_count_tenant_jobs_on_delete
+  AFTER DELETE ON background_jobs FOR EACH ROW
+  WHEN (jsonb_typeof(OLD.metadata->'tenant') = 'string')
+  EXECUTE PROCEDURE swirl_count_tenant_jobs();
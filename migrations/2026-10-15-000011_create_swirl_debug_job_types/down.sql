@@ -0,0 +1 @@
+DROP TABLE swirl_debug_job_types;
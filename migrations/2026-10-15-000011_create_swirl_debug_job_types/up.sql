@@ -0,0 +1,4 @@
+CREATE TABLE swirl_debug_job_types (
+  job_type TEXT PRIMARY KEY,
+  enabled_until TIMESTAMP NOT NULL
+);
@@ -0,0 +1,20 @@
+-- Lets runners which are listening on the swirl_jobs channel know a job was
+-- enqueued, instead of waiting to poll. The payload is the job's queue.
+-- Postgres only delivers the notification once the transaction commits, and
+-- collapses duplicate notifications sent by the same transaction.
+--
+-- The trigger is installed whether or not any runner listens, so every enqueue
+-- pays for the notification. Transactions which sent one also take a
+-- database-wide lock while they commit, which can limit the rate of enqueues
+-- under heavy load. Applications which never use `Builder::listen_for_jobs`
+-- can drop the trigger once migrations have run.
+CREATE FUNCTION swirl_notify_job_enqueued() RETURNS trigger AS $$
+BEGIN
+  PERFORM pg_notify('swirl_jobs', NEW.queue);
+  RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER swirl_notify_job_enqueued
+  AFTER INSERT ON background_jobs FOR EACH ROW
+  EXECUTE PROCEDURE swirl_notify_job_enqueued();
@@ -0,0 +1 @@
+DROP MATERIALIZED VIEW swirl_queue_stats;
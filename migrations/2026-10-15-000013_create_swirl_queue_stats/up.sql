@@ -0,0 +1,15 @@
+-- A summary of the queue for dashboards, which is only as fresh as the last
+-- time swirl_refresh_queue_stats was run
+CREATE MATERIALIZED VIEW swirl_queue_stats AS
+  SELECT
+    job_type,
+    queue,
+    COUNT(*) AS jobs,
+    COUNT(*) FILTER (WHERE retries > 0) AS failing_jobs,
+    MIN(created_at) AS oldest_created_at,
+    now()::timestamp AS refreshed_at
+  FROM background_jobs
+  GROUP BY job_type, queue;
+
+-- Required to refresh the view concurrently
+CREATE UNIQUE INDEX swirl_queue_stats_job_type_queue ON swirl_queue_stats (job_type, queue);
@@ -0,0 +1,2 @@
+ALTER TABLE swirl_failed_jobs DROP COLUMN first_failure;
+ALTER TABLE background_jobs DROP COLUMN first_failure;
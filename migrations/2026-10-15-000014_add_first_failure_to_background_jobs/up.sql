@@ -0,0 +1,5 @@
+-- A snapshot of the job and the runner it ran on, taken the first time the
+-- job failed. It is kept when the job is retried or moved to
+-- swirl_failed_jobs, so it can be compared with the job's current state.
+ALTER TABLE background_jobs ADD COLUMN first_failure JSONB;
+ALTER TABLE swirl_failed_jobs ADD COLUMN first_failure JSONB;
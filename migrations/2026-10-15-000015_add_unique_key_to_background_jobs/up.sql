@@ -0,0 +1,5 @@
+-- Jobs enqueued with a unique key are skipped if a job of the same type with
+-- the same key is already in the queue.
+ALTER TABLE background_jobs ADD COLUMN unique_key TEXT;
+CREATE UNIQUE INDEX background_jobs_job_type_unique_key
+  ON background_jobs (job_type, unique_key) WHERE unique_key IS NOT NULL;
@@ -0,0 +1,5 @@
+-- The time and error fingerprint of the most recent failures of the job,
+-- oldest first. It is kept when the job is moved to swirl_failed_jobs, so
+-- operators can see whether a job fails the same way every time.
+ALTER TABLE background_jobs ADD COLUMN retry_history JSONB NOT NULL DEFAULT '[]';
+ALTER TABLE swirl_failed_jobs ADD COLUMN retry_history JSONB NOT NULL DEFAULT '[]';
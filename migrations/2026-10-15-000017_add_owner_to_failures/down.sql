@@ -0,0 +1,2 @@
+ALTER TABLE swirl_failure_samples DROP COLUMN owner;
+ALTER TABLE swirl_failed_jobs DROP COLUMN owner;
@@ -0,0 +1,3 @@
+-- The team which owns the job's code, as given by Job::OWNER when it failed
+ALTER TABLE swirl_failed_jobs ADD COLUMN owner TEXT;
+ALTER TABLE swirl_failure_samples ADD COLUMN owner TEXT;
@@ -0,0 +1,2 @@
+DROP FUNCTION swirl_queue_names(TEXT[]);
+DROP TABLE swirl_queue_aliases;
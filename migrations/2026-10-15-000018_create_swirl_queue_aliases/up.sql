@@ -0,0 +1,26 @@
+-- Lets a queue be renamed without stopping the world. Jobs enqueued in
+-- `alias` are run by workers of `queue` and vice versa, so producers and
+-- workers can move to the new name in any order.
+CREATE TABLE swirl_queue_aliases (
+  alias TEXT PRIMARY KEY,
+  queue TEXT NOT NULL CHECK (queue <> alias)
+);
+
+CREATE INDEX swirl_queue_aliases_queue ON swirl_queue_aliases (queue);
+
+-- Every queue name which is treated as the same queue as one of `names`,
+-- including `names` themselves. Marked STABLE so that Postgres evaluates it
+-- once per query, and can still use indexes on background_jobs.queue.
+CREATE FUNCTION swirl_queue_names(names TEXT[]) RETURNS TEXT[] AS $$
+  WITH canonical AS (
+    SELECT COALESCE(a.queue, n.name) AS queue
+    FROM unnest(names) AS n(name)
+    LEFT JOIN swirl_queue_aliases a ON a.alias = n.name
+  )
+  SELECT array_agg(DISTINCT name) FROM (
+    SELECT queue AS name FROM canonical
+    UNION SELECT a.alias FROM swirl_queue_aliases a
+      WHERE a.queue IN (SELECT queue FROM canonical)
+    UNION SELECT unnest(names)
+  ) all_names
+$$ LANGUAGE SQL STABLE;
@@ -0,0 +1,14 @@
+-- One row for each call to Runner::run_until_empty on a runner built with
+-- record_runs, so that periodic runs which stopped happening or failed can be
+-- noticed
+CREATE TABLE swirl_runs (
+  id BIGSERIAL PRIMARY KEY,
+  hostname TEXT,
+  started_at TIMESTAMP NOT NULL,
+  finished_at TIMESTAMP NOT NULL DEFAULT now(),
+  jobs_run BIGINT NOT NULL,
+  failures BIGINT NOT NULL,
+  error TEXT
+);
+
+CREATE INDEX swirl_runs_finished_at ON swirl_runs (finished_at);
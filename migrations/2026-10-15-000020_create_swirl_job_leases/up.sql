@@ -0,0 +1,13 @@
+-- Leases on running jobs, for runners built with lease_duration. A job's own
+-- row is locked by the transaction it runs in, so its lease is kept here where
+-- other runners can see it. Runners renew their leases while the jobs run, and
+-- terminate the connection holding a job whose lease has expired.
+CREATE TABLE swirl_job_leases (
+  job_id BIGINT PRIMARY KEY,
+  locked_by BIGINT NOT NULL REFERENCES swirl_workers (id) ON DELETE CASCADE,
+  backend_pid INTEGER NOT NULL,
+  locked_at TIMESTAMP NOT NULL DEFAULT now(),
+  locked_until TIMESTAMP NOT NULL
+);
+
+CREATE INDEX swirl_job_leases_locked_until ON swirl_job_leases (locked_until);
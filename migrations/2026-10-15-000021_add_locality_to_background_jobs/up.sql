@@ -0,0 +1,3 @@
+-- Where the data a job works on lives, such as a region or shard. Runners
+-- with the same locality prefer these jobs, but any runner can run them.
+ALTER TABLE background_jobs ADD COLUMN locality TEXT;
@@ -0,0 +1 @@
+DROP TABLE swirl_supersede_keys;
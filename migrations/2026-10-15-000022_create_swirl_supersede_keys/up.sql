@@ -0,0 +1,13 @@
+-- The latest job enqueued with enqueue_superseding for each job type and
+-- supersede key. Enqueueing another job with the same key locks its row here,
+-- cancels the job it points to unless that job is running, and points it at
+-- the new job. Running jobs are locked, so they can't be tracked by a unique
+-- index on background_jobs itself.
+CREATE TABLE swirl_supersede_keys (
+  job_type TEXT NOT NULL,
+  supersede_key TEXT NOT NULL,
+  job_id BIGINT NOT NULL REFERENCES background_jobs (id) ON DELETE CASCADE,
+  PRIMARY KEY (job_type, supersede_key)
+);
+
+CREATE INDEX swirl_supersede_keys_job_id ON swirl_supersede_keys (job_id);
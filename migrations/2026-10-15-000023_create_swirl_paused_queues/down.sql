@@ -0,0 +1 @@
+DROP TABLE swirl_paused_queues;
@@ -0,0 +1,6 @@
+-- Queues which runners don't fetch jobs from until they are resumed. Jobs can
+-- still be enqueued in a paused queue.
+CREATE TABLE swirl_paused_queues (
+  queue TEXT PRIMARY KEY,
+  paused_at TIMESTAMP NOT NULL DEFAULT now()
+);
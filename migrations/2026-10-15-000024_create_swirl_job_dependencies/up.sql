@@ -0,0 +1,11 @@
+-- The jobs each job waits for before it can run. A job is held back while any
+-- job it depends on is still in background_jobs or swirl_failed_jobs, so
+-- dependencies don't need to be cleaned up when a job succeeds, and a job
+-- which finishes while a dependent is being enqueued can't be missed.
+CREATE TABLE swirl_job_dependencies (
+  job_id BIGINT NOT NULL REFERENCES background_jobs (id) ON DELETE CASCADE,
+  depends_on BIGINT NOT NULL,
+  PRIMARY KEY (job_id, depends_on)
+);
+
+CREATE INDEX swirl_job_dependencies_depends_on ON swirl_job_dependencies (depends_on);
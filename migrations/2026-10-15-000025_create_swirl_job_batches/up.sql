@@ -0,0 +1,12 @@
+-- The jobs in each batch enqueued with enqueue_batch. A batch's id is the id
+-- of the job which is run once it completes, which is held back while any job
+-- in the batch is still in background_jobs. Jobs which failed for the last
+-- time stay in the batch, so the completion job can find them in
+-- swirl_failed_jobs. A batch is removed once its completion job succeeds.
+CREATE TABLE swirl_job_batches (
+  batch_id BIGINT NOT NULL,
+  job_id BIGINT NOT NULL,
+  PRIMARY KEY (batch_id, job_id)
+);
+
+CREATE INDEX swirl_job_batches_job_id ON swirl_job_batches (job_id);
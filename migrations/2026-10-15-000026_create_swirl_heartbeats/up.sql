@@ -0,0 +1,6 @@
+-- The latest run of swirl::heartbeat::HeartbeatJob in each queue
+CREATE TABLE swirl_heartbeats (
+  queue TEXT PRIMARY KEY,
+  enqueued_at TIMESTAMP NOT NULL,
+  completed_at TIMESTAMP NOT NULL
+);
@@ -0,0 +1 @@
+DROP TABLE swirl_job_progress;
@@ -0,0 +1,10 @@
+-- The latest progress reported by each running job. It's written from another
+-- connection while the job's row is locked by the transaction it runs in, so
+-- there is no foreign key to background_jobs, whose check would have to wait
+-- for that lock.
+CREATE TABLE swirl_job_progress (
+  job_id BIGINT PRIMARY KEY,
+  current BIGINT NOT NULL,
+  total BIGINT NOT NULL,
+  updated_at TIMESTAMP NOT NULL
+);
@@ -0,0 +1,10 @@
+-- The values returned by jobs which succeeded, kept until expires_at
+CREATE TABLE swirl_job_results (
+  job_id BIGINT PRIMARY KEY,
+  job_type TEXT NOT NULL,
+  result JSONB NOT NULL,
+  completed_at TIMESTAMP NOT NULL DEFAULT now(),
+  expires_at TIMESTAMP NOT NULL
+);
+
+CREATE INDEX swirl_job_results_expires_at ON swirl_job_results (expires_at);
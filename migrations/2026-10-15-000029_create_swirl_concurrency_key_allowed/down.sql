@@ -0,0 +1 @@
+DROP FUNCTION swirl_concurrency_key_allowed(JSONB, TEXT[]);
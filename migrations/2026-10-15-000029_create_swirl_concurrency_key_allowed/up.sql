@@ -0,0 +1,7 @@
+-- Whether a job with the given metadata may be fetched by a runner which has
+-- found that the jobs with the concurrency keys in `excluded` are already
+-- running. Jobs without a concurrency key are always allowed.
+CREATE FUNCTION swirl_concurrency_key_allowed(metadata JSONB, excluded TEXT[]) RETURNS BOOLEAN AS $$
+  SELECT metadata ->> 'concurrency_key' IS NULL
+    OR metadata ->> 'concurrency_key' <> ALL(excluded)
+$$ LANGUAGE SQL IMMUTABLE;
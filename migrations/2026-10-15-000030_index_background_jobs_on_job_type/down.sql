@@ -0,0 +1 @@
+DROP INDEX background_jobs_job_type_priority_id;
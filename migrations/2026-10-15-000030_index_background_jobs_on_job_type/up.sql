@@ -0,0 +1,3 @@
+-- Lets FairFetchQuery find the next job type with jobs of the highest
+-- priority without sorting the whole queue
+CREATE INDEX background_jobs_job_type_priority_id ON background_jobs (job_type, priority DESC, id);
@@ -0,0 +1,4 @@
+ALTER TABLE swirl_failed_jobs
+  DROP COLUMN scheduled_at,
+  DROP COLUMN unique_key,
+  DROP COLUMN locality;
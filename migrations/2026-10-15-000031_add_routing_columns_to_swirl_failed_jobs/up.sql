@@ -0,0 +1,6 @@
+-- Failed jobs keep where they should run, when they were scheduled for and
+-- their unique key, so retrying them puts them back in the queue as they were
+ALTER TABLE swirl_failed_jobs
+  ADD COLUMN scheduled_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  ADD COLUMN unique_key TEXT,
+  ADD COLUMN locality TEXT;
@@ -0,0 +1,4 @@
+DELETE FROM swirl_job_leases
+  WHERE locked_by NOT IN (SELECT id FROM swirl_workers);
+ALTER TABLE swirl_job_leases ADD CONSTRAINT swirl_job_leases_locked_by_fkey
+  FOREIGN KEY (locked_by) REFERENCES swirl_workers (id) ON DELETE CASCADE;
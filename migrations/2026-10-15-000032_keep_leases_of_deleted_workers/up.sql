@@ -0,0 +1,4 @@
+-- Deleting a stale worker must not delete its leases, or the connections
+-- still holding its jobs would never be terminated. Leases are only removed
+-- once they have been released or reclaimed.
+ALTER TABLE swirl_job_leases DROP CONSTRAINT swirl_job_leases_locked_by_fkey;
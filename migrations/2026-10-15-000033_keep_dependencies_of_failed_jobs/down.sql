@@ -0,0 +1,4 @@
+DELETE FROM swirl_job_dependencies
+  WHERE job_id NOT IN (SELECT id FROM background_jobs);
+ALTER TABLE swirl_job_dependencies ADD CONSTRAINT swirl_job_dependencies_job_id_fkey
+  FOREIGN KEY (job_id) REFERENCES background_jobs (id) ON DELETE CASCADE;
@@ -0,0 +1,5 @@
+-- A job which is moved to swirl_failed_jobs keeps its id, so its dependencies
+-- must outlive its row in background_jobs, or retrying it would let it run
+-- before the jobs it depends on. They are deleted along with the job once it
+-- succeeds or is purged.
+ALTER TABLE swirl_job_dependencies DROP CONSTRAINT swirl_job_dependencies_job_id_fkey;
@@ -0,0 +1,187 @@
+//! Enqueues a configurable mix of jobs, so the load an application expects can
+//! be rehearsed against its real database and runners before launch.
+//!
+//! The mix is read from `LOADGEN_MIX`, a comma separated list of
+//! `name=weight:payload_bytes:duration_ms:failure_rate` entries, such as
+//! `small=90:100:1:0.01,large=10:100000:50:0.05`. Each entry's jobs carry a
+//! payload of the given size, take the given time to run, and fail with the
+//! given probability. They are placed in the queue `loadgen.<name>`, so they
+//! can be told apart from real jobs with `swirl stats`, and only runners of
+//! this example pick them up.
+//!
+//! - `LOADGEN_MODE`: `enqueue`, `run` or `both` (the default). Start several
+//!   processes in `run` mode to rehearse with more than one runner
+//! - `LOADGEN_JOBS`: the number of jobs to enqueue, 10k by default
+//! - `LOADGEN_RATE`: the most jobs to enqueue per second. Jobs are enqueued as
+//!   fast as possible if this isn't set
+//! - `LOADGEN_THREADS`: the number of threads to run jobs on
+
+use diesel::prelude::*;
+use std::collections::hash_map::RandomState;
+use std::error::Error;
+use std::hash::{BuildHasher, Hasher};
+use std::thread;
+use std::time::{Duration, Instant};
+use swirl::*;
+
+const DEFAULT_MIX: &str = "small=90:100:1:0.01,large=9:100000:50:0.05,slow=1:1000:2000:0.2";
+
+#[swirl::background_job]
+fn loadgen_job(payload: String, duration_ms: u64, failure_rate: f64) -> Result<(), PerformError> {
+    thread::sleep(Duration::from_millis(duration_ms));
+    if random_fraction() < failure_rate {
+        return Err(format!("simulated failure of a {} byte job", payload.len()).into());
+    }
+    Ok(())
+}
+
+/// One entry of `LOADGEN_MIX`
+struct Profile {
+    queue: String,
+    weight: u32,
+    payload_bytes: usize,
+    duration_ms: u64,
+    failure_rate: f64,
+}
+
+impl Profile {
+    fn parse(entry: &str) -> Result<Self, Box<dyn Error>> {
+        let invalid = || format!("invalid LOADGEN_MIX entry `{}`", entry);
+        let (name, settings) = entry.split_once('=').ok_or_else(invalid)?;
+        let settings = settings.split(':').collect::<Vec<_>>();
+        match settings.as_slice() {
+            [weight, payload_bytes, duration_ms, failure_rate] => Ok(Self {
+                queue: format!("loadgen.{}", name.trim()),
+                weight: weight.parse().map_err(|_| invalid())?,
+                payload_bytes: payload_bytes.parse().map_err(|_| invalid())?,
+                duration_ms: duration_ms.parse().map_err(|_| invalid())?,
+                failure_rate: failure_rate.parse().map_err(|_| invalid())?,
+            }),
+            _ => Err(invalid().into()),
+        }
+    }
+
+    fn job(&self) -> loadgen_job::Job {
+        loadgen_job(
+            "x".repeat(self.payload_bytes),
+            self.duration_ms,
+            self.failure_rate,
+        )
+    }
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let database_url = dotenv::var("DATABASE_URL")?;
+    let mode = dotenv::var("LOADGEN_MODE").unwrap_or_else(|_| "both".into());
+    let mix = dotenv::var("LOADGEN_MIX").unwrap_or_else(|_| DEFAULT_MIX.into());
+    let profiles = mix
+        .split(',')
+        .map(Profile::parse)
+        .collect::<Result<Vec<_>, _>>()?;
+    if profiles.iter().map(|p| p.weight).sum::<u32>() == 0 {
+        return Err("LOADGEN_MIX must have an entry with a weight above 0".into());
+    }
+
+    let mut builder = Runner::builder(())
+        .database_url(database_url)
+        .queues(profiles.iter().map(|p| p.queue.clone()))
+        .measure_lock_hold_times(true);
+    if let Ok(threads) = dotenv::var("LOADGEN_THREADS") {
+        builder = builder.thread_count(threads.parse()?);
+    }
+    let runner = builder.build();
+
+    match mode.as_str() {
+        "enqueue" => enqueue_jobs(&*runner.connection_pool().get()?, &profiles),
+        "run" => run_jobs(&runner),
+        "both" => {
+            enqueue_jobs(&*runner.connection_pool().get()?, &profiles)?;
+            run_jobs(&runner)
+        }
+        _ => Err(format!("unknown LOADGEN_MODE `{}`", mode).into()),
+    }
+}
+
+fn enqueue_jobs(conn: &PgConnection, profiles: &[Profile]) -> Result<(), Box<dyn Error>> {
+    let count = match dotenv::var("LOADGEN_JOBS") {
+        Ok(count) => count.parse()?,
+        Err(_) => 10_000,
+    };
+    let interval = match dotenv::var("LOADGEN_RATE") {
+        Ok(rate) => Some(Duration::from_secs(1).div_f64(rate.parse()?)),
+        Err(_) => None,
+    };
+    let total_weight = profiles.iter().map(|p| p.weight).sum::<u32>();
+
+    println!("Enqueuing {} jobs", count);
+    let started = Instant::now();
+    let mut latencies = Vec::with_capacity(count);
+    for i in 0..count {
+        if let Some(interval) = interval {
+            let due = interval * i as u32;
+            if let Some(wait) = due.checked_sub(started.elapsed()) {
+                thread::sleep(wait);
+            }
+        }
+        let mut pick = (random_fraction() * f64::from(total_weight)) as u32;
+        let profile = profiles
+            .iter()
+            .find(|p| {
+                let found = pick < p.weight;
+                pick = pick.saturating_sub(p.weight);
+                found
+            })
+            .unwrap_or(&profiles[0]);
+
+        let enqueue_started = Instant::now();
+        queue(&profile.queue).enqueue(conn, profile.job())?;
+        latencies.push(enqueue_started.elapsed());
+    }
+
+    let elapsed = started.elapsed();
+    latencies.sort();
+    println!(
+        "Enqueued {} jobs in {:?} ({:.0} jobs/s)",
+        count,
+        elapsed,
+        count as f64 / elapsed.as_secs_f64()
+    );
+    if !latencies.is_empty() {
+        let percentile = |p: usize| latencies[(latencies.len() - 1) * p / 100];
+        println!("enqueue p50: {:?}", percentile(50));
+        println!("enqueue p99: {:?}", percentile(99));
+    }
+    Ok(())
+}
+
+fn run_jobs<Pool>(runner: &Runner<(), Pool>) -> Result<(), Box<dyn Error>>
+where
+    Pool: db::DieselPool + 'static,
+{
+    println!("Running jobs");
+    let summary = runner.run_until_empty()?;
+    println!(
+        "Ran {} jobs in {:?} ({:.0} jobs/s), {} failed",
+        summary.jobs_run,
+        summary.duration,
+        summary.jobs_run as f64 / summary.duration.as_secs_f64(),
+        summary.failures
+    );
+    if let Some(times) = runner.lock_hold_times() {
+        println!(
+            "lock hold p50: {:?}",
+            times.percentile(50.0).unwrap_or_default()
+        );
+        println!(
+            "lock hold p99: {:?}",
+            times.percentile(99.0).unwrap_or_default()
+        );
+    }
+    Ok(())
+}
+
+/// A random number between 0 and 1, from the randomly keyed hasher in std
+fn random_fraction() -> f64 {
+    let bits = RandomState::new().build_hasher().finish();
+    (bits >> 11) as f64 / (1u64 << 53) as f64
+}
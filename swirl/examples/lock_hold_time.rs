@@ -0,0 +1,64 @@
+//! Measures how long jobs hold their locks, and fails if it has regressed.
+//!
+//! Set `LOCK_HOLD_BASELINE_MICROS` to the p99 lock hold time of a previous run
+//! to compare against it. The run fails if the p99 is more than 20% slower.
+
+use diesel::prelude::*;
+use std::error::Error;
+use std::time::Duration;
+use swirl::*;
+
+/// How much slower than the baseline the p99 can be before it's a regression
+const ALLOWED_REGRESSION: f64 = 1.2;
+
+#[swirl::background_job]
+fn dummy_job() -> Result<(), PerformError> {
+    Ok(())
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let database_url = dotenv::var("DATABASE_URL")?;
+    println!("Enqueuing 10k jobs");
+    let runner = Runner::builder(())
+        .database_url(database_url)
+        .measure_lock_hold_times(true)
+        .build();
+    enqueue_jobs(&*runner.connection_pool().get()?).unwrap();
+    println!("Running jobs");
+
+    runner.run_all_pending_jobs()?;
+    runner.check_for_failed_jobs()?;
+
+    let times = runner
+        .lock_hold_times()
+        .expect("lock hold times were not measured");
+    let p99 = times.percentile(99.0).unwrap_or_default();
+    println!("Measured {} jobs", times.count());
+    println!("mean: {:?}", times.mean().unwrap_or_default());
+    println!("p50:  {:?}", times.percentile(50.0).unwrap_or_default());
+    println!("p99:  {:?}", p99);
+    println!("max:  {:?}", times.max().unwrap_or_default());
+
+    if let Ok(baseline) = dotenv::var("LOCK_HOLD_BASELINE_MICROS") {
+        let baseline = Duration::from_micros(baseline.parse()?);
+        if p99.as_secs_f64() > baseline.as_secs_f64() * ALLOWED_REGRESSION {
+            return Err(format!(
+                "p99 lock hold time regressed from {:?} to {:?}",
+                baseline, p99
+            )
+            .into());
+        }
+        println!("No regression from baseline p99 of {:?}", baseline);
+    }
+
+    Ok(())
+}
+
+fn enqueue_jobs(conn: &PgConnection) -> Result<(), EnqueueError> {
+    use diesel::sql_query;
+    sql_query("TRUNCATE TABLE background_jobs;").execute(conn)?;
+    for _ in 0..10_000 {
+        dummy_job().enqueue(conn)?;
+    }
+    Ok(())
+}
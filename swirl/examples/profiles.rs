@@ -0,0 +1,94 @@
+//! Measures each `Profile` against a burst of short jobs and a trickle of jobs
+//! enqueued one at a time, which is how the numbers the profiles use were
+//! picked. Run it against a database with the swirl migrations applied, and
+//! nothing else using its queue.
+//!
+//! - `PROFILES_JOBS`: the number of jobs in the burst, 10k by default
+//! - `PROFILES_JOB_MS`: how long each job takes to run, 1ms by default
+//! - `PROFILES_TRICKLE`: the number of jobs in the trickle, 10 by default.
+//!   Each one waits for the previous one to start, so with `Profile::Batch`
+//!   this takes a few minutes
+
+use diesel::prelude::*;
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use swirl::*;
+
+type StartLatencies = Arc<Mutex<Vec<Duration>>>;
+
+#[swirl::background_job]
+fn profiled_job(
+    env: &StartLatencies,
+    enqueued_at_micros: u64,
+    duration_ms: u64,
+) -> Result<(), PerformError> {
+    let waited = micros_since_epoch().saturating_sub(enqueued_at_micros);
+    env.lock().unwrap().push(Duration::from_micros(waited));
+    thread::sleep(Duration::from_millis(duration_ms));
+    Ok(())
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let database_url = dotenv::var("DATABASE_URL")?;
+    let jobs = env_or("PROFILES_JOBS", 10_000)?;
+    let job_ms = env_or("PROFILES_JOB_MS", 1)?;
+    let trickle = env_or("PROFILES_TRICKLE", 10)?;
+
+    for profile in &[Profile::LowLatency, Profile::HighThroughput, Profile::Batch] {
+        let latencies = StartLatencies::default();
+        let runner = Runner::builder(latencies.clone())
+            .database_url(database_url.as_str())
+            .profile(*profile)
+            .build();
+        let conn = runner.connection_pool().get()?;
+        diesel::sql_query("DELETE FROM background_jobs").execute(&*conn)?;
+
+        for _ in 0..jobs {
+            profiled_job(0, job_ms).enqueue(&*conn)?;
+        }
+        let summary = runner.run_until_empty()?;
+        latencies.lock().unwrap().clear();
+
+        let shutdown = runner.shutdown_handle();
+        let running = thread::spawn(move || runner.run_forever(Duration::from_secs(1)));
+        for enqueued in 1..=trickle {
+            // Spread out, so that jobs are enqueued at different points
+            // between polls
+            thread::sleep(Duration::from_millis(37));
+            profiled_job(micros_since_epoch(), job_ms).enqueue(&*conn)?;
+            while (latencies.lock().unwrap().len() as u64) < enqueued {
+                thread::sleep(Duration::from_millis(1));
+            }
+        }
+        shutdown.shutdown();
+        running.join().unwrap();
+
+        let mut latencies = latencies.lock().unwrap().clone();
+        latencies.sort();
+        let percentile = |p: usize| latencies[(latencies.len() - 1) * p / 100];
+        println!(
+            "{:?}: {:.0} jobs/s, start latency p50 {:?}, p99 {:?}",
+            profile,
+            summary.jobs_run as f64 / summary.duration.as_secs_f64(),
+            percentile(50),
+            percentile(99),
+        );
+    }
+    Ok(())
+}
+
+fn env_or(name: &str, default: u64) -> Result<u64, Box<dyn Error>> {
+    match dotenv::var(name) {
+        Ok(value) => Ok(value.parse()?),
+        Err(_) => Ok(default),
+    }
+}
+
+fn micros_since_epoch() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros() as u64
+}
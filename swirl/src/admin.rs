@@ -0,0 +1,1228 @@
+//! Functions for inspecting and managing the job queue
+//!
+//! These are intended to be used by operators, either from a console or an
+//! admin page. None of them are needed to enqueue or run jobs.
+
+use diesel::pg::data_types::PgInterval;
+use diesel::prelude::*;
+use diesel::sql_types::{
+    Array, BigInt, Integer, Interval, Jsonb, Nullable, SmallInt, Text, Timestamp,
+};
+use diesel::{sql_query, update};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::errors::AdminError;
+use crate::registry::JobVTable;
+use crate::worker::LIVE_WORKER_TIMEOUT;
+
+/// A summary of a job in the queue, as returned by [`list_jobs`].
+///
+/// To avoid loading large payloads, only a preview of the job's arguments is
+/// included.
+#[derive(Debug, Clone, QueryableByName)]
+pub struct JobSummary {
+    /// The id of the job
+    #[sql_type = "BigInt"]
+    pub id: i64,
+
+    /// The type of the job, as given by [`Job::JOB_TYPE`](crate::Job::JOB_TYPE)
+    #[sql_type = "Text"]
+    pub job_type: String,
+
+    /// The queue the job was placed in
+    #[sql_type = "Text"]
+    pub queue: String,
+
+    /// The priority of the job
+    #[sql_type = "SmallInt"]
+    pub priority: i16,
+
+    /// The number of times this job has failed
+    #[sql_type = "Integer"]
+    pub retries: i32,
+
+    /// The last time this job failed
+    #[sql_type = "Timestamp"]
+    pub last_retry: SystemTime,
+
+    /// When this job was enqueued
+    #[sql_type = "Timestamp"]
+    pub created_at: SystemTime,
+
+    /// When this job was scheduled to first run
+    #[sql_type = "Timestamp"]
+    pub scheduled_at: SystemTime,
+
+    /// The job's arguments serialized as JSON, truncated to
+    /// [`PreviewOptions::max_length`] characters
+    #[sql_type = "Text"]
+    pub data_preview: String,
+
+    /// The size of the job's full serialized arguments in bytes
+    #[sql_type = "Integer"]
+    pub data_size: i32,
+}
+
+/// The progress of a running job, as returned by [`job_progress`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Queryable)]
+pub struct JobProgress {
+    /// The units of work the job has done
+    pub current: i64,
+
+    /// The units of work the job has to do in total
+    pub total: i64,
+
+    /// When the job last reported its progress
+    pub updated_at: SystemTime,
+}
+
+impl JobProgress {
+    /// How much of its work the job has done, between 0 and 1
+    pub fn fraction(&self) -> f64 {
+        if self.total <= 0 {
+            return 0.0;
+        }
+        (self.current as f64 / self.total as f64).clamp(0.0, 1.0)
+    }
+}
+
+/// Everything about a job in the queue, including its full arguments, as
+/// returned by [`get_job`]
+#[derive(Debug, Clone, Queryable)]
+pub struct JobDetails {
+    /// The id of the job
+    pub id: i64,
+
+    /// The type of the job
+    pub job_type: String,
+
+    /// The job's arguments
+    pub data: serde_json::Value,
+
+    /// The queue the job was placed in
+    pub queue: String,
+
+    /// The priority of the job
+    pub priority: i16,
+
+    /// The metadata the job was enqueued with
+    pub metadata: serde_json::Value,
+
+    /// The number of times this job has failed
+    pub retries: i32,
+
+    /// The last time this job failed
+    pub last_retry: SystemTime,
+
+    /// When this job was enqueued
+    pub created_at: SystemTime,
+
+    /// When this job was scheduled to first run
+    pub scheduled_at: SystemTime,
+
+    /// When this job will be retried, if its
+    /// [`RetryPolicy`](crate::RetryPolicy) decided when
+    pub retry_at: Option<SystemTime>,
+}
+
+/// The number of jobs of one type, as returned by [`count_jobs_by_type`]
+#[derive(Debug, Clone, PartialEq, Eq, QueryableByName)]
+pub struct JobTypeCount {
+    /// The type of the jobs
+    #[sql_type = "Text"]
+    pub job_type: String,
+
+    /// The number of jobs, including jobs which are running or waiting to be
+    /// retried
+    #[sql_type = "BigInt"]
+    pub jobs: i64,
+
+    /// The number of jobs which have failed at least once
+    #[sql_type = "BigInt"]
+    pub failing_jobs: i64,
+}
+
+/// The number of jobs of one type in one queue, as returned by
+/// [`queue_stats`]
+#[derive(Debug, Clone, QueryableByName)]
+pub struct QueueStats {
+    /// The type of the jobs
+    #[sql_type = "Text"]
+    pub job_type: String,
+
+    /// The queue the jobs were placed in
+    #[sql_type = "Text"]
+    pub queue: String,
+
+    /// The number of jobs, including jobs which are running or waiting to be
+    /// retried
+    #[sql_type = "BigInt"]
+    pub jobs: i64,
+
+    /// The number of jobs which have failed at least once
+    #[sql_type = "BigInt"]
+    pub failing_jobs: i64,
+
+    /// When the oldest of the jobs was enqueued
+    #[sql_type = "Timestamp"]
+    pub oldest_created_at: SystemTime,
+
+    /// When these numbers were counted
+    #[sql_type = "Timestamp"]
+    pub refreshed_at: SystemTime,
+}
+
+/// A runner which is currently processing the queue, as returned by
+/// [`list_workers`]
+#[derive(Debug, Clone, Queryable)]
+pub struct Worker {
+    /// The id of the worker
+    pub id: i64,
+
+    /// The hostname of the machine the runner is on
+    pub hostname: String,
+
+    /// The process id of the runner
+    pub pid: i32,
+
+    /// The version given to [`Builder::worker_version`](crate::Builder::worker_version)
+    pub version: Option<String>,
+
+    /// The queues the runner was configured to run jobs from, or `None` if it
+    /// runs jobs from all queues
+    pub queues: Option<Vec<String>>,
+
+    /// The number of threads the runner uses to run jobs
+    pub thread_count: i32,
+
+    /// When the runner first looked for jobs
+    pub started_at: SystemTime,
+
+    /// The last time the runner reported that it was alive
+    pub last_heartbeat_at: SystemTime,
+}
+
+/// A job which is running right now, and the runner running it, as returned
+/// by [`running_jobs`]
+#[derive(Debug, Clone, QueryableByName)]
+pub struct RunningJob {
+    /// The id of the job
+    #[sql_type = "BigInt"]
+    pub job_id: i64,
+
+    /// The id of the runner in `swirl_workers`, or `None` if the runner
+    /// wasn't registered when it started the job
+    #[sql_type = "Nullable<BigInt>"]
+    pub worker_id: Option<i64>,
+
+    /// The hostname of the machine the runner is on
+    #[sql_type = "Nullable<Text>"]
+    pub hostname: Option<String>,
+
+    /// The process id of the runner
+    #[sql_type = "Nullable<Integer>"]
+    pub pid: Option<i32>,
+
+    /// The process id of the Postgres backend holding the job's lock
+    #[sql_type = "Integer"]
+    pub backend_pid: i32,
+}
+
+/// A failed run of a job, recorded when
+/// [`Builder::failure_samples`](crate::Builder::failure_samples) is enabled
+#[derive(Debug, Clone, Queryable)]
+pub struct FailureSample {
+    /// The id of the sample
+    pub id: i64,
+
+    /// The id of the job which failed. The job may no longer exist.
+    pub job_id: i64,
+
+    /// The type of the job which failed
+    pub job_type: String,
+
+    /// Identifies failures of the same kind. Samples with the same job type,
+    /// and errors which only differ by numbers, have the same fingerprint.
+    pub fingerprint: String,
+
+    /// The arguments the job was run with
+    pub data: serde_json::Value,
+
+    /// The error the job failed with
+    pub error: String,
+
+    /// When the job failed
+    pub created_at: SystemTime,
+
+    /// The [`OWNER`](crate::Job::OWNER) of the job when it failed
+    pub owner: Option<String>,
+}
+
+/// A job which failed for the last time, and was moved to `swirl_failed_jobs`.
+/// Returned by [`list_failed_jobs`].
+#[derive(Debug, Clone, Queryable)]
+pub struct FailedJob {
+    /// The id the job had while it was queued
+    pub id: i64,
+
+    /// The type of the job
+    pub job_type: String,
+
+    /// The job's arguments
+    pub data: serde_json::Value,
+
+    /// The priority of the job
+    pub priority: i16,
+
+    /// The queue the job was placed in
+    pub queue: String,
+
+    /// The metadata the job was enqueued with
+    pub metadata: serde_json::Value,
+
+    /// The number of times the job failed, including the last failure
+    pub retries: i32,
+
+    /// When the job was enqueued
+    pub created_at: SystemTime,
+
+    /// The error the job failed with the last time it was run
+    pub error: String,
+
+    /// When the job was moved to `swirl_failed_jobs`
+    pub failed_at: SystemTime,
+
+    /// A snapshot of the job taken the first time it failed. See
+    /// [`first_failure`].
+    pub first_failure: Option<serde_json::Value>,
+
+    /// The [`OWNER`](crate::Job::OWNER) of the job when it failed for the
+    /// last time
+    pub owner: Option<String>,
+}
+
+/// One failure of a job, as returned by [`retry_history`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetryAttempt {
+    /// When the job failed
+    pub failed_at: SystemTime,
+
+    /// A fingerprint of the job's type and the error it failed with, the same
+    /// as [`FailureSample::fingerprint`]. Errors which only differ by numbers
+    /// have the same fingerprint.
+    pub fingerprint: String,
+}
+
+/// The pending jobs and quota of a tenant, as returned by [`list_tenants`].
+///
+/// A job belongs to a tenant if its metadata has a `"tenant"` key whose value
+/// is a string.
+#[derive(Debug, Clone, Queryable)]
+pub struct Tenant {
+    /// The name of the tenant
+    pub tenant: String,
+
+    /// The number of the tenant's jobs which are in the queue, including jobs
+    /// which are running or waiting to be retried
+    pub pending_jobs: i64,
+
+    /// The most pending jobs the tenant may have, or `None` if there is no
+    /// limit
+    pub max_pending_jobs: Option<i64>,
+}
+
+/// A call to [`Runner::run_until_empty`](crate::Runner::run_until_empty) on a
+/// runner built with [`Builder::record_runs`](crate::Builder::record_runs), as
+/// returned by [`list_runs`]
+#[derive(Debug, Clone, Queryable)]
+pub struct RecordedRun {
+    /// The id of the run
+    pub id: i64,
+
+    /// The hostname of the machine the runner was on, if it could be
+    /// determined
+    pub hostname: Option<String>,
+
+    /// When the run started, according to the database's clock
+    pub started_at: SystemTime,
+
+    /// When the run finished, according to the database's clock
+    pub finished_at: SystemTime,
+
+    /// The number of jobs which were run, including jobs which failed
+    pub jobs_run: i64,
+
+    /// The number of jobs which failed
+    pub failures: i64,
+
+    /// The error which ended the run early, if there was one
+    pub error: Option<String>,
+}
+
+/// A queue name which is treated as another queue, as returned by
+/// [`list_queue_aliases`]
+#[derive(Debug, Clone, PartialEq, Eq, Queryable)]
+pub struct QueueAlias {
+    /// The name which is an alias
+    pub alias: String,
+
+    /// The queue it is an alias of
+    pub queue: String,
+}
+
+/// Controls how much of a job's arguments are included in a [`JobSummary`]
+#[derive(Debug, Clone)]
+pub struct PreviewOptions {
+    /// The maximum number of characters to include in the preview.
+    ///
+    /// Defaults to 200.
+    pub max_length: i32,
+
+    /// Top level keys whose values should be replaced with `"[REDACTED]"` in
+    /// the preview. Use this for arguments such as email addresses or tokens
+    /// which shouldn't be shown to everyone who can see the job list.
+    ///
+    /// Defaults to no keys.
+    pub redacted_keys: Vec<String>,
+}
+
+impl Default for PreviewOptions {
+    fn default() -> Self {
+        Self {
+            max_length: 200,
+            redacted_keys: Vec::new(),
+        }
+    }
+}
+
+/// Selects jobs for [`search_jobs`] and [`boost`]. Only jobs which match every
+/// field which is set are selected.
+///
+/// Filters can be built up with the methods below, or by setting the fields
+/// directly.
+///
+/// ```
+/// # use swirl::admin::JobFilter;
+/// let filter = JobFilter::new()
+///     .job_type("send_email")
+///     .data_at(&["user", "id"], 42);
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct JobFilter {
+    /// Only select jobs of this type
+    pub job_type: Option<String>,
+
+    /// Only select jobs in this queue
+    pub queue: Option<String>,
+
+    /// Only select jobs whose metadata contains this JSON object, such as
+    /// `{"tenant": "acme"}`. Nested objects are matched the same way.
+    pub metadata: Option<serde_json::Value>,
+
+    /// Only select jobs whose arguments contain this JSON, using Postgres'
+    /// `@>` operator. For example `{"user_id": 42}` selects jobs which have
+    /// a `user_id` of 42, whatever their other arguments are.
+    pub data: Option<serde_json::Value>,
+}
+
+impl JobFilter {
+    /// A filter which selects every job
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only select jobs of type `job_type`
+    pub fn job_type<S: Into<String>>(mut self, job_type: S) -> Self {
+        self.job_type = Some(job_type.into());
+        self
+    }
+
+    /// Only select jobs in `queue`
+    pub fn queue<S: Into<String>>(mut self, queue: S) -> Self {
+        self.queue = Some(queue.into());
+        self
+    }
+
+    /// Only select jobs whose metadata contains `value`. Calling this more
+    /// than once selects jobs which contain all of the values.
+    pub fn metadata_contains(mut self, value: serde_json::Value) -> Self {
+        merge_contained(&mut self.metadata, value);
+        self
+    }
+
+    /// Only select jobs whose arguments contain `value`. Calling this more than
+    /// once selects jobs which contain all of the values.
+    pub fn data_contains(mut self, value: serde_json::Value) -> Self {
+        merge_contained(&mut self.data, value);
+        self
+    }
+
+    /// Only select jobs whose arguments have `value` at `path`, which is a
+    /// list of object keys. `data_at(&["user", "id"], 42)` is the same as
+    /// `data_contains(json!({"user": {"id": 42}}))`.
+    pub fn data_at<V: Into<serde_json::Value>>(self, path: &[&str], value: V) -> Self {
+        let value = path.iter().rev().fold(value.into(), |value, key| {
+            let mut object = serde_json::Map::new();
+            object.insert((*key).into(), value);
+            serde_json::Value::Object(object)
+        });
+        self.data_contains(value)
+    }
+}
+
+/// Combines `value` into `target`, so that JSON containing `target` contains
+/// both the old value and `value`
+fn merge_contained(target: &mut Option<serde_json::Value>, value: serde_json::Value) {
+    use serde_json::Value;
+
+    match (target, value) {
+        (Some(Value::Object(existing)), Value::Object(new)) => {
+            for (key, value) in new {
+                let mut entry = existing.remove(&key);
+                merge_contained(&mut entry, value);
+                if let Some(merged) = entry {
+                    existing.insert(key, merged);
+                }
+            }
+        }
+        (Some(Value::Array(existing)), Value::Array(new)) => existing.extend(new),
+        (target, value) => *target = Some(value),
+    }
+}
+
+/// Lists the jobs in the queue ordered by id, along with a preview of their
+/// arguments.
+pub fn list_jobs(
+    conn: &PgConnection,
+    offset: i64,
+    limit: i64,
+    preview: &PreviewOptions,
+) -> QueryResult<Vec<JobSummary>> {
+    search_jobs(conn, &JobFilter::default(), offset, limit, preview)
+}
+
+/// Lists the jobs in the queue which are selected by `filter`, in the same
+/// way as [`list_jobs`].
+///
+/// This is meant for finding the jobs for a specific user or record, such as
+/// with `JobFilter::new().data_at(&["user_id"], 42)`. Filtering on arguments
+/// isn't indexed, so this reads every job in the queue.
+pub fn search_jobs(
+    conn: &PgConnection,
+    filter: &JobFilter,
+    offset: i64,
+    limit: i64,
+    preview: &PreviewOptions,
+) -> QueryResult<Vec<JobSummary>> {
+    sql_query(
+        "SELECT id, job_type, queue, priority, retries, last_retry, created_at, scheduled_at, \
+         left(( \
+             CASE WHEN jsonb_typeof(data) = 'object' THEN COALESCE(( \
+                 SELECT jsonb_object_agg(key, CASE WHEN key = ANY($1) \
+                     THEN '\"[REDACTED]\"'::jsonb ELSE value END) \
+                 FROM jsonb_each(data) \
+             ), data) ELSE data END \
+         )::text, $2) AS data_preview, \
+         octet_length(data::text) AS data_size \
+         FROM background_jobs \
+         WHERE ($5::text IS NULL OR job_type = $5) \
+         AND ($6::text IS NULL OR queue = $6) \
+         AND ($7::jsonb IS NULL OR metadata @> $7) \
+         AND ($8::jsonb IS NULL OR data @> $8) \
+         ORDER BY id LIMIT $3 OFFSET $4",
+    )
+    .bind::<Array<Text>, _>(&preview.redacted_keys)
+    .bind::<Integer, _>(preview.max_length)
+    .bind::<BigInt, _>(limit)
+    .bind::<BigInt, _>(offset)
+    .bind::<Nullable<Text>, _>(filter.job_type.as_ref())
+    .bind::<Nullable<Text>, _>(filter.queue.as_ref())
+    .bind::<Nullable<Jsonb>, _>(filter.metadata.as_ref())
+    .bind::<Nullable<Jsonb>, _>(filter.data.as_ref())
+    .load(conn)
+}
+
+/// Loads a job from the queue, including its full arguments. Returns `None` if
+/// there is no job with the given id in the queue.
+///
+/// Use [`list_jobs`] or [`search_jobs`] to find the job, and this to inspect
+/// it.
+pub fn get_job(conn: &PgConnection, job_id: i64) -> QueryResult<Option<JobDetails>> {
+    use crate::schema::background_jobs::dsl::*;
+
+    background_jobs
+        .find(job_id)
+        .select((
+            id,
+            job_type,
+            data,
+            queue,
+            priority,
+            metadata,
+            retries,
+            last_retry,
+            created_at,
+            scheduled_at,
+            retry_at,
+        ))
+        .first(conn)
+        .optional()
+}
+
+/// The progress most recently reported by a running job with
+/// [`JobContext::set_progress`]. Returns `None` if the job isn't in the queue,
+/// or hasn't reported any progress.
+///
+/// Progress is kept while the job is waiting to be retried, so a job which
+/// failed part way through shows how far it got until it runs again.
+///
+/// [`JobContext::set_progress`]: crate::JobContext::set_progress
+pub fn job_progress(conn: &PgConnection, job_id: i64) -> QueryResult<Option<JobProgress>> {
+    use crate::schema::{background_jobs, swirl_job_progress};
+
+    swirl_job_progress::table
+        .find(job_id)
+        .filter(
+            swirl_job_progress::job_id.eq_any(background_jobs::table.select(background_jobs::id)),
+        )
+        .select((
+            swirl_job_progress::current,
+            swirl_job_progress::total,
+            swirl_job_progress::updated_at,
+        ))
+        .first(conn)
+        .optional()
+}
+
+/// Counts the jobs of each type which are in the queue right now, ordered by
+/// job type.
+///
+/// Unlike [`queue_stats`], this counts every job in the queue, so the numbers
+/// are always current, but it gets slow once there are millions of jobs.
+pub fn count_jobs_by_type(conn: &PgConnection) -> QueryResult<Vec<JobTypeCount>> {
+    sql_query(
+        "SELECT job_type, COUNT(*) AS jobs, \
+         COUNT(*) FILTER (WHERE retries > 0) AS failing_jobs \
+         FROM background_jobs GROUP BY job_type ORDER BY job_type",
+    )
+    .load(conn)
+}
+
+/// Replaces the arguments of a job, and makes it eligible to run immediately.
+///
+/// This is useful for fixing jobs which will never succeed because of a bad
+/// argument, such as a typo in an email address. The new arguments are checked
+/// against the job's registered type before the job is updated. The job's
+/// retry count is left as is, but any [`RetryPolicy`](crate::RetryPolicy)
+/// which delayed or stopped its retries is cleared.
+///
+/// Returns [`AdminError::JobRunning`] if the job is currently locked by a
+/// runner.
+pub fn retry_with(
+    conn: &PgConnection,
+    job_id: i64,
+    new_args: serde_json::Value,
+) -> Result<(), AdminError> {
+    use crate::schema::background_jobs::dsl::*;
+
+    conn.transaction(|| {
+        let locked_job_type = background_jobs
+            .find(job_id)
+            .select(job_type)
+            .for_update()
+            .skip_locked()
+            .first::<String>(conn)
+            .optional()?;
+        let locked_job_type = match locked_job_type {
+            Some(t) => t,
+            None => return Err(not_found_or_running(conn, job_id)?),
+        };
+
+        JobVTable::find(&locked_job_type)
+            .ok_or(AdminError::UnknownJobType(locked_job_type))?
+            .validate(&new_args)
+            .map_err(AdminError::InvalidArguments)?;
+
+        update(background_jobs.find(job_id))
+            .set((
+                data.eq(new_args),
+                last_retry.eq(UNIX_EPOCH),
+                retry_at.eq(None::<SystemTime>),
+            ))
+            .execute(conn)?;
+        Ok(())
+    })
+}
+
+/// Makes a job which is waiting to be retried eligible to run immediately, and
+/// resets its retry count to zero.
+///
+/// This is meant for after the bug which made the job fail has been fixed.
+/// Jobs which have run out of retries have been moved to `swirl_failed_jobs`,
+/// and are retried with [`retry_failed_job`] instead.
+///
+/// Returns [`AdminError::JobRunning`] if the job is currently locked by a
+/// runner.
+pub fn retry_job(conn: &PgConnection, job_id: i64) -> Result<(), AdminError> {
+    use crate::schema::background_jobs::dsl::*;
+
+    conn.transaction(|| {
+        let locked = background_jobs
+            .find(job_id)
+            .select(id)
+            .for_update()
+            .skip_locked()
+            .first::<i64>(conn)
+            .optional()?;
+        if locked.is_none() {
+            return Err(not_found_or_running(conn, job_id)?);
+        }
+
+        update(background_jobs.find(job_id))
+            .set((
+                retries.eq(0),
+                last_retry.eq(UNIX_EPOCH),
+                retry_at.eq(None::<SystemTime>),
+            ))
+            .execute(conn)?;
+        Ok(())
+    })
+}
+
+/// Does [`retry_job`] for every job which has failed at least once and is
+/// waiting to be retried. Returns the number of jobs which were reset.
+///
+/// Jobs which are running are skipped.
+pub fn retry_all_failed(conn: &PgConnection) -> QueryResult<usize> {
+    sql_query(
+        "UPDATE background_jobs \
+         SET retries = 0, last_retry = '1970-01-01', retry_at = NULL \
+         WHERE id IN ( \
+             SELECT id FROM background_jobs WHERE retries > 0 FOR UPDATE SKIP LOCKED \
+         )",
+    )
+    .execute(conn)
+}
+
+/// Counts the jobs of each type in each queue, ordered by job type and queue.
+///
+/// Rather than counting the jobs in the queue, which gets slow once there are
+/// millions of them, this reads the `swirl_queue_stats` materialized view.
+/// The numbers are from the last time the view was refreshed, which can be
+/// done periodically with
+/// [`maintenance::RefreshQueueStats`](crate::maintenance::RefreshQueueStats).
+/// Job types which had no jobs at the time are not included.
+pub fn queue_stats(conn: &PgConnection) -> QueryResult<Vec<QueueStats>> {
+    sql_query(
+        "SELECT job_type, queue, jobs, failing_jobs, oldest_created_at, refreshed_at \
+         FROM swirl_queue_stats ORDER BY job_type, queue",
+    )
+    .load(conn)
+}
+
+/// Lists the runners which are currently processing the queue, ordered by when
+/// they started.
+///
+/// Runners register themselves the first time they look for jobs, and are
+/// included as long as they have checked in within the last 90 seconds.
+pub fn list_workers(conn: &PgConnection) -> QueryResult<Vec<Worker>> {
+    use crate::schema::swirl_workers::dsl::*;
+    use diesel::dsl::{now, IntervalDsl};
+
+    let timeout = (LIVE_WORKER_TIMEOUT.as_secs() as i32).seconds();
+    swirl_workers
+        .filter(last_heartbeat_at.gt(now - timeout))
+        .order((started_at, id))
+        .load(conn)
+}
+
+/// Lists the jobs which are running right now, and the runners running them,
+/// ordered by job id.
+///
+/// Runners label the connection holding each job's lock with the job's id and
+/// their own, so this is read from `pg_stat_activity` and is always up to
+/// date. A runner which has died never shows up here, since its jobs are no
+/// longer locked.
+pub fn running_jobs(conn: &PgConnection) -> QueryResult<Vec<RunningJob>> {
+    sql_query(
+        "SELECT r.job_id, r.worker_id, w.hostname, w.pid, r.backend_pid FROM ( \
+             SELECT substring(application_name FROM '^swirl job (\\d+)')::bigint AS job_id, \
+             substring(application_name FROM ' worker (\\d+)$')::bigint AS worker_id, \
+             pid AS backend_pid \
+             FROM pg_stat_activity \
+             WHERE datname = current_database() AND application_name ~ '^swirl job \\d+' \
+         ) r \
+         LEFT JOIN swirl_workers w ON w.id = r.worker_id \
+         ORDER BY r.job_id",
+    )
+    .load(conn)
+}
+
+/// Terminates the connections holding jobs whose lease has expired, so that
+/// they can be run again. Returns the number of connections which were
+/// terminated.
+///
+/// Runners built with [`Builder::lease_duration`](crate::Builder::lease_duration)
+/// already do this whenever they renew their own leases. This is for when
+/// none of them are running.
+pub fn reclaim_expired_leases(conn: &PgConnection) -> QueryResult<i64> {
+    crate::storage::reclaim_expired_leases(conn)
+}
+
+/// Lists the most recent failure samples, newest first.
+///
+/// If `job_type` is given, only samples for that job type are returned.
+pub fn list_failure_samples(
+    conn: &PgConnection,
+    job_type: Option<&str>,
+    limit: i64,
+) -> QueryResult<Vec<FailureSample>> {
+    use crate::schema::swirl_failure_samples::dsl;
+
+    let mut query = dsl::swirl_failure_samples
+        .order(dsl::id.desc())
+        .limit(limit)
+        .into_boxed();
+    if let Some(job_type) = job_type {
+        query = query.filter(dsl::job_type.eq(job_type));
+    }
+    query.load(conn)
+}
+
+/// Lists the jobs which failed for the last time, most recently failed first.
+///
+/// Jobs end up here once they run out of retries, or fail with an error whose
+/// [`RetryPolicy`](crate::RetryPolicy) is `Never`.
+pub fn list_failed_jobs(
+    conn: &PgConnection,
+    offset: i64,
+    limit: i64,
+) -> QueryResult<Vec<FailedJob>> {
+    use crate::schema::swirl_failed_jobs::dsl::*;
+
+    swirl_failed_jobs
+        .select((
+            id,
+            job_type,
+            data,
+            priority,
+            queue,
+            metadata,
+            retries,
+            created_at,
+            error,
+            failed_at,
+            first_failure,
+            owner,
+        ))
+        .order((failed_at.desc(), id.desc()))
+        .offset(offset)
+        .limit(limit)
+        .load(conn)
+}
+
+/// Moves a job from `swirl_failed_jobs` back into the queue, to be run as soon
+/// as possible.
+///
+/// If `new_args` is given, the job's arguments are replaced with it, after
+/// checking them against the job's registered type. The job keeps its id,
+/// locality and unique key, and its retry count starts over from zero.
+///
+/// Returns [`AdminError::JobNotFound`] if there is no failed job with the
+/// given id. If a job of the same type has been enqueued with the job's unique
+/// key since it failed, the job is left in `swirl_failed_jobs` and a
+/// [`AdminError::DatabaseError`] for the unique violation is returned.
+pub fn retry_failed_job(
+    conn: &PgConnection,
+    job_id: i64,
+    new_args: Option<serde_json::Value>,
+) -> Result<(), AdminError> {
+    use crate::schema::swirl_failed_jobs::dsl::*;
+
+    conn.transaction(|| {
+        let failed_job_type = swirl_failed_jobs
+            .find(job_id)
+            .select(job_type)
+            .for_update()
+            .first::<String>(conn)
+            .optional()?
+            .ok_or(AdminError::JobNotFound)?;
+
+        if let Some(new_args) = &new_args {
+            JobVTable::find(&failed_job_type)
+                .ok_or(AdminError::UnknownJobType(failed_job_type))?
+                .validate(new_args)
+                .map_err(AdminError::InvalidArguments)?;
+        }
+
+        sql_query(
+            "WITH retried AS (DELETE FROM swirl_failed_jobs WHERE id = $1 RETURNING *) \
+             INSERT INTO background_jobs \
+                 (id, job_type, data, priority, queue, metadata, min_worker_version, \
+                 first_failure, retry_history, scheduled_at, unique_key, locality) \
+             SELECT id, job_type, COALESCE($2, data), priority, queue, metadata, \
+                 min_worker_version, first_failure, retry_history, scheduled_at, \
+                 unique_key, locality \
+             FROM retried",
+        )
+        .bind::<BigInt, _>(job_id)
+        .bind::<Nullable<Jsonb>, _>(new_args)
+        .execute(conn)?;
+        Ok(())
+    })
+}
+
+/// Loads the snapshot taken the first time a job failed, which is kept while
+/// the job is retried, edited with [`retry_with`], or moved to
+/// `swirl_failed_jobs`. Comparing it with the job's current arguments shows
+/// what the job looked like before anyone touched it.
+///
+/// The snapshot is a JSON object with the following keys:
+///
+/// - `data`: the job's arguments when it failed
+/// - `error`: the error it failed with
+/// - `failed_at`: when it failed, as a timestamp without a time zone
+/// - `worker_id`: the id of the runner in [`list_workers`], if it was
+///   registered
+/// - `worker_version`: the runner's
+///   [`Builder::worker_version`](crate::Builder::worker_version)
+/// - `hostname`: the hostname of the runner's machine, if it could be
+///   determined
+///
+/// Returns `None` if the job has never failed, or doesn't exist.
+pub fn first_failure(conn: &PgConnection, job_id: i64) -> QueryResult<Option<serde_json::Value>> {
+    use crate::schema::{background_jobs, swirl_failed_jobs};
+
+    let queued = background_jobs::table
+        .find(job_id)
+        .select(background_jobs::first_failure)
+        .first(conn)
+        .optional()?;
+    match queued {
+        Some(snapshot) => Ok(snapshot),
+        None => swirl_failed_jobs::table
+            .find(job_id)
+            .select(swirl_failed_jobs::first_failure)
+            .first(conn)
+            .optional()
+            .map(Option::flatten),
+    }
+}
+
+/// Loads the most recent failures of a job, oldest first, whether it is still
+/// being retried or has been moved to `swirl_failed_jobs`. Only the last 10
+/// failures are kept.
+///
+/// If every attempt has the same fingerprint, the job is failing the same way
+/// each time, and retrying it is unlikely to help. A fingerprint which changes
+/// between attempts points at a flaky dependency instead.
+///
+/// Returns `None` if the job doesn't exist.
+pub fn retry_history(conn: &PgConnection, job_id: i64) -> QueryResult<Option<Vec<RetryAttempt>>> {
+    use crate::schema::{background_jobs, swirl_failed_jobs};
+
+    let queued = background_jobs::table
+        .find(job_id)
+        .select(background_jobs::retry_history)
+        .first::<serde_json::Value>(conn)
+        .optional()?;
+    let history = match queued {
+        Some(history) => Some(history),
+        None => swirl_failed_jobs::table
+            .find(job_id)
+            .select(swirl_failed_jobs::retry_history)
+            .first(conn)
+            .optional()?,
+    };
+    Ok(history.map(|history| {
+        history
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|attempt| {
+                let failed_at = attempt["failed_at"].as_f64()?;
+                let fingerprint = attempt["fingerprint"].as_str()?;
+                Some(RetryAttempt {
+                    failed_at: UNIX_EPOCH + Duration::from_secs_f64(failed_at),
+                    fingerprint: fingerprint.into(),
+                })
+            })
+            .collect()
+    }))
+}
+
+/// Deletes the jobs in `swirl_failed_jobs` which failed longer than
+/// `older_than` ago, along with their checkpoints and dependencies. Returns the
+/// number of jobs which were deleted.
+pub fn purge_failed_jobs(conn: &PgConnection, older_than: Duration) -> QueryResult<usize> {
+    let older_than = PgInterval::from_microseconds(older_than.as_micros() as i64);
+    conn.transaction(|| {
+        sql_query(
+            "DELETE FROM background_job_checkpoints WHERE job_id IN ( \
+                 SELECT id FROM swirl_failed_jobs WHERE failed_at < now() - $1 \
+             )",
+        )
+        .bind::<Interval, _>(&older_than)
+        .execute(conn)?;
+        sql_query(
+            "DELETE FROM swirl_job_dependencies WHERE job_id IN ( \
+                 SELECT id FROM swirl_failed_jobs WHERE failed_at < now() - $1 \
+             )",
+        )
+        .bind::<Interval, _>(&older_than)
+        .execute(conn)?;
+        sql_query("DELETE FROM swirl_failed_jobs WHERE failed_at < now() - $1")
+            .bind::<Interval, _>(&older_than)
+            .execute(conn)
+    })
+}
+
+/// Deletes the results in `swirl_job_results` which have expired, returning
+/// the number of results which were deleted. See
+/// [`Job::RESULT_RETENTION`](crate::Job::RESULT_RETENTION).
+pub fn purge_job_results(conn: &PgConnection) -> QueryResult<usize> {
+    use crate::schema::swirl_job_results::dsl::*;
+    use diesel::dsl::now;
+
+    diesel::delete(swirl_job_results.filter(expires_at.le(now))).execute(conn)
+}
+
+/// Lists the `limit` most recent runs recorded in `swirl_runs`, newest first.
+///
+/// A scheduler which has stopped starting the runner shows up as a latest run
+/// which finished longer ago than the schedule allows, and a failing one as a
+/// run with an [`error`](RecordedRun::error).
+pub fn list_runs(conn: &PgConnection, limit: i64) -> QueryResult<Vec<RecordedRun>> {
+    use crate::schema::swirl_runs::dsl::*;
+
+    swirl_runs
+        .order((finished_at.desc(), id.desc()))
+        .limit(limit)
+        .load(conn)
+}
+
+/// Deletes the runs in `swirl_runs` which finished longer than `older_than`
+/// ago, returning the number of runs which were deleted
+pub fn purge_runs(conn: &PgConnection, older_than: Duration) -> QueryResult<usize> {
+    use crate::schema::swirl_runs::dsl::*;
+    use diesel::dsl::now;
+
+    let older_than = PgInterval::from_microseconds(older_than.as_micros() as i64);
+    diesel::delete(swirl_runs.filter(finished_at.lt(now - older_than.into_sql::<Interval>())))
+        .execute(conn)
+}
+
+/// The number of rows updated at a time by [`rename_job_type`] and [`boost`]
+const BATCH_SIZE: i64 = 1000;
+
+/// Changes the job type of all queued jobs with the type `old_name` to
+/// `new_name`, returning the number of jobs which were updated.
+///
+/// This is useful when a job has been renamed, but jobs with its old name are
+/// still in the queue. Jobs are updated in batches, so this can safely be used
+/// on large queues. Jobs which are currently running are skipped, and will keep
+/// their old name if they fail.
+pub fn rename_job_type(conn: &PgConnection, old_name: &str, new_name: &str) -> QueryResult<usize> {
+    if old_name == new_name {
+        return Ok(0);
+    }
+
+    let mut total = 0;
+    loop {
+        let updated = sql_query(
+            "UPDATE background_jobs SET job_type = $2 WHERE id IN ( \
+                 SELECT id FROM background_jobs WHERE job_type = $1 \
+                 ORDER BY id LIMIT $3 FOR UPDATE SKIP LOCKED \
+             )",
+        )
+        .bind::<Text, _>(old_name)
+        .bind::<Text, _>(new_name)
+        .bind::<BigInt, _>(BATCH_SIZE)
+        .execute(conn)?;
+        total += updated;
+        if (updated as i64) < BATCH_SIZE {
+            return Ok(total);
+        }
+    }
+}
+
+/// Raises the priority of the queued jobs selected by `filter` to
+/// `new_priority`, returning the number of jobs which were updated.
+///
+/// This is meant for expediting a specific backlog during an incident, such as
+/// the jobs of one customer. Jobs which already have a priority of at least
+/// `new_priority` are left alone, so this never lowers a job's priority. Like
+/// [`rename_job_type`], jobs are updated in batches and jobs which are
+/// currently running are skipped.
+pub fn boost(conn: &PgConnection, filter: &JobFilter, new_priority: i16) -> QueryResult<usize> {
+    let mut total = 0;
+    loop {
+        let updated = sql_query(
+            "UPDATE background_jobs SET priority = $1 WHERE id IN ( \
+                 SELECT id FROM background_jobs WHERE priority < $1 \
+                 AND ($2::text IS NULL OR job_type = $2) \
+                 AND ($3::text IS NULL OR queue = $3) \
+                 AND ($4::jsonb IS NULL OR metadata @> $4) \
+                 AND ($5::jsonb IS NULL OR data @> $5) \
+                 ORDER BY id LIMIT $6 FOR UPDATE SKIP LOCKED \
+             )",
+        )
+        .bind::<SmallInt, _>(new_priority)
+        .bind::<Nullable<Text>, _>(filter.job_type.as_ref())
+        .bind::<Nullable<Text>, _>(filter.queue.as_ref())
+        .bind::<Nullable<Jsonb>, _>(filter.metadata.as_ref())
+        .bind::<Nullable<Jsonb>, _>(filter.data.as_ref())
+        .bind::<BigInt, _>(BATCH_SIZE)
+        .execute(conn)?;
+        total += updated;
+        if (updated as i64) < BATCH_SIZE {
+            return Ok(total);
+        }
+    }
+}
+
+/// Lists every tenant which has had jobs enqueued or a quota set, ordered by
+/// name
+pub fn list_tenants(conn: &PgConnection) -> QueryResult<Vec<Tenant>> {
+    use crate::schema::swirl_tenants::dsl::*;
+
+    swirl_tenants.order(tenant).load(conn)
+}
+
+/// Limits the number of pending jobs `name` may have to `max_pending_jobs`, or
+/// removes the limit if it is `None`.
+///
+/// Once a tenant has reached its quota, enqueueing its jobs fails with
+/// [`EnqueueError::QuotaExceeded`](crate::EnqueueError::QuotaExceeded) until
+/// some of them have finished. Lowering a quota below the number of pending
+/// jobs doesn't remove any jobs.
+pub fn set_tenant_quota(
+    conn: &PgConnection,
+    name: &str,
+    max_pending_jobs: Option<i64>,
+) -> QueryResult<()> {
+    use crate::schema::swirl_tenants::dsl;
+
+    diesel::insert_into(dsl::swirl_tenants)
+        .values((
+            dsl::tenant.eq(name),
+            dsl::max_pending_jobs.eq(max_pending_jobs),
+        ))
+        .on_conflict(dsl::tenant)
+        .do_update()
+        .set(dsl::max_pending_jobs.eq(max_pending_jobs))
+        .execute(conn)?;
+    Ok(())
+}
+
+/// Makes every runner treat the queue `alias` as the same queue as `queue`.
+///
+/// Runners which run jobs from either queue run jobs from both, so a queue can
+/// be renamed without deploying producers and runners at the same time: alias
+/// the old name to the new one, then update producers and runners in any
+/// order, and remove the alias once no jobs are left in the old queue. This
+/// takes effect the next time each runner fetches a job.
+///
+/// Aliases are transitive, so if `queue` is itself an alias, `alias` is
+/// treated as the same queue as the one `queue` is an alias of. If `queue`
+/// was the queue `alias` is an alias of, the alias is reversed.
+pub fn set_queue_alias(conn: &PgConnection, alias: &str, queue: &str) -> QueryResult<()> {
+    use crate::schema::swirl_queue_aliases::dsl;
+
+    if alias == queue {
+        return Ok(());
+    }
+
+    conn.transaction(|| {
+        let target = dsl::swirl_queue_aliases
+            .find(queue)
+            .select(dsl::queue)
+            .for_update()
+            .first::<String>(conn)
+            .optional()?;
+        let target = match target {
+            Some(target) if target != alias => target,
+            _ => queue.to_string(),
+        };
+
+        // Keep every alias pointing at the one name which isn't an alias
+        diesel::delete(dsl::swirl_queue_aliases.find(&target)).execute(conn)?;
+        diesel::update(dsl::swirl_queue_aliases.filter(dsl::queue.eq(alias)))
+            .set(dsl::queue.eq(&target))
+            .execute(conn)?;
+        diesel::insert_into(dsl::swirl_queue_aliases)
+            .values((dsl::alias.eq(alias), dsl::queue.eq(&target)))
+            .on_conflict(dsl::alias)
+            .do_update()
+            .set(dsl::queue.eq(&target))
+            .execute(conn)?;
+        Ok(())
+    })
+}
+
+/// Stops treating the queue `alias` as the same queue as the one it was an
+/// alias of.
+///
+/// Returns `false` if `alias` wasn't an alias.
+pub fn remove_queue_alias(conn: &PgConnection, alias: &str) -> QueryResult<bool> {
+    use crate::schema::swirl_queue_aliases::dsl;
+
+    let deleted = diesel::delete(dsl::swirl_queue_aliases.find(alias)).execute(conn)?;
+    Ok(deleted > 0)
+}
+
+/// Lists every queue alias, ordered by the queue they are an alias of
+pub fn list_queue_aliases(conn: &PgConnection) -> QueryResult<Vec<QueueAlias>> {
+    use crate::schema::swirl_queue_aliases::dsl::*;
+
+    swirl_queue_aliases.order((queue, alias)).load(conn)
+}
+
+/// Puts jobs of type `job_type` into debug mode for `duration`.
+///
+/// While a job type is in debug mode, runners write verbose logs to stderr for
+/// each of its runs, including the job's arguments, metadata and how long it
+/// took. This is intended for investigating a misbehaving job type in
+/// production without redeploying. Runners cache which job types are in debug
+/// mode, so it may take a few seconds for this to take effect.
+pub fn enable_debug_logging(
+    conn: &PgConnection,
+    job_type: &str,
+    duration: Duration,
+) -> QueryResult<()> {
+    use crate::schema::swirl_debug_job_types::dsl;
+    use diesel::dsl::now;
+
+    let duration = PgInterval::from_microseconds(duration.as_micros() as i64);
+    let until = now + duration.into_sql::<Interval>();
+    diesel::insert_into(dsl::swirl_debug_job_types)
+        .values((dsl::job_type.eq(job_type), dsl::enabled_until.eq(until)))
+        .on_conflict(dsl::job_type)
+        .do_update()
+        .set(dsl::enabled_until.eq(until))
+        .execute(conn)?;
+    Ok(())
+}
+
+/// Takes jobs of type `job_type` out of debug mode before its duration is up.
+///
+/// Returns `false` if the job type wasn't in debug mode.
+pub fn disable_debug_logging(conn: &PgConnection, job_type: &str) -> QueryResult<bool> {
+    use crate::schema::swirl_debug_job_types::dsl;
+
+    let deleted = diesel::delete(dsl::swirl_debug_job_types.find(job_type)).execute(conn)?;
+    Ok(deleted > 0)
+}
+
+/// Determines why a job could not be locked
+fn not_found_or_running(conn: &PgConnection, job_id: i64) -> QueryResult<AdminError> {
+    use crate::schema::background_jobs::dsl::*;
+    use diesel::dsl::exists;
+
+    let job_exists = diesel::select(exists(background_jobs.find(job_id))).get_result(conn)?;
+    if job_exists {
+        Ok(AdminError::JobRunning)
+    } else {
+        Ok(AdminError::JobNotFound)
+    }
+}
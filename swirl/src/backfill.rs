@@ -0,0 +1,165 @@
+//! Enqueueing a job for every row of a query
+//!
+//! The most common way to introduce a new job is to enqueue it once for every
+//! existing record, such as to fill in a new column. [`enqueue_from_query`]
+//! does this for queries of any size by loading them a page at a time, using
+//! the key of the last row of each page to load the next. Each page is
+//! enqueued in its own transaction, so an interrupted backfill can be resumed
+//! with [`Backfill::after`] from the last key it reported.
+
+use diesel::prelude::*;
+
+use crate::errors::EnqueueError;
+use crate::Job;
+
+/// The number of rows loaded and enqueued at a time by default
+const DEFAULT_BATCH_SIZE: i64 = 1000;
+
+type ProgressCallback<'a, K> = dyn FnMut(Progress<'_, K>) + 'a;
+
+/// How far a backfill has gotten, passed to the function given to
+/// [`Backfill::on_progress`] after each batch
+#[derive(Debug, Clone, Copy)]
+pub struct Progress<'a, K> {
+    /// The number of batches which have been enqueued
+    pub batches: u64,
+
+    /// The number of jobs which have been enqueued
+    pub jobs: u64,
+
+    /// The key of the last row which was enqueued. Passing this to
+    /// [`Backfill::after`] continues from the next row.
+    pub last_key: &'a K,
+}
+
+/// Options for [`enqueue_from_query`], for backfills which need more control
+#[allow(missing_debug_implementations)]
+pub struct Backfill<'a, K> {
+    batch_size: i64,
+    after: Option<K>,
+    on_progress: Option<Box<ProgressCallback<'a, K>>>,
+}
+
+impl<'a, K> Default for Backfill<'a, K> {
+    fn default() -> Self {
+        Self {
+            batch_size: DEFAULT_BATCH_SIZE,
+            after: None,
+            on_progress: None,
+        }
+    }
+}
+
+impl<'a, K> Backfill<'a, K> {
+    /// A backfill with the default options
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The number of rows to load and enqueue at a time.
+    ///
+    /// Defaults to 1000
+    pub fn batch_size(mut self, batch_size: i64) -> Self {
+        self.batch_size = batch_size;
+        self
+    }
+
+    /// Start after the row with the key `key`, such as to resume a backfill
+    /// which was interrupted
+    pub fn after(mut self, key: K) -> Self {
+        self.after = Some(key);
+        self
+    }
+
+    /// Call `f` after each batch has been enqueued
+    pub fn on_progress<F>(mut self, f: F) -> Self
+    where
+        F: FnMut(Progress<'_, K>) + 'a,
+    {
+        self.on_progress = Some(Box::new(f));
+        self
+    }
+
+    /// Runs the backfill. See [`enqueue_from_query`].
+    pub fn run<Row, J, Q, F>(
+        mut self,
+        conn: &PgConnection,
+        mut query: Q,
+        mut to_job: F,
+    ) -> Result<u64, EnqueueError>
+    where
+        Q: FnMut(Option<&K>, i64) -> QueryResult<Vec<(K, Row)>>,
+        F: FnMut(Row) -> J,
+        J: Job,
+    {
+        let mut batches = 0;
+        let mut jobs = 0;
+        loop {
+            let rows = query(self.after.as_ref(), self.batch_size)?;
+            let row_count = rows.len() as i64;
+            let last_key = conn.transaction(|| {
+                let mut last_key = None;
+                for (key, row) in rows {
+                    to_job(row).enqueue(conn)?;
+                    last_key = Some(key);
+                }
+                Ok::<_, EnqueueError>(last_key)
+            })?;
+
+            if let Some(key) = last_key {
+                batches += 1;
+                jobs += row_count as u64;
+                if let Some(on_progress) = &mut self.on_progress {
+                    on_progress(Progress {
+                        batches,
+                        jobs,
+                        last_key: &key,
+                    });
+                }
+                self.after = Some(key);
+            }
+            if row_count < self.batch_size {
+                return Ok(jobs);
+            }
+        }
+    }
+}
+
+/// Enqueues the job returned by `to_job` for every row returned by `query`,
+/// and returns the number of jobs which were enqueued.
+///
+/// `query` is given the key of the last row it returned, or `None` for the
+/// first page, and the number of rows to return. It must return up to that
+/// many `(key, row)` pairs with a key greater than the one it was given,
+/// ordered by key. This keeps each page fast no matter how far into the table
+/// it is, unlike `OFFSET`. For example:
+///
+/// ```ignore
+/// swirl::backfill::enqueue_from_query(
+///     conn,
+///     |after, limit| {
+///         users::table
+///             .select((users::id, (users::id, users::email)))
+///             .filter(users::id.gt(after.copied().unwrap_or(0)))
+///             .order(users::id)
+///             .limit(limit)
+///             .load::<(i32, (i32, String))>(conn)
+///     },
+///     |(id, email)| send_welcome_email(id, email),
+/// )?;
+/// ```
+///
+/// Rows are enqueued 1000 at a time, each batch in its own transaction. Use
+/// [`Backfill`] to change the batch size, or to report progress.
+pub fn enqueue_from_query<K, Row, J, Q, F>(
+    conn: &PgConnection,
+    query: Q,
+    to_job: F,
+) -> Result<u64, EnqueueError>
+where
+    Q: FnMut(Option<&K>, i64) -> QueryResult<Vec<(K, Row)>>,
+    F: FnMut(Row) -> J,
+    J: Job,
+{
+    Backfill::new().run(conn, query, to_job)
+}
@@ -0,0 +1,84 @@
+use diesel::{Connection, PgConnection};
+use std::panic::Location;
+
+use crate::enqueue::{EnqueueOptions, JobHandle};
+use crate::errors::EnqueueError;
+use crate::{storage, Job};
+
+#[allow(missing_debug_implementations)]
+/// The jobs of a batch being enqueued with [`enqueue_batch`]
+#[derive(Clone, Copy)]
+pub struct Batch<'a> {
+    conn: &'a PgConnection,
+    id: i64,
+}
+
+impl Batch<'_> {
+    /// The id of the batch, which is the id of the job run once it completes
+    pub fn id(&self) -> i64 {
+        self.id
+    }
+
+    /// Enqueues `job` as part of this batch.
+    ///
+    /// The batch's id is recorded in the job's metadata as `batch_id`.
+    #[track_caller]
+    pub fn enqueue<T: Job>(&self, job: T) -> Result<JobHandle, EnqueueError> {
+        let mut options = EnqueueOptions::for_job::<T>();
+        options.add_automatic_metadata(Location::caller());
+        options
+            .metadata
+            .entry("batch_id")
+            .or_insert_with(|| self.id.into());
+        let enqueued =
+            storage::enqueue_job(self.conn, job, options)?.ok_or(EnqueueError::Duplicate)?;
+        storage::add_to_batch(self.conn, self.id, enqueued.id)?;
+        Ok(JobHandle::new(enqueued.id))
+    }
+}
+
+/// Enqueues a batch of jobs, and `on_complete` to be run once every job in
+/// the batch has finished.
+///
+/// The jobs are enqueued by `f`, using [`Batch::enqueue`]. A job has finished
+/// once it has succeeded, been cancelled, or failed for the last time, so
+/// `on_complete` still runs if some of the jobs failed. It can find out which
+/// ones with [`JobContext::failed_batch_jobs`]. If the batch is empty,
+/// `on_complete` is run right away.
+///
+/// Everything is enqueued in a single transaction, so if `f` returns an error
+/// none of the jobs are enqueued. Returns the handle of `on_complete`, whose
+/// id is also the batch's id.
+///
+/// ```ignore
+/// swirl::enqueue_batch(&conn, send_report(report_id), |batch| {
+///     for account_id in account_ids {
+///         batch.enqueue(summarize_account(report_id, account_id))?;
+///     }
+///     Ok(())
+/// })?;
+/// ```
+///
+/// [`JobContext::failed_batch_jobs`]: crate::JobContext::failed_batch_jobs
+#[track_caller]
+pub fn enqueue_batch<T, F>(
+    conn: &PgConnection,
+    on_complete: T,
+    f: F,
+) -> Result<JobHandle, EnqueueError>
+where
+    T: Job,
+    F: FnOnce(&Batch<'_>) -> Result<(), EnqueueError>,
+{
+    let mut options = EnqueueOptions::for_job::<T>();
+    options.add_automatic_metadata(Location::caller());
+    conn.transaction(|| {
+        let enqueued =
+            storage::enqueue_job(conn, on_complete, options)?.ok_or(EnqueueError::Duplicate)?;
+        f(&Batch {
+            conn,
+            id: enqueued.id,
+        })?;
+        Ok(JobHandle::new(enqueued.id))
+    })
+}
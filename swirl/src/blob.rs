@@ -0,0 +1,105 @@
+use diesel::prelude::*;
+use diesel::sql_query;
+use diesel::sql_types::{BigInt, Binary, Integer, Nullable, Oid};
+use std::io::{self, Read};
+use std::ops::Deref;
+
+/// The number of bytes fetched from the database at a time
+const CHUNK_SIZE: i32 = 256 * 1024;
+
+#[derive(QueryableByName)]
+struct Chunk {
+    #[sql_type = "Nullable<Binary>"]
+    chunk: Option<Vec<u8>>,
+}
+
+enum Source {
+    LargeObject(u32),
+    Bytea { query: String, id: i64 },
+}
+
+#[allow(missing_debug_implementations)]
+/// Streams a binary payload out of the database.
+///
+/// Rather than loading the whole payload at once, it is fetched in chunks as
+/// it is read, so only one chunk is held in memory at a time. Returned by
+/// [`JobContext::large_object_reader`] and [`JobContext::bytea_reader`].
+///
+/// [`JobContext::large_object_reader`]: crate::JobContext::large_object_reader
+/// [`JobContext::bytea_reader`]: crate::JobContext::bytea_reader
+pub struct BlobReader<'a> {
+    conn: Box<dyn Deref<Target = PgConnection> + 'a>,
+    source: Source,
+    offset: i64,
+    chunk: Vec<u8>,
+    position: usize,
+    finished: bool,
+}
+
+impl<'a> BlobReader<'a> {
+    pub(crate) fn large_object(conn: Box<dyn Deref<Target = PgConnection> + 'a>, oid: u32) -> Self {
+        Self::new(conn, Source::LargeObject(oid))
+    }
+
+    pub(crate) fn bytea(
+        conn: Box<dyn Deref<Target = PgConnection> + 'a>,
+        table: &str,
+        column: &str,
+        id: i64,
+    ) -> Self {
+        let query = format!(
+            "SELECT substring({} FROM ($1 + 1)::integer FOR $2) AS chunk FROM {} WHERE id = $3",
+            quote_identifier(column),
+            quote_identifier(table),
+        );
+        Self::new(conn, Source::Bytea { query, id })
+    }
+
+    fn new(conn: Box<dyn Deref<Target = PgConnection> + 'a>, source: Source) -> Self {
+        Self {
+            conn,
+            source,
+            offset: 0,
+            chunk: Vec::new(),
+            position: 0,
+            finished: false,
+        }
+    }
+
+    fn fetch_chunk(&mut self) -> QueryResult<Vec<u8>> {
+        let chunk = match &self.source {
+            Source::LargeObject(oid) => sql_query("SELECT lo_get($1, $2, $3) AS chunk")
+                .bind::<Oid, _>(*oid)
+                .bind::<BigInt, _>(self.offset)
+                .bind::<Integer, _>(CHUNK_SIZE)
+                .get_result::<Chunk>(&**self.conn)?,
+            Source::Bytea { query, id } => sql_query(query.as_str())
+                .bind::<BigInt, _>(self.offset)
+                .bind::<Integer, _>(CHUNK_SIZE)
+                .bind::<BigInt, _>(*id)
+                .get_result::<Chunk>(&**self.conn)?,
+        };
+        Ok(chunk.chunk.unwrap_or_default())
+    }
+}
+
+impl<'a> Read for BlobReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position == self.chunk.len() && !self.finished {
+            self.chunk = self.fetch_chunk().map_err(io::Error::other)?;
+            self.position = 0;
+            self.offset += self.chunk.len() as i64;
+            self.finished = self.chunk.len() < CHUNK_SIZE as usize;
+        }
+
+        let remaining = &self.chunk[self.position..];
+        let len = remaining.len().min(buf.len());
+        buf[..len].copy_from_slice(&remaining[..len]);
+        self.position += len;
+        Ok(len)
+    }
+}
+
+fn quote_identifier(identifier: &str) -> String {
+    format!("\"{}\"", identifier.replace('"', "\"\""))
+}
@@ -0,0 +1,77 @@
+#[cfg(feature = "notify")]
+use postgres::fallible_iterator::FallibleIterator;
+#[cfg(feature = "notify")]
+use postgres::{Client, NoTls};
+#[cfg(feature = "notify")]
+use std::time::Duration;
+
+#[cfg(feature = "notify")]
+use crate::errors::WaitError;
+
+/// How a job finished running, as sent by runners with
+/// [`Builder::notify_on_completion`](crate::Builder::notify_on_completion)
+/// enabled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JobOutcome {
+    /// The job succeeded, and was removed from the queue
+    Succeeded,
+
+    /// The job failed. It will be retried unless it ran out of retries, which
+    /// can be checked with [`job_status`](crate::job_status).
+    Failed,
+
+    #[doc(hidden)]
+    /// Match on `_` instead, more variants may be added in the future
+    __NonExhaustive,
+}
+
+impl JobOutcome {
+    /// The payload of the notification sent for this outcome
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            JobOutcome::Succeeded => "succeeded",
+            JobOutcome::Failed => "failed",
+            JobOutcome::__NonExhaustive => unreachable!(),
+        }
+    }
+}
+
+/// The channel the completion of the given job is notified on
+pub(crate) fn channel(job_id: i64) -> String {
+    format!("swirl_job_{}", job_id)
+}
+
+/// Blocks until the job with the given id finishes running, or until `timeout`
+/// has passed.
+///
+/// Diesel has no way to receive notifications, so this opens its own
+/// connection to `database_url` for as long as it waits.
+#[cfg(feature = "notify")]
+pub(crate) fn wait(
+    database_url: &str,
+    job_id: i64,
+    timeout: Duration,
+) -> Result<JobOutcome, WaitError> {
+    let mut client = Client::connect(database_url, NoTls)?;
+    client.batch_execute(&format!("LISTEN {}", channel(job_id)))?;
+
+    // The job may have finished before we started listening
+    let row = client.query_one(
+        "SELECT EXISTS (SELECT 1 FROM background_jobs WHERE id = $1), \
+         EXISTS (SELECT 1 FROM swirl_failed_jobs WHERE id = $1)",
+        &[&job_id],
+    )?;
+    match (row.get(0), row.get(1)) {
+        (true, _) => {}
+        (false, true) => return Ok(JobOutcome::Failed),
+        (false, false) => return Ok(JobOutcome::Succeeded),
+    }
+
+    let mut notifications = client.notifications();
+    let notification = notifications.timeout_iter(timeout).next()?;
+    match notification {
+        Some(n) if n.payload() == JobOutcome::Succeeded.as_str() => Ok(JobOutcome::Succeeded),
+        Some(_) => Ok(JobOutcome::Failed),
+        None => Err(WaitError::Timeout),
+    }
+}
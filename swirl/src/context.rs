@@ -0,0 +1,375 @@
+use diesel::PgConnection;
+use serde::{de::DeserializeOwned, Serialize};
+use std::cell::RefCell;
+use std::panic::Location;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::blob::BlobReader;
+use crate::db::DieselPoolObj;
+use crate::enqueue::{EnqueueOptions, JobHandle};
+use crate::errors::{EnqueueError, PerformError};
+use crate::runner::ProgressWriter;
+use crate::scope::{self, Scope, ThreadBudget};
+use crate::storage::{self, FetchOptions};
+use crate::Job;
+
+type DeferredEnqueue = Box<dyn FnOnce(&PgConnection) -> Result<(), EnqueueError>>;
+
+/// The transaction a job is run in, and the jobs it has asked to enqueue once
+/// that transaction has committed
+pub(crate) struct JobTransaction<'a> {
+    conn: &'a PgConnection,
+    deferred: RefCell<Vec<DeferredEnqueue>>,
+}
+
+impl<'a> JobTransaction<'a> {
+    pub(crate) fn new(conn: &'a PgConnection) -> Self {
+        Self {
+            conn,
+            deferred: RefCell::new(Vec::new()),
+        }
+    }
+
+    /// The number of jobs which have been given to
+    /// [`JobContext::enqueue_on_commit`] so far
+    pub(crate) fn deferred_len(&self) -> usize {
+        self.deferred.borrow().len()
+    }
+
+    /// Forgets the jobs given to [`JobContext::enqueue_on_commit`] after the
+    /// first `keep`, because the job which enqueued them did not succeed
+    pub(crate) fn discard_deferred(&self, keep: usize) {
+        self.deferred.borrow_mut().truncate(keep);
+    }
+
+    /// Enqueues the jobs given to [`JobContext::enqueue_on_commit`]. This must
+    /// only be called once the transaction has committed.
+    pub(crate) fn enqueue_deferred(&self) {
+        for enqueue in self.deferred.borrow_mut().drain(..) {
+            if let Err(e) = enqueue(self.conn) {
+                eprintln!("Failed to enqueue follow-up job: {}", e);
+            }
+        }
+    }
+}
+
+#[allow(missing_debug_implementations)]
+/// Information about the job being run, and access to the resources the runner
+/// provides to it.
+///
+/// Jobs defined with `#[swirl::background_job]` can receive this by taking an
+/// argument of type `&JobContext`.
+pub struct JobContext<'a> {
+    job_id: i64,
+    priority: i16,
+    queue: String,
+    retries: u32,
+    max_retries: Option<u32>,
+    enqueued_at: Option<SystemTime>,
+    started_at: Instant,
+    yield_threshold: Option<Duration>,
+    transaction: &'a JobTransaction<'a>,
+    pool: &'a dyn DieselPoolObj,
+    fetch_options: &'a FetchOptions,
+    thread_budget: &'a ThreadBudget,
+    progress: Option<&'a ProgressWriter>,
+}
+
+/// What a runner gives to the jobs it runs, besides the job itself
+pub(crate) struct JobResources<'a> {
+    pub(crate) pool: &'a dyn DieselPoolObj,
+    pub(crate) fetch_options: &'a FetchOptions,
+    pub(crate) thread_budget: &'a ThreadBudget,
+    pub(crate) yield_threshold: Option<Duration>,
+    pub(crate) progress: Option<&'a ProgressWriter>,
+}
+
+impl<'a> JobContext<'a> {
+    pub(crate) fn new(
+        job: &storage::BackgroundJob,
+        transaction: &'a JobTransaction<'a>,
+        max_retries: Option<u32>,
+        resources: JobResources<'a>,
+    ) -> Self {
+        let enqueued_at = job.metadata["enqueued_at"]
+            .as_f64()
+            .filter(|secs| secs.is_finite() && *secs >= 0.0)
+            .map(|secs| UNIX_EPOCH + Duration::from_secs_f64(secs));
+        Self {
+            job_id: job.id,
+            priority: job.priority,
+            queue: job.queue.clone(),
+            retries: job.retries.max(0) as u32,
+            max_retries,
+            enqueued_at,
+            started_at: Instant::now(),
+            yield_threshold: resources.yield_threshold,
+            transaction,
+            pool: resources.pool,
+            fetch_options: resources.fetch_options,
+            thread_budget: resources.thread_budget,
+            progress: resources.progress,
+        }
+    }
+
+    /// The id of the job being run
+    pub fn job_id(&self) -> i64 {
+        self.job_id
+    }
+
+    /// The priority of the job being run
+    pub fn priority(&self) -> i16 {
+        self.priority
+    }
+
+    /// The queue the job being run was placed in
+    pub fn queue(&self) -> &str {
+        &self.queue
+    }
+
+    /// The number of times the job being run has failed before. This is `0`
+    /// the first time a job is run.
+    pub fn retries(&self) -> u32 {
+        self.retries
+    }
+
+    /// Returns `true` if the job won't be retried should this run fail.
+    ///
+    /// This is the case once the job has been retried as many times as the
+    /// job's [`MAX_RETRIES`](crate::Job::MAX_RETRIES) or the runner's
+    /// [`Builder::max_retries`](crate::Builder::max_retries) allow. It is
+    /// always `false` when there is no limit. A [`RetryPolicy`] which gives up
+    /// sooner is not taken into account.
+    ///
+    /// [`RetryPolicy`]: crate::RetryPolicy
+    pub fn is_final_attempt(&self) -> bool {
+        self.max_retries.is_some_and(|max| self.retries >= max)
+    }
+
+    /// When the job being run was enqueued, according to the enqueuing
+    /// process.
+    ///
+    /// This is read from the `enqueued_at` key of the job's
+    /// [`metadata`](crate::EnqueueOptions::metadata), so it is `None` if the
+    /// job was inserted without it, or middleware removed it.
+    pub fn enqueued_at(&self) -> Option<SystemTime> {
+        self.enqueued_at
+    }
+
+    /// Returns `true` if this job should stop running to make room for more
+    /// urgent work.
+    ///
+    /// This will be the case once the job has been running for longer than the
+    /// threshold given to [`Builder::job_yield_threshold`], and there is a job
+    /// with a higher priority waiting in one of this runner's queues. Jobs
+    /// which want to yield should save a checkpoint, and return [`JobYielded`]
+    /// to be put back in the queue. This will always return `false` if no
+    /// threshold was configured.
+    ///
+    /// [`Builder::job_yield_threshold`]: crate::Builder::job_yield_threshold
+    /// [`JobYielded`]: crate::JobYielded
+    pub fn should_yield(&self) -> Result<bool, PerformError> {
+        match self.yield_threshold {
+            Some(threshold) if self.started_at.elapsed() >= threshold => {
+                let conn = self.pool.get()?;
+                let waiting = storage::higher_priority_job_waiting(
+                    &**conn,
+                    self.fetch_options,
+                    self.priority,
+                )?;
+                Ok(waiting)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// Runs `f` with a [`Scope`], which can run sub-tasks of this job in
+    /// parallel. Every sub-task has finished by the time this returns.
+    ///
+    /// Sub-tasks only get a thread of their own while the runner has fewer
+    /// jobs and sub-tasks running than its
+    /// [`thread_count`](crate::Builder::thread_count), and run on this job's
+    /// thread otherwise. This lets a job which processes many files speed up
+    /// when the runner is idle, without running more work at once than the
+    /// runner was configured for.
+    ///
+    /// ```ignore
+    /// let sizes = ctx.scope(|s| {
+    ///     let tasks = files.iter().map(|f| s.spawn(move || resize(f))).collect::<Vec<_>>();
+    ///     tasks.into_iter().map(SubTask::join).collect::<Vec<_>>()
+    /// });
+    /// ```
+    ///
+    /// Sub-tasks can't use [`connection`](Self::connection), since it is only
+    /// usable from the job's thread. They can get their own connection from
+    /// [`pool`](Self::pool).
+    pub fn scope<'env, F, T>(&'env self, f: F) -> T
+    where
+        F: for<'scope> FnOnce(&Scope<'scope, 'env>) -> T,
+    {
+        scope::scope(self.thread_budget, f)
+    }
+
+    /// The connection pool the runner was built with
+    pub fn pool(&self) -> &'a dyn DieselPoolObj {
+        self.pool
+    }
+
+    /// The connection this job's transaction is running on.
+    ///
+    /// Anything written with this connection is committed along with the
+    /// job's completion, and rolled back if the job fails or yields. This
+    /// includes jobs enqueued with it, so a follow-up job enqueued with
+    /// `follow_up.enqueue(ctx.connection())` exists if and only if this job
+    /// succeeded.
+    ///
+    /// Rows written with this connection stay locked, and follow-up jobs are
+    /// invisible to other runners, until this job has finished. Use
+    /// [`enqueue_on_commit`](Self::enqueue_on_commit) for follow-up jobs which
+    /// don't need to be atomic with this one, and [`pool`](Self::pool) for
+    /// writes which should be kept even if this job fails.
+    pub fn connection(&self) -> &'a PgConnection {
+        self.transaction.conn
+    }
+
+    /// Enqueues `job` in this job's transaction, so the follow-up job exists
+    /// if and only if this job succeeds.
+    ///
+    /// This is the same as `job.enqueue(ctx.connection())`, except that this
+    /// job's id is recorded in the follow-up job's metadata as
+    /// `parent_job_id`. An error enqueueing the follow-up job should usually
+    /// be returned, so this job is retried rather than finishing without it.
+    #[track_caller]
+    pub fn enqueue<T: Job>(&self, job: T) -> Result<JobHandle, EnqueueError> {
+        let options = self.follow_up_options::<T>(Location::caller());
+        storage::enqueue_job(self.connection(), job, options)?
+            .map(|job| JobHandle::new(job.id))
+            .ok_or(EnqueueError::Duplicate)
+    }
+
+    /// The ids of the jobs which failed for the last time in the batch this
+    /// job was enqueued to complete with [`enqueue_batch`].
+    ///
+    /// Returns an empty list if this job doesn't complete a batch.
+    ///
+    /// [`enqueue_batch`]: crate::enqueue_batch
+    pub fn failed_batch_jobs(&self) -> Result<Vec<i64>, PerformError> {
+        Ok(storage::failed_batch_jobs(self.connection(), self.job_id)?)
+    }
+
+    /// Enqueues `job` once this job's transaction has committed.
+    ///
+    /// The job is only enqueued if this job succeeds. Unlike
+    /// [`enqueue`](Self::enqueue), the follow-up job is inserted after this
+    /// job has been removed from the queue, so an error enqueueing it can't
+    /// cause this job to be retried. Such errors are logged instead.
+    #[track_caller]
+    pub fn enqueue_on_commit<T: Job + 'static>(&self, job: T) {
+        let options = self.follow_up_options::<T>(Location::caller());
+        self.transaction
+            .deferred
+            .borrow_mut()
+            .push(Box::new(move |conn| {
+                storage::enqueue_job(conn, job, options).map(|_| ())
+            }));
+    }
+
+    /// The options a follow-up job enqueued from `location` is enqueued with
+    fn follow_up_options<T: Job>(&self, location: &Location<'_>) -> EnqueueOptions {
+        let mut options = EnqueueOptions::for_job::<T>();
+        options.add_automatic_metadata(location);
+        options
+            .metadata
+            .entry("parent_job_id")
+            .or_insert_with(|| self.job_id.into());
+        options
+    }
+
+    /// Streams the contents of the large object with the given oid.
+    ///
+    /// This allows jobs to operate on payloads which are too large to be
+    /// passed as arguments or held in memory. The object is read in chunks
+    /// using its own connection from the pool.
+    pub fn large_object_reader(&self, oid: u32) -> Result<BlobReader<'a>, PerformError> {
+        Ok(BlobReader::large_object(self.pool.get()?, oid))
+    }
+
+    /// Streams the contents of a `bytea` column from the row of `table` whose
+    /// `id` column is equal to `id`.
+    ///
+    /// Like [`large_object_reader`](Self::large_object_reader), the value is
+    /// read in chunks so it never has to be held in memory all at once.
+    pub fn bytea_reader(
+        &self,
+        table: &str,
+        column: &str,
+        id: i64,
+    ) -> Result<BlobReader<'a>, PerformError> {
+        Ok(BlobReader::bytea(self.pool.get()?, table, column, id))
+    }
+
+    /// Reports that this job has done `current` out of `total` units of work,
+    /// such as rows imported, so that a UI can show a progress bar with
+    /// [`admin::job_progress`].
+    ///
+    /// This never waits on the database. The latest progress is written from
+    /// another connection every 100ms or so, outside of this job's
+    /// transaction, so it can be seen while the job is still running and
+    /// isn't rolled back if the job fails. Progress reported during a
+    /// [replay](crate::Runner::replay) is discarded.
+    ///
+    /// [`admin::job_progress`]: crate::admin::job_progress
+    pub fn set_progress(&self, current: u64, total: u64) {
+        if let Some(progress) = self.progress {
+            progress.report(self.job_id, current, total);
+        }
+    }
+
+    /// Saves `result` as the value this job returned, so that whoever
+    /// enqueued it can look it up with [`job_result`] for the next
+    /// `retention`.
+    ///
+    /// The result is written in this job's transaction, so it is only kept if
+    /// the job succeeds. Jobs defined with `#[swirl::background_job]` which
+    /// return `Result<T, PerformError>` call this automatically, with their
+    /// [`RESULT_RETENTION`](crate::Job::RESULT_RETENTION).
+    ///
+    /// [`job_result`]: crate::job_result
+    pub fn save_result<T: Serialize>(
+        &self,
+        result: &T,
+        retention: Duration,
+    ) -> Result<(), PerformError> {
+        let result = serde_json::to_value(result)?;
+        storage::save_result(self.connection(), self.job_id, &result, retention)?;
+        Ok(())
+    }
+
+    /// Loads the state most recently given to [`save_checkpoint`] by this job.
+    ///
+    /// Returns `None` if this is the first time the job is being run, or it
+    /// never saved a checkpoint.
+    ///
+    /// [`save_checkpoint`]: Self::save_checkpoint
+    pub fn load_checkpoint<T: DeserializeOwned>(&self) -> Result<Option<T>, PerformError> {
+        let conn = self.pool.get()?;
+        match storage::load_checkpoint(&**conn, self.job_id)? {
+            Some(state) => Ok(Some(serde_json::from_value(state)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Saves the progress of this job.
+    ///
+    /// The checkpoint is committed immediately, and will still be there if the
+    /// job later fails. When the job is retried it can call
+    /// [`load_checkpoint`](Self::load_checkpoint) to resume from where it left
+    /// off, rather than starting over. Checkpoints are deleted once the job
+    /// succeeds.
+    pub fn save_checkpoint<T: Serialize>(&self, state: &T) -> Result<(), PerformError> {
+        let state = serde_json::to_value(state)?;
+        let conn = self.pool.get()?;
+        storage::save_checkpoint(&**conn, self.job_id, state)?;
+        Ok(())
+    }
+}
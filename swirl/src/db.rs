@@ -40,16 +40,20 @@ pub trait DieselPoolObj {
     ///
     /// This function will heap allocate the connection. This allocation can
     /// be avoided by using [`Self::with_connection`]
-    fn get(&self) -> Result<Box<dyn Deref<Target = PgConnection> + '_>, Box<dyn Error>>;
+    fn get(
+        &self,
+    ) -> Result<Box<dyn Deref<Target = PgConnection> + '_>, Box<dyn Error + Send + Sync>>;
 
     fn with_connection(
         &self,
-        f: &dyn Fn(&PgConnection) -> Result<(), Box<dyn Error>>,
-    ) -> Result<(), Box<dyn Error>>;
+        f: &dyn Fn(&PgConnection) -> Result<(), Box<dyn Error + Send + Sync>>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>>;
 }
 
 impl<T: DieselPool> DieselPoolObj for T {
-    fn get(&self) -> Result<Box<dyn Deref<Target = PgConnection> + '_>, Box<dyn Error>> {
+    fn get(
+        &self,
+    ) -> Result<Box<dyn Deref<Target = PgConnection> + '_>, Box<dyn Error + Send + Sync>> {
         DieselPool::get(self)
             .map(|v| Box::new(v) as _)
             .map_err(|v| Box::new(v) as _)
@@ -57,8 +61,8 @@ impl<T: DieselPool> DieselPoolObj for T {
 
     fn with_connection(
         &self,
-        f: &dyn Fn(&PgConnection) -> Result<(), Box<dyn Error>>,
-    ) -> Result<(), Box<dyn Error>> {
+        f: &dyn Fn(&PgConnection) -> Result<(), Box<dyn Error + Send + Sync>>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
         let conn = DieselPool::get(self)?;
         f(&conn)
     }
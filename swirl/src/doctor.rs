@@ -0,0 +1,273 @@
+//! Diagnostics for a swirl installation
+//!
+//! [`doctor`] inspects the database swirl is pointed at and reports anything
+//! which is likely to cause problems. Including its output in bug reports is
+//! greatly appreciated.
+
+use diesel::dsl::now;
+use diesel::prelude::*;
+use diesel::sql_query;
+use diesel::sql_types::{BigInt, Bool, Integer, Text};
+use std::fmt;
+
+/// The columns swirl expects on `background_jobs`, and their types as reported
+/// by `information_schema.columns`
+const EXPECTED_COLUMNS: &[(&str, &str)] = &[
+    ("id", "bigint"),
+    ("job_type", "text"),
+    ("data", "jsonb"),
+    ("retries", "integer"),
+    ("last_retry", "timestamp without time zone"),
+    ("created_at", "timestamp without time zone"),
+    ("priority", "smallint"),
+    ("queue", "text"),
+    ("metadata", "jsonb"),
+    ("min_worker_version", "ARRAY"),
+    ("scheduled_at", "timestamp without time zone"),
+    ("retry_at", "timestamp without time zone"),
+    ("first_failure", "jsonb"),
+    ("unique_key", "text"),
+    ("retry_history", "jsonb"),
+    ("locality", "text"),
+];
+
+/// The indexes swirl expects on `background_jobs`
+const EXPECTED_INDEXES: &[&str] = &[
+    "background_jobs_pkey",
+    "background_jobs_priority_id",
+    "background_jobs_queue_priority_id",
+    "background_jobs_job_type_unique_key",
+];
+
+/// The versions of swirl's migrations, as recorded by Diesel
+const EXPECTED_MIGRATIONS: &[&str] = &[
+    "20180503150523",
+    "20261015000001",
+    "20261015000002",
+    "20261015000003",
+    "20261015000004",
+    "20261015000005",
+    "20261015000006",
+    "20261015000007",
+    "20261015000008",
+    "20261015000009",
+    "20261015000010",
+    "20261015000011",
+    "20261015000012",
+    "20261015000013",
+    "20261015000014",
+    "20261015000015",
+    "20261015000016",
+    "20261015000017",
+    "20261015000018",
+    "20261015000019",
+    "20261015000020",
+    "20261015000021",
+    "20261015000022",
+    "20261015000023",
+    "20261015000024",
+    "20261015000025",
+    "20261015000026",
+    "20261015000027",
+    "20261015000028",
+    "20261015000029",
+    "20261015000030",
+    "20261015000031",
+    "20261015000032",
+    "20261015000033",
+];
+
+/// Jobs which were enqueued longer than this many seconds ago are reported as
+/// outdated
+const OUTDATED_JOB_AGE: i32 = 24 * 60 * 60;
+
+/// Transactions which have held locks on `background_jobs` for longer than
+/// this many seconds are reported as stuck
+const STUCK_LOCK_AGE: i32 = 60 * 60;
+
+/// The result of running [`doctor`]
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct DoctorReport {
+    /// Whether the `background_jobs` table exists at all. If this is `false`,
+    /// no other checks were run.
+    pub table_exists: bool,
+
+    /// Columns which are missing or have an unexpected type
+    pub schema_problems: Vec<String>,
+
+    /// Indexes which swirl expects to exist, but do not
+    pub missing_indexes: Vec<String>,
+
+    /// Swirl migrations which have not been run.
+    ///
+    /// This check is skipped if the database is not managed by Diesel's
+    /// migration runner.
+    pub missing_migrations: Vec<String>,
+
+    /// The number of jobs which were enqueued more than a day ago and are
+    /// still in the queue
+    pub outdated_jobs: i64,
+
+    /// The number of connections which have held a lock on `background_jobs`
+    /// for more than an hour. This usually means a job has hung.
+    pub stuck_locks: i64,
+}
+
+impl DoctorReport {
+    /// Returns `true` if no problems were found
+    pub fn is_healthy(&self) -> bool {
+        self.table_exists
+            && self.schema_problems.is_empty()
+            && self.missing_indexes.is_empty()
+            && self.missing_migrations.is_empty()
+            && self.outdated_jobs == 0
+            && self.stuck_locks == 0
+    }
+}
+
+impl fmt::Display for DoctorReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if !self.table_exists {
+            return writeln!(f, "The background_jobs table does not exist");
+        }
+        if self.is_healthy() {
+            return writeln!(f, "No problems found");
+        }
+        for problem in &self.schema_problems {
+            writeln!(f, "Schema problem: {}", problem)?;
+        }
+        for index in &self.missing_indexes {
+            writeln!(f, "Missing index: {}", index)?;
+        }
+        for migration in &self.missing_migrations {
+            writeln!(f, "Migration has not been run: {}", migration)?;
+        }
+        if self.outdated_jobs > 0 {
+            writeln!(
+                f,
+                "{} jobs were enqueued more than a day ago",
+                self.outdated_jobs
+            )?;
+        }
+        if self.stuck_locks > 0 {
+            writeln!(
+                f,
+                "{} connections have held job locks for more than an hour",
+                self.stuck_locks
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(QueryableByName)]
+struct Column {
+    #[sql_type = "Text"]
+    column_name: String,
+    #[sql_type = "Text"]
+    data_type: String,
+}
+
+#[derive(QueryableByName)]
+struct Name {
+    #[sql_type = "Text"]
+    name: String,
+}
+
+#[derive(QueryableByName)]
+struct Exists {
+    #[sql_type = "Bool"]
+    exists: bool,
+}
+
+#[derive(QueryableByName)]
+struct Count {
+    #[sql_type = "BigInt"]
+    count: i64,
+}
+
+/// Checks the database for common problems with a swirl installation.
+///
+/// This checks that the `background_jobs` table has the expected columns and
+/// indexes, that all of swirl's migrations have been run, and looks for jobs
+/// which have been sitting in the queue for a long time or have held their
+/// lock for a long time.
+pub fn doctor(conn: &PgConnection) -> QueryResult<DoctorReport> {
+    let mut report = DoctorReport::default();
+
+    report.table_exists = relation_exists(conn, "background_jobs")?;
+    if !report.table_exists {
+        return Ok(report);
+    }
+
+    let columns = sql_query(
+        "SELECT column_name::text AS column_name, data_type::text AS data_type \
+         FROM information_schema.columns \
+         WHERE table_schema = current_schema() AND table_name = 'background_jobs'",
+    )
+    .load::<Column>(conn)?;
+    for &(name, expected_type) in EXPECTED_COLUMNS {
+        match columns.iter().find(|c| c.column_name == name) {
+            None => report
+                .schema_problems
+                .push(format!("column `{}` is missing", name)),
+            Some(c) if c.data_type != expected_type => report.schema_problems.push(format!(
+                "column `{}` has type `{}`, expected `{}`",
+                name, c.data_type, expected_type
+            )),
+            Some(_) => {}
+        }
+    }
+
+    let indexes = sql_query(
+        "SELECT indexname::text AS name FROM pg_indexes \
+         WHERE schemaname = current_schema() AND tablename = 'background_jobs'",
+    )
+    .load::<Name>(conn)?;
+    report.missing_indexes = EXPECTED_INDEXES
+        .iter()
+        .filter(|&&expected| !indexes.iter().any(|i| i.name == expected))
+        .map(|s| s.to_string())
+        .collect();
+
+    if relation_exists(conn, "__diesel_schema_migrations")? {
+        let versions = sql_query("SELECT version::text AS name FROM __diesel_schema_migrations")
+            .load::<Name>(conn)?;
+        report.missing_migrations = EXPECTED_MIGRATIONS
+            .iter()
+            .filter(|&&expected| !versions.iter().any(|v| v.name == expected))
+            .map(|s| s.to_string())
+            .collect();
+    }
+
+    report.outdated_jobs = {
+        use crate::schema::background_jobs::dsl::*;
+        use diesel::dsl::IntervalDsl;
+
+        background_jobs
+            .filter(created_at.lt(now - OUTDATED_JOB_AGE.seconds()))
+            .count()
+            .get_result(conn)?
+    };
+
+    report.stuck_locks = sql_query(
+        "SELECT COUNT(DISTINCT l.pid) AS count \
+         FROM pg_locks l INNER JOIN pg_stat_activity a ON a.pid = l.pid \
+         WHERE l.relation = 'background_jobs'::regclass \
+         AND l.mode = 'RowShareLock' \
+         AND l.pid <> pg_backend_pid() \
+         AND a.xact_start < now() - $1 * interval '1 second'",
+    )
+    .bind::<Integer, _>(STUCK_LOCK_AGE)
+    .get_result::<Count>(conn)?
+    .count;
+
+    Ok(report)
+}
+
+fn relation_exists(conn: &PgConnection, name: &str) -> QueryResult<bool> {
+    sql_query("SELECT to_regclass($1) IS NOT NULL AS exists")
+        .bind::<Text, _>(name)
+        .get_result::<Exists>(conn)
+        .map(|e| e.exists)
+}
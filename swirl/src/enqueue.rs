@@ -0,0 +1,379 @@
+use serde_json::{Map, Value};
+use std::panic::Location;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[cfg(feature = "notify")]
+use crate::completion::{self, JobOutcome};
+use crate::errors::EnqueueError;
+#[cfg(feature = "notify")]
+use crate::errors::WaitError;
+use crate::registry::JobVTable;
+use crate::{storage, Job};
+use diesel::{Connection, PgConnection};
+
+#[cfg(feature = "metrics")]
+pub(crate) mod metrics;
+#[cfg(feature = "metrics")]
+pub use metrics::{
+    enqueue_metrics_snapshot, EnqueueMetrics, EnqueueMetricsSnapshot, PayloadHistogram,
+};
+
+/// When a job should first be run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Schedule {
+    /// As soon as possible
+    Now,
+    /// Once the given time has passed
+    At(SystemTime),
+    /// Once the given amount of time has passed. This is measured using the
+    /// database's clock, not the clock of the machine enqueueing the job.
+    In(Duration),
+}
+
+/// A job which has been enqueued, returned by [`Job::enqueue`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct JobHandle {
+    id: i64,
+}
+
+impl JobHandle {
+    pub(crate) fn new(id: i64) -> Self {
+        Self { id }
+    }
+
+    /// The id of the job, which is how the functions in
+    /// [`admin`](crate::admin) refer to it
+    pub fn id(&self) -> i64 {
+        self.id
+    }
+
+    /// Blocks until the job finishes running, or until `timeout` has passed,
+    /// and returns whether it succeeded.
+    ///
+    /// Runners must have [`Builder::notify_on_completion`] enabled, or this
+    /// only returns once the timeout has passed. A separate connection to
+    /// `database_url` is opened to listen for the notification. TLS is not
+    /// supported for this connection.
+    ///
+    /// The job can't be run until the transaction which enqueued it has
+    /// committed, so this should not be called inside that transaction.
+    ///
+    /// Requires the `notify` feature.
+    ///
+    /// [`Builder::notify_on_completion`]: crate::Builder::notify_on_completion
+    #[cfg(feature = "notify")]
+    pub fn wait_for_completion(
+        &self,
+        database_url: &str,
+        timeout: Duration,
+    ) -> Result<JobOutcome, WaitError> {
+        completion::wait(database_url, self.id, timeout)
+    }
+}
+
+/// Options controlling how a job is enqueued
+#[derive(Debug, Clone, PartialEq)]
+pub struct EnqueueOptions {
+    /// The queue the job is placed in.
+    ///
+    /// Defaults to [`Job::QUEUE`]
+    pub queue: String,
+
+    /// The priority of the job. Jobs with a higher priority are run first.
+    ///
+    /// Defaults to [`Job::PRIORITY`]
+    pub priority: i16,
+
+    /// Arbitrary data stored alongside the job, such as a trace id. This is
+    /// not passed to the job, but is available to [`Builder::job_filter`] and
+    /// the admin functions.
+    ///
+    /// When a job is enqueued, the following keys are filled in unless they
+    /// are already present:
+    ///
+    /// - `enqueued_at`: the time the job was enqueued according to the
+    ///   enqueuing process, in seconds since the Unix epoch
+    /// - `enqueued_on`: the hostname of the enqueuing machine, if it could be
+    ///   determined
+    /// - `enqueued_from`: the source location of the call to
+    ///   [`Job::enqueue`], as `file:line:column`
+    /// - `parent_job_id`: the id of the job which enqueued this one, if it was
+    ///   enqueued with [`JobContext::enqueue`] or
+    ///   [`JobContext::enqueue_on_commit`]
+    /// - `batch_id`: the id of the batch the job is part of, if it was
+    ///   enqueued with [`Batch::enqueue`]
+    /// - `concurrency_key`: the job's
+    ///   [`concurrency_key`](Self::concurrency_key), if it has one
+    ///
+    /// Middleware which doesn't want these recorded can remove them.
+    ///
+    /// If the `tenant` key is a string, the job counts towards that tenant's
+    /// quota, and enqueueing it fails once the quota is reached. See
+    /// [`admin::set_tenant_quota`](crate::admin::set_tenant_quota).
+    ///
+    /// Defaults to an empty object
+    ///
+    /// [`Builder::job_filter`]: crate::Builder::job_filter
+    /// [`JobContext::enqueue`]: crate::JobContext::enqueue
+    /// [`JobContext::enqueue_on_commit`]: crate::JobContext::enqueue_on_commit
+    /// [`Batch::enqueue`]: crate::Batch::enqueue
+    pub metadata: Map<String, Value>,
+
+    /// The oldest version of the application which can run this job, as
+    /// numbers separated by dots such as `"1.4.2"`.
+    ///
+    /// Runners whose [`Builder::worker_version`] is older than this, or which
+    /// have no version configured, will not run the job. Use this when the
+    /// way a job is performed changes in a way older code can't handle.
+    ///
+    /// Defaults to `None`
+    ///
+    /// [`Builder::worker_version`]: crate::Builder::worker_version
+    pub min_worker_version: Option<String>,
+
+    /// When the job should first be run.
+    ///
+    /// Defaults to [`Schedule::Now`]
+    pub schedule: Schedule,
+
+    /// Skip enqueueing the job if a job of the same type with the same key is
+    /// already in the queue. A job stays in the queue while it is running and
+    /// while it is waiting to be retried, but not once it has been moved to
+    /// `swirl_failed_jobs`. See [`Job::enqueue_unique`].
+    ///
+    /// Defaults to `None`
+    pub unique_key: Option<String>,
+
+    /// Where the data the job works on lives, such as a region or a shard.
+    /// Runners with the same [`Builder::locality`] run the job before jobs
+    /// of the same priority which are elsewhere, but any runner can run it.
+    ///
+    /// Defaults to `None`
+    ///
+    /// [`Builder::locality`]: crate::Builder::locality
+    pub locality: Option<String>,
+
+    /// The ids of jobs which must succeed before this job is run, such as the
+    /// earlier steps of a pipeline. See [`Job::enqueue_after`].
+    ///
+    /// A job is held back while any job it depends on is waiting to run,
+    /// running, waiting to be retried, or in `swirl_failed_jobs`. Jobs which
+    /// have already succeeded, or which don't exist, don't hold it back. If a
+    /// job it depends on is cancelled or removed from `swirl_failed_jobs`, it
+    /// is no longer held back by that job. A job keeps its dependencies while it
+    /// is in `swirl_failed_jobs` itself, so it still waits for them if it is
+    /// retried.
+    ///
+    /// Defaults to an empty list
+    pub depends_on: Vec<i64>,
+
+    /// A key shared by jobs which must not run at the same time as each
+    /// other, such as the id of the account they sync. At most one job with
+    /// the same key runs at a time across all runners. Other jobs with the
+    /// key stay in the queue until it has finished, while runners move on to
+    /// other work.
+    ///
+    /// Unlike [`unique_key`](Self::unique_key), this applies across job
+    /// types, and doesn't stop jobs from being enqueued. It is stored in the
+    /// job's metadata as `concurrency_key`.
+    ///
+    /// Defaults to `None`
+    pub concurrency_key: Option<String>,
+}
+
+impl Default for EnqueueOptions {
+    /// The options a job is enqueued with if it doesn't override any of
+    /// [`Job`]'s defaults
+    fn default() -> Self {
+        Self {
+            queue: "default".into(),
+            priority: 0,
+            metadata: Map::new(),
+            min_worker_version: None,
+            schedule: Schedule::Now,
+            unique_key: None,
+            locality: None,
+            depends_on: Vec::new(),
+            concurrency_key: None,
+        }
+    }
+}
+
+impl EnqueueOptions {
+    /// The options a job of type `T` is enqueued with by default
+    pub fn for_job<T: Job>() -> Self {
+        Self {
+            queue: T::QUEUE.into(),
+            priority: T::PRIORITY,
+            metadata: Map::new(),
+            min_worker_version: None,
+            schedule: Schedule::Now,
+            unique_key: None,
+            locality: None,
+            depends_on: Vec::new(),
+            concurrency_key: None,
+        }
+    }
+
+    pub(crate) fn add_automatic_metadata(&mut self, location: &Location<'_>) {
+        let enqueued_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs_f64())
+            .unwrap_or_default();
+        self.metadata
+            .entry("enqueued_at")
+            .or_insert_with(|| enqueued_at.into());
+        if let Some(host) = hostname::get().ok().and_then(|h| h.into_string().ok()) {
+            self.metadata
+                .entry("enqueued_on")
+                .or_insert_with(|| host.into());
+        }
+        self.metadata
+            .entry("enqueued_from")
+            .or_insert_with(|| location.to_string().into());
+    }
+}
+
+/// A hook which is run before every job is enqueued.
+///
+/// Middleware can change the options a job is enqueued with, add metadata to
+/// it, or prevent it from being enqueued by returning an error. This allows
+/// policies which apply to all jobs to be written once, rather than at every
+/// place a job is enqueued.
+///
+/// Middleware is registered with [`register_enqueue_middleware!`], and is run
+/// in an unspecified order.
+pub trait EnqueueMiddleware: Send + Sync + 'static {
+    /// Called before a job is inserted into the database.
+    ///
+    /// `data` is the job's serialized arguments. Returning an error will
+    /// prevent the job from being enqueued, and the error will be returned
+    /// from [`Job::enqueue`].
+    fn before_enqueue(
+        &self,
+        job_type: &str,
+        data: &Value,
+        options: &mut EnqueueOptions,
+    ) -> Result<(), EnqueueError>;
+}
+
+/// Register middleware to be run before every job is enqueued. The argument
+/// must be an expression whose type implements [`EnqueueMiddleware`].
+#[macro_export]
+macro_rules! register_enqueue_middleware {
+    ($middleware: expr) => {
+        $crate::inventory::submit! {
+            #![crate = swirl]
+            swirl::EnqueueHook::new($middleware)
+        }
+    };
+}
+
+#[doc(hidden)]
+pub struct EnqueueHook(Box<dyn EnqueueMiddleware>);
+
+inventory::collect!(EnqueueHook);
+
+impl EnqueueHook {
+    pub fn new<T: EnqueueMiddleware>(middleware: T) -> Self {
+        EnqueueHook(Box::new(middleware))
+    }
+}
+
+/// Runs all registered middleware for a job which is about to be enqueued
+pub(crate) fn run_middleware(
+    job_type: &str,
+    data: &Value,
+    options: &mut EnqueueOptions,
+) -> Result<(), EnqueueError> {
+    for hook in inventory::iter::<EnqueueHook> {
+        hook.0.before_enqueue(job_type, data, options)?;
+    }
+    Ok(())
+}
+
+/// Enqueues a job from its type and serialized arguments, rather than from a
+/// value implementing [`Job`].
+///
+/// This is meant for jobs enqueued on behalf of producers which aren't written
+/// in Rust, such as from an HTTP endpoint. If a job of type `job_type` is
+/// registered in this binary, `data` is checked before the job is inserted: it
+/// must deserialize as the job's arguments, and match the job's
+/// [`JSON_SCHEMA`](Job::JSON_SCHEMA) if it has one. Jobs which don't are
+/// rejected with [`EnqueueError::InvalidArguments`], rather than failing once a
+/// runner picks them up. Jobs of types which aren't registered are inserted
+/// without being checked.
+///
+/// `options` is not filled in from the job's type, so registered jobs should
+/// usually be given [`EnqueueOptions::for_job`].
+#[track_caller]
+pub fn insert_raw_job(
+    conn: &PgConnection,
+    job_type: &str,
+    data: Value,
+    mut options: EnqueueOptions,
+) -> Result<JobHandle, EnqueueError> {
+    if let Some(vtable) = JobVTable::find(job_type) {
+        vtable
+            .validate(&data)
+            .map_err(|e| EnqueueError::InvalidArguments(e.to_string()))?;
+        #[cfg(feature = "jsonschema")]
+        validate_json_schema(&vtable, &data)?;
+    }
+    options.add_automatic_metadata(Location::caller());
+    storage::insert_job(conn, job_type, data, options)?
+        .map(|job| JobHandle::new(job.id))
+        .ok_or(EnqueueError::Duplicate)
+}
+
+/// Enqueues `job`, and cancels the job of the same type previously enqueued
+/// with the same `key`, for jobs where only the latest version matters such
+/// as sending a notification with a user's unread count.
+///
+/// The previous job is only cancelled if it is still waiting to run, which
+/// includes waiting to be retried. If a runner has already started it, it is
+/// left to finish and both jobs run. The new job is enqueued and the previous
+/// one cancelled in a single transaction, so concurrent calls with the same
+/// key always leave exactly one of their jobs pending.
+#[track_caller]
+pub fn enqueue_superseding<T: Job>(
+    conn: &PgConnection,
+    key: &str,
+    job: T,
+) -> Result<JobHandle, EnqueueError> {
+    let mut options = EnqueueOptions::for_job::<T>();
+    options.add_automatic_metadata(Location::caller());
+    conn.transaction(|| {
+        let enqueued = storage::enqueue_job(conn, job, options)?.ok_or(EnqueueError::Duplicate)?;
+        storage::supersede_job(conn, T::JOB_TYPE, key, enqueued.id)?;
+        Ok(JobHandle::new(enqueued.id))
+    })
+}
+
+/// Checks `data` against the job's [`JSON_SCHEMA`](Job::JSON_SCHEMA), if it
+/// has one
+#[cfg(feature = "jsonschema")]
+fn validate_json_schema(vtable: &JobVTable, data: &Value) -> Result<(), EnqueueError> {
+    use jsonschema::JSONSchema;
+
+    let schema = match vtable.json_schema() {
+        Some(schema) => schema,
+        None => return Ok(()),
+    };
+    let invalid_schema = |e: &dyn std::fmt::Display| {
+        EnqueueError::InvalidArguments(format!("invalid schema: {}", e))
+    };
+    let schema = serde_json::from_str(schema).map_err(|e| invalid_schema(&e))?;
+    let schema = JSONSchema::compile(&schema).map_err(|e| invalid_schema(&e))?;
+    if let Err(errors) = schema.validate(data) {
+        let errors = errors
+            .map(|e| {
+                let path = e.instance_path.to_string();
+                let path = if path.is_empty() { "/" } else { &path };
+                format!("{}: {}", path, e)
+            })
+            .collect::<Vec<_>>();
+        return Err(EnqueueError::InvalidArguments(errors.join("; ")));
+    }
+    Ok(())
+}
@@ -0,0 +1,238 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::errors::EnqueueError;
+use crate::runner::metrics::{escape, DurationHistogram};
+use crate::storage::EnqueuedJob;
+
+/// The upper bounds of the buckets of [`PayloadHistogram`], in bytes
+const BUCKETS: &[u64] = &[
+    256, 1024, 4096, 16_384, 65_536, 262_144, 1_048_576, 4_194_304, 16_777_216,
+];
+
+/// The enqueue metrics of every job type enqueued by this process
+static METRICS: Mutex<BTreeMap<String, EnqueueMetrics>> = Mutex::new(BTreeMap::new());
+
+/// A histogram of the size of jobs' serialized arguments, with buckets from
+/// 256 bytes to 16MiB
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PayloadHistogram {
+    /// The number of jobs which fell into each bucket, not including earlier
+    /// buckets
+    counts: Vec<u64>,
+    sum: u64,
+    count: u64,
+}
+
+impl Default for PayloadHistogram {
+    fn default() -> Self {
+        Self {
+            counts: vec![0; BUCKETS.len()],
+            sum: 0,
+            count: 0,
+        }
+    }
+}
+
+impl PayloadHistogram {
+    fn record(&mut self, bytes: u64) {
+        if let Some(bucket) = BUCKETS.iter().position(|&le| bytes <= le) {
+            self.counts[bucket] += 1;
+        }
+        self.sum += bytes;
+        self.count += 1;
+    }
+
+    /// The upper bound of each bucket in bytes, and the number of jobs which
+    /// were at most that large
+    pub fn buckets(&self) -> impl Iterator<Item = (u64, u64)> + '_ {
+        BUCKETS
+            .iter()
+            .zip(&self.counts)
+            .scan(0, |total, (&le, &count)| {
+                *total += count;
+                Some((le, *total))
+            })
+    }
+
+    /// The total size of the jobs which were measured
+    pub fn sum(&self) -> u64 {
+        self.sum
+    }
+
+    /// The number of jobs which were measured
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+}
+
+/// The enqueue metrics of one job type, as returned by
+/// [`enqueue_metrics_snapshot`].
+///
+/// These cover every job of the type enqueued by this process, whichever
+/// connection it was enqueued on.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct EnqueueMetrics {
+    /// The number of jobs which were enqueued
+    pub enqueued: u64,
+
+    /// The number of jobs which were skipped, because a job with the same
+    /// unique key was already in the queue
+    pub duplicates: u64,
+
+    /// The number of jobs whose arguments couldn't be serialized
+    pub serialization_failures: u64,
+
+    /// The number of jobs which couldn't be inserted, such as because of a
+    /// database error, or because middleware or a tenant's quota rejected them
+    pub failures: u64,
+
+    /// How long inserting jobs took, whether or not it succeeded
+    pub duration: DurationHistogram,
+
+    /// The size of jobs' serialized arguments
+    pub payload_bytes: PayloadHistogram,
+}
+
+/// The enqueue metrics of every job type, as returned by
+/// [`enqueue_metrics_snapshot`]
+///
+/// Like [`MetricsSnapshot`](crate::MetricsSnapshot), its `Display`
+/// implementation renders the metrics in Prometheus' text format. A process
+/// which both enqueues and runs jobs can serve both from its `/metrics`
+/// endpoint.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct EnqueueMetricsSnapshot {
+    /// The metrics of each job type, by job type
+    pub job_types: BTreeMap<String, EnqueueMetrics>,
+}
+
+impl EnqueueMetricsSnapshot {
+    fn write_counter<F>(
+        &self,
+        f: &mut fmt::Formatter,
+        name: &str,
+        help: &str,
+        value: F,
+    ) -> fmt::Result
+    where
+        F: Fn(&EnqueueMetrics) -> u64,
+    {
+        writeln!(f, "# HELP {} {}", name, help)?;
+        writeln!(f, "# TYPE {} counter", name)?;
+        for (job_type, metrics) in &self.job_types {
+            writeln!(f, "{}{{{}}} {}", name, labels(job_type), value(metrics))?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for EnqueueMetricsSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.write_counter(
+            f,
+            "swirl_jobs_enqueued_total",
+            "Jobs which were enqueued",
+            |m| m.enqueued,
+        )?;
+        self.write_counter(
+            f,
+            "swirl_enqueue_duplicates_total",
+            "Jobs which were skipped because of their unique key",
+            |m| m.duplicates,
+        )?;
+        self.write_counter(
+            f,
+            "swirl_enqueue_serialization_failures_total",
+            "Jobs whose arguments couldn't be serialized",
+            |m| m.serialization_failures,
+        )?;
+        self.write_counter(
+            f,
+            "swirl_enqueue_failures_total",
+            "Jobs which couldn't be inserted",
+            |m| m.failures,
+        )?;
+
+        let name = "swirl_enqueue_duration_seconds";
+        writeln!(f, "# HELP {} How long inserting jobs took", name)?;
+        writeln!(f, "# TYPE {} histogram", name)?;
+        for (job_type, metrics) in &self.job_types {
+            let labels = labels(job_type);
+            for (le, count) in metrics.duration.buckets() {
+                writeln!(f, "{}_bucket{{{},le=\"{}\"}} {}", name, labels, le, count)?;
+            }
+            let count = metrics.duration.count();
+            writeln!(f, "{}_bucket{{{},le=\"+Inf\"}} {}", name, labels, count)?;
+            let sum = metrics.duration.sum().as_secs_f64();
+            writeln!(f, "{}_sum{{{}}} {}", name, labels, sum)?;
+            writeln!(f, "{}_count{{{}}} {}", name, labels, count)?;
+        }
+
+        let name = "swirl_enqueue_payload_bytes";
+        writeln!(f, "# HELP {} The size of jobs' serialized arguments", name)?;
+        writeln!(f, "# TYPE {} histogram", name)?;
+        for (job_type, metrics) in &self.job_types {
+            let labels = labels(job_type);
+            for (le, count) in metrics.payload_bytes.buckets() {
+                writeln!(f, "{}_bucket{{{},le=\"{}\"}} {}", name, labels, le, count)?;
+            }
+            let count = metrics.payload_bytes.count();
+            writeln!(f, "{}_bucket{{{},le=\"+Inf\"}} {}", name, labels, count)?;
+            let sum = metrics.payload_bytes.sum();
+            writeln!(f, "{}_sum{{{}}} {}", name, labels, sum)?;
+            writeln!(f, "{}_count{{{}}} {}", name, labels, count)?;
+        }
+        Ok(())
+    }
+}
+
+/// The labels of a job type's enqueue metrics
+fn labels(job_type: &str) -> String {
+    format!("job_type=\"{}\"", escape(job_type))
+}
+
+/// The enqueue metrics recorded by this process so far.
+///
+/// Requires the `metrics` feature.
+pub fn enqueue_metrics_snapshot() -> EnqueueMetricsSnapshot {
+    let job_types = METRICS.lock().unwrap_or_else(|e| e.into_inner());
+    EnqueueMetricsSnapshot {
+        job_types: job_types.clone(),
+    }
+}
+
+/// Records that the arguments of a job of type `job_type` couldn't be
+/// serialized
+pub(crate) fn serialization_failed(job_type: &str) {
+    update(job_type, |metrics| metrics.serialization_failures += 1);
+}
+
+/// Records an attempt to insert a job of type `job_type` whose arguments were
+/// `payload_bytes` long, which took `duration` and returned `result`
+pub(crate) fn inserted(
+    job_type: &str,
+    payload_bytes: usize,
+    duration: Duration,
+    result: &Result<Option<EnqueuedJob>, EnqueueError>,
+) {
+    update(job_type, |metrics| {
+        match result {
+            Ok(Some(_)) => metrics.enqueued += 1,
+            Ok(None) => metrics.duplicates += 1,
+            Err(_) => metrics.failures += 1,
+        }
+        metrics.duration.record(duration);
+        metrics.payload_bytes.record(payload_bytes as u64);
+    });
+}
+
+fn update(job_type: &str, f: impl FnOnce(&mut EnqueueMetrics)) {
+    let mut job_types = METRICS.lock().unwrap_or_else(|e| e.into_inner());
+    match job_types.get_mut(job_type) {
+        Some(metrics) => f(metrics),
+        None => f(job_types.entry(job_type.into()).or_default()),
+    }
+}
@@ -1,8 +1,7 @@
 use diesel::result::Error as DieselError;
 use std::error::Error;
 use std::fmt;
-
-use crate::db::DieselPool;
+use std::time::Duration;
 
 /// An error occurred queueing the job
 #[derive(Debug)]
@@ -13,6 +12,28 @@ pub enum EnqueueError {
     /// An error occurred inserting the job into the database
     DatabaseError(DieselError),
 
+    /// An [`EnqueueMiddleware`](crate::EnqueueMiddleware) prevented the job
+    /// from being enqueued, for the given reason
+    Vetoed(String),
+
+    /// The given [`min_worker_version`](crate::EnqueueOptions::min_worker_version)
+    /// was not made up of numbers separated by dots
+    InvalidVersion(String),
+
+    /// The given tenant already has as many pending jobs as its quota allows.
+    /// See [`admin::set_tenant_quota`](crate::admin::set_tenant_quota).
+    QuotaExceeded(String),
+
+    /// A job of the same type with the same
+    /// [`unique_key`](crate::EnqueueOptions::unique_key) is already in the
+    /// queue, so the job was not enqueued. [`Job::enqueue_unique`](crate::Job::enqueue_unique)
+    /// returns `None` instead.
+    Duplicate,
+
+    /// The arguments given to [`insert_raw_job`](crate::insert_raw_job) don't
+    /// match the job's type, for the given reason
+    InvalidArguments(String),
+
     #[doc(hidden)]
     /// Match on `_` instead, more variants may be added in the future
     __NonExhaustive,
@@ -35,6 +56,17 @@ impl fmt::Display for EnqueueError {
         match self {
             EnqueueError::SerializationError(e) => e.fmt(f),
             EnqueueError::DatabaseError(e) => e.fmt(f),
+            EnqueueError::Vetoed(reason) => write!(f, "The job was not enqueued: {}", reason),
+            EnqueueError::InvalidVersion(version) => write!(f, "Invalid version {}", version),
+            EnqueueError::QuotaExceeded(tenant) => {
+                write!(f, "Tenant {} has reached its quota of pending jobs", tenant)
+            }
+            EnqueueError::Duplicate => {
+                write!(f, "A job with the same unique key is already in the queue")
+            }
+            EnqueueError::InvalidArguments(reason) => {
+                write!(f, "Invalid job arguments: {}", reason)
+            }
             EnqueueError::__NonExhaustive => unreachable!(),
         }
     }
@@ -45,70 +77,403 @@ impl Error for EnqueueError {
         match self {
             EnqueueError::SerializationError(e) => Some(e),
             EnqueueError::DatabaseError(e) => Some(e),
+            EnqueueError::Vetoed(_)
+            | EnqueueError::InvalidVersion(_)
+            | EnqueueError::QuotaExceeded(_)
+            | EnqueueError::Duplicate
+            | EnqueueError::InvalidArguments(_) => None,
             EnqueueError::__NonExhaustive => unreachable!(),
         }
     }
 }
 
-/// An error occurred performing the job
-pub type PerformError = Box<dyn Error>;
+/// An error returned by the functions in [`admin`](crate::admin)
+#[derive(Debug)]
+pub enum AdminError {
+    /// No job exists with the given id
+    JobNotFound,
+
+    /// The job is locked by a runner which is currently running it
+    JobRunning,
+
+    /// No job is registered with the given job type
+    UnknownJobType(String),
+
+    /// The given arguments could not be deserialized as the job's arguments
+    InvalidArguments(serde_json::error::Error),
+
+    /// An error occurred querying the database
+    DatabaseError(DieselError),
+
+    #[doc(hidden)]
+    /// Match on `_` instead, more variants may be added in the future
+    __NonExhaustive,
+}
+
+impl From<DieselError> for AdminError {
+    fn from(e: DieselError) -> Self {
+        AdminError::DatabaseError(e)
+    }
+}
+
+impl fmt::Display for AdminError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AdminError::JobNotFound => write!(f, "No job exists with that id"),
+            AdminError::JobRunning => write!(f, "The job is currently running"),
+            AdminError::UnknownJobType(job_type) => {
+                write!(f, "No job is registered with the type {}", job_type)
+            }
+            AdminError::InvalidArguments(e) => write!(f, "Invalid job arguments: {}", e),
+            AdminError::DatabaseError(e) => e.fmt(f),
+            AdminError::__NonExhaustive => unreachable!(),
+        }
+    }
+}
+
+impl Error for AdminError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            AdminError::InvalidArguments(e) => Some(e),
+            AdminError::DatabaseError(e) => Some(e),
+            AdminError::JobNotFound | AdminError::JobRunning | AdminError::UnknownJobType(_) => {
+                None
+            }
+            AdminError::__NonExhaustive => unreachable!(),
+        }
+    }
+}
+
+/// An error returned by [`Importer::import`](crate::interop::Importer::import)
+#[derive(Debug)]
+pub enum ImportError {
+    /// The payload is missing a required key, or its arguments don't match
+    /// the argument names they were mapped with
+    MalformedPayload(String),
+
+    /// No swirl job was mapped to the payload's job type
+    UnmappedJobType(String),
+
+    /// The arguments could not be deserialized as the mapped job
+    InvalidArguments(serde_json::error::Error),
+
+    /// The job could not be enqueued
+    EnqueueError(EnqueueError),
+
+    #[doc(hidden)]
+    /// Match on `_` instead, more variants may be added in the future
+    __NonExhaustive,
+}
+
+impl From<EnqueueError> for ImportError {
+    fn from(e: EnqueueError) -> Self {
+        ImportError::EnqueueError(e)
+    }
+}
+
+impl fmt::Display for ImportError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ImportError::MalformedPayload(reason) => write!(f, "Malformed payload: {}", reason),
+            ImportError::UnmappedJobType(job_type) => {
+                write!(f, "No job is mapped to the type {}", job_type)
+            }
+            ImportError::InvalidArguments(e) => write!(f, "Invalid job arguments: {}", e),
+            ImportError::EnqueueError(e) => e.fmt(f),
+            ImportError::__NonExhaustive => unreachable!(),
+        }
+    }
+}
+
+impl Error for ImportError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ImportError::InvalidArguments(e) => Some(e),
+            ImportError::EnqueueError(e) => Some(e),
+            ImportError::MalformedPayload(_) | ImportError::UnmappedJobType(_) => None,
+            ImportError::__NonExhaustive => unreachable!(),
+        }
+    }
+}
+
+/// An error returned by
+/// [`JobHandle::wait_for_completion`](crate::JobHandle::wait_for_completion)
+#[cfg(feature = "notify")]
+#[derive(Debug)]
+pub enum WaitError {
+    /// The job didn't finish running before the timeout passed
+    Timeout,
+
+    /// An error occurred connecting to the database or listening for the
+    /// job's completion
+    DatabaseError(postgres::Error),
+
+    #[doc(hidden)]
+    /// Match on `_` instead, more variants may be added in the future
+    __NonExhaustive,
+}
+
+#[cfg(feature = "notify")]
+impl From<postgres::Error> for WaitError {
+    fn from(e: postgres::Error) -> Self {
+        WaitError::DatabaseError(e)
+    }
+}
+
+#[cfg(feature = "notify")]
+impl fmt::Display for WaitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WaitError::Timeout => f.write_str("Timed out waiting for the job to finish"),
+            WaitError::DatabaseError(e) => e.fmt(f),
+            WaitError::__NonExhaustive => unreachable!(),
+        }
+    }
+}
+
+#[cfg(feature = "notify")]
+impl Error for WaitError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            WaitError::DatabaseError(e) => Some(e),
+            WaitError::Timeout => None,
+            WaitError::__NonExhaustive => unreachable!(),
+        }
+    }
+}
+
+/// An error occurred performing the job. See [`Failure`] for errors which
+/// shouldn't be retried.
+///
+/// This is `Send` and `Sync`, so errors can be handed to other threads, such
+/// as for reporting, and any error type which is can be returned with `?`.
+pub type PerformError = Box<dyn Error + Send + Sync>;
+
+/// Returned by a job to put itself back in the queue, without being counted as
+/// a failure.
+///
+/// This is usually done after [`JobContext::should_yield`] returns `true`, so
+/// that higher priority jobs can run. Jobs which do this should save their
+/// progress with [`JobContext::save_checkpoint`] first.
+///
+/// [`JobContext::should_yield`]: crate::JobContext::should_yield
+/// [`JobContext::save_checkpoint`]: crate::JobContext::save_checkpoint
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct JobYielded;
+
+impl fmt::Display for JobYielded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "The job yielded to higher priority jobs")
+    }
+}
+
+impl Error for JobYielded {}
+
+/// An error returned by a job, which says whether the job should be retried.
+///
+/// Jobs can return any error, and are retried according to their
+/// [`RetryPolicy`](crate::RetryPolicy) unless it is wrapped in
+/// `Failure::Fatal`. Errors which will never go away on their own, such as
+/// invalid input, should be returned as fatal:
+///
+/// ```ignore
+/// let address = parse_address(&input).map_err(|e| Failure::Fatal(e.into()))?;
+/// ```
+///
+/// The first `Failure` in the chain of [`source`](Error::source)s of a job's
+/// error decides. Its [`FailureKind`](crate::FailureKind) is the kind of the
+/// error it wraps.
+#[derive(Debug)]
+pub enum Failure {
+    /// The job is not retried, and is moved to `swirl_failed_jobs` right
+    /// away regardless of its retry policy
+    Fatal(PerformError),
+
+    /// The job is retried according to its retry policy. This is the same as
+    /// returning the wrapped error directly.
+    Retryable(PerformError),
+
+    #[doc(hidden)]
+    /// Match on `_` instead, more variants may be added in the future
+    __NonExhaustive,
+}
+
+impl Failure {
+    /// Returns `true` if the first `Failure` in the chain of `error` is fatal
+    pub(crate) fn is_fatal(error: &(dyn Error + 'static)) -> bool {
+        let mut current = Some(error);
+        while let Some(e) = current {
+            if let Some(failure) = e.downcast_ref::<Failure>() {
+                return matches!(failure, Failure::Fatal(_));
+            }
+            current = e.source();
+        }
+        false
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Failure::Fatal(e) => write!(f, "Fatal error: {}", e),
+            Failure::Retryable(e) => e.fmt(f),
+            Failure::__NonExhaustive => unreachable!(),
+        }
+    }
+}
+
+impl Error for Failure {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Failure::Fatal(e) | Failure::Retryable(e) => Some(&**e),
+            Failure::__NonExhaustive => unreachable!(),
+        }
+    }
+}
+
+/// The runner which returned a [`FetchError`], and what it was doing when the
+/// error occurred
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FetchContext {
+    /// The queues the runner runs jobs from, or `None` for all of them. See
+    /// [`Builder::queues`](crate::Builder::queues).
+    pub queues: Option<Vec<String>>,
+
+    /// The number of threads the runner runs jobs on
+    pub thread_count: usize,
+
+    /// The number of those threads which were running a job, or a sub-task
+    /// of one, when the error occurred
+    pub busy_threads: usize,
+}
+
+impl fmt::Display for FetchContext {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} of {} threads busy, ",
+            self.busy_threads, self.thread_count
+        )?;
+        match &self.queues {
+            Some(queues) => write!(f, "running jobs from {}", queues.join(", ")),
+            None => write!(f, "running jobs from all queues"),
+        }
+    }
+}
+
+/// An error returned by [`Builder::try_build`](crate::Builder::try_build)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BuildError {
+    /// No jobs are registered with the runner, and
+    /// [`Builder::require_nonempty_registry`](crate::Builder::require_nonempty_registry)
+    /// was called
+    EmptyRegistry,
+
+    #[doc(hidden)]
+    /// Match on `_` instead, more variants may be added in the future
+    __NonExhaustive,
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BuildError::EmptyRegistry => write!(
+                f,
+                "No jobs are registered with the runner. Check that the crates defining \
+                 jobs are linked with `swirl::register_jobs!`, and that the jobs use the \
+                 runner's environment type"
+            ),
+            BuildError::__NonExhaustive => unreachable!(),
+        }
+    }
+}
+
+impl Error for BuildError {}
+
+/// The error a job is failed with when it runs for longer than its
+/// [`TIMEOUT`](crate::Job::TIMEOUT). Its [`FailureKind`](crate::FailureKind)
+/// is `Timeout`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct JobTimedOut(
+    /// The timeout the job exceeded
+    pub Duration,
+);
+
+impl fmt::Display for JobTimedOut {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "The job did not finish within its timeout of {:?}",
+            self.0
+        )
+    }
+}
+
+impl Error for JobTimedOut {}
 
 /// An error occurred while attempting to fetch jobs from the queue
-pub enum FetchError<Pool: DieselPool> {
+#[derive(Debug)]
+pub enum FetchError {
     /// We could not acquire a database connection from the pool.
     ///
     /// Either the connection pool is too small, or new connections cannot be
     /// established.
-    NoDatabaseConnection(Pool::Error),
+    NoDatabaseConnection(Box<dyn Error + Send + Sync>, FetchContext),
 
     /// Could not execute the query to load a job from the database.
-    FailedLoadingJob(DieselError),
+    FailedLoadingJob(DieselError, FetchContext),
 
     /// No message was received from the worker thread.
     ///
     /// Either the thread pool is too small, or jobs have hung indefinitely
-    NoMessageReceived,
+    NoMessageReceived(FetchContext),
+
+    #[doc(hidden)]
+    /// Match on `_` instead, more variants may be added in the future
+    __NonExhaustive,
 }
 
-impl<Pool: DieselPool> fmt::Debug for FetchError<Pool> {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+impl FetchError {
+    /// The runner which returned this error
+    pub fn context(&self) -> &FetchContext {
         match self {
-            FetchError::NoDatabaseConnection(e) => {
-                f.debug_tuple("NoDatabaseConnection").field(e).finish()
-            }
-            FetchError::FailedLoadingJob(e) => f.debug_tuple("FailedLoadingJob").field(e).finish(),
-            FetchError::NoMessageReceived => f.debug_struct("NoMessageReceived").finish(),
+            FetchError::NoDatabaseConnection(_, context)
+            | FetchError::FailedLoadingJob(_, context)
+            | FetchError::NoMessageReceived(context) => context,
+            FetchError::__NonExhaustive => unreachable!(),
         }
     }
 }
 
-impl<Pool: DieselPool> fmt::Display for FetchError<Pool> {
+impl fmt::Display for FetchError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            FetchError::NoDatabaseConnection(e) => {
+            FetchError::NoDatabaseConnection(e, _) => {
                 write!(f, "Timed out acquiring a database connection. ")?;
                 write!(f, "Try increasing the connection pool size: ")?;
                 write!(f, "{}", e)?;
             }
-            FetchError::FailedLoadingJob(e) => {
+            FetchError::FailedLoadingJob(e, _) => {
                 write!(f, "An error occurred loading a job from the database: ")?;
                 write!(f, "{}", e)?;
             }
-            FetchError::NoMessageReceived => {
+            FetchError::NoMessageReceived(_) => {
                 write!(f, "No message was received from the worker thread. ")?;
                 write!(f, "Try increasing the thread pool size or timeout period.")?;
             }
+            FetchError::__NonExhaustive => unreachable!(),
         }
-        Ok(())
+        write!(f, " ({})", self.context())
     }
 }
 
-impl<Pool: DieselPool> Error for FetchError<Pool> {
+impl Error for FetchError {
     fn source(&self) -> Option<&(dyn Error + 'static)> {
         match self {
-            FetchError::NoDatabaseConnection(e) => Some(e),
-            FetchError::FailedLoadingJob(e) => Some(e),
-            FetchError::NoMessageReceived => None,
+            FetchError::NoDatabaseConnection(e, _) => Some(&**e),
+            FetchError::FailedLoadingJob(e, _) => Some(e),
+            FetchError::NoMessageReceived(_) => None,
+            FetchError::__NonExhaustive => unreachable!(),
         }
     }
 }
@@ -0,0 +1,204 @@
+use diesel::pg::Pg;
+use diesel::prelude::*;
+use diesel::sql_types::Bool;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::schema::background_jobs;
+use crate::storage::{self, BackgroundJob, Excluded, FetchOptions};
+
+/// A condition on `background_jobs`, as returned by [`FetchRequest::filter`]
+pub type FetchFilter = Box<dyn BoxableExpression<background_jobs::table, Pg, SqlType = Bool>>;
+
+/// The query a runner uses to find and lock the next job to run, given to
+/// [`Builder::fetcher`](crate::Builder::fetcher).
+///
+/// This allows the way jobs are claimed to be changed without forking swirl,
+/// such as to add planner hints or order jobs differently. The runner still
+/// applies [`Builder::job_filter`](crate::Builder::job_filter) and
+/// concurrency limits to the jobs this returns. Jobs which are rejected are
+/// unlocked again and added to the exclusions of the next request.
+pub trait FetchQuery: Send + Sync + 'static {
+    /// Finds the next job matching `request`, and locks its row for the rest
+    /// of the current transaction. Rows which are already locked must be
+    /// skipped, usually with `FOR UPDATE SKIP LOCKED`, or runners will wait
+    /// on each other. Returns `None` if there are no jobs to run.
+    fn fetch(
+        &self,
+        conn: &PgConnection,
+        request: &FetchRequest<'_>,
+    ) -> QueryResult<Option<BackgroundJob>>;
+}
+
+/// The query runners use unless they are given a [`FetchQuery`]. Jobs are
+/// fetched in order of priority, then in the order they were enqueued.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DefaultFetchQuery;
+
+impl FetchQuery for DefaultFetchQuery {
+    fn fetch(
+        &self,
+        conn: &PgConnection,
+        request: &FetchRequest<'_>,
+    ) -> QueryResult<Option<BackgroundJob>> {
+        storage::find_next_unlocked_job(conn, request.options, request.excluded).optional()
+    }
+}
+
+/// A [`FetchQuery`] which takes turns between job types, or between queues,
+/// so that a type with a large backlog can't hold up the others.
+///
+/// With [`DefaultFetchQuery`], a small job type which needs to be run quickly
+/// waits behind every job enqueued before it, such as 100k jobs of a
+/// backfill. This query instead fetches the oldest job of the next type in
+/// alphabetical order after the type it fetched last, going back to the first
+/// type once it reaches the end. Each type then gets its turn regardless of
+/// how many jobs the others have.
+///
+/// Jobs of a higher priority are still fetched first, and types only take
+/// turns among the jobs of the highest priority which is waiting. The runner's
+/// [`locality`](crate::Builder::locality) is not taken into account. Turns are
+/// kept per runner, so runners fetch in the same order as each other rather
+/// than splitting up the types between them.
+///
+/// ```ignore
+/// Runner::builder(env).fetcher(FairFetchQuery::by_job_type())
+/// ```
+#[derive(Debug, Default)]
+pub struct FairFetchQuery {
+    by_queue: bool,
+    /// The job type or queue of the job which was fetched last
+    last: Mutex<Option<String>>,
+}
+
+impl FairFetchQuery {
+    /// Takes turns between job types
+    pub fn by_job_type() -> Self {
+        Self::default()
+    }
+
+    /// Takes turns between queues. Queues are compared by the name the job
+    /// was enqueued with, so a queue and its aliases take separate turns.
+    pub fn by_queue() -> Self {
+        Self {
+            by_queue: true,
+            ..Self::default()
+        }
+    }
+}
+
+impl FetchQuery for FairFetchQuery {
+    fn fetch(
+        &self,
+        conn: &PgConnection,
+        request: &FetchRequest<'_>,
+    ) -> QueryResult<Option<BackgroundJob>> {
+        let (options, excluded) = (request.options, request.excluded);
+        let last = self.last.lock().unwrap_or_else(|e| e.into_inner()).clone();
+        let mut job = None;
+        if let Some(last) = &last {
+            job =
+                storage::find_next_job_in_turn(conn, options, excluded, self.by_queue, Some(last))?;
+        }
+        if job.is_none() {
+            job = storage::find_next_job_in_turn(conn, options, excluded, self.by_queue, None)?;
+        }
+        if job.is_none() {
+            // Every job of the highest priority is already running
+            job = storage::find_next_unlocked_job(conn, options, excluded).optional()?;
+        }
+        if let Some(job) = &job {
+            let turn = if self.by_queue {
+                &job.queue
+            } else {
+                &job.job_type
+            };
+            *self.last.lock().unwrap_or_else(|e| e.into_inner()) = Some(turn.clone());
+        }
+        Ok(job)
+    }
+}
+
+/// The jobs a runner is looking for, passed to [`FetchQuery::fetch`]
+#[derive(Debug, Clone, Copy)]
+pub struct FetchRequest<'a> {
+    options: &'a FetchOptions,
+    excluded: &'a Excluded,
+}
+
+impl<'a> FetchRequest<'a> {
+    pub(crate) fn new(options: &'a FetchOptions, excluded: &'a Excluded) -> Self {
+        Self { options, excluded }
+    }
+
+    /// Everything below combined into one condition, for use with Diesel's
+    /// query builder. Jobs which don't match it must not be returned.
+    pub fn filter(&self) -> FetchFilter {
+        storage::fetch_filter(self.options, self.excluded)
+    }
+
+    /// The queues the runner runs jobs from, or `None` for all of them.
+    ///
+    /// This doesn't include the aliases of these queues, which are added by
+    /// [`filter`](Self::filter). See
+    /// [`admin::set_queue_alias`](crate::admin::set_queue_alias).
+    pub fn queues(&self) -> Option<&'a [String]> {
+        self.options.queues.as_deref()
+    }
+
+    /// The job types the runner runs, or `None` for all of them. See
+    /// [`Builder::only_job_types`](crate::Builder::only_job_types).
+    pub fn job_types(&self) -> Option<&'a [String]> {
+        self.options.job_types.as_deref()
+    }
+
+    /// The job types the runner never runs. See
+    /// [`Builder::except_job_types`](crate::Builder::except_job_types).
+    pub fn except_job_types(&self) -> &'a [String] {
+        &self.options.except_job_types
+    }
+
+    /// The runner's [`Builder::worker_version`](crate::Builder::worker_version)
+    pub fn worker_version(&self) -> Option<&'a str> {
+        self.options.worker_version.as_deref()
+    }
+
+    /// How long before a job is due the runner will run it
+    pub fn early_execution_slack(&self) -> Duration {
+        self.options.early_execution_slack
+    }
+
+    /// The runner's [`Builder::locality`](crate::Builder::locality). Jobs in
+    /// this locality should be returned before other jobs of the same
+    /// priority, unless those have been due for longer than
+    /// [`locality_window`](Self::locality_window).
+    pub fn locality(&self) -> Option<&'a str> {
+        self.options.locality.as_deref()
+    }
+
+    /// How long jobs outside the runner's locality may be passed over
+    pub fn locality_window(&self) -> Duration {
+        self.options.locality_window
+    }
+
+    /// Jobs which were rejected by the runner's job filter
+    pub fn excluded_ids(&self) -> &'a [i64] {
+        &self.excluded.ids
+    }
+
+    /// Job types which have reached their concurrency limit
+    pub fn excluded_job_types(&self) -> &'a [String] {
+        &self.excluded.job_types
+    }
+
+    /// Queues which have reached their concurrency limit
+    pub fn excluded_queues(&self) -> &'a [String] {
+        &self.excluded.queues
+    }
+
+    /// Concurrency keys which a running job already holds. See
+    /// [`EnqueueOptions::concurrency_key`](crate::EnqueueOptions::concurrency_key).
+    pub fn excluded_concurrency_keys(&self) -> &'a [String] {
+        &self.excluded.concurrency_keys
+    }
+}
@@ -0,0 +1,108 @@
+//! An end to end check that jobs are being run
+//!
+//! Runners registering as alive, and the queue being short, don't prove that
+//! jobs are actually getting through. Enqueue [`HeartbeatJob`] periodically,
+//! such as once a minute from a cron job, and alert if [`last_heartbeat`]
+//! reports that none has completed for longer than a few of those periods.
+//!
+//! ```ignore
+//! match swirl::heartbeat::last_heartbeat(&conn)? {
+//!     Some(heartbeat) if heartbeat.age < Duration::from_secs(5 * 60) => {}
+//!     _ => page_someone("background jobs aren't being run"),
+//! }
+//! ```
+
+use diesel::prelude::*;
+use diesel::sql_query;
+use diesel::sql_types::{BigInt, Double, Text, Timestamp};
+use serde_derive::{Deserialize, Serialize};
+use std::time::{Duration, SystemTime};
+
+use crate::context::JobContext;
+use crate::errors::PerformError;
+use crate::registry::JobVTable;
+use crate::Job;
+
+/// A job which does nothing but record that it ran in `swirl_heartbeats`.
+///
+/// It can be run by any runner regardless of its environment type. The latest
+/// heartbeat is kept for each queue, so enqueue it in each queue whose runners
+/// should be checked, using [`Queue::enqueue`](crate::Queue::enqueue).
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct HeartbeatJob;
+
+impl Job for HeartbeatJob {
+    type Environment = ();
+    const JOB_TYPE: &'static str = "swirl_heartbeat";
+
+    fn perform(self, _: &(), ctx: &JobContext<'_>) -> Result<(), PerformError> {
+        sql_query(
+            "INSERT INTO swirl_heartbeats (queue, enqueued_at, completed_at) \
+             SELECT queue, created_at, now() FROM background_jobs WHERE id = $1 \
+             ON CONFLICT (queue) DO UPDATE \
+             SET enqueued_at = excluded.enqueued_at, completed_at = excluded.completed_at",
+        )
+        .bind::<BigInt, _>(ctx.job_id())
+        .execute(ctx.connection())?;
+        Ok(())
+    }
+}
+
+inventory::submit!(JobVTable::from_env_agnostic_job::<HeartbeatJob>());
+
+/// The most recent run of a [`HeartbeatJob`], as returned by
+/// [`last_heartbeat`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Heartbeat {
+    /// The queue the job was run from
+    pub queue: String,
+    /// When the job was enqueued
+    pub enqueued_at: SystemTime,
+    /// When the job completed
+    pub completed_at: SystemTime,
+    /// How long ago the job completed, according to the database's clock
+    pub age: Duration,
+}
+
+impl Heartbeat {
+    /// How long the job took to get through the queue, from being enqueued
+    /// until it completed
+    pub fn latency(&self) -> Duration {
+        self.completed_at
+            .duration_since(self.enqueued_at)
+            .unwrap_or_default()
+    }
+}
+
+#[derive(QueryableByName)]
+struct HeartbeatRow {
+    #[sql_type = "Text"]
+    queue: String,
+    #[sql_type = "Timestamp"]
+    enqueued_at: SystemTime,
+    #[sql_type = "Timestamp"]
+    completed_at: SystemTime,
+    #[sql_type = "Double"]
+    age: f64,
+}
+
+/// The most recent run of a [`HeartbeatJob`] in any queue, or `None` if one
+/// has never completed.
+///
+/// Times are recorded using the database's clock, so they aren't affected by
+/// skew between the runners and the machine checking on them.
+pub fn last_heartbeat(conn: &PgConnection) -> QueryResult<Option<Heartbeat>> {
+    let row = sql_query(
+        "SELECT queue, enqueued_at, completed_at, \
+         EXTRACT(epoch FROM now() - completed_at)::float8 AS age \
+         FROM swirl_heartbeats ORDER BY completed_at DESC LIMIT 1",
+    )
+    .get_result::<HeartbeatRow>(conn)
+    .optional()?;
+    Ok(row.map(|row| Heartbeat {
+        queue: row.queue,
+        enqueued_at: row.enqueued_at,
+        completed_at: row.completed_at,
+        age: Duration::from_secs_f64(row.age.max(0.0)),
+    }))
+}
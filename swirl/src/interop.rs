@@ -0,0 +1,169 @@
+//! Importing jobs from other job queues
+//!
+//! When moving an application onto swirl, the old queue usually still has
+//! jobs in it. An [`Importer`] converts the payloads of those jobs into swirl
+//! jobs and enqueues them, so the old queue can be drained by swirl's runners
+//! instead of keeping the old workers around.
+//!
+//! Read payloads out of the old queue with whatever it provides, such as a
+//! Faktory `FETCH` loop or a query against the `background_jobs` crate's
+//! storage, and pass each one to [`Importer::import`]. Each foreign job type
+//! must be mapped to a swirl job with [`Importer::job`].
+
+use diesel::PgConnection;
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+
+use crate::enqueue::EnqueueOptions;
+use crate::errors::ImportError;
+use crate::Job;
+
+/// The wire format of a job payload
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// A Faktory job, as returned by `FETCH`. The type is read from `jobtype`,
+    /// the arguments from `args`, and the id from `jid`.
+    Faktory,
+
+    /// A serialized `JobInfo` from the `background_jobs` crate. The type is
+    /// read from `name`, the arguments from `args`, and the id from `id`.
+    BackgroundJobs,
+
+    #[doc(hidden)]
+    /// Match on `_` instead, more variants may be added in the future
+    __NonExhaustive,
+}
+
+impl Format {
+    fn name(self) -> &'static str {
+        match self {
+            Format::Faktory => "faktory",
+            Format::BackgroundJobs => "background_jobs",
+            Format::__NonExhaustive => unreachable!(),
+        }
+    }
+
+    /// The keys holding the job's type, arguments and id
+    fn keys(self) -> (&'static str, &'static str, &'static str) {
+        match self {
+            Format::Faktory => ("jobtype", "args", "jid"),
+            Format::BackgroundJobs => ("name", "args", "id"),
+            Format::__NonExhaustive => unreachable!(),
+        }
+    }
+}
+
+type EnqueueMapped = fn(&PgConnection, Value, Map<String, Value>) -> Result<(), ImportError>;
+
+struct Mapping {
+    arg_names: Vec<String>,
+    enqueue: EnqueueMapped,
+}
+
+/// Converts jobs from other job queues into swirl jobs
+#[allow(missing_debug_implementations)]
+#[derive(Default)]
+pub struct Importer {
+    mappings: HashMap<String, Mapping>,
+}
+
+impl Importer {
+    /// An importer with no job types mapped
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Import jobs whose type is `foreign_type` as jobs of type `J`.
+    ///
+    /// Arguments which are an object are deserialized as `J` directly.
+    /// Positional arguments, such as Faktory's `args` array, are given the
+    /// names in `arg_names` in order. If `arg_names` is empty, an array
+    /// holding a single object is treated as that object.
+    ///
+    /// The job is enqueued with `J`'s default options.
+    pub fn job<J: Job>(mut self, foreign_type: &str, arg_names: &[&str]) -> Self {
+        let mapping = Mapping {
+            arg_names: arg_names.iter().map(|&name| name.into()).collect(),
+            enqueue: enqueue_as::<J>,
+        };
+        self.mappings.insert(foreign_type.into(), mapping);
+        self
+    }
+
+    /// Enqueues the job described by `payload`.
+    ///
+    /// The job's metadata records where it came from, under the
+    /// `imported_from` key as an object with the format and the job's original
+    /// id. See [`EnqueueOptions::metadata`].
+    pub fn import(
+        &self,
+        conn: &PgConnection,
+        format: Format,
+        payload: &Value,
+    ) -> Result<(), ImportError> {
+        let (type_key, args_key, id_key) = format.keys();
+        let foreign_type = payload
+            .get(type_key)
+            .and_then(Value::as_str)
+            .ok_or_else(|| ImportError::MalformedPayload(format!("`{}` is missing", type_key)))?;
+        let mapping = self
+            .mappings
+            .get(foreign_type)
+            .ok_or_else(|| ImportError::UnmappedJobType(foreign_type.into()))?;
+        let args = payload.get(args_key).cloned().unwrap_or(Value::Null);
+        let args = name_args(args, &mapping.arg_names)?;
+
+        let mut imported_from = Map::new();
+        imported_from.insert("format".into(), format.name().into());
+        imported_from.insert(
+            "id".into(),
+            payload.get(id_key).cloned().unwrap_or(Value::Null),
+        );
+        let mut metadata = Map::new();
+        metadata.insert("imported_from".into(), imported_from.into());
+        (mapping.enqueue)(conn, args, metadata)
+    }
+}
+
+/// Enqueues a job of type `J` with `args`, adding `metadata` to its defaults
+fn enqueue_as<J: Job>(
+    conn: &PgConnection,
+    args: Value,
+    metadata: Map<String, Value>,
+) -> Result<(), ImportError> {
+    let job = serde_json::from_value::<J>(args).map_err(ImportError::InvalidArguments)?;
+    let mut options = EnqueueOptions::for_job::<J>();
+    options.metadata.extend(metadata);
+    job.enqueue_with(conn, options)?;
+    Ok(())
+}
+
+/// Turns positional arguments into an object with the given names
+fn name_args(args: Value, names: &[String]) -> Result<Value, ImportError> {
+    let positional = match args {
+        Value::Array(positional) => positional,
+        args => return Ok(args),
+    };
+    if names.is_empty() {
+        let mut positional = positional.into_iter();
+        return match (positional.next(), positional.next()) {
+            (Some(single @ Value::Object(_)), None) => Ok(single),
+            _ => Err(ImportError::MalformedPayload(
+                "positional arguments were given, but no argument names were mapped".into(),
+            )),
+        };
+    }
+    if positional.len() != names.len() {
+        return Err(ImportError::MalformedPayload(format!(
+            "expected {} arguments, got {}",
+            names.len(),
+            positional.len()
+        )));
+    }
+    Ok(names
+        .iter()
+        .cloned()
+        .zip(positional)
+        .collect::<Map<_, _>>()
+        .into())
+}
@@ -1,9 +1,13 @@
 use diesel::PgConnection;
 use serde::{de::DeserializeOwned, Serialize};
+use std::panic::Location;
+use std::time::{Duration, SystemTime};
 
-use crate::db::DieselPoolObj;
+use crate::context::JobContext;
+use crate::enqueue::{EnqueueOptions, JobHandle, Schedule};
 use crate::errors::{EnqueueError, PerformError};
-use crate::storage;
+use crate::retry::RetryPolicy;
+use crate::storage::{self, EnqueuedJob};
 
 /// A background job, meant to be run asynchronously.
 pub trait Job: Serialize + DeserializeOwned {
@@ -17,12 +21,201 @@ pub trait Job: Serialize + DeserializeOwned {
     /// Typically this is the name of your struct in `snake_case`
     const JOB_TYPE: &'static str;
 
+    /// The priority of this job. Jobs with a higher priority are run before
+    /// jobs with a lower priority, regardless of when they were enqueued.
+    ///
+    /// Defaults to 0
+    const PRIORITY: i16 = 0;
+
+    /// The queue this job is placed in. Runners can be configured to only run
+    /// jobs from certain queues with [`Builder::queues`].
+    ///
+    /// Defaults to `"default"`
+    ///
+    /// [`Builder::queues`]: crate::Builder::queues
+    const QUEUE: &'static str = "default";
+
+    /// The number of times this job is retried after failing before it is
+    /// given up on. Jobs which have been given up on are moved to
+    /// `swirl_failed_jobs`. This takes precedence over
+    /// [`Builder::max_retries`].
+    ///
+    /// Defaults to `None`, which uses the runner's setting
+    ///
+    /// [`Builder::max_retries`]: crate::Builder::max_retries
+    const MAX_RETRIES: Option<u32> = None;
+
+    /// The team or person responsible for this job's code, such as
+    /// `"team-billing"`.
+    ///
+    /// When the job fails, the owner is recorded with the failure in
+    /// `swirl_failed_jobs` and `swirl_failure_samples`, and included in the
+    /// runner's [JSON logs](crate::Builder::json_logs), so that alerts can be
+    /// routed to whoever can fix it.
+    ///
+    /// Defaults to `None`
+    const OWNER: Option<&'static str> = None;
+
+    /// A JSON Schema which the job's serialized arguments must match when the
+    /// job is enqueued with [`insert_raw_job`](crate::insert_raw_job), such as
+    /// `include_str!("send_email.schema.json")`.
+    ///
+    /// Arguments are always checked against the job's own type as well, so
+    /// this is only needed for rules the type can't express, such as ranges
+    /// of numbers or the format of strings. Requires the `jsonschema` feature
+    /// to have any effect.
+    ///
+    /// Defaults to `None`
+    const JSON_SCHEMA: Option<&'static str> = None;
+
+    /// How long the job may run before it is failed with [`JobTimedOut`].
+    ///
+    /// A job which runs for longer has its transaction rolled back by
+    /// terminating its database connection, which releases its lock, and is
+    /// then retried like any other failure. The job's thread can't be stopped
+    /// from the outside, so it stays busy until the job returns.
+    ///
+    /// Defaults to `None`, which uses the runner's
+    /// [`default_job_timeout`](crate::Builder::default_job_timeout)
+    ///
+    /// [`JobTimedOut`]: crate::JobTimedOut
+    const TIMEOUT: Option<Duration> = None;
+
+    /// How long the value returned by this job is kept for once it
+    /// succeeds, for jobs defined with `#[swirl::background_job]` which
+    /// return `Result<T, PerformError>`. See [`job_result`](crate::job_result).
+    ///
+    /// Expired results are no longer returned, and are deleted by
+    /// [`admin::purge_job_results`](crate::admin::purge_job_results).
+    ///
+    /// Defaults to 7 days
+    const RESULT_RETENTION: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+    /// How long to wait before retrying this job after it fails. This takes
+    /// precedence over [`Builder::default_retry_policy`], but not over
+    /// policies for specific kinds of failures given to
+    /// [`Builder::retry_policy`].
+    ///
+    /// Defaults to `None`, which uses the runner's setting
+    ///
+    /// [`Builder::default_retry_policy`]: crate::Builder::default_retry_policy
+    /// [`Builder::retry_policy`]: crate::Builder::retry_policy
+    fn retry_policy() -> Option<RetryPolicy> {
+        None
+    }
+
     /// Enqueue this job to be run at some point in the future.
-    fn enqueue(self, conn: &PgConnection) -> Result<(), EnqueueError> {
-        storage::enqueue_job(conn, self)
+    ///
+    /// The time, host, and source location the job was enqueued from are
+    /// recorded in its metadata. See [`EnqueueOptions::metadata`] for details.
+    ///
+    /// Returns a handle holding the new job's id, which can be used to look
+    /// the job up later.
+    #[track_caller]
+    fn enqueue(self, conn: &PgConnection) -> Result<JobHandle, EnqueueError> {
+        self.enqueue_with(conn, EnqueueOptions::for_job::<Self>())
+    }
+
+    /// Enqueue this job to be run once `time` has passed.
+    ///
+    /// `time` can be a `SystemTime`, or anything which converts into one such
+    /// as chrono's `DateTime<Utc>`.
+    #[track_caller]
+    fn enqueue_at<T: Into<SystemTime>>(
+        self,
+        conn: &PgConnection,
+        time: T,
+    ) -> Result<JobHandle, EnqueueError> {
+        let options = EnqueueOptions {
+            schedule: Schedule::At(time.into()),
+            ..EnqueueOptions::for_job::<Self>()
+        };
+        self.enqueue_with(conn, options)
+    }
+
+    /// Enqueue this job to be run once `delay` has passed, according to the
+    /// database's clock.
+    #[track_caller]
+    fn enqueue_in(self, conn: &PgConnection, delay: Duration) -> Result<JobHandle, EnqueueError> {
+        let options = EnqueueOptions {
+            schedule: Schedule::In(delay),
+            ..EnqueueOptions::for_job::<Self>()
+        };
+        self.enqueue_with(conn, options)
+    }
+
+    /// Enqueue this job with options other than the defaults for its type.
+    #[track_caller]
+    fn enqueue_with(
+        self,
+        conn: &PgConnection,
+        mut options: EnqueueOptions,
+    ) -> Result<JobHandle, EnqueueError> {
+        options.add_automatic_metadata(Location::caller());
+        let enqueued = storage::enqueue_job(conn, self, options)?;
+        enqueued
+            .map(|job| JobHandle::new(job.id))
+            .ok_or(EnqueueError::Duplicate)
+    }
+
+    /// Enqueue this job, and return the row which was stored for it.
+    ///
+    /// This is the same as [`enqueue`](Self::enqueue), but gives the job's id
+    /// and when it will run without needing a second query, such as to log
+    /// them or add them to a trace.
+    #[track_caller]
+    fn enqueue_returning(self, conn: &PgConnection) -> Result<EnqueuedJob, EnqueueError> {
+        let mut options = EnqueueOptions::for_job::<Self>();
+        options.add_automatic_metadata(Location::caller());
+        storage::enqueue_job(conn, self, options)?.ok_or(EnqueueError::Duplicate)
+    }
+
+    /// Enqueue this job to be run once all of the jobs in `parents` have
+    /// succeeded, such as the transform step of a pipeline which has to wait
+    /// for its downloads.
+    ///
+    /// The job waits while a parent is being retried, and for as long as a
+    /// parent is in `swirl_failed_jobs`. See [`EnqueueOptions::depends_on`]
+    /// for the details.
+    #[track_caller]
+    fn enqueue_after(
+        self,
+        conn: &PgConnection,
+        parents: &[JobHandle],
+    ) -> Result<JobHandle, EnqueueError> {
+        let options = EnqueueOptions {
+            depends_on: parents.iter().map(JobHandle::id).collect(),
+            ..EnqueueOptions::for_job::<Self>()
+        };
+        self.enqueue_with(conn, options)
+    }
+
+    /// Enqueue this job unless a job of the same type with the same `key` is
+    /// already in the queue, such as to avoid reindexing the same record
+    /// twice. Returns `None` if the job was skipped.
+    ///
+    /// Jobs are only compared by their key, not their arguments. See
+    /// [`EnqueueOptions::unique_key`] for when a job counts as being in the
+    /// queue.
+    #[track_caller]
+    fn enqueue_unique<K: Into<String>>(
+        self,
+        conn: &PgConnection,
+        key: K,
+    ) -> Result<Option<JobHandle>, EnqueueError> {
+        let mut options = EnqueueOptions {
+            unique_key: Some(key.into()),
+            ..EnqueueOptions::for_job::<Self>()
+        };
+        options.add_automatic_metadata(Location::caller());
+        let enqueued = storage::enqueue_job(conn, self, options)?;
+        Ok(enqueued.map(|job| JobHandle::new(job.id)))
     }
 
     /// The logic involved in actually performing this job.
-    fn perform(self, env: &Self::Environment, pool: &dyn DieselPoolObj)
-        -> Result<(), PerformError>;
+    ///
+    /// `ctx` provides the connection the job's transaction is running on and
+    /// the connection pool the runner was built with, as well as information
+    /// about the job being run.
+    fn perform(self, env: &Self::Environment, ctx: &JobContext<'_>) -> Result<(), PerformError>;
 }
@@ -2,30 +2,84 @@
 
 #[macro_use]
 extern crate diesel;
+#[cfg(feature = "migrations")]
+#[macro_use]
+extern crate diesel_migrations;
 
 #[doc(hidden)]
 pub extern crate inventory;
 #[doc(hidden)]
 pub extern crate serde;
 
+mod batch;
+mod blob;
+mod completion;
+mod context;
+mod doctor;
+mod enqueue;
+mod executor;
+mod fetch;
 mod job;
+mod middleware;
+#[cfg(feature = "migrations")]
+mod migrations;
+mod queue;
 mod registry;
+mod retry;
 mod runner;
+mod scope;
+mod status;
 mod storage;
+mod worker;
 
+pub mod admin;
+pub mod backfill;
 pub mod db;
 pub mod errors;
+pub mod heartbeat;
+#[cfg(feature = "interop")]
+pub mod interop;
+#[cfg(feature = "maintenance")]
+pub mod maintenance;
+pub mod replay;
 pub mod schema;
+#[cfg(feature = "testing")]
+pub mod testing;
 
 pub use swirl_proc_macro::*;
 
 #[doc(hidden)]
 pub use serde_derive::{Deserialize, Serialize};
 
+pub use batch::{enqueue_batch, Batch};
+pub use blob::BlobReader;
+pub use completion::JobOutcome;
+pub use context::JobContext;
+pub use doctor::{doctor, DoctorReport};
+#[cfg(feature = "metrics")]
+pub use enqueue::{
+    enqueue_metrics_snapshot, EnqueueMetrics, EnqueueMetricsSnapshot, PayloadHistogram,
+};
+pub use enqueue::{
+    enqueue_superseding, insert_raw_job, EnqueueMiddleware, EnqueueOptions, JobHandle, Schedule,
+};
 pub use errors::*;
+pub use fetch::{DefaultFetchQuery, FairFetchQuery, FetchFilter, FetchQuery, FetchRequest};
 pub use job::*;
+pub use middleware::JobMiddleware;
+#[cfg(feature = "migrations")]
+pub use migrations::{run_migrations, RunMigrationsError};
+pub use queue::{queue, Queue};
 pub use registry::Registry;
+pub use retry::{FailureKind, RetryPolicy};
 pub use runner::*;
+pub use scope::{Scope, SubTask};
+pub use status::{cancel_job, job_result, job_status, Cancellation, JobStatus};
+pub use storage::{BackgroundJob, EnqueuedJob};
 
+#[doc(hidden)]
+pub use enqueue::EnqueueHook;
+#[doc(hidden)]
+pub use executor::block_on;
 #[doc(hidden)]
 pub use registry::JobVTable;
@@ -0,0 +1,139 @@
+//! Housekeeping jobs which ship with swirl
+//!
+//! These jobs are registered automatically when the `maintenance` feature is
+//! enabled, and can be run by any runner regardless of its environment type.
+//! Enqueue them from whatever you use to schedule periodic work, such as a cron
+//! job or a scheduled task on your platform.
+
+use diesel::prelude::*;
+use diesel::sql_query;
+use diesel::sql_types::BigInt;
+use serde_derive::{Deserialize, Serialize};
+use std::time::Duration;
+
+use crate::admin;
+use crate::context::JobContext;
+use crate::errors::PerformError;
+use crate::registry::JobVTable;
+use crate::Job;
+
+/// Terminates connections which have held a lock on a job for too long.
+///
+/// A job which hangs keeps its row locked, so it will never be retried. This
+/// job terminates the backend of any transaction which has held a lock on
+/// `background_jobs` for longer than `max_duration`. The hung job's
+/// transaction is rolled back, and it will be retried like any other failure.
+///
+/// `max_duration` should be comfortably longer than your slowest job.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReapStuckJobs {
+    /// How long a lock can be held before it is considered stuck
+    pub max_duration: Duration,
+}
+
+impl Job for ReapStuckJobs {
+    type Environment = ();
+    const JOB_TYPE: &'static str = "swirl_reap_stuck_jobs";
+
+    fn perform(self, _: &(), ctx: &JobContext<'_>) -> Result<(), PerformError> {
+        let conn = ctx.pool().get()?;
+        sql_query(
+            "SELECT pg_terminate_backend(pid) FROM ( \
+                 SELECT DISTINCT l.pid FROM pg_locks l \
+                 INNER JOIN pg_stat_activity a ON a.pid = l.pid \
+                 WHERE l.relation = 'background_jobs'::regclass \
+                 AND l.mode = 'RowShareLock' \
+                 AND l.pid <> pg_backend_pid() \
+                 AND a.xact_start < now() - $1 * interval '1 second' \
+             ) stuck",
+        )
+        .bind::<BigInt, _>(self.max_duration.as_secs() as i64)
+        .execute(&**conn)?;
+        Ok(())
+    }
+}
+
+/// Updates the planner statistics for swirl's tables.
+///
+/// The job queue sees far more churn than most tables, so autovacuum's
+/// statistics can fall behind and lead to poor plans for fetching jobs.
+/// Running this after a large batch of jobs has been enqueued or completed
+/// keeps them accurate.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RefreshStats;
+
+impl Job for RefreshStats {
+    type Environment = ();
+    const JOB_TYPE: &'static str = "swirl_refresh_stats";
+
+    fn perform(self, _: &(), ctx: &JobContext<'_>) -> Result<(), PerformError> {
+        let conn = ctx.pool().get()?;
+        sql_query("ANALYZE background_jobs, background_job_checkpoints").execute(&**conn)?;
+        Ok(())
+    }
+}
+
+/// Deletes jobs which failed for the last time more than `max_age` ago.
+///
+/// Jobs which run out of retries are kept in `swirl_failed_jobs` until they
+/// are retried or deleted. This keeps that table from growing forever. See
+/// [`admin::purge_failed_jobs`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PruneFailedJobs {
+    /// How long failed jobs are kept for
+    pub max_age: Duration,
+}
+
+impl Job for PruneFailedJobs {
+    type Environment = ();
+    const JOB_TYPE: &'static str = "swirl_prune_failed_jobs";
+
+    fn perform(self, _: &(), ctx: &JobContext<'_>) -> Result<(), PerformError> {
+        let conn = ctx.pool().get()?;
+        admin::purge_failed_jobs(&**conn, self.max_age)?;
+        Ok(())
+    }
+}
+
+/// Deletes the results saved by jobs once they have expired.
+///
+/// Expired results are never returned, but are kept in `swirl_job_results`
+/// until this runs. See [`admin::purge_job_results`].
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PruneJobResults;
+
+impl Job for PruneJobResults {
+    type Environment = ();
+    const JOB_TYPE: &'static str = "swirl_prune_job_results";
+
+    fn perform(self, _: &(), ctx: &JobContext<'_>) -> Result<(), PerformError> {
+        let conn = ctx.pool().get()?;
+        admin::purge_job_results(&**conn)?;
+        Ok(())
+    }
+}
+
+/// Recounts the jobs in the queue for [`admin::queue_stats`].
+///
+/// The view is refreshed concurrently, so dashboards can keep reading the old
+/// numbers while it runs. How often to run this depends on how fresh your
+/// dashboards need to be. Once a minute is plenty for most.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RefreshQueueStats;
+
+impl Job for RefreshQueueStats {
+    type Environment = ();
+    const JOB_TYPE: &'static str = "swirl_refresh_queue_stats";
+
+    fn perform(self, _: &(), ctx: &JobContext<'_>) -> Result<(), PerformError> {
+        let conn = ctx.pool().get()?;
+        sql_query("REFRESH MATERIALIZED VIEW CONCURRENTLY swirl_queue_stats").execute(&**conn)?;
+        Ok(())
+    }
+}
+
+inventory::submit!(JobVTable::from_env_agnostic_job::<ReapStuckJobs>());
+inventory::submit!(JobVTable::from_env_agnostic_job::<RefreshStats>());
+inventory::submit!(JobVTable::from_env_agnostic_job::<PruneFailedJobs>());
+inventory::submit!(JobVTable::from_env_agnostic_job::<PruneJobResults>());
+inventory::submit!(JobVTable::from_env_agnostic_job::<RefreshQueueStats>());
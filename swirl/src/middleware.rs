@@ -0,0 +1,78 @@
+use diesel::PgConnection;
+use std::error::Error;
+use std::time::Duration;
+
+use crate::errors::PerformError;
+use crate::JobMeta;
+
+/// Hooks which are run around every job, given to
+/// [`Builder::middleware`](crate::Builder::middleware).
+///
+/// This allows behavior which applies to every job, such as setting up an
+/// error tracker's scope, configuring the database session, or timing jobs,
+/// to be written once rather than in every job. All of the hooks are called on
+/// the thread which runs the job, and default to doing nothing.
+///
+/// When several middleware are given, `before_perform` is called in the order
+/// they were added, and `on_failure` and `after_perform` in the reverse order,
+/// so each middleware wraps the ones added after it.
+pub trait JobMiddleware: Send + Sync + 'static {
+    /// Called before the job is performed.
+    ///
+    /// `conn` is the connection the job is locked with, and which
+    /// [`JobContext::connection`](crate::JobContext::connection) returns. This
+    /// is called in the same savepoint the job runs in, so settings made with
+    /// `SET LOCAL` apply to the job, and are discarded if it fails.
+    ///
+    /// Returning an error fails the job without performing it, as if the job
+    /// had returned the error. The `before_perform` of later middleware is not
+    /// called, but `on_failure` and `after_perform` are called for every
+    /// middleware.
+    fn before_perform(&self, _job: &JobMeta<'_>, _conn: &PgConnection) -> Result<(), PerformError> {
+        Ok(())
+    }
+
+    /// Called after the job has finished running, whether it succeeded,
+    /// failed or yielded. `duration` includes the time spent in
+    /// `before_perform`.
+    fn after_perform(&self, _job: &JobMeta<'_>, _duration: Duration) {}
+
+    /// Called when the job fails, with the error it returned or the formatted
+    /// panic message if it panicked. This is called before `after_perform`,
+    /// and for every failure, including ones which will be retried.
+    fn on_failure(&self, _job: &JobMeta<'_>, _error: &dyn Error) {}
+}
+
+/// The middleware a runner was built with, in the order they were added
+#[derive(Default)]
+pub(crate) struct MiddlewareStack(Vec<Box<dyn JobMiddleware>>);
+
+impl MiddlewareStack {
+    pub(crate) fn push(&mut self, middleware: Box<dyn JobMiddleware>) {
+        self.0.push(middleware);
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub(crate) fn before_perform(
+        &self,
+        job: &JobMeta<'_>,
+        conn: &PgConnection,
+    ) -> Result<(), PerformError> {
+        self.0.iter().try_for_each(|m| m.before_perform(job, conn))
+    }
+
+    pub(crate) fn on_failure(&self, job: &JobMeta<'_>, error: &dyn Error) {
+        for middleware in self.0.iter().rev() {
+            middleware.on_failure(job, error);
+        }
+    }
+
+    pub(crate) fn after_perform(&self, job: &JobMeta<'_>, duration: Duration) {
+        for middleware in self.0.iter().rev() {
+            middleware.after_perform(job, duration);
+        }
+    }
+}
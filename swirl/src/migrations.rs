@@ -0,0 +1,33 @@
+//! Creating and upgrading swirl's tables
+//!
+//! Swirl's migrations are embedded in the crate, so they don't need to be
+//! copied into your own migrations directory. Each one is recorded in Diesel's
+//! `__diesel_schema_migrations` table under the same version as in swirl's
+//! repository, so [`run_migrations`] only runs the ones added since it was
+//! last called, and skips any which were already run from a copy of swirl's
+//! migrations directory.
+//!
+//! Requires the `migrations` feature.
+
+use diesel::PgConnection;
+
+pub use diesel_migrations::RunMigrationsError;
+
+embed_migrations!("../migrations");
+
+/// Runs any of swirl's migrations which have not been run yet.
+///
+/// Call this when your application starts, or from your own migration
+/// tooling, after upgrading swirl.
+///
+/// ```no_run
+/// # use diesel::prelude::*;
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let conn = PgConnection::establish("postgres://localhost/my_app")?;
+/// swirl::run_migrations(&conn)?;
+/// # Ok(())
+/// # }
+/// ```
+pub fn run_migrations(conn: &PgConnection) -> Result<(), RunMigrationsError> {
+    embedded_migrations::run(conn)
+}
@@ -0,0 +1,116 @@
+use diesel::prelude::*;
+use std::fmt;
+use std::panic::Location;
+
+use crate::enqueue::{EnqueueOptions, JobHandle};
+use crate::errors::EnqueueError;
+use crate::schema::{background_jobs, swirl_paused_queues};
+use crate::{storage, Job};
+
+/// A queue jobs can be placed in, as returned by [`queue`].
+///
+/// Queues are usually defined once as constants, so call sites can't
+/// misspell their names:
+///
+/// ```ignore
+/// const EMAILS: Queue<'static> = Queue::new("emails");
+///
+/// EMAILS.enqueue(&conn, send_welcome_email(user_id))?;
+/// let runner = Runner::builder(env).queues(vec![EMAILS]).build();
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Queue<'a> {
+    name: &'a str,
+}
+
+/// The queue with the given name. See [`Queue`].
+pub fn queue(name: &str) -> Queue<'_> {
+    Queue::new(name)
+}
+
+impl<'a> Queue<'a> {
+    /// The queue with the given name
+    pub const fn new(name: &'a str) -> Self {
+        Self { name }
+    }
+
+    /// The name of the queue
+    pub fn name(&self) -> &'a str {
+        self.name
+    }
+
+    /// Enqueues `job` in this queue, rather than in its type's
+    /// [`QUEUE`](Job::QUEUE)
+    #[track_caller]
+    pub fn enqueue<T: Job>(&self, conn: &PgConnection, job: T) -> Result<JobHandle, EnqueueError> {
+        let mut options = EnqueueOptions {
+            queue: self.name.into(),
+            ..EnqueueOptions::for_job::<T>()
+        };
+        options.add_automatic_metadata(Location::caller());
+        storage::enqueue_job(conn, job, options)?
+            .map(|job| JobHandle::new(job.id))
+            .ok_or(EnqueueError::Duplicate)
+    }
+
+    /// The number of jobs in this queue, including jobs which are running or
+    /// waiting to be retried.
+    ///
+    /// Unlike [`admin::queue_stats`](crate::admin::queue_stats), the jobs are
+    /// counted when this is called, which gets slower as the queue grows.
+    pub fn depth(&self, conn: &PgConnection) -> QueryResult<i64> {
+        background_jobs::table
+            .filter(background_jobs::queue.eq(self.name))
+            .count()
+            .get_result(conn)
+    }
+
+    /// Stops every runner from starting jobs in this queue, until it is
+    /// [resumed](Self::resume).
+    ///
+    /// Jobs can still be enqueued in the queue while it is paused, and jobs
+    /// which were already running are left to finish. This takes effect the
+    /// next time each runner fetches a job. Queues are paused by name, so
+    /// [aliases](crate::admin::set_queue_alias) have to be paused separately.
+    pub fn pause(&self, conn: &PgConnection) -> QueryResult<()> {
+        diesel::insert_into(swirl_paused_queues::table)
+            .values(swirl_paused_queues::queue.eq(self.name))
+            .on_conflict_do_nothing()
+            .execute(conn)?;
+        Ok(())
+    }
+
+    /// Lets runners start jobs in this queue again after it was
+    /// [paused](Self::pause).
+    ///
+    /// Returns `false` if the queue wasn't paused.
+    pub fn resume(&self, conn: &PgConnection) -> QueryResult<bool> {
+        let deleted = diesel::delete(swirl_paused_queues::table.find(self.name)).execute(conn)?;
+        Ok(deleted > 0)
+    }
+
+    /// Whether this queue is [paused](Self::pause)
+    pub fn is_paused(&self, conn: &PgConnection) -> QueryResult<bool> {
+        use diesel::dsl::exists;
+
+        diesel::select(exists(swirl_paused_queues::table.find(self.name))).get_result(conn)
+    }
+}
+
+impl fmt::Display for Queue<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name)
+    }
+}
+
+impl AsRef<str> for Queue<'_> {
+    fn as_ref(&self) -> &str {
+        self.name
+    }
+}
+
+impl From<Queue<'_>> for String {
+    fn from(queue: Queue<'_>) -> Self {
+        queue.name.into()
+    }
+}
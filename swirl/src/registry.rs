@@ -3,9 +3,13 @@
 use std::any::{Any, TypeId};
 use std::collections::HashMap;
 use std::marker::PhantomData;
+use std::panic::RefUnwindSafe;
+use std::sync::Arc;
+use std::time::Duration;
 
-use crate::db::DieselPoolObj;
+use crate::context::JobContext;
 use crate::errors::PerformError;
+use crate::retry::RetryPolicy;
 use crate::Job;
 
 #[derive(Default)]
@@ -14,29 +18,68 @@ use crate::Job;
 /// functions at runtime.
 pub struct Registry<Env> {
     jobs: HashMap<&'static str, JobVTable>,
+    environments: HashMap<&'static str, Arc<JobEnvironment>>,
     _marker: PhantomData<Env>,
 }
 
+type JobEnvironment = dyn Any + Send + Sync + RefUnwindSafe;
+
+impl<Env> Registry<Env> {
+    /// Returns `true` if no jobs are registered, not counting swirl's own
+    /// maintenance jobs which can be run in any environment
+    pub fn is_empty(&self) -> bool {
+        self.jobs.values().all(|vtable| vtable.env_type.is_none())
+    }
+}
+
 impl<Env: 'static> Registry<Env> {
     /// Loads the registry from all invocations of [`register_job!`] for this
-    /// environment type
+    /// environment type, along with any jobs which can be run in any
+    /// environment
     pub fn load() -> Self {
         let jobs = inventory::iter::<JobVTable>
             .into_iter()
-            .filter(|vtable| vtable.env_type == TypeId::of::<Env>())
+            .filter(|vtable| vtable.env_type.map_or(true, |t| t == TypeId::of::<Env>()))
             .map(|&vtable| (vtable.job_type, vtable))
             .collect();
 
         Self {
             jobs: jobs,
+            environments: HashMap::new(),
             _marker: PhantomData,
         }
     }
 
+    /// Registers a job which will be run with `env` as its environment, rather
+    /// than the environment given to the runner.
+    ///
+    /// This allows jobs which need different resources to be run by the same
+    /// runner, without having to combine all of those resources into a single
+    /// environment type.
+    pub fn register_with<J>(&mut self, env: J::Environment)
+    where
+        J: Job,
+        J::Environment: Send + Sync + RefUnwindSafe,
+    {
+        self.jobs.insert(J::JOB_TYPE, JobVTable::from_job::<J>());
+        self.environments.insert(J::JOB_TYPE, Arc::new(env));
+    }
+
+    /// Whether a job of the given type can be run
+    pub(crate) fn contains(&self, job_type: &str) -> bool {
+        self.jobs.contains_key(job_type)
+    }
+
+    /// Get the vtable for a given job type
+    pub(crate) fn vtable(&self, job_type: &str) -> Option<&JobVTable> {
+        self.jobs.get(job_type)
+    }
+
     /// Get the perform function for a given job type
     pub fn get(&self, job_type: &str) -> Option<PerformJob<Env>> {
         self.jobs.get(job_type).map(|&vtable| PerformJob {
             vtable,
+            environment: self.environments.get(job_type).cloned(),
             _marker: PhantomData,
         })
     }
@@ -54,12 +97,45 @@ macro_rules! register_job {
     };
 }
 
+/// Make sure the jobs defined in other crates are registered.
+///
+/// Jobs are collected when the program starts from every [`register_job!`]
+/// which was linked into it. When jobs are defined in a library crate which
+/// the binary never refers to directly, such as a crate of jobs shared by a
+/// web server and a worker, the linker can leave that crate out, and its jobs
+/// will be missing from the [`Registry`]. Naming the crates here, at the root
+/// of the binary, makes sure they are linked:
+///
+/// ```ignore
+/// swirl::register_jobs!(email_jobs, index_jobs);
+/// ```
+///
+/// [`Runner::run_forever`](crate::Runner::run_forever) warns about any job
+/// types in the queue which aren't registered, which is usually the first
+/// sign that this is needed.
+#[macro_export]
+macro_rules! register_jobs {
+    ($($krate: ident),* $(,)?) => {
+        $(
+            #[allow(unused_extern_crates)]
+            extern crate $krate;
+        )*
+    };
+}
+
 #[doc(hidden)]
 #[derive(Clone, Copy)]
 pub struct JobVTable {
-    env_type: TypeId,
+    /// `None` if this job can be run with any environment
+    env_type: Option<TypeId>,
     job_type: &'static str,
-    perform: fn(serde_json::Value, &dyn Any, &dyn DieselPoolObj) -> Result<(), PerformError>,
+    perform: fn(serde_json::Value, &dyn Any, &JobContext<'_>) -> Result<(), PerformError>,
+    validate: fn(&serde_json::Value) -> Result<(), serde_json::Error>,
+    max_retries: Option<u32>,
+    retry_policy: fn() -> Option<RetryPolicy>,
+    owner: Option<&'static str>,
+    json_schema: Option<&'static str>,
+    timeout: Option<Duration>,
 }
 
 inventory::collect!(JobVTable);
@@ -67,17 +143,83 @@ inventory::collect!(JobVTable);
 impl JobVTable {
     pub fn from_job<T: Job>() -> Self {
         Self {
-            env_type: TypeId::of::<T::Environment>(),
+            env_type: Some(TypeId::of::<T::Environment>()),
             job_type: T::JOB_TYPE,
             perform: perform_job::<T>,
+            validate: validate_job::<T>,
+            max_retries: T::MAX_RETRIES,
+            retry_policy: T::retry_policy,
+            owner: T::OWNER,
+            json_schema: T::JSON_SCHEMA,
+            timeout: T::TIMEOUT,
+        }
+    }
+
+    /// Creates a vtable for a job which doesn't use its environment, and can
+    /// be run by a runner with any environment type
+    pub(crate) fn from_env_agnostic_job<T: Job<Environment = ()>>() -> Self {
+        Self {
+            env_type: None,
+            job_type: T::JOB_TYPE,
+            perform: perform_env_agnostic_job::<T>,
+            validate: validate_job::<T>,
+            max_retries: T::MAX_RETRIES,
+            retry_policy: T::retry_policy,
+            owner: T::OWNER,
+            json_schema: T::JSON_SCHEMA,
+            timeout: T::TIMEOUT,
         }
     }
+
+    /// Finds the job registered with the given type, regardless of which
+    /// environment it uses
+    pub(crate) fn find(job_type: &str) -> Option<Self> {
+        inventory::iter::<JobVTable>
+            .into_iter()
+            .find(|vtable| vtable.job_type == job_type)
+            .copied()
+    }
+
+    /// The value of [`Job::MAX_RETRIES`] for this job
+    pub(crate) fn max_retries(&self) -> Option<u32> {
+        self.max_retries
+    }
+
+    /// The value of [`Job::OWNER`] for this job
+    pub(crate) fn owner(&self) -> Option<&'static str> {
+        self.owner
+    }
+
+    /// The value of [`Job::TIMEOUT`] for this job
+    pub(crate) fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    /// The value of [`Job::JSON_SCHEMA`] for this job
+    #[allow(dead_code)] // Only used with some features enabled
+    pub(crate) fn json_schema(&self) -> Option<&'static str> {
+        self.json_schema
+    }
+
+    /// The value of [`Job::retry_policy`] for this job
+    pub(crate) fn retry_policy(&self) -> Option<RetryPolicy> {
+        (self.retry_policy)()
+    }
+
+    /// Checks that `data` can be deserialized as this job's arguments
+    pub(crate) fn validate(&self, data: &serde_json::Value) -> Result<(), serde_json::Error> {
+        (self.validate)(data)
+    }
+}
+
+fn validate_job<T: Job>(data: &serde_json::Value) -> Result<(), serde_json::Error> {
+    T::deserialize(data).map(|_| ())
 }
 
 fn perform_job<T: Job>(
     data: serde_json::Value,
     env: &dyn Any,
-    pool: &dyn DieselPoolObj,
+    ctx: &JobContext<'_>,
 ) -> Result<(), PerformError> {
     let environment = env.downcast_ref().ok_or_else::<PerformError, _>(|| {
         "Incorrect environment type. This should never happen. \
@@ -85,11 +227,21 @@ fn perform_job<T: Job>(
             .into()
     })?;
     let data = serde_json::from_value(data)?;
-    T::perform(data, environment, pool)
+    T::perform(data, environment, ctx)
+}
+
+fn perform_env_agnostic_job<T: Job<Environment = ()>>(
+    data: serde_json::Value,
+    _: &dyn Any,
+    ctx: &JobContext<'_>,
+) -> Result<(), PerformError> {
+    let data = serde_json::from_value(data)?;
+    T::perform(data, &(), ctx)
 }
 
 pub struct PerformJob<Env> {
     vtable: JobVTable,
+    environment: Option<Arc<JobEnvironment>>,
     _marker: PhantomData<Env>,
 }
 
@@ -98,9 +250,12 @@ impl<Env: 'static> PerformJob<Env> {
         &self,
         data: serde_json::Value,
         env: &Env,
-        pool: &dyn DieselPoolObj,
+        ctx: &JobContext<'_>,
     ) -> Result<(), PerformError> {
         let perform_fn = self.vtable.perform;
-        perform_fn(data, env, pool)
+        match &self.environment {
+            Some(job_env) => perform_fn(data, &**job_env, ctx),
+            None => perform_fn(data, env, ctx),
+        }
     }
 }
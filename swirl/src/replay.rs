@@ -0,0 +1,85 @@
+//! Recording failed job runs so they can be replayed locally
+//!
+//! When [`Builder::record_failures_to`] is set, every failed run of a job is
+//! written to a JSON file in the given directory. The file can be copied to a
+//! development machine, and replayed with [`Runner::replay`] against a runner
+//! configured with a development environment.
+//!
+//! [`Builder::record_failures_to`]: crate::Builder::record_failures_to
+//! [`Runner::replay`]: crate::Runner::replay
+
+use serde_derive::{Deserialize, Serialize};
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::storage::BackgroundJob;
+
+/// A failed run of a job, as written by
+/// [`Builder::record_failures_to`](crate::Builder::record_failures_to)
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Recording {
+    /// The id of the job
+    pub job_id: i64,
+    /// The type of the job
+    pub job_type: String,
+    /// The job's serialized arguments
+    pub data: serde_json::Value,
+    /// The priority of the job
+    pub priority: i16,
+    /// The queue the job was placed in
+    pub queue: String,
+    /// The metadata the job was enqueued with
+    pub metadata: serde_json::Value,
+    /// The error the job failed with
+    pub error: String,
+    /// When the job failed, in seconds since the Unix epoch
+    pub recorded_at: f64,
+}
+
+impl Recording {
+    /// Loads a recording from a file
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let file = File::open(path)?;
+        serde_json::from_reader(io::BufReader::new(file)).map_err(Into::into)
+    }
+
+    pub(crate) fn into_job(self) -> BackgroundJob {
+        BackgroundJob {
+            id: self.job_id,
+            job_type: self.job_type,
+            data: self.data,
+            priority: self.priority,
+            queue: self.queue,
+            metadata: self.metadata,
+            retries: 0,
+        }
+    }
+}
+
+/// Writes a recording of a failed job to `dir`, returning the path of the
+/// file which was written
+pub(crate) fn record(dir: &Path, job: BackgroundJob, error: String) -> io::Result<PathBuf> {
+    let recorded_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs_f64())
+        .unwrap_or_default();
+    let path = dir.join(format!(
+        "{}-{}-{}.json",
+        job.job_type, job.id, recorded_at as u64
+    ));
+    let recording = Recording {
+        job_id: job.id,
+        job_type: job.job_type,
+        data: job.data,
+        priority: job.priority,
+        queue: job.queue,
+        metadata: job.metadata,
+        error,
+        recorded_at,
+    };
+    let file = File::create(&path)?;
+    serde_json::to_writer_pretty(io::BufWriter::new(file), &recording)?;
+    Ok(path)
+}
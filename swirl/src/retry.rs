@@ -0,0 +1,262 @@
+use diesel::result::{ConnectionError, Error as DieselError};
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::errors::{Failure, JobTimedOut};
+use crate::registry::JobVTable;
+
+/// The kind of error a job failed with, used to pick a [`RetryPolicy`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FailureKind {
+    /// The job's arguments could not be deserialized. Retrying will usually
+    /// fail the same way until the arguments are fixed, for example with
+    /// [`admin::retry_with`](crate::admin::retry_with) or
+    /// [`admin::retry_failed_job`](crate::admin::retry_failed_job).
+    Deserialization,
+
+    /// An operation timed out. This includes IO errors of kind `TimedOut`,
+    /// queries cancelled by `statement_timeout`, and jobs which exceeded their
+    /// [`TIMEOUT`](crate::Job::TIMEOUT).
+    Timeout,
+
+    /// A query failed, or a connection to the database could not be made
+    Database,
+
+    /// Any other error
+    Other,
+
+    #[doc(hidden)]
+    /// Match on `_` instead, more variants may be added in the future
+    __NonExhaustive,
+}
+
+impl FailureKind {
+    /// Classifies `error` by looking at it and the errors which caused it.
+    /// The first error in the chain which is recognized decides the kind.
+    pub fn of(error: &(dyn Error + 'static)) -> Self {
+        let mut current = Some(error);
+        while let Some(e) = current {
+            if let Some(kind) = Self::classify(e) {
+                return kind;
+            }
+            current = e.source();
+        }
+        FailureKind::Other
+    }
+
+    fn classify(e: &(dyn Error + 'static)) -> Option<Self> {
+        if e.is::<serde_json::Error>() {
+            Some(FailureKind::Deserialization)
+        } else if e.is::<JobTimedOut>() {
+            Some(FailureKind::Timeout)
+        } else if let Some(e) = e.downcast_ref::<io::Error>() {
+            match e.kind() {
+                io::ErrorKind::TimedOut => Some(FailureKind::Timeout),
+                _ => None,
+            }
+        } else if let Some(e) = e.downcast_ref::<DieselError>() {
+            match e {
+                DieselError::DatabaseError(_, info) if info.message().contains("timeout") => {
+                    Some(FailureKind::Timeout)
+                }
+                DieselError::DeserializationError(_) | DieselError::SerializationError(_) => None,
+                _ => Some(FailureKind::Database),
+            }
+        } else if e.is::<ConnectionError>() {
+            Some(FailureKind::Database)
+        } else {
+            None
+        }
+    }
+}
+
+/// When a failed job is run again
+#[derive(Clone)]
+pub enum RetryPolicy {
+    /// Wait `base * 2^retries` after each failure, where `retries` includes
+    /// the failure which just happened.
+    ///
+    /// Jobs which have no policy configured are retried this way with a base
+    /// of one minute.
+    Exponential {
+        /// The delay before the first retry is twice this
+        base: Duration,
+    },
+
+    /// Like [`Exponential`](Self::Exponential), but each delay is shortened
+    /// by a random amount of up to half. This stops jobs which failed at the
+    /// same time, such as during an outage, from all being retried at once.
+    ExponentialWithJitter {
+        /// The longest delay before the first retry is twice this
+        base: Duration,
+    },
+
+    /// Wait `step * retries` after each failure, where `retries` includes
+    /// the failure which just happened
+    Linear {
+        /// The delay before the first retry
+        step: Duration,
+    },
+
+    /// Wait the same amount of time after every failure
+    Fixed(Duration),
+
+    /// Decide with a function, which is given the number of times the job
+    /// has failed including the failure which just happened. Returning `None`
+    /// stops the job from being retried, as with [`Never`](Self::Never).
+    ///
+    /// Construct this with [`RetryPolicy::custom`].
+    Custom(Arc<dyn Fn(u32) -> Option<Duration> + Send + Sync>),
+
+    /// Don't retry the job. It is moved to `swirl_failed_jobs`, where it can
+    /// be inspected and retried with the functions in
+    /// [`admin`](crate::admin).
+    Never,
+}
+
+impl RetryPolicy {
+    /// A policy which calls `f` to decide how long to wait before retrying.
+    /// See [`RetryPolicy::Custom`].
+    pub fn custom<F>(f: F) -> Self
+    where
+        F: Fn(u32) -> Option<Duration> + Send + Sync + 'static,
+    {
+        RetryPolicy::Custom(Arc::new(f))
+    }
+
+    /// How long to wait before running a job which has failed `failures`
+    /// times, or `None` if it shouldn't be run again
+    fn delay(&self, failures: u32) -> Option<Duration> {
+        // Larger exponents would wait for longer than anyone will care about,
+        // and risk overflowing
+        let exponent = failures.min(20);
+        match self {
+            RetryPolicy::Exponential { base } => Some(*base * (1 << exponent)),
+            RetryPolicy::ExponentialWithJitter { base } => {
+                let delay = *base * (1 << exponent);
+                Some(delay.mul_f64(1.0 - random_fraction() / 2.0))
+            }
+            RetryPolicy::Linear { step } => Some(*step * failures),
+            RetryPolicy::Fixed(delay) => Some(*delay),
+            RetryPolicy::Custom(f) => f(failures),
+            RetryPolicy::Never => None,
+        }
+    }
+}
+
+impl fmt::Debug for RetryPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RetryPolicy::Exponential { base } => {
+                f.debug_struct("Exponential").field("base", base).finish()
+            }
+            RetryPolicy::ExponentialWithJitter { base } => f
+                .debug_struct("ExponentialWithJitter")
+                .field("base", base)
+                .finish(),
+            RetryPolicy::Linear { step } => f.debug_struct("Linear").field("step", step).finish(),
+            RetryPolicy::Fixed(delay) => f.debug_tuple("Fixed").field(delay).finish(),
+            RetryPolicy::Custom(_) => f.write_str("Custom(..)"),
+            RetryPolicy::Never => f.write_str("Never"),
+        }
+    }
+}
+
+/// A random number between 0 and 1.
+///
+/// This only needs to be different between calls, so rather than pulling in
+/// a random number generator we use the randomly keyed hasher from std.
+fn random_fraction() -> f64 {
+    let bits = RandomState::new().build_hasher().finish();
+    (bits >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// When a failed job is run next
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum NextRun {
+    /// Once the default exponential backoff has passed
+    DefaultBackoff,
+    /// Once the given amount of time has passed
+    After(Duration),
+    /// Never
+    Never,
+}
+
+/// How a runner retries failed jobs, as configured on its builder
+#[derive(Debug, Clone, Default)]
+pub(crate) struct RetrySettings {
+    pub(crate) default_policy: Option<RetryPolicy>,
+    pub(crate) policies: HashMap<FailureKind, RetryPolicy>,
+    pub(crate) max_retries: Option<u32>,
+}
+
+impl RetrySettings {
+    /// The number of times a job may be retried, preferring the limit given
+    /// for the job's type over the runner's
+    pub(crate) fn max_retries(&self, job: Option<&JobVTable>) -> Option<u32> {
+        job.and_then(|j| j.max_retries()).or(self.max_retries)
+    }
+
+    /// Decides when a job which has just failed with `error` is run next.
+    ///
+    /// `failures` includes the failure which just happened. Settings given for
+    /// the job's type are used in place of the runner's, except that a policy
+    /// for the kind of failure always takes precedence. Jobs which failed with
+    /// [`Failure::Fatal`] are never run again.
+    pub(crate) fn next_run(
+        &self,
+        job: Option<&JobVTable>,
+        error: &(dyn Error + 'static),
+        failures: u32,
+    ) -> NextRun {
+        if Failure::is_fatal(error) {
+            return NextRun::Never;
+        }
+        if self.max_retries(job).map_or(false, |max| failures > max) {
+            return NextRun::Never;
+        }
+
+        let policy = self
+            .policies
+            .get(&FailureKind::of(error))
+            .cloned()
+            .or_else(|| job.and_then(|j| j.retry_policy()))
+            .or_else(|| self.default_policy.clone());
+        match policy {
+            Some(policy) => policy
+                .delay(failures)
+                .map_or(NextRun::Never, NextRun::After),
+            None => NextRun::DefaultBackoff,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::errors::{EnqueueError, PerformError};
+
+    #[test]
+    fn failures_are_classified_by_their_cause() {
+        let deserialization = serde_json::from_str::<i32>("\"one\"").unwrap_err();
+        let timeout = std::io::Error::new(std::io::ErrorKind::TimedOut, "timed out");
+        let wrapped = EnqueueError::DatabaseError(diesel::result::Error::NotFound);
+
+        assert_eq!(
+            FailureKind::Deserialization,
+            FailureKind::of(&deserialization)
+        );
+        assert_eq!(FailureKind::Timeout, FailureKind::of(&timeout));
+        assert_eq!(FailureKind::Database, FailureKind::of(&wrapped));
+        assert_eq!(
+            FailureKind::Other,
+            FailureKind::of(&*PerformError::from("nope"))
+        );
+    }
+}
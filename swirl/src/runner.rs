@@ -3,27 +3,161 @@ use diesel::prelude::*;
 use diesel::r2d2;
 use std::any::Any;
 use std::error::Error;
-use std::panic::{catch_unwind, AssertUnwindSafe, PanicInfo, RefUnwindSafe, UnwindSafe};
-use std::sync::Arc;
-use std::time::Duration;
+use std::panic::{catch_unwind, AssertUnwindSafe, RefUnwindSafe, UnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 use threadpool::ThreadPool;
 
+use crate::completion::JobOutcome;
+use crate::context::{JobResources, JobTransaction};
 use crate::db::*;
 use crate::errors::*;
-use crate::{storage, Registry};
+use crate::fetch::{DefaultFetchQuery, FetchQuery, FetchRequest};
+use crate::middleware::{JobMiddleware, MiddlewareStack};
+use crate::registry::JobVTable;
+use crate::replay::{self, Recording};
+use crate::retry::{FailureKind, RetryPolicy, RetrySettings};
+use crate::scope::ThreadBudget;
+use crate::storage::{self, FetchOptions};
+use crate::worker::WorkerRegistration;
+use crate::{Job, JobContext, Registry};
+#[cfg(feature = "tokio")]
+pub use async_runner::AsyncRunner;
+use batch_size::BatchSizer;
+use debug_log::{DebugJobTypes, DebugLog};
 use event::*;
+#[cfg(feature = "tracing")]
+use job_span::JobSpan;
+use json_log::JsonLog;
+use lease::Leases;
+#[cfg(feature = "notify")]
+use listener::Listener;
+pub use lock_hold::LockHoldTimes;
+#[cfg(feature = "metrics")]
+use metrics::Metrics;
+#[cfg(feature = "metrics")]
+pub use metrics::{DurationHistogram, JobTypeMetrics, MetricsSnapshot};
+use panic_format::PanicFormat;
+pub use plugin::RunnerPlugin;
+pub use profile::Profile;
+pub(crate) use progress::ProgressWriter;
+use run_summary::RunCounts;
+pub use run_summary::RunSummary;
+pub use shutdown::{InFlightJob, ShutdownHandle, ShutdownReport};
+use shutdown::{RunningJobs, ShutdownTimeouts};
+pub use throttle::FailureThrottle;
+use watchdog::{Watchdog, WatchedJob};
 
+#[cfg(feature = "tokio")]
+mod async_runner;
+mod batch_size;
 mod channel;
+mod debug_log;
 mod event;
+#[cfg(feature = "tracing")]
+mod job_span;
+mod json_log;
+mod lease;
+#[cfg(feature = "notify")]
+mod listener;
+mod lock_hold;
+#[cfg(feature = "metrics")]
+pub(crate) mod metrics;
+mod panic_format;
+mod plugin;
+mod profile;
+mod progress;
+mod run_summary;
+mod shutdown;
+mod throttle;
+mod watchdog;
+
+/// How often a runner which is waiting for jobs checks whether it has been
+/// shut down
+const SHUTDOWN_CHECK_INTERVAL: Duration = Duration::from_millis(100);
 
 pub struct NoConnectionPoolGiven;
 
+/// Information about a job which is about to be run, passed to the predicate
+/// given to [`Builder::job_filter`]
+#[derive(Debug, Clone, Copy)]
+pub struct JobMeta<'a> {
+    /// The id of the job
+    pub id: i64,
+    /// The type of the job, as given by [`Job::JOB_TYPE`](crate::Job::JOB_TYPE)
+    pub job_type: &'a str,
+    /// The job's serialized arguments
+    pub data: &'a serde_json::Value,
+    /// The queue the job was placed in
+    pub queue: &'a str,
+    /// The metadata the job was enqueued with. This is always a JSON object.
+    pub metadata: &'a serde_json::Value,
+}
+
+type JobFilter = dyn Fn(&JobMeta<'_>) -> bool + Send + Sync;
+
+/// A job which failed, passed to the function given to
+/// [`Builder::on_job_error`]
+#[derive(Debug, Clone, Copy)]
+pub struct JobFailure<'a> {
+    /// The id of the job
+    pub id: i64,
+    /// The type of the job, as given by [`Job::JOB_TYPE`](crate::Job::JOB_TYPE)
+    pub job_type: &'a str,
+    /// The queue the job was placed in
+    pub queue: &'a str,
+    /// The [`OWNER`](crate::Job::OWNER) of the job, if it has one
+    pub owner: Option<&'static str>,
+    /// The number of times the job has failed, including this failure
+    pub failures: i32,
+    /// The error the job returned, or the formatted panic message if it
+    /// panicked
+    pub error: &'a dyn Error,
+    /// The number of failures like this one which weren't passed to the
+    /// handler since it was last called with one, because of
+    /// [`Builder::throttle_job_errors`]. This is always `0` if job errors
+    /// aren't throttled.
+    pub suppressed: u64,
+}
+
+type JobErrorHandler = dyn Fn(&JobFailure<'_>) + Send + Sync;
+
 #[allow(missing_debug_implementations)]
 pub struct Builder<Env, ConnectionPoolBuilder> {
     connection_pool_or_builder: ConnectionPoolBuilder,
     environment: Env,
     thread_count: Option<usize>,
+    max_batch_size: usize,
     job_start_timeout: Option<Duration>,
+    job_filter: Option<Arc<JobFilter>>,
+    fetcher: Option<Arc<dyn FetchQuery>>,
+    job_yield_threshold: Option<Duration>,
+    catch_panics: bool,
+    panic_format: PanicFormat,
+    default_job_timeout: Option<Duration>,
+    lease_duration: Option<Duration>,
+    json_logs: bool,
+    on_job_error: Option<Arc<JobErrorHandler>>,
+    job_error_throttle: Option<Arc<FailureThrottle>>,
+    record_runs: bool,
+    failure_samples: Option<u32>,
+    record_failures_to: Option<Arc<PathBuf>>,
+    asynchronous_completions: bool,
+    notify_on_completion: bool,
+    measure_lock_hold_times: bool,
+    retry_settings: RetrySettings,
+    max_fetch_error_backoff: Option<Duration>,
+    poll_interval: Option<Duration>,
+    shutdown_timeouts: ShutdownTimeouts,
+    #[cfg(feature = "notify")]
+    listen_url: Option<String>,
+    fetch_options: FetchOptions,
+    middleware: MiddlewareStack,
+    plugins: Vec<Arc<dyn RunnerPlugin>>,
+    require_nonempty_registry: bool,
+    registry: Registry<Env>,
 }
 
 impl<Env, ConnectionPoolBuilder> Builder<Env, ConnectionPoolBuilder> {
@@ -39,6 +173,62 @@ impl<Env, ConnectionPoolBuilder> Builder<Env, ConnectionPoolBuilder> {
         self.thread_count.unwrap_or(5)
     }
 
+    /// Make [`build`](Self::build) panic, and `try_build` return
+    /// [`BuildError::EmptyRegistry`], if no jobs are registered with the
+    /// runner.
+    ///
+    /// Otherwise a runner with no jobs starts as normal, and fails every job
+    /// it runs as an unknown job type. This usually means the crates defining
+    /// jobs weren't linked (see [`register_jobs!`](crate::register_jobs)), or
+    /// that the jobs use a different environment type than the runner.
+    pub fn require_nonempty_registry(mut self) -> Self {
+        self.require_nonempty_registry = true;
+        self
+    }
+
+    fn check_registry(&self) -> Result<(), BuildError> {
+        if self.require_nonempty_registry && self.registry.is_empty() {
+            Err(BuildError::EmptyRegistry)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Let each thread claim up to `max_batch_size` jobs before committing,
+    /// rather than one.
+    ///
+    /// The number of jobs claimed is tuned from how long recent jobs took, so
+    /// that a batch takes around 100ms. Jobs which take less than a
+    /// millisecond are claimed in large batches, which saves a commit per
+    /// job, while jobs which take longer than 100ms are still claimed one at a
+    /// time. Every job in a batch stays locked until the batch has finished,
+    /// and if the runner crashes part way through, the jobs which already ran
+    /// are run again. Once the runner is shut down, each batch is committed
+    /// as soon as its current job finishes, and no more jobs are claimed.
+    ///
+    /// Defaults to 1
+    pub fn max_batch_size(mut self, max_batch_size: usize) -> Self {
+        self.max_batch_size = max_batch_size;
+        self
+    }
+
+    /// Apply the defaults of `profile`, which is tuned for a kind of workload.
+    /// See [`Profile`] for the settings each profile uses.
+    ///
+    /// This overwrites the settings the profile covers, including the
+    /// [`poll_interval`](Self::poll_interval), so call it before any other
+    /// methods you want to take precedence.
+    pub fn profile(mut self, profile: Profile) -> Self {
+        let settings = profile.settings();
+        self.thread_count = Some(settings.thread_count);
+        self.job_start_timeout = Some(settings.job_start_timeout);
+        self.max_batch_size = settings.max_batch_size;
+        self.fetch_options.early_execution_slack = settings.early_execution_slack;
+        self.poll_interval = Some(settings.poll_interval);
+        self.max_fetch_error_backoff = Some(settings.max_fetch_error_backoff);
+        self
+    }
+
     /// The amount of time to wait for a job to start before assuming an error
     /// has occurred.
     ///
@@ -48,13 +238,582 @@ impl<Env, ConnectionPoolBuilder> Builder<Env, ConnectionPoolBuilder> {
         self
     }
 
+    /// Fail jobs which run for longer than `timeout`, unless their job type
+    /// has a [`TIMEOUT`](crate::Job::TIMEOUT) of its own. See `Job::TIMEOUT`
+    /// for how jobs which time out are stopped.
+    ///
+    /// By default, jobs may run for as long as they like.
+    pub fn default_job_timeout(mut self, timeout: Duration) -> Self {
+        self.default_job_timeout = Some(timeout);
+        self
+    }
+
+    /// Hold a lease on each job while it runs, which is renewed every third
+    /// of `duration`. If the runner stops renewing its leases, such as when
+    /// its host becomes unreachable, other runners with leases enabled
+    /// terminate the connection holding the job's lock once the lease has
+    /// expired, so the job can be run again.
+    ///
+    /// Without a lease, a job whose runner has stopped responding stays
+    /// locked until Postgres notices its connection has dropped, which can
+    /// take hours. Like a job which is terminated by
+    /// [`maintenance::ReapStuckJobs`](crate::maintenance::ReapStuckJobs), the
+    /// job's transaction is rolled back, so it may have been partially run.
+    ///
+    /// Leases are only taken once the runner has been registered in
+    /// `swirl_workers`, which happens the first time it looks for jobs. By
+    /// default, jobs are run without a lease.
+    pub fn lease_duration(mut self, duration: Duration) -> Self {
+        self.lease_duration = Some(duration);
+        self
+    }
+
+    /// Only run jobs for which `filter` returns `true`.
+    ///
+    /// Jobs which are rejected are left in the queue for other runners to pick
+    /// up. This is useful for running a worker which only handles a subset of
+    /// jobs, such as a debugging worker which only runs jobs with a specific
+    /// argument.
+    pub fn job_filter<F>(mut self, filter: F) -> Self
+    where
+        F: Fn(&JobMeta<'_>) -> bool + Send + Sync + 'static,
+    {
+        self.job_filter = Some(Arc::new(filter));
+        self
+    }
+
+    /// Run `middleware` around every job. See [`JobMiddleware`] for when each
+    /// of its hooks is called.
+    ///
+    /// This can be called more than once to add several middleware. Each one
+    /// wraps the jobs and the middleware which were added after it.
+    pub fn middleware<M: JobMiddleware>(mut self, middleware: M) -> Self {
+        self.middleware.push(Box::new(middleware));
+        self
+    }
+
+    /// Add `plugin` to the runner. See [`RunnerPlugin`] for what plugins can
+    /// do.
+    ///
+    /// The plugin configures the builder right away, so builder methods
+    /// called after this override the settings it made. This can be called
+    /// more than once to add several plugins.
+    pub fn plugin<P: RunnerPlugin>(self, plugin: P) -> Self {
+        let mut builder = plugin.configure(self);
+        builder.plugins.push(Arc::new(plugin));
+        builder
+    }
+
+    /// Use `fetcher` to find and lock the next job to run, instead of
+    /// [`DefaultFetchQuery`].
+    ///
+    /// This is meant for policies swirl doesn't support, such as adding
+    /// planner hints to the query. See [`FetchQuery`] for what it must do.
+    /// [`FairFetchQuery`](crate::FairFetchQuery) can be used to take turns
+    /// between job types or queues.
+    pub fn fetcher<F: FetchQuery>(mut self, fetcher: F) -> Self {
+        self.fetcher = Some(Arc::new(fetcher));
+        self
+    }
+
+    /// Allows jobs to be run up to `slack` before they are due.
+    ///
+    /// Whether a job is due is always decided using the database's clock, so
+    /// skew between runners' clocks never causes jobs to run early. Setting a
+    /// small amount of slack lets a runner pick up a job which is about to
+    /// become due, rather than finding no work and waiting to poll again.
+    ///
+    /// By default, jobs are never run early.
+    pub fn early_execution_slack(mut self, slack: Duration) -> Self {
+        self.fetch_options.early_execution_slack = slack;
+        self
+    }
+
+    /// The locality of this runner, such as its region or the shard it is
+    /// closest to. Jobs enqueued with the same
+    /// [`locality`](crate::EnqueueOptions::locality) are run before other
+    /// jobs of the same priority.
+    ///
+    /// This is only a preference. A job in another locality which has been
+    /// due for longer than the [`locality_window`](Self::locality_window) is
+    /// run in order like any other job, so it is never starved by a busy
+    /// locality. By default, runners have no locality and run jobs in order.
+    pub fn locality<S: Into<String>>(mut self, locality: S) -> Self {
+        self.fetch_options.locality = Some(locality.into());
+        self
+    }
+
+    /// How long jobs in other localities can be passed over for jobs in this
+    /// runner's [`locality`](Self::locality).
+    ///
+    /// Defaults to 30 seconds
+    pub fn locality_window(mut self, window: Duration) -> Self {
+        self.fetch_options.locality_window = window;
+        self
+    }
+
+    /// How long a job must have been running before
+    /// [`JobContext::should_yield`] will tell it to make room for higher
+    /// priority jobs.
+    ///
+    /// By default, jobs are never asked to yield.
+    pub fn job_yield_threshold(mut self, threshold: Duration) -> Self {
+        self.job_yield_threshold = Some(threshold);
+        self
+    }
+
+    /// Only run jobs which were placed in one of the given queues.
+    ///
+    /// By default, jobs from all queues are run.
+    pub fn queues<I, S>(mut self, queues: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.fetch_options.queues = Some(queues.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// Only run jobs of the given types, as given by [`Job::JOB_TYPE`].
+    ///
+    /// Jobs of other types are left in the queue for other runners, without
+    /// being locked. This allows a worker to run some of the jobs in the
+    /// registry, such as jobs which need resources only some machines have,
+    /// without moving them to their own queue.
+    ///
+    /// By default, jobs of all types are run.
+    pub fn only_job_types<I, S>(mut self, job_types: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.fetch_options.job_types = Some(job_types.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// Never run jobs of the given types, as given by [`Job::JOB_TYPE`].
+    ///
+    /// Like [`only_job_types`](Self::only_job_types), the jobs are left in
+    /// the queue for other runners.
+    pub fn except_job_types<I, S>(mut self, job_types: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.fetch_options
+            .except_job_types
+            .extend(job_types.into_iter().map(Into::into));
+        self
+    }
+
+    /// The version of the application this runner is part of, as numbers
+    /// separated by dots such as `"1.4.2"`.
+    ///
+    /// This is recorded in `swirl_workers`, so operators can see which versions
+    /// are processing the queue. Jobs which were enqueued with a newer
+    /// [`min_worker_version`] are skipped.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `version` is not made up of numbers separated by dots.
+    ///
+    /// [`min_worker_version`]: crate::EnqueueOptions::min_worker_version
+    pub fn worker_version<S: Into<String>>(mut self, version: S) -> Self {
+        let version = version.into();
+        assert!(
+            storage::parse_version(&version).is_some(),
+            "Invalid worker version {}",
+            version,
+        );
+        self.fetch_options.worker_version = Some(version);
+        self
+    }
+
+    /// Limits how many jobs of the given type can run at once, across every
+    /// runner connected to the database.
+    ///
+    /// Unlike [`thread_count`](Self::thread_count), which only applies to a
+    /// single process, this limit holds no matter how many runners are
+    /// started. Every runner should be configured with the same limit. If they
+    /// disagree, runners with a lower limit will stop picking up jobs once it
+    /// is reached, but runners with a higher limit will continue to.
+    pub fn job_type_concurrency_limit<S: Into<String>>(mut self, job_type: S, limit: u32) -> Self {
+        self.fetch_options
+            .job_type_limits
+            .insert(job_type.into(), limit);
+        self
+    }
+
+    /// Limits how many jobs from the given queue can run at once, across every
+    /// runner connected to the database.
+    ///
+    /// See [`job_type_concurrency_limit`](Self::job_type_concurrency_limit)
+    /// for details.
+    pub fn queue_concurrency_limit<S: Into<String>>(mut self, queue: S, limit: u32) -> Self {
+        self.fetch_options.queue_limits.insert(queue.into(), limit);
+        self
+    }
+
+    /// Whether panics in jobs should be caught and treated as failures.
+    ///
+    /// When this is set to `false`, a panicking job will abort the entire
+    /// process, so that it can be restarted by a supervisor. The job will not
+    /// be marked as failed. Its lock is released when the process exits and
+    /// its database connection is closed, so it will be retried right away by
+    /// the next runner to pick it up, without any backoff. This means that a
+    /// job which always panics will crash every runner which tries to run it.
+    ///
+    /// Defaults to `true`
+    pub fn catch_panics(mut self, catch_panics: bool) -> Self {
+        self.catch_panics = catch_panics;
+        self
+    }
+
+    /// Cut the messages of jobs which panic off after this many bytes. This
+    /// keeps panics which include large values, such as a failed
+    /// `assert_eq!` on a request body, from filling the logs.
+    ///
+    /// By default, messages are not shortened
+    pub fn max_panic_message_length(mut self, max_length: usize) -> Self {
+        self.panic_format.max_length = Some(max_length);
+        self
+    }
+
+    /// Whether the messages of jobs which panic should include the name of
+    /// the thread which ran them.
+    ///
+    /// Defaults to `false`
+    pub fn panic_messages_include_thread_name(mut self, include: bool) -> Self {
+        self.panic_format.include_thread_name = include;
+        self
+    }
+
+    /// Use `f` to describe panics whose payload is a `T`, such as when jobs
+    /// call `std::panic::panic_any` with an error type of your own.
+    ///
+    /// Panics with a `&str` or `String` payload, which includes anything
+    /// passed to `panic!`, are described by their message. Other payloads are
+    /// reported only as "job panicked" unless their type is given here.
+    pub fn panic_payload<T, F>(mut self, f: F) -> Self
+    where
+        T: Any,
+        F: Fn(&T) -> String + Send + Sync + 'static,
+    {
+        self.panic_format.add_payload_formatter(f);
+        self
+    }
+
+    /// Whether to print the lifecycle of each job to stdout as JSON, with one
+    /// object per line.
+    ///
+    /// This is intended for deployments which collect logs from stdout, and
+    /// want them in a machine readable format without setting up a logging
+    /// framework. A line is printed when a job starts, and when it succeeds,
+    /// fails or yields. Every line has the fields `timestamp`, `event`,
+    /// `job_id`, `job_type` and `queue`. Lines for finished jobs also have
+    /// `duration_ms`, and lines for failed jobs have `error`. These field names
+    /// will not change.
+    ///
+    /// When this is enabled, failures are no longer printed to stderr. With
+    /// the `tracing` feature enabled, jobs are also reported to `tracing`
+    /// whether or not this is enabled.
+    ///
+    /// Defaults to `false`
+    pub fn json_logs(mut self, json_logs: bool) -> Self {
+        self.json_logs = json_logs;
+        self
+    }
+
+    /// Call `f` whenever a job fails, instead of printing the failure to
+    /// stderr.
+    ///
+    /// This is meant for sending failures to a structured logging system or
+    /// an error tracker. `f` is called on the thread which ran the job, before
+    /// the failure is recorded in the database, so it should return quickly.
+    /// It is called for every failure, including ones which will be retried.
+    pub fn on_job_error<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&JobFailure<'_>) + Send + Sync + 'static,
+    {
+        self.on_job_error = Some(Arc::new(f));
+        self
+    }
+
+    /// Call the function given to [`on_job_error`](Self::on_job_error) at
+    /// most once per `window` for each kind of failure, so that an incident
+    /// which fails many jobs in the same way sends one alert rather than
+    /// thousands.
+    ///
+    /// Failures are grouped as described in [`FailureThrottle`]. The next
+    /// call for a kind of failure after its window has passed includes the
+    /// number of failures which were left out in
+    /// [`JobFailure::suppressed`]. Failures are still logged, recorded and
+    /// retried as usual.
+    ///
+    /// By default, the function is called for every failure.
+    pub fn throttle_job_errors(mut self, window: Duration) -> Self {
+        self.job_error_throttle = Some(Arc::new(FailureThrottle::new(window)));
+        self
+    }
+
+    /// Record a summary of each call to [`Runner::run_until_empty`] in
+    /// `swirl_runs`, including calls which fail with an error.
+    ///
+    /// This is meant for runners started periodically by cron or a similar
+    /// scheduler. Since every run leaves a row behind, a scheduler which
+    /// stopped running the runner can be noticed by the age of the latest
+    /// row. See [`admin::list_runs`](crate::admin::list_runs).
+    ///
+    /// Defaults to `false`
+    pub fn record_runs(mut self, record_runs: bool) -> Self {
+        self.record_runs = record_runs;
+        self
+    }
+
+    /// Record the arguments and error of failed jobs in
+    /// `swirl_failure_samples`, keeping the `per_fingerprint` most recent
+    /// samples of each kind of failure.
+    ///
+    /// Failures are recorded even if the job later succeeds when retried, so
+    /// this gives visibility into flaky jobs which would otherwise go
+    /// unnoticed. Samples can be viewed with
+    /// [`admin::list_failure_samples`](crate::admin::list_failure_samples).
+    ///
+    /// By default, no samples are recorded.
+    pub fn failure_samples(mut self, per_fingerprint: u32) -> Self {
+        self.failure_samples = Some(per_fingerprint);
+        self
+    }
+
+    /// Decide when jobs which fail with errors of the given kind are retried.
+    ///
+    /// This allows failures to be handled according to their cause. For
+    /// example, jobs whose arguments can't be deserialized can be left alone
+    /// until someone fixes them, while timeouts are retried quickly and
+    /// database errors are given time for the database to recover:
+    ///
+    /// ```ignore
+    /// Runner::builder(env)
+    ///     .retry_policy(FailureKind::Deserialization, RetryPolicy::Never)
+    ///     .retry_policy(FailureKind::Timeout, RetryPolicy::Fixed(Duration::from_secs(5)))
+    ///     .retry_policy(
+    ///         FailureKind::Database,
+    ///         RetryPolicy::Exponential { base: Duration::from_secs(10 * 60) },
+    ///     )
+    /// ```
+    ///
+    /// See [`FailureKind::of`] for how errors are classified. Failures of
+    /// kinds without a policy use the job's [`Job::retry_policy`], or
+    /// [`default_retry_policy`](Self::default_retry_policy). Jobs which panic
+    /// are treated as having failed with [`FailureKind::Other`].
+    pub fn retry_policy(mut self, kind: FailureKind, policy: RetryPolicy) -> Self {
+        self.retry_settings.policies.insert(kind, policy);
+        self
+    }
+
+    /// Decide when failed jobs are retried, unless their type or the kind of
+    /// failure has a policy of its own.
+    ///
+    /// Defaults to exponential backoff, waiting two minutes after the first
+    /// failure and doubling from there
+    pub fn default_retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.retry_settings.default_policy = Some(policy);
+        self
+    }
+
+    /// Give up on jobs once they have been retried this many times. Jobs
+    /// which have been given up on are moved to `swirl_failed_jobs`, where
+    /// they can be inspected and retried with
+    /// [`admin::list_failed_jobs`](crate::admin::list_failed_jobs) and
+    /// [`admin::retry_failed_job`](crate::admin::retry_failed_job). Jobs with
+    /// their own [`Job::MAX_RETRIES`] use that instead.
+    ///
+    /// By default, jobs are retried forever.
+    pub fn max_retries(mut self, max_retries: u32) -> Self {
+        self.retry_settings.max_retries = Some(max_retries);
+        self
+    }
+
+    /// Write a [`Recording`] of every failed job run to a file in `dir`, so the
+    /// failure can be reproduced elsewhere with [`Runner::replay`].
+    ///
+    /// This is a debugging aid. The job's arguments are written to disk as is,
+    /// so be careful enabling it for jobs which handle sensitive data.
+    ///
+    /// By default, nothing is recorded.
+    ///
+    /// [`Recording`]: crate::replay::Recording
+    pub fn record_failures_to<P: Into<PathBuf>>(mut self, dir: P) -> Self {
+        self.record_failures_to = Some(Arc::new(dir.into()));
+        self
+    }
+
+    /// The longest [`Runner::run_forever`] waits before trying to fetch jobs
+    /// again, after consecutive errors fetching them.
+    ///
+    /// Defaults to 60 seconds.
+    pub fn max_fetch_error_backoff(mut self, backoff: Duration) -> Self {
+        self.max_fetch_error_backoff = Some(backoff);
+        self
+    }
+
+    /// How long [`Runner::run_forever`] sleeps whenever the queue is empty,
+    /// in place of the interval it is given. This lets the interval be set
+    /// along with the rest of the runner's configuration, such as by a
+    /// [`Profile`].
+    ///
+    /// By default, the interval given to `run_forever` is used.
+    pub fn poll_interval(mut self, interval: Duration) -> Self {
+        self.poll_interval = Some(interval);
+        self
+    }
+
+    /// How long [`Runner::run_forever`] waits for running jobs to finish once
+    /// the runner has been shut down, before returning anyway.
+    ///
+    /// Jobs which are still running are abandoned rather than failed. They
+    /// are listed in the returned [`ShutdownReport`], and stay locked until
+    /// the process exits, at which point their transactions are rolled back
+    /// and another runner retries them. Exit soon after `run_forever`
+    /// returns, so they aren't left locked for long.
+    ///
+    /// By default, `run_forever` waits for running jobs indefinitely.
+    pub fn soft_shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.shutdown_timeouts.soft = Some(timeout);
+        self
+    }
+
+    /// Abort the process if any jobs are still running this long after the
+    /// runner has been shut down, such as when a job is stuck and the process
+    /// would otherwise never exit. The ids of the jobs are printed to stderr
+    /// first. Like jobs abandoned after the
+    /// [`soft_shutdown_timeout`](Self::soft_shutdown_timeout), they are
+    /// retried by another runner.
+    ///
+    /// By default, the process is never aborted.
+    pub fn hard_shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.shutdown_timeouts.hard = Some(timeout);
+        self
+    }
+
+    /// Have [`Runner::run_forever`] wake up as soon as a job is enqueued,
+    /// rather than waiting for the next poll.
+    ///
+    /// The runner opens a separate connection to `database_url` which listens
+    /// for the notification sent whenever a row is inserted into
+    /// `background_jobs`. TLS is not supported for this connection. The poll
+    /// interval is still used as a fallback, and to pick up jobs which were
+    /// scheduled for later or are waiting to be retried, so it can be much
+    /// longer than it would otherwise be.
+    ///
+    /// Requires the `notify` feature.
+    #[cfg(feature = "notify")]
+    pub fn listen_for_jobs<S: Into<String>>(mut self, database_url: S) -> Self {
+        self.listen_url = Some(database_url.into());
+        self
+    }
+
+    /// Whether to commit the result of a job without waiting for it to be
+    /// flushed to disk.
+    ///
+    /// Normally each job's completion is its own durable commit, which limits
+    /// throughput when jobs take only a few milliseconds. When this is enabled,
+    /// Postgres instead flushes completions in groups, every
+    /// `wal_writer_delay` (200ms by default) or once `wal_writer_flush_after`
+    /// bytes have been written, whichever comes first. Tune those settings on
+    /// the server to control how large the groups are.
+    ///
+    /// This only affects what happens if the database server crashes. Jobs
+    /// which completed shortly before the crash may be rolled back, and will be
+    /// run again. Jobs must already tolerate being run more than once, so this
+    /// is safe for most jobs, but it should not be enabled if running a job
+    /// twice would be harmful. Data written by jobs on other connections is
+    /// not affected.
+    ///
+    /// Defaults to `false`
+    pub fn asynchronous_completions(mut self, asynchronous_completions: bool) -> Self {
+        self.asynchronous_completions = asynchronous_completions;
+        self
+    }
+
+    /// Send a notification when each job finishes running, so that processes
+    /// waiting on it with [`JobHandle::wait_for_completion`] find out right
+    /// away.
+    ///
+    /// The notification is sent with `pg_notify` on the channel
+    /// `swirl_job_<id>`, and is delivered when the job's transaction commits.
+    /// Its payload is `succeeded` or `failed`. Jobs which fail are notified
+    /// every time they fail, including when they will be retried.
+    ///
+    /// Defaults to `false`
+    ///
+    /// [`JobHandle::wait_for_completion`]: crate::JobHandle::wait_for_completion
+    pub fn notify_on_completion(mut self, notify_on_completion: bool) -> Self {
+        self.notify_on_completion = notify_on_completion;
+        self
+    }
+
+    /// Record how long each job holds its lock, so that it can be inspected
+    /// with [`Runner::lock_hold_times`].
+    ///
+    /// Every measurement is kept in memory, so this is intended for benchmarks
+    /// rather than long running processes.
+    ///
+    /// Defaults to `false`
+    pub fn measure_lock_hold_times(mut self, measure: bool) -> Self {
+        self.measure_lock_hold_times = measure;
+        self
+    }
+
+    /// Run jobs of type `J` with `env` as their environment, rather than the
+    /// environment given to [`Runner::builder`].
+    ///
+    /// `J` does not need to use the same environment type as the runner.
+    pub fn register_with<J>(mut self, env: J::Environment) -> Self
+    where
+        J: Job,
+        J::Environment: Send + Sync + RefUnwindSafe,
+        Env: 'static,
+    {
+        self.registry.register_with::<J>(env);
+        self
+    }
+
     /// Provide a connection pool to be used by the runner
     pub fn connection_pool<NewPool>(self, pool: NewPool) -> Builder<Env, NewPool> {
         Builder {
             connection_pool_or_builder: pool,
             environment: self.environment,
             thread_count: self.thread_count,
+            max_batch_size: self.max_batch_size,
             job_start_timeout: self.job_start_timeout,
+            job_filter: self.job_filter,
+            fetcher: self.fetcher,
+            job_yield_threshold: self.job_yield_threshold,
+            catch_panics: self.catch_panics,
+            panic_format: self.panic_format,
+            default_job_timeout: self.default_job_timeout,
+            lease_duration: self.lease_duration,
+            json_logs: self.json_logs,
+            on_job_error: self.on_job_error,
+            job_error_throttle: self.job_error_throttle,
+            record_runs: self.record_runs,
+            failure_samples: self.failure_samples,
+            record_failures_to: self.record_failures_to,
+            asynchronous_completions: self.asynchronous_completions,
+            notify_on_completion: self.notify_on_completion,
+            measure_lock_hold_times: self.measure_lock_hold_times,
+            retry_settings: self.retry_settings,
+            max_fetch_error_backoff: self.max_fetch_error_backoff,
+            poll_interval: self.poll_interval,
+            shutdown_timeouts: self.shutdown_timeouts,
+            #[cfg(feature = "notify")]
+            listen_url: self.listen_url,
+            fetch_options: self.fetch_options,
+            middleware: self.middleware,
+            plugins: self.plugins,
+            require_nonempty_registry: self.require_nonempty_registry,
+            registry: self.registry,
         }
     }
 }
@@ -95,18 +854,84 @@ impl<Env> Builder<Env, R2d2Builder> {
     }
 
     /// Build the runner with an r2d2 connection pool.
+    ///
+    /// # Panics
+    ///
+    /// If [`try_build`](Self::try_build) would return an error
     pub fn build(self) -> Runner<Env, r2d2::Pool<r2d2::ConnectionManager<PgConnection>>> {
+        self.try_build().unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Build the runner with an r2d2 connection pool, or return an error if
+    /// the runner is misconfigured. See [`BuildError`] for what is checked.
+    pub fn try_build(
+        self,
+    ) -> Result<Runner<Env, r2d2::Pool<r2d2::ConnectionManager<PgConnection>>>, BuildError> {
+        self.check_registry()?;
         let thread_count = self.get_thread_count();
         let connection_pool_size = thread_count as u32 * 2;
         let connection_pool = self.connection_pool_or_builder.build(connection_pool_size);
 
-        Runner {
+        Ok(Runner {
             connection_pool,
-            thread_pool: ThreadPool::new(thread_count),
+            thread_count,
+            thread_pool: Mutex::new(None),
+            thread_budget: Arc::new(ThreadBudget::new(thread_count)),
+            run_counts: Arc::default(),
+            batch_sizer: Arc::new(BatchSizer::new(self.max_batch_size)),
+            watchdog: Arc::default(),
+            leases: self.lease_duration.map(|d| Arc::new(Leases::new(d))),
+            progress: Arc::default(),
+            worker: Arc::new(WorkerRegistration::new(thread_count)),
             environment: Arc::new(self.environment),
-            registry: Arc::new(Registry::load()),
             job_start_timeout: self.job_start_timeout.unwrap_or(Duration::from_secs(10)),
-        }
+            job_filter: self.job_filter,
+            fetcher: self.fetcher,
+            job_yield_threshold: self.job_yield_threshold,
+            catch_panics: self.catch_panics,
+            panic_format: Arc::new(self.panic_format),
+            default_job_timeout: self.default_job_timeout,
+            json_logs: self.json_logs,
+            on_job_error: self.on_job_error,
+            job_error_throttle: self.job_error_throttle,
+            record_runs: self.record_runs,
+            failure_samples: self.failure_samples,
+            record_failures_to: self.record_failures_to,
+            asynchronous_completions: self.asynchronous_completions,
+            notify_on_completion: self.notify_on_completion,
+            retry_settings: Arc::new(self.retry_settings),
+            debug_job_types: Arc::default(),
+            max_fetch_error_backoff: self
+                .max_fetch_error_backoff
+                .unwrap_or(Duration::from_secs(60)),
+            poll_interval: self.poll_interval,
+            shutdown: ShutdownHandle::default(),
+            shutdown_timeouts: self.shutdown_timeouts,
+            running_jobs: RunningJobs::default(),
+            #[cfg(feature = "notify")]
+            listener: self.listen_url.map(Listener::new),
+            lock_hold_times: if self.measure_lock_hold_times {
+                Some(Arc::default())
+            } else {
+                None
+            },
+            #[cfg(feature = "metrics")]
+            metrics: Arc::default(),
+            fetch_options: Arc::new(self.fetch_options),
+            middleware: Arc::new(self.middleware),
+            plugins: self.plugins,
+            registry: Arc::new(self.registry),
+        })
+    }
+
+    /// Build an [`AsyncRunner`] with an r2d2 connection pool.
+    ///
+    /// Requires the `tokio` feature.
+    #[cfg(feature = "tokio")]
+    pub fn build_async(
+        self,
+    ) -> AsyncRunner<Env, r2d2::Pool<r2d2::ConnectionManager<PgConnection>>> {
+        AsyncRunner::new(self.build())
     }
 }
 
@@ -115,14 +940,77 @@ where
     ConnectionPool: DieselPool,
 {
     /// Build the runner
+    ///
+    /// # Panics
+    ///
+    /// If [`try_build`](Self::try_build) would return an error
     pub fn build(self) -> Runner<Env, ConnectionPool> {
-        Runner {
-            thread_pool: ThreadPool::new(self.get_thread_count()),
+        self.try_build().unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Build the runner, or return an error if it is misconfigured. See
+    /// [`BuildError`] for what is checked.
+    pub fn try_build(self) -> Result<Runner<Env, ConnectionPool>, BuildError> {
+        self.check_registry()?;
+        let thread_count = self.get_thread_count();
+        Ok(Runner {
+            thread_count,
+            thread_pool: Mutex::new(None),
+            thread_budget: Arc::new(ThreadBudget::new(thread_count)),
+            run_counts: Arc::default(),
+            batch_sizer: Arc::new(BatchSizer::new(self.max_batch_size)),
+            watchdog: Arc::default(),
+            leases: self.lease_duration.map(|d| Arc::new(Leases::new(d))),
+            progress: Arc::default(),
+            worker: Arc::new(WorkerRegistration::new(thread_count)),
             connection_pool: self.connection_pool_or_builder,
             environment: Arc::new(self.environment),
-            registry: Arc::new(Registry::load()),
             job_start_timeout: self.job_start_timeout.unwrap_or(Duration::from_secs(10)),
-        }
+            job_filter: self.job_filter,
+            fetcher: self.fetcher,
+            job_yield_threshold: self.job_yield_threshold,
+            catch_panics: self.catch_panics,
+            panic_format: Arc::new(self.panic_format),
+            default_job_timeout: self.default_job_timeout,
+            json_logs: self.json_logs,
+            on_job_error: self.on_job_error,
+            job_error_throttle: self.job_error_throttle,
+            record_runs: self.record_runs,
+            failure_samples: self.failure_samples,
+            record_failures_to: self.record_failures_to,
+            asynchronous_completions: self.asynchronous_completions,
+            notify_on_completion: self.notify_on_completion,
+            retry_settings: Arc::new(self.retry_settings),
+            debug_job_types: Arc::default(),
+            max_fetch_error_backoff: self
+                .max_fetch_error_backoff
+                .unwrap_or(Duration::from_secs(60)),
+            poll_interval: self.poll_interval,
+            shutdown: ShutdownHandle::default(),
+            shutdown_timeouts: self.shutdown_timeouts,
+            running_jobs: RunningJobs::default(),
+            #[cfg(feature = "notify")]
+            listener: self.listen_url.map(Listener::new),
+            lock_hold_times: if self.measure_lock_hold_times {
+                Some(Arc::default())
+            } else {
+                None
+            },
+            #[cfg(feature = "metrics")]
+            metrics: Arc::default(),
+            fetch_options: Arc::new(self.fetch_options),
+            middleware: Arc::new(self.middleware),
+            plugins: self.plugins,
+            registry: Arc::new(self.registry),
+        })
+    }
+
+    /// Build an [`AsyncRunner`], which runs jobs as Tokio tasks
+    ///
+    /// Requires the `tokio` feature.
+    #[cfg(feature = "tokio")]
+    pub fn build_async(self) -> AsyncRunner<Env, ConnectionPool> {
+        AsyncRunner::new(self.build())
     }
 }
 
@@ -130,10 +1018,47 @@ where
 /// The core runner responsible for locking and running jobs
 pub struct Runner<Env: 'static, ConnectionPool> {
     connection_pool: ConnectionPool,
-    thread_pool: ThreadPool,
+    thread_count: usize,
+    thread_pool: Mutex<Option<ThreadPool>>,
+    thread_budget: Arc<ThreadBudget>,
+    run_counts: Arc<RunCounts>,
+    batch_sizer: Arc<BatchSizer>,
+    watchdog: Arc<Watchdog>,
+    leases: Option<Arc<Leases>>,
+    progress: Arc<ProgressWriter>,
+    worker: Arc<WorkerRegistration>,
     environment: Arc<Env>,
     registry: Arc<Registry<Env>>,
     job_start_timeout: Duration,
+    job_filter: Option<Arc<JobFilter>>,
+    fetcher: Option<Arc<dyn FetchQuery>>,
+    job_yield_threshold: Option<Duration>,
+    catch_panics: bool,
+    panic_format: Arc<PanicFormat>,
+    default_job_timeout: Option<Duration>,
+    json_logs: bool,
+    on_job_error: Option<Arc<JobErrorHandler>>,
+    job_error_throttle: Option<Arc<FailureThrottle>>,
+    record_runs: bool,
+    failure_samples: Option<u32>,
+    record_failures_to: Option<Arc<PathBuf>>,
+    asynchronous_completions: bool,
+    notify_on_completion: bool,
+    retry_settings: Arc<RetrySettings>,
+    debug_job_types: Arc<DebugJobTypes>,
+    max_fetch_error_backoff: Duration,
+    poll_interval: Option<Duration>,
+    shutdown: ShutdownHandle,
+    shutdown_timeouts: ShutdownTimeouts,
+    running_jobs: RunningJobs,
+    #[cfg(feature = "notify")]
+    listener: Option<Listener>,
+    lock_hold_times: Option<Arc<Mutex<Vec<Duration>>>>,
+    #[cfg(feature = "metrics")]
+    metrics: Arc<Metrics>,
+    fetch_options: Arc<FetchOptions>,
+    middleware: Arc<MiddlewareStack>,
+    plugins: Vec<Arc<dyn RunnerPlugin>>,
 }
 
 impl<Env> Runner<Env, NoConnectionPoolGiven> {
@@ -148,7 +1073,38 @@ impl<Env> Runner<Env, NoConnectionPoolGiven> {
             connection_pool_or_builder: NoConnectionPoolGiven,
             environment,
             thread_count: None,
+            max_batch_size: 1,
             job_start_timeout: None,
+            job_filter: None,
+            fetcher: None,
+            job_yield_threshold: None,
+            catch_panics: true,
+            panic_format: PanicFormat::default(),
+            default_job_timeout: None,
+            lease_duration: None,
+            json_logs: false,
+            on_job_error: None,
+            job_error_throttle: None,
+            record_runs: false,
+            failure_samples: None,
+            record_failures_to: None,
+            asynchronous_completions: false,
+            notify_on_completion: false,
+            measure_lock_hold_times: false,
+            retry_settings: RetrySettings::default(),
+            max_fetch_error_backoff: None,
+            poll_interval: None,
+            shutdown_timeouts: ShutdownTimeouts::default(),
+            #[cfg(feature = "notify")]
+            listen_url: None,
+            fetch_options: FetchOptions {
+                locality_window: Duration::from_secs(30),
+                ..FetchOptions::default()
+            },
+            middleware: MiddlewareStack::default(),
+            plugins: Vec::new(),
+            require_nonempty_registry: false,
+            registry: Registry::load(),
         }
     }
 }
@@ -159,6 +1115,11 @@ impl<Env, ConnectionPool> Runner<Env, ConnectionPool> {
     pub fn connection_pool(&self) -> &ConnectionPool {
         &self.connection_pool
     }
+
+    /// A handle which can be used to stop this runner from another thread
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
 }
 
 impl<Env, ConnectionPool> Runner<Env, ConnectionPool>
@@ -172,14 +1133,29 @@ where
     /// but does not wait for them to complete. When this function returns, at
     /// least one thread will have tried to acquire a new job, and found there
     /// were none in the queue.
-    pub fn run_all_pending_jobs(&self) -> Result<(), FetchError<ConnectionPool>> {
+    ///
+    /// The first call registers this runner in `swirl_workers`, and later calls
+    /// update its heartbeat. The runner is deregistered when it is dropped.
+    ///
+    /// Once the runner has been shut down with a [`ShutdownHandle`], this
+    /// returns without starting any more jobs.
+    pub fn run_all_pending_jobs(&self) -> Result<(), FetchError> {
         use std::cmp::max;
 
-        let max_threads = self.thread_pool.max_count();
+        self.worker
+            .heartbeat(&self.connection_pool, &self.fetch_options);
+
+        let thread_pool = self.thread_pool();
+        // Threads lent to the sub-tasks of running jobs aren't available
+        let busy_threads = || max(thread_pool.active_count(), self.thread_budget.in_use());
+        let max_threads = self.thread_count;
         let (sender, receiver) = channel::new(max_threads);
         let mut pending_messages = 0;
         loop {
-            let available_threads = max_threads - self.thread_pool.active_count();
+            if self.shutdown.is_shutdown() {
+                return Ok(());
+            }
+            let available_threads = max_threads.saturating_sub(busy_threads());
 
             let jobs_to_queue = if pending_messages == 0 {
                 // If we have no queued jobs talking to us, and there are no
@@ -198,37 +1174,369 @@ where
             match receiver.recv_timeout(self.job_start_timeout) {
                 Ok(Event::Working) => pending_messages -= 1,
                 Ok(Event::NoJobAvailable) => return Ok(()),
-                Ok(Event::ErrorLoadingJob(e)) => return Err(FetchError::FailedLoadingJob(e)),
+                Ok(Event::ErrorLoadingJob(e)) => {
+                    let context = self.fetch_context(busy_threads());
+                    return Err(FetchError::FailedLoadingJob(e, context));
+                }
                 Ok(Event::FailedToAcquireConnection(e)) => {
-                    return Err(FetchError::NoDatabaseConnection(e));
+                    let context = self.fetch_context(busy_threads());
+                    return Err(FetchError::NoDatabaseConnection(e.into(), context));
+                }
+                Err(_) => {
+                    let context = self.fetch_context(busy_threads());
+                    return Err(FetchError::NoMessageReceived(context));
+                }
+            }
+        }
+    }
+
+    /// Runs jobs until there are none left which are due, and waits for them
+    /// to finish. Returns a summary of the jobs which were run.
+    ///
+    /// This is meant for runners which are started periodically, such as by
+    /// cron, rather than running continuously. Jobs which fail are only run
+    /// again by the same call if they are due to be retried before the queue
+    /// is empty.
+    ///
+    /// If the runner was built with [`Builder::record_runs`], the summary is
+    /// stored in `swirl_runs`. Runs which fail with an error are recorded as
+    /// well, with the error and the jobs which were run before it occurred.
+    pub fn run_until_empty(&self) -> Result<RunSummary, FetchError> {
+        let started_at = Instant::now();
+        let before = self.run_counts.totals();
+        let result = self.run_passes_until_empty();
+        let summary = self.run_counts.since(&before, started_at.elapsed());
+
+        if self.record_runs {
+            let error = result.as_ref().err().map(ToString::to_string);
+            let recorded = self.connection().and_then(|conn| {
+                storage::record_run(&*conn, &summary, error.as_deref()).map_err(Into::into)
+            });
+            if let Err(e) = recorded {
+                eprintln!("Failed to record run: {}", e);
+            }
+        }
+        result.map(|()| summary)
+    }
+
+    /// Calls [`run_all_pending_jobs`](Self::run_all_pending_jobs) and waits
+    /// for the jobs it started, until a pass doesn't run any jobs
+    fn run_passes_until_empty(&self) -> Result<(), FetchError> {
+        let thread_pool = self.thread_pool();
+        loop {
+            let jobs_run = self.run_counts.jobs_run();
+            let result = self.run_all_pending_jobs();
+            thread_pool.join();
+            result?;
+            if self.run_counts.jobs_run() == jobs_run || self.shutdown.is_shutdown() {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Runs jobs until the runner is shut down
+    ///
+    /// This calls [`run_all_pending_jobs`](Self::run_all_pending_jobs) in a
+    /// loop, sleeping for `poll_interval` whenever the queue is empty, or for
+    /// the [`Builder::poll_interval`] if one was given. If
+    /// [`Builder::listen_for_jobs`] was given, the runner also wakes up as soon
+    /// as a job is enqueued.
+    ///
+    /// Errors fetching jobs, such as the database being unavailable, are
+    /// logged to stderr rather than returned. After an error the runner waits
+    /// before trying again, starting at `poll_interval` and doubling after
+    /// each consecutive error, up to [`Builder::max_fetch_error_backoff`].
+    ///
+    /// Once [`ShutdownHandle::shutdown`] is called on a handle from
+    /// [`shutdown_handle`](Self::shutdown_handle), no more jobs are started,
+    /// and this returns once the jobs which were already running have
+    /// finished, or once the
+    /// [`soft_shutdown_timeout`](Builder::soft_shutdown_timeout) has passed.
+    /// The returned report lists any jobs which were still running.
+    pub fn run_forever(&self, poll_interval: Duration) -> ShutdownReport {
+        if self.registry.is_empty() {
+            eprintln!(
+                "No jobs are registered with this runner, so every job it runs will fail. \
+                 See `Builder::require_nonempty_registry` for common causes"
+            );
+        }
+        match self.unregistered_job_types() {
+            Ok(job_types) => {
+                for job_type in job_types {
+                    eprintln!(
+                        "Jobs of type {} are in the queue, but that type is not registered. \
+                         If it's defined in another crate, see `swirl::register_jobs!`",
+                        job_type
+                    );
                 }
-                Err(_) => return Err(FetchError::NoMessageReceived),
             }
+            Err(e) => eprintln!("Failed to check for unregistered job types: {}", e),
+        }
+        self.start_plugin_maintenance();
+        let poll_interval = self.poll_interval.unwrap_or(poll_interval);
+        self.run_until(poll_interval, || self.shutdown.is_shutdown());
+        self.drain()
+    }
+
+    /// Starts a thread for each plugin with a maintenance interval, which runs
+    /// its maintenance until the runner is shut down
+    fn start_plugin_maintenance(&self) {
+        for plugin in &self.plugins {
+            let interval = match plugin.maintenance_interval() {
+                Some(interval) => interval,
+                None => continue,
+            };
+            let plugin = Arc::clone(plugin);
+            let pool = self.connection_pool.clone();
+            let shutdown = self.shutdown.clone();
+            #[cfg(feature = "metrics")]
+            let metrics = Arc::clone(&self.metrics);
+            thread::spawn(move || loop {
+                sleep_unless_stopped(interval, &|| shutdown.is_shutdown());
+                if shutdown.is_shutdown() {
+                    return;
+                }
+                if let Err(e) = plugin.maintenance(&pool) {
+                    eprintln!("Maintenance of plugin {} failed: {}", plugin.name(), e);
+                }
+                #[cfg(feature = "metrics")]
+                plugin.record_metrics(&metrics.snapshot());
+            });
+        }
+    }
+
+    /// Waits for running jobs to finish after the runner has been shut down,
+    /// giving up on them once the shutdown timeouts have passed
+    fn drain(&self) -> ShutdownReport {
+        let thread_pool = self.thread_pool();
+        if let Some(hard) = self.shutdown_timeouts.hard {
+            let running_jobs = self.running_jobs.clone();
+            thread::spawn(move || {
+                thread::sleep(hard);
+                let abandoned_jobs = running_jobs.ids();
+                if !abandoned_jobs.is_empty() {
+                    eprintln!(
+                        "Jobs {:?} were still running {:?} after shutdown, aborting",
+                        abandoned_jobs, hard
+                    );
+                    std::process::abort();
+                }
+            });
+        }
+
+        let soft = match self.shutdown_timeouts.soft {
+            Some(soft) => soft,
+            None => {
+                thread_pool.join();
+                return ShutdownReport::default();
+            }
+        };
+        let deadline = Instant::now() + soft;
+        while thread_pool.active_count() > 0 || thread_pool.queued_count() > 0 {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining == Duration::from_secs(0) {
+                let abandoned_jobs = self.running_jobs.ids();
+                eprintln!(
+                    "Jobs {:?} were still running {:?} after shutdown, abandoning them",
+                    abandoned_jobs, soft
+                );
+                return ShutdownReport { abandoned_jobs };
+            }
+            thread::sleep(remaining.min(SHUTDOWN_CHECK_INTERVAL));
+        }
+        ShutdownReport::default()
+    }
+
+    /// The loop behind [`run_forever`](Self::run_forever), which returns once
+    /// `should_stop` returns `true`
+    fn run_until<F: Fn() -> bool>(&self, poll_interval: Duration, should_stop: F) {
+        use std::cmp::{max, min};
+
+        let mut backoff = poll_interval;
+        while !should_stop() {
+            match self.run_all_pending_jobs() {
+                Ok(()) => {
+                    backoff = poll_interval;
+                    self.wait_for_new_jobs(poll_interval, &should_stop);
+                }
+                Err(e) => {
+                    eprintln!("Failed to fetch jobs, retrying in {:?}: {}", backoff, e);
+                    sleep_unless_stopped(backoff, &should_stop);
+                    backoff = min(
+                        backoff * 2,
+                        max(poll_interval, self.max_fetch_error_backoff),
+                    );
+                }
+            }
+        }
+    }
+
+    /// Waits up to `timeout` for a job to be enqueued, or until `should_stop`
+    /// returns `true`. Without a listener, only the timeout or stopping ends
+    /// the wait early.
+    #[cfg(feature = "notify")]
+    fn wait_for_new_jobs(&self, timeout: Duration, should_stop: &dyn Fn() -> bool) {
+        let listener = match &self.listener {
+            Some(listener) => listener,
+            None => return sleep_unless_stopped(timeout, should_stop),
+        };
+        let queues = self.fetch_options.queues.as_deref();
+        let deadline = Instant::now() + timeout;
+        while !should_stop() {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining == Duration::from_secs(0) {
+                return;
+            }
+            match listener.wait(remaining.min(SHUTDOWN_CHECK_INTERVAL), queues) {
+                Ok(true) => return,
+                Ok(false) => {}
+                Err(e) => {
+                    eprintln!("Failed to listen for new jobs: {}", e);
+                    return sleep_unless_stopped(remaining, should_stop);
+                }
+            }
+        }
+    }
+
+    #[cfg(not(feature = "notify"))]
+    fn wait_for_new_jobs(&self, timeout: Duration, should_stop: &dyn Fn() -> bool) {
+        sleep_unless_stopped(timeout, should_stop)
+    }
+
+    /// Describes the runner for a [`FetchError`], given how many of its
+    /// threads are busy
+    fn fetch_context(&self, busy_threads: usize) -> FetchContext {
+        FetchContext {
+            queues: self.fetch_options.queues.clone(),
+            thread_count: self.thread_count,
+            busy_threads,
         }
     }
 
+    /// The pool jobs are run on. Its threads are only started the first time
+    /// this is called, so that runners which are turned into an `AsyncRunner`
+    /// never start them.
+    fn thread_pool(&self) -> ThreadPool {
+        let mut thread_pool = self.thread_pool.lock().unwrap_or_else(|e| e.into_inner());
+        thread_pool
+            .get_or_insert_with(|| ThreadPool::new(self.thread_count))
+            .clone()
+    }
+
     fn run_single_job(&self, sender: EventSender<ConnectionPool>) {
+        self.get_single_job(sender, self.perform_job())
+    }
+
+    /// Runs a job which has been locked, by looking up its type in the registry
+    fn perform_job(
+        &self,
+    ) -> impl FnMut(storage::BackgroundJob, &JobTransaction<'_>) -> Result<(), PerformError>
+           + Send
+           + UnwindSafe
+           + 'static {
         let environment = Arc::clone(&self.environment);
         let registry = Arc::clone(&self.registry);
+        let job_yield_threshold = self.job_yield_threshold;
+        let fetch_options = Arc::clone(&self.fetch_options);
+        let thread_budget = Arc::clone(&self.thread_budget);
+        // Custom retry policies are closures, which may not be unwind safe.
+        // Only the maximum number of retries is read from them here.
+        let retry_settings = AssertUnwindSafe(Arc::clone(&self.retry_settings));
+        let progress = Arc::clone(&self.progress);
         // FIXME: https://github.com/sfackler/r2d2/pull/70
         let connection_pool = AssertUnwindSafe(self.connection_pool().clone());
-        self.get_single_job(sender, move |job| {
+        move |job: storage::BackgroundJob, transaction: &JobTransaction<'_>| {
             let perform_job = registry
                 .get(&job.job_type)
                 .ok_or_else(|| PerformError::from(format!("Unknown job type {}", job.job_type)))?;
-            perform_job.perform(job.data, &environment, &connection_pool.0)
-        })
+            progress.start(&connection_pool.0);
+            let ctx = JobContext::new(
+                &job,
+                transaction,
+                retry_settings.0.max_retries(registry.vtable(&job.job_type)),
+                JobResources {
+                    pool: &connection_pool.0,
+                    fetch_options: &fetch_options,
+                    thread_budget: &thread_budget,
+                    yield_threshold: job_yield_threshold,
+                    progress: Some(&*progress),
+                },
+            );
+            perform_job.perform(job.data, &environment, &ctx)
+        }
     }
 
     fn get_single_job<F>(&self, sender: EventSender<ConnectionPool>, f: F)
     where
-        F: FnOnce(storage::BackgroundJob) -> Result<(), PerformError> + Send + UnwindSafe + 'static,
+        F: FnMut(storage::BackgroundJob, &JobTransaction<'_>) -> Result<(), PerformError>
+            + Send
+            + UnwindSafe
+            + 'static,
+    {
+        self.thread_pool().execute(self.job_task(sender, f))
+    }
+
+    /// Locks the next job and passes it to `f`, then updates the job with the
+    /// result. Progress is reported over `sender`.
+    ///
+    /// If [`Builder::max_batch_size`] was given, more jobs are locked and run
+    /// the same way once the first has finished, and the transaction is only
+    /// committed once the batch is complete.
+    ///
+    /// The returned function blocks, and is run on a thread from the pool or,
+    /// for an `AsyncRunner`, with `tokio::task::spawn_blocking`.
+    fn job_task<F>(
+        &self,
+        sender: EventSender<ConnectionPool>,
+        mut f: F,
+    ) -> impl FnOnce() + Send + 'static
+    where
+        F: FnMut(storage::BackgroundJob, &JobTransaction<'_>) -> Result<(), PerformError>
+            + Send
+            + UnwindSafe
+            + 'static,
     {
         use diesel::result::Error::RollbackTransaction;
 
         // The connection may not be `Send` so we need to clone the pool instead
         let pool = self.connection_pool.clone();
-        self.thread_pool.execute(move || {
+        let job_filter = self.job_filter.clone();
+        let fetcher = self.fetcher.clone();
+        let fetch_options = Arc::clone(&self.fetch_options);
+        let catch_panics = self.catch_panics;
+        let panic_format = Arc::clone(&self.panic_format);
+        let json_logs = self.json_logs;
+        let on_job_error = self.on_job_error.clone();
+        let job_error_throttle = self.job_error_throttle.clone();
+        let failure_samples = self.failure_samples;
+        let record_failures_to = self.record_failures_to.clone();
+        let asynchronous_completions = self.asynchronous_completions;
+        let notify_on_completion = self.notify_on_completion;
+        let retry_settings = Arc::clone(&self.retry_settings);
+        let registry = Arc::clone(&self.registry);
+        let debug_job_types = Arc::clone(&self.debug_job_types);
+        let lock_hold_times = self.lock_hold_times.clone();
+        #[cfg(feature = "metrics")]
+        let metrics = Arc::clone(&self.metrics);
+        let worker = Arc::clone(&self.worker);
+        let running_jobs = self.running_jobs.clone();
+        let thread_budget = Arc::clone(&self.thread_budget);
+        let batch_sizer = Arc::clone(&self.batch_sizer);
+        let run_counts = Arc::clone(&self.run_counts);
+        let middleware = Arc::clone(&self.middleware);
+        let shutdown = self.shutdown.clone();
+        let default_job_timeout = self.default_job_timeout;
+        let watchdog = Arc::clone(&self.watchdog);
+        let leases = self.leases.clone();
+        let progress = Arc::clone(&self.progress);
+        move || {
+            // Tasks queued before the runner was shut down only start once a
+            // thread is free, by which time the job should be left for
+            // another runner
+            if shutdown.is_shutdown() {
+                sender.send(Event::NoJobAvailable);
+                return;
+            }
             let conn = match pool.get() {
                 Ok(conn) => conn,
                 Err(e) => {
@@ -237,46 +1545,407 @@ where
                 }
             };
 
+            let transaction = JobTransaction::new(&conn);
+            let batch_size = batch_sizer.batch_size();
+            let mut locked = Vec::new();
+            let mut budget_slot = None;
+            let mut connection_pid = None;
+            let mut timed_out = false;
             let job_run_result = conn.transaction::<_, diesel::result::Error, _>(|| {
-                let job = match storage::find_next_unlocked_job(&conn).optional() {
-                    Ok(Some(j)) => {
-                        sender.send(Event::Working);
-                        j
+                let filter = job_filter.as_deref();
+                let fetcher = fetcher.as_deref().unwrap_or(&DefaultFetchQuery);
+                // Jobs which were put back in the queue are still locked by
+                // this transaction, so later jobs in the batch must skip them
+                let mut excluded = storage::Excluded::default();
+                while locked.len() < batch_size {
+                    let job = if locked.is_empty() {
+                        match find_next_accepted_job(
+                            &conn,
+                            fetcher,
+                            &fetch_options,
+                            filter,
+                            &mut excluded,
+                        ) {
+                            Ok(Some(j)) => {
+                                budget_slot = Some(thread_budget.claim());
+                                sender.send(Event::Working);
+                                j
+                            }
+                            Ok(None) => {
+                                sender.send(Event::NoJobAvailable);
+                                return Ok(());
+                            }
+                            Err(e) => {
+                                sender.send(Event::ErrorLoadingJob(e));
+                                return Err(RollbackTransaction);
+                            }
+                        }
+                    } else {
+                        if shutdown.is_shutdown() {
+                            break;
+                        }
+                        // Fetched in a savepoint, so that an error ends the
+                        // batch without rolling back the jobs which already ran.
+                        // The error is reported by the next fetch instead.
+                        let next = conn.transaction(|| {
+                            find_next_accepted_job(
+                                &conn,
+                                fetcher,
+                                &fetch_options,
+                                filter,
+                                &mut excluded,
+                            )
+                        });
+                        match next {
+                            Ok(Some(j)) => j,
+                            Ok(None) | Err(_) => break,
+                        }
+                    };
+                    locked.push((job.id, Instant::now()));
+                    running_jobs.insert(&job);
+                    excluded.ids.push(job.id);
+                    storage::label_running_job(&conn, job.id, worker.id())?;
+
+                    let job_id = job.id;
+                    let job_type = job.job_type.clone();
+                    let queue = job.queue.clone();
+                    let failures = job.retries + 1;
+                    let recorded_job = record_failures_to.as_ref().map(|_| job.clone());
+                    let middleware_job = if middleware.is_empty() {
+                        None
+                    } else {
+                        Some(job.clone())
+                    };
+                    let started_at = Instant::now();
+                    let owner = registry.vtable(&job_type).and_then(JobVTable::owner);
+                    let json_log = if json_logs {
+                        Some(JsonLog::start(&job, owner))
+                    } else {
+                        None
+                    };
+                    let debug_log = if debug_job_types.contains(&conn, &job.job_type) {
+                        Some(DebugLog::start(&job))
+                    } else {
+                        None
+                    };
+                    #[cfg(feature = "metrics")]
+                    let metrics_run = metrics.start(&job, owner);
+                    #[cfg(feature = "tracing")]
+                    let job_span = JobSpan::start(&job, owner);
+                    #[cfg(feature = "tracing")]
+                    let _entered = job_span.enter();
+
+                    let timeout = registry
+                        .vtable(&job_type)
+                        .and_then(JobVTable::timeout)
+                        .or(default_job_timeout);
+                    if connection_pid.is_none() && (timeout.is_some() || leases.is_some()) {
+                        connection_pid = Some(storage::backend_pid(&conn)?);
+                    }
+                    if let (Some(leases), Some(worker_id), Some(pid)) =
+                        (&leases, worker.id(), connection_pid)
+                    {
+                        leases.acquire(&pool, worker_id, job_id, pid);
                     }
-                    Ok(None) => {
-                        sender.send(Event::NoJobAvailable);
-                        return Ok(());
+                    if let (Some(timeout), Some(pid)) = (timeout, connection_pid) {
+                        let error = JobTimedOut(timeout);
+                        watchdog.watch(
+                            &pool,
+                            WatchedJob {
+                                job_id,
+                                backend_pid: pid,
+                                timeout,
+                                next_run: retry_settings.next_run(
+                                    registry.vtable(&job_type),
+                                    &error,
+                                    failures as u32,
+                                ),
+                                environment: worker.environment(&fetch_options),
+                                owner,
+                            },
+                        );
                     }
-                    Err(e) => {
-                        sender.send(Event::ErrorLoadingJob(e));
+
+                    // The job is run in a savepoint, so anything it wrote with
+                    // `JobContext::connection` is rolled back unless it succeeds
+                    let deferred_len = transaction.deferred_len();
+                    let mut result = Ok(());
+                    let savepoint = conn.transaction(|| {
+                        let run = AssertUnwindSafe(|| {
+                            if let Some(job) = &middleware_job {
+                                middleware.before_perform(&job.meta(), &conn)?;
+                            }
+                            f(job, &transaction)
+                        });
+                        result = match catch_unwind(run) {
+                            Ok(result) => result,
+                            // The panic message has already been printed by the panic hook
+                            Err(_) if !catch_panics => std::process::abort(),
+                            Err(e) => Err(panic_format.format(&*e).into()),
+                        };
+                        if timeout.is_some() && watchdog.unwatch(job_id) {
+                            timed_out = true;
+                        }
+                        if result.is_ok() {
+                            Ok(())
+                        } else {
+                            transaction.discard_deferred(deferred_len);
+                            Err(RollbackTransaction)
+                        }
+                    });
+                    running_jobs.finished(job_id);
+                    progress.finished(job_id);
+                    match savepoint {
+                        Ok(()) | Err(RollbackTransaction) => {}
+                        Err(e) => return Err(e),
+                    }
+                    // The watchdog has already failed the job, and terminated
+                    // the connection
+                    if timed_out {
                         return Err(RollbackTransaction);
                     }
-                };
-                let job_id = job.id;
-
-                let result = catch_unwind(|| f(job))
-                    .map_err(|e| try_to_extract_panic_info(&e))
-                    .and_then(|r| r);
-
-                match result {
-                    Ok(_) => storage::delete_successful_job(&conn, job_id)?,
-                    Err(e) => {
-                        eprintln!("Job {} failed to run: {}", job_id, e);
-                        storage::update_failed_job(&conn, job_id);
+                    if let Some(job) = &middleware_job {
+                        match &result {
+                            Err(e) if !e.is::<JobYielded>() => {
+                                middleware.on_failure(&job.meta(), &**e)
+                            }
+                            _ => {}
+                        }
+                        middleware.after_perform(&job.meta(), started_at.elapsed());
+                    }
+                    batch_sizer.record(started_at.elapsed());
+
+                    if asynchronous_completions {
+                        storage::disable_synchronous_commit(&conn)?;
+                    }
+
+                    match result {
+                        Ok(_) => {
+                            storage::delete_job(&conn, job_id)?;
+                            if notify_on_completion {
+                                storage::notify_completion(&conn, job_id, JobOutcome::Succeeded)?;
+                            }
+                            if let Some(log) = &json_log {
+                                log.succeeded();
+                            }
+                            if let Some(log) = &debug_log {
+                                log.succeeded();
+                            }
+                            #[cfg(feature = "metrics")]
+                            metrics_run.succeeded();
+                            run_counts.finished();
+                            #[cfg(feature = "tracing")]
+                            job_span.succeeded();
+                        }
+                        // Committing without updating the job puts it back in the queue
+                        Err(e) if e.is::<JobYielded>() => {
+                            if let Some(log) = &json_log {
+                                log.yielded();
+                            }
+                            if let Some(log) = &debug_log {
+                                log.yielded();
+                            }
+                            #[cfg(feature = "metrics")]
+                            metrics_run.yielded();
+                            run_counts.finished();
+                            #[cfg(feature = "tracing")]
+                            job_span.yielded();
+                        }
+                        Err(e) => {
+                            if let Some(log) = &json_log {
+                                log.failed(&e);
+                            }
+                            match &on_job_error {
+                                Some(on_job_error) => {
+                                    let suppressed = match &job_error_throttle {
+                                        Some(throttle) => throttle.check(&job_type, &*e),
+                                        None => Some(0),
+                                    };
+                                    if let Some(suppressed) = suppressed {
+                                        on_job_error(&JobFailure {
+                                            id: job_id,
+                                            job_type: &job_type,
+                                            queue: &queue,
+                                            owner,
+                                            failures,
+                                            error: &*e,
+                                            suppressed,
+                                        })
+                                    }
+                                }
+                                // Already reported as JSON or by the job's span
+                                None if json_log.is_some() || cfg!(feature = "tracing") => {}
+                                None => match owner {
+                                    Some(owner) => eprintln!(
+                                        "Job {} failed to run: {} (owned by {})",
+                                        job_id, e, owner
+                                    ),
+                                    None => eprintln!("Job {} failed to run: {}", job_id, e),
+                                },
+                            }
+                            if let Some(log) = &debug_log {
+                                log.failed(&e);
+                            }
+                            #[cfg(feature = "metrics")]
+                            metrics_run.failed();
+                            run_counts.failed();
+                            #[cfg(feature = "tracing")]
+                            job_span.failed(&e);
+                            if let (Some(dir), Some(job)) = (&record_failures_to, recorded_job) {
+                                if let Err(err) = replay::record(dir, job, e.to_string()) {
+                                    eprintln!("Failed to record job {}: {}", job_id, err);
+                                }
+                            }
+                            if let Some(limit) = failure_samples {
+                                // Recorded in a savepoint, so that an error here
+                                // doesn't prevent the job from being updated
+                                let _ = conn.transaction(|| {
+                                    storage::record_failure_sample(
+                                        &conn,
+                                        job_id,
+                                        &e.to_string(),
+                                        limit,
+                                        owner,
+                                    )
+                                });
+                            }
+                            let next_run = retry_settings.next_run(
+                                registry.vtable(&job_type),
+                                &*e,
+                                failures as u32,
+                            );
+                            storage::update_failed_job(
+                                &conn,
+                                job_id,
+                                next_run,
+                                &e.to_string(),
+                                &worker.environment(&fetch_options),
+                                owner,
+                            );
+                            if notify_on_completion {
+                                storage::notify_completion(&conn, job_id, JobOutcome::Failed)?;
+                            }
+                        }
                     }
                 }
                 Ok(())
             });
 
+            // The locks are released once the transaction has committed
+            for &(job_id, _) in &locked {
+                running_jobs.remove(job_id);
+            }
+            let lease_lost = match &leases {
+                Some(leases) => {
+                    let job_ids = locked.iter().map(|&(id, _)| id).collect::<Vec<_>>();
+                    leases.release(&pool, &job_ids)
+                }
+                None => false,
+            };
+            drop(budget_slot);
+            if let Some(times) = &lock_hold_times {
+                let mut times = times.lock().unwrap_or_else(|e| e.into_inner());
+                times.extend(locked.iter().map(|(_, locked_at)| locked_at.elapsed()));
+            }
+
             match job_run_result {
-                Ok(_) | Err(RollbackTransaction) => {}
+                Ok(_) => transaction.enqueue_deferred(),
+                Err(RollbackTransaction) => {}
+                // The connection was terminated by the watchdog, or by another
+                // runner after the job's lease expired
+                Err(_) if timed_out || lease_lost => {}
                 Err(e) => {
                     panic!("Failed to update job: {:?}", e);
                 }
             }
+        }
+    }
+
+    /// The jobs this runner is running right now, in order of id.
+    ///
+    /// This only looks at the runner's own state, so it is cheap enough to
+    /// serve from a debug endpoint. Use
+    /// [`admin::running_jobs`](crate::admin::running_jobs) to see the jobs
+    /// every runner is running.
+    pub fn in_flight(&self) -> Vec<InFlightJob> {
+        self.running_jobs.in_flight()
+    }
+
+    /// How long jobs run by this runner have held their locks.
+    ///
+    /// Returns `None` unless [`Builder::measure_lock_hold_times`] was enabled.
+    /// Only jobs which have finished are included.
+    pub fn lock_hold_times(&self) -> Option<LockHoldTimes> {
+        self.lock_hold_times.as_ref().map(|times| {
+            let times = times.lock().unwrap_or_else(|e| e.into_inner());
+            LockHoldTimes::new(times.clone())
         })
     }
 
+    /// Runs the job in a [`Recording`] once on the current thread, using this
+    /// runner's environment and connection pool.
+    ///
+    /// This is meant for reproducing a failure recorded by
+    /// [`Builder::record_failures_to`] against a development environment. The
+    /// job is not locked or updated in the database, and doesn't need to
+    /// exist there. Like a normal run, the job runs in a transaction which is
+    /// only committed if it succeeds.
+    pub fn replay<P: AsRef<Path>>(&self, recording: P) -> Result<(), PerformError> {
+        let job = Recording::load(recording)?.into_job();
+        let perform_job = self
+            .registry
+            .get(&job.job_type)
+            .ok_or_else(|| PerformError::from(format!("Unknown job type {}", job.job_type)))?;
+        let conn = self.connection()?;
+        let transaction = JobTransaction::new(&conn);
+        conn.transaction(|| {
+            let ctx = JobContext::new(
+                &job,
+                &transaction,
+                self.retry_settings
+                    .max_retries(self.registry.vtable(&job.job_type)),
+                JobResources {
+                    pool: &self.connection_pool,
+                    fetch_options: &self.fetch_options,
+                    thread_budget: &self.thread_budget,
+                    yield_threshold: self.job_yield_threshold,
+                    progress: None,
+                },
+            );
+            perform_job.perform(job.data.clone(), &self.environment, &ctx)
+        })?;
+        transaction.enqueue_deferred();
+        Ok(())
+    }
+
+    /// The types of the jobs in this runner's queues which it has no job
+    /// registered for. Jobs of these types will fail when they are run.
+    pub fn unregistered_job_types(&self) -> Result<Vec<String>, Box<dyn Error + Send + Sync>> {
+        let mut job_types = storage::queued_job_types(&*self.connection()?, &self.fetch_options)?;
+        job_types.retain(|job_type| !self.registry.contains(job_type));
+        Ok(job_types)
+    }
+
+    /// Counters and durations of the jobs this runner has run, along with the
+    /// depth of the queue, for each job type.
+    ///
+    /// The snapshot renders itself in Prometheus' text format with
+    /// `to_string`, so it can be served from a `/metrics` endpoint.
+    ///
+    /// Requires the `metrics` feature.
+    #[cfg(feature = "metrics")]
+    pub fn metrics_snapshot(&self) -> Result<MetricsSnapshot, Box<dyn Error + Send + Sync>> {
+        let mut snapshot = self.metrics.snapshot();
+        for (job_type, depth, oldest_job_age) in storage::queue_depths(&*self.connection()?)? {
+            let owner = self.registry.vtable(&job_type).and_then(JobVTable::owner);
+            let metrics = snapshot.job_types.entry(job_type).or_default();
+            metrics.owner = owner;
+            metrics.queue_depth = depth;
+            metrics.oldest_job_age = Some(oldest_job_age);
+        }
+        Ok(snapshot)
+    }
+
     fn connection(&self) -> Result<DieselPooledConn<ConnectionPool>, Box<dyn Error + Send + Sync>> {
         self.connection_pool.get().map_err(Into::into)
     }
@@ -286,6 +1955,8 @@ where
     ///
     /// This function is intended for use in tests. If any jobs have failed, it
     /// will return `swirl::JobsFailed` with the number of jobs that failed.
+    /// Jobs which have been given up on and moved to `swirl_failed_jobs` are
+    /// not counted.
     ///
     /// If any other unexpected errors occurred, such as panicked worker threads
     /// or an error loading the job count from the database, an opaque error
@@ -301,8 +1972,9 @@ where
     }
 
     fn wait_for_jobs(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
-        self.thread_pool.join();
-        let panic_count = self.thread_pool.panic_count();
+        let thread_pool = self.thread_pool();
+        thread_pool.join();
+        let panic_count = thread_pool.panic_count();
         if panic_count == 0 {
             Ok(())
         } else {
@@ -311,21 +1983,76 @@ where
     }
 }
 
-/// Try to figure out what's in the box, and print it if we can.
+/// Sleeps for `duration`, or until `should_stop` returns `true`
+fn sleep_unless_stopped(duration: Duration, should_stop: &dyn Fn() -> bool) {
+    let deadline = Instant::now() + duration;
+    while !should_stop() {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining == Duration::from_secs(0) {
+            return;
+        }
+        thread::sleep(remaining.min(SHUTDOWN_CHECK_INTERVAL));
+    }
+}
+
+/// Finds and locks the next job which is accepted by `filter`, and which
+/// doesn't exceed any of the concurrency limits in `options`, or share its
+/// concurrency key with a running job. Jobs in `excluded` are skipped, and
+/// rejected jobs are added to it.
 ///
-/// The actual error type we will get from `panic::catch_unwind` is really poorly documented.
-/// However, the `panic::set_hook` functions deal with a `PanicInfo` type, and its payload is
-/// documented as "commonly but not always `&'static str` or `String`". So we can try all of those,
-/// and give up if we didn't get one of those three types.
-fn try_to_extract_panic_info(info: &(dyn Any + Send + 'static)) -> PerformError {
-    if let Some(x) = info.downcast_ref::<PanicInfo>() {
-        format!("job panicked: {}", x).into()
-    } else if let Some(x) = info.downcast_ref::<&'static str>() {
-        format!("job panicked: {}", x).into()
-    } else if let Some(x) = info.downcast_ref::<String>() {
-        format!("job panicked: {}", x).into()
-    } else {
-        "job panicked".into()
+/// Each job is locked inside of a savepoint, so that jobs which are rejected
+/// are unlocked again for other runners to pick up. Once a limit has been
+/// reached, all other jobs it applies to are skipped without being locked.
+/// Any job may have a concurrency key, so unlike the other checks, this one
+/// can't be skipped when the runner has no filter or limits.
+fn find_next_accepted_job(
+    conn: &PgConnection,
+    fetcher: &dyn FetchQuery,
+    options: &FetchOptions,
+    filter: Option<&JobFilter>,
+    excluded: &mut storage::Excluded,
+) -> QueryResult<Option<storage::BackgroundJob>> {
+    use diesel::result::Error::{NotFound, RollbackTransaction};
+
+    loop {
+        let result = conn.transaction(|| {
+            let job = fetcher
+                .fetch(conn, &FetchRequest::new(options, excluded))?
+                .ok_or(NotFound)?;
+            if !filter.map_or(true, |f| f(&job.meta())) {
+                excluded.ids.push(job.id);
+                return Err(RollbackTransaction);
+            }
+            if let Some(&limit) = options.job_type_limits.get(&job.job_type) {
+                let key = format!("job_type:{}", job.job_type);
+                if !storage::try_take_concurrency_slot(conn, &key, limit)? {
+                    excluded.job_types.push(job.job_type);
+                    return Err(RollbackTransaction);
+                }
+            }
+            if let Some(&limit) = options.queue_limits.get(&job.queue) {
+                let key = format!("queue:{}", job.queue);
+                if !storage::try_take_concurrency_slot(conn, &key, limit)? {
+                    excluded.queues.push(job.queue);
+                    return Err(RollbackTransaction);
+                }
+            }
+            if let Some(concurrency_key) = job.metadata["concurrency_key"].as_str() {
+                let key = format!("concurrency_key:{}", concurrency_key);
+                if !storage::try_take_concurrency_slot(conn, &key, 1)? {
+                    excluded.concurrency_keys.push(concurrency_key.into());
+                    return Err(RollbackTransaction);
+                }
+            }
+            Ok(job)
+        });
+
+        match result {
+            Ok(job) => return Ok(Some(job)),
+            Err(NotFound) => return Ok(None),
+            Err(RollbackTransaction) => {}
+            Err(e) => return Err(e),
+        }
     }
 }
 
@@ -336,9 +2063,22 @@ mod tests {
 
     use super::*;
     use crate::schema::background_jobs::dsl::*;
+    use std::cell::Cell;
     use std::panic::AssertUnwindSafe;
     use std::sync::{Arc, Barrier, Mutex, MutexGuard};
 
+    #[test]
+    fn lock_hold_time_percentiles() {
+        let times = LockHoldTimes::new((1..=100).rev().map(Duration::from_millis).collect());
+
+        assert_eq!(100, times.count());
+        assert_eq!(Some(Duration::from_millis(1)), times.percentile(0.0));
+        assert_eq!(Some(Duration::from_millis(51)), times.percentile(50.0));
+        assert_eq!(Some(Duration::from_millis(99)), times.percentile(99.0));
+        assert_eq!(Some(Duration::from_millis(100)), times.max());
+        assert_eq!(None, LockHoldTimes::default().max());
+    }
+
     #[test]
     fn jobs_are_locked_when_fetched() {
         let _guard = TestGuard::lock();
@@ -351,7 +2091,7 @@ mod tests {
         let return_barrier = Arc::new(AssertUnwindSafe(Barrier::new(2)));
         let return_barrier2 = return_barrier.clone();
 
-        runner.get_single_job(channel::dummy_sender(), move |job| {
+        runner.get_single_job(channel::dummy_sender(), move |job, _| {
             fetch_barrier.0.wait(); // Tell thread 2 it can lock its job
             assert_eq!(first_job_id, job.id);
             return_barrier.0.wait(); // Wait for thread 2 to lock its job
@@ -359,7 +2099,7 @@ mod tests {
         });
 
         fetch_barrier2.0.wait(); // Wait until thread 1 locks its job
-        runner.get_single_job(channel::dummy_sender(), move |job| {
+        runner.get_single_job(channel::dummy_sender(), move |job, _| {
             assert_eq!(second_job_id, job.id);
             return_barrier2.0.wait(); // Tell thread 1 it can unlock its job
             Ok(())
@@ -368,6 +2108,72 @@ mod tests {
         runner.wait_for_jobs().unwrap();
     }
 
+    #[test]
+    fn jobs_in_the_runners_locality_are_fetched_first() {
+        use diesel::dsl::{now, IntervalDsl};
+
+        let _guard = TestGuard::lock();
+        let runner = runner();
+        let conn = runner.connection().unwrap();
+        let insert_job = |job_locality: &str, due_for: i32| {
+            ::diesel::insert_into(background_jobs)
+                .values((
+                    job_type.eq("Foo"),
+                    data.eq(serde_json::json!(null)),
+                    locality.eq(job_locality),
+                    scheduled_at.eq(now - due_for.seconds()),
+                ))
+                .returning(id)
+                .get_result::<i64>(&*conn)
+                .unwrap()
+        };
+        let elsewhere = insert_job("us", 0);
+        let nearby = insert_job("eu", 0);
+        let waited_too_long = insert_job("us", 60);
+
+        let options = FetchOptions {
+            locality: Some("eu".into()),
+            locality_window: Duration::from_secs(30),
+            ..FetchOptions::default()
+        };
+        let mut excluded = storage::Excluded::default();
+        let mut fetched = Vec::new();
+        for _ in 0..3 {
+            let job = storage::find_next_unlocked_job(&conn, &options, &excluded).unwrap();
+            excluded.ids.push(job.id);
+            fetched.push(job.id);
+        }
+        assert_eq!(vec![nearby, waited_too_long, elsewhere], fetched);
+    }
+
+    #[test]
+    fn running_jobs_are_listed_as_in_flight() {
+        let _guard = TestGuard::lock();
+
+        let runner = runner();
+        let job_id = create_dummy_job(&runner).id;
+        let started = Arc::new(AssertUnwindSafe(Barrier::new(2)));
+        let started2 = started.clone();
+        let finish = Arc::new(AssertUnwindSafe(Barrier::new(2)));
+        let finish2 = finish.clone();
+
+        runner.get_single_job(channel::dummy_sender(), move |_, _| {
+            started.0.wait();
+            finish.0.wait();
+            Ok(())
+        });
+
+        started2.0.wait();
+        let in_flight = runner.in_flight();
+        assert_eq!(1, in_flight.len());
+        assert_eq!(job_id, in_flight[0].id);
+        assert_eq!("Foo", in_flight[0].job_type);
+        finish2.0.wait();
+
+        runner.wait_for_jobs().unwrap();
+        assert!(runner.in_flight().is_empty());
+    }
+
     #[test]
     fn jobs_are_deleted_when_successfully_run() {
         let _guard = TestGuard::lock();
@@ -375,7 +2181,7 @@ mod tests {
         let runner = runner();
         create_dummy_job(&runner);
 
-        runner.get_single_job(channel::dummy_sender(), |_| Ok(()));
+        runner.get_single_job(channel::dummy_sender(), |_, _| Ok(()));
         runner.wait_for_jobs().unwrap();
 
         let remaining_jobs = background_jobs
@@ -393,7 +2199,7 @@ mod tests {
         let barrier = Arc::new(AssertUnwindSafe(Barrier::new(2)));
         let barrier2 = barrier.clone();
 
-        runner.get_single_job(channel::dummy_sender(), move |_| {
+        runner.get_single_job(channel::dummy_sender(), move |_, _| {
             barrier.0.wait();
             // error so the job goes back into the queue
             Err("nope".into())
@@ -432,7 +2238,7 @@ mod tests {
         let runner = runner();
         let job_id = create_dummy_job(&runner).id;
 
-        runner.get_single_job(channel::dummy_sender(), |_| panic!());
+        runner.get_single_job(channel::dummy_sender(), |_, _| panic!());
         runner.wait_for_jobs().unwrap();
 
         let tries = background_jobs
@@ -444,6 +2250,504 @@ mod tests {
         assert_eq!(1, tries);
     }
 
+    #[test]
+    fn panic_messages_use_the_configured_format() {
+        struct CustomPayload(u32);
+
+        let mut format = PanicFormat::default();
+        format.add_payload_formatter(|p: &CustomPayload| format!("custom error {}", p.0));
+        let custom: Box<dyn Any + Send> = Box::new(CustomPayload(42));
+        let message: Box<dyn Any + Send> = Box::new(String::from("a very long message"));
+        let unknown: Box<dyn Any + Send> = Box::new(1u8);
+
+        assert_eq!("job panicked: custom error 42", format.format(&*custom));
+        assert_eq!(
+            "job panicked: a very long message",
+            format.format(&*message)
+        );
+        assert_eq!("job panicked", format.format(&*unknown));
+
+        format.max_length = Some(6);
+        assert_eq!("job panicked: a very...", format.format(&*message));
+
+        format.include_thread_name = true;
+        let on_thread = thread::Builder::new()
+            .name("job-thread".into())
+            .spawn(move || format.format(&*custom))
+            .unwrap()
+            .join()
+            .unwrap();
+        assert_eq!("job panicked on thread 'job-thread': custom...", on_thread);
+    }
+
+    #[test]
+    fn jobs_rejected_by_the_job_filter_are_left_in_the_queue() {
+        let _guard = TestGuard::lock();
+
+        let runner = builder()
+            .job_filter(|job| *job.data == serde_json::json!("accepted"))
+            .build();
+        let rejected_job_id = create_dummy_job(&runner).id;
+        let accepted_job_id = ::diesel::insert_into(background_jobs)
+            .values((job_type.eq("Foo"), data.eq(serde_json::json!("accepted"))))
+            .returning(id)
+            .get_result::<i64>(&*runner.connection().unwrap())
+            .unwrap();
+
+        runner.get_single_job(channel::dummy_sender(), move |job, _| {
+            assert_eq!(accepted_job_id, job.id);
+            Ok(())
+        });
+        runner.wait_for_jobs().unwrap();
+
+        let remaining_jobs = background_jobs
+            .select(id)
+            .for_update()
+            .skip_locked()
+            .load::<i64>(&*runner.connection().unwrap());
+        assert_eq!(Ok(vec![rejected_job_id]), remaining_jobs);
+    }
+
+    #[test]
+    fn fast_jobs_are_claimed_in_batches() {
+        let _guard = TestGuard::lock();
+
+        let runner = builder().max_batch_size(10).build();
+        create_dummy_job(&runner);
+        // Nothing is known about how long jobs take until one has run
+        runner.get_single_job(channel::dummy_sender(), |_, _| Ok(()));
+        runner.wait_for_jobs().unwrap();
+
+        let yielded_job_id = create_dummy_job(&runner).id;
+        let last_job_id = create_dummy_job(&runner).id;
+        let ran = Arc::new(Mutex::new(Vec::new()));
+        let ran2 = Arc::clone(&ran);
+        runner.get_single_job(channel::dummy_sender(), move |job, _| {
+            ran2.lock().unwrap().push(job.id);
+            if job.id == yielded_job_id {
+                Err(JobYielded.into())
+            } else {
+                Ok(())
+            }
+        });
+        runner.wait_for_jobs().unwrap();
+
+        // The yielded job is still in the queue, but isn't run twice
+        assert_eq!(vec![yielded_job_id, last_job_id], *ran.lock().unwrap());
+        let remaining_jobs = background_jobs
+            .select(id)
+            .load::<i64>(&*runner.connection().unwrap());
+        assert_eq!(Ok(vec![yielded_job_id]), remaining_jobs);
+    }
+
+    #[test]
+    fn building_without_jobs_fails_when_jobs_are_required() {
+        struct NoJobs;
+
+        let database_url =
+            dotenv::var("TEST_DATABASE_URL").expect("TEST_DATABASE_URL must be set to run tests");
+        let builder = crate::Runner::builder(NoJobs).database_url(database_url);
+        assert!(builder.registry.is_empty());
+
+        match builder.require_nonempty_registry().try_build() {
+            Err(BuildError::EmptyRegistry) => {}
+            Err(e) => panic!("Expected an empty registry error, got {}", e),
+            Ok(_) => panic!("Expected the build to fail"),
+        }
+    }
+
+    #[test]
+    fn job_failures_are_passed_to_the_error_handler() {
+        let _guard = TestGuard::lock();
+
+        let failures = Arc::new(Mutex::new(Vec::new()));
+        let failures2 = Arc::clone(&failures);
+        let runner = builder()
+            .on_job_error(move |failure| {
+                failures2.lock().unwrap().push((
+                    failure.id,
+                    failure.job_type.to_string(),
+                    failure.failures,
+                    failure.error.to_string(),
+                ))
+            })
+            .build();
+        let job_id = create_dummy_job(&runner).id;
+
+        runner.get_single_job(channel::dummy_sender(), |_, _| Err("nope".into()));
+        runner.wait_for_jobs().unwrap();
+
+        let expected = vec![(job_id, "Foo".to_string(), 1, "nope".to_string())];
+        assert_eq!(expected, *failures.lock().unwrap());
+    }
+
+    #[test]
+    fn throttled_job_errors_are_passed_to_the_handler_once_per_kind() {
+        let _guard = TestGuard::lock();
+
+        let failures = Arc::new(Mutex::new(Vec::new()));
+        let failures2 = Arc::clone(&failures);
+        let runner = builder()
+            .throttle_job_errors(Duration::from_secs(60 * 60))
+            .on_job_error(move |failure| {
+                failures2
+                    .lock()
+                    .unwrap()
+                    .push((failure.error.to_string(), failure.suppressed))
+            })
+            .build();
+
+        for error in &["timed out after 5s", "timed out after 30s", "nope"] {
+            create_dummy_job(&runner);
+            runner.get_single_job(channel::dummy_sender(), move |_, _| Err((*error).into()));
+            runner.wait_for_jobs().unwrap();
+        }
+
+        let expected = vec![
+            ("timed out after 5s".to_string(), 0),
+            ("nope".to_string(), 0),
+        ];
+        assert_eq!(expected, *failures.lock().unwrap());
+    }
+
+    #[test]
+    fn failure_throttles_count_the_failures_they_suppress() {
+        let throttle = FailureThrottle::new(Duration::from_millis(50));
+        let error = |message: &str| -> PerformError { message.into() };
+
+        assert_eq!(Some(0), throttle.check("Foo", &*error("job 1 failed")));
+        assert_eq!(None, throttle.check("Foo", &*error("job 2 failed")));
+        assert_eq!(None, throttle.check("Foo", &*error("job 3 failed")));
+        assert_eq!(Some(0), throttle.check("Bar", &*error("job 4 failed")));
+        thread::sleep(Duration::from_millis(60));
+        assert_eq!(Some(2), throttle.check("Foo", &*error("job 5 failed")));
+    }
+
+    #[test]
+    fn middleware_wraps_jobs_in_the_order_it_was_added() {
+        struct Recorder(&'static str, Arc<Mutex<Vec<String>>>);
+
+        impl JobMiddleware for Recorder {
+            fn before_perform(
+                &self,
+                _job: &JobMeta<'_>,
+                _conn: &PgConnection,
+            ) -> Result<(), PerformError> {
+                self.1.lock().unwrap().push(format!("{} before", self.0));
+                Ok(())
+            }
+
+            fn after_perform(&self, _job: &JobMeta<'_>, _duration: Duration) {
+                self.1.lock().unwrap().push(format!("{} after", self.0));
+            }
+
+            fn on_failure(&self, _job: &JobMeta<'_>, error: &dyn Error) {
+                let event = format!("{} failed with {}", self.0, error);
+                self.1.lock().unwrap().push(event);
+            }
+        }
+
+        let _guard = TestGuard::lock();
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let runner = builder()
+            .middleware(Recorder("outer", Arc::clone(&events)))
+            .middleware(Recorder("inner", Arc::clone(&events)))
+            .build();
+        create_dummy_job(&runner);
+
+        let events2 = Arc::clone(&events);
+        runner.get_single_job(channel::dummy_sender(), move |_, _| {
+            events2.lock().unwrap().push("job".into());
+            Err("nope".into())
+        });
+        runner.wait_for_jobs().unwrap();
+
+        let expected = vec![
+            "outer before",
+            "inner before",
+            "job",
+            "inner failed with nope",
+            "outer failed with nope",
+            "inner after",
+            "outer after",
+        ];
+        assert_eq!(expected, *events.lock().unwrap());
+    }
+
+    #[test]
+    fn custom_fetchers_choose_the_next_job() {
+        struct NewestFirst;
+
+        impl FetchQuery for NewestFirst {
+            fn fetch(
+                &self,
+                conn: &PgConnection,
+                request: &FetchRequest<'_>,
+            ) -> QueryResult<Option<storage::BackgroundJob>> {
+                background_jobs
+                    .select((id, job_type, data, priority, queue, metadata, retries))
+                    .filter(request.filter())
+                    .order(id.desc())
+                    .for_update()
+                    .skip_locked()
+                    .first(conn)
+                    .optional()
+            }
+        }
+
+        let _guard = TestGuard::lock();
+
+        let runner = builder()
+            .fetcher(NewestFirst)
+            .job_filter(|job| *job.data != serde_json::json!("rejected"))
+            .build();
+        create_dummy_job(&runner);
+        let expected_job_id = create_dummy_job(&runner).id;
+        ::diesel::insert_into(background_jobs)
+            .values((job_type.eq("Foo"), data.eq(serde_json::json!("rejected"))))
+            .execute(&*runner.connection().unwrap())
+            .unwrap();
+
+        let fetched = Arc::new(Mutex::new(None));
+        let fetched2 = Arc::clone(&fetched);
+        runner.get_single_job(channel::dummy_sender(), move |job, _| {
+            *fetched2.lock().unwrap() = Some(job.id);
+            Ok(())
+        });
+        runner.wait_for_jobs().unwrap();
+
+        assert_eq!(Some(expected_job_id), *fetched.lock().unwrap());
+    }
+
+    #[test]
+    fn settings_given_after_a_profile_take_precedence() {
+        let builder = builder()
+            .profile(Profile::Batch)
+            .thread_count(3)
+            .max_batch_size(10)
+            .early_execution_slack(Duration::from_secs(5));
+
+        assert_eq!(3, builder.get_thread_count());
+        assert_eq!(10, builder.max_batch_size);
+        assert_eq!(Some(Duration::from_secs(60)), builder.job_start_timeout);
+        assert_eq!(Some(Duration::from_secs(30)), builder.poll_interval);
+        assert_eq!(
+            Duration::from_secs(5),
+            builder.fetch_options.early_execution_slack
+        );
+        assert_eq!(
+            Some(Duration::from_secs(5 * 60)),
+            builder.max_fetch_error_backoff
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "notify")]
+    fn listeners_wake_up_when_a_job_is_enqueued() {
+        let _guard = TestGuard::lock();
+
+        let runner = runner();
+        let database_url = dotenv::var("TEST_DATABASE_URL").unwrap();
+        let listener = Listener::new(database_url);
+        // Connects and starts listening
+        listener.wait(Duration::from_millis(1), None).unwrap();
+        create_dummy_job(&runner);
+
+        let started = Instant::now();
+        assert!(listener.wait(Duration::from_secs(30), None).unwrap());
+        assert!(started.elapsed() < Duration::from_secs(10));
+    }
+
+    #[test]
+    #[cfg(feature = "tokio")]
+    fn async_runners_run_pending_jobs() {
+        let _guard = TestGuard::lock();
+
+        create_dummy_job(&runner());
+        let runner = builder().build_async();
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            runner.run_all_pending_jobs().await.unwrap();
+            // The job's type isn't registered, so it fails
+            assert_eq!(Err(JobsFailed(1)), runner.check_for_failed_jobs().await);
+        });
+    }
+
+    #[test]
+    fn run_forever_returns_once_the_runner_is_shut_down() {
+        let _guard = TestGuard::lock();
+
+        let runner = runner();
+        let handle = runner.shutdown_handle();
+        let shutdown = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(100));
+            handle.shutdown();
+        });
+
+        let started = Instant::now();
+        runner.run_forever(Duration::from_secs(60));
+        assert!(started.elapsed() < Duration::from_secs(10));
+        assert!(runner.shutdown_handle().is_shutdown());
+        shutdown.join().unwrap();
+    }
+
+    #[test]
+    fn queued_tasks_do_not_claim_jobs_after_shutdown() {
+        let _guard = TestGuard::lock();
+
+        let runner = builder().thread_count(1).build();
+        let first_job_id = create_dummy_job(&runner).id;
+        let second_job_id = create_dummy_job(&runner).id;
+        let started = Arc::new(Barrier::new(2));
+        let finish = Arc::new(Barrier::new(2));
+        let (job_started, job_finish) = (Arc::clone(&started), Arc::clone(&finish));
+
+        runner.get_single_job(channel::dummy_sender(), move |job, _| {
+            assert_eq!(first_job_id, job.id);
+            job_started.wait();
+            job_finish.wait();
+            Ok(())
+        });
+        started.wait();
+        // Queued behind the running job, since the runner has one thread
+        runner.get_single_job(channel::dummy_sender(), |_, _| {
+            panic!("A job was claimed after shutdown")
+        });
+        runner.shutdown_handle().shutdown();
+        finish.wait();
+        runner.wait_for_jobs().unwrap();
+
+        let conn = runner.connection().unwrap();
+        let remaining = background_jobs
+            .select(id)
+            .for_update()
+            .skip_locked()
+            .load::<i64>(&*conn)
+            .unwrap();
+        assert_eq!(vec![second_job_id], remaining);
+    }
+
+    #[test]
+    fn jobs_running_after_the_soft_shutdown_timeout_are_abandoned() {
+        let _guard = TestGuard::lock();
+
+        let runner = builder()
+            .soft_shutdown_timeout(Duration::from_millis(50))
+            .build();
+        let job_id = create_dummy_job(&runner).id;
+        let started = Arc::new(Barrier::new(2));
+        let finish = Arc::new(Barrier::new(2));
+        let (job_started, job_finish) = (Arc::clone(&started), Arc::clone(&finish));
+        runner.get_single_job(channel::dummy_sender(), move |_, _| {
+            job_started.wait();
+            job_finish.wait();
+            Ok(())
+        });
+
+        started.wait();
+        runner.shutdown_handle().shutdown();
+        let report = runner.drain();
+        assert_eq!(vec![job_id], report.abandoned_jobs);
+
+        finish.wait();
+        runner.wait_for_jobs().unwrap();
+        assert!(runner.drain().is_clean());
+    }
+
+    #[test]
+    fn run_until_keeps_polling_until_told_to_stop() {
+        let _guard = TestGuard::lock();
+
+        let runner = runner();
+        let job_id = create_dummy_job(&runner).id;
+        let polls = Cell::new(0);
+
+        runner.run_until(Duration::from_millis(10), || {
+            polls.set(polls.get() + 1);
+            polls.get() > 10
+        });
+        runner.wait_for_jobs().unwrap();
+
+        // The job has no registered type, so it failed and is waiting for
+        // its retry rather than being run on every poll
+        let tries = background_jobs
+            .find(job_id)
+            .select(retries)
+            .first::<i32>(&*runner.connection().unwrap())
+            .unwrap();
+        assert_eq!(1, tries);
+    }
+
+    #[test]
+    fn plugins_configure_the_runner_and_run_their_maintenance() {
+        struct RecordingPlugin(Arc<Mutex<Vec<String>>>);
+
+        impl RunnerPlugin for RecordingPlugin {
+            fn name(&self) -> &str {
+                "recording"
+            }
+
+            fn configure<E, P>(&self, builder: crate::Builder<E, P>) -> crate::Builder<E, P> {
+                let events = Arc::clone(&self.0);
+                builder.on_job_error(move |failure| {
+                    events
+                        .lock()
+                        .unwrap()
+                        .push(format!("job {} failed", failure.id));
+                })
+            }
+
+            fn maintenance_interval(&self) -> Option<Duration> {
+                Some(Duration::from_millis(10))
+            }
+
+            fn maintenance(&self, pool: &dyn DieselPoolObj) -> Result<(), PerformError> {
+                pool.get()?;
+                self.0.lock().unwrap().push("maintenance".into());
+                Ok(())
+            }
+        }
+
+        let _guard = TestGuard::lock();
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let runner = builder()
+            .plugin(RecordingPlugin(Arc::clone(&events)))
+            .build();
+        let job_id = create_dummy_job(&runner).id;
+        let shutdown = runner.shutdown_handle();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(200));
+            shutdown.shutdown();
+        });
+        runner.run_forever(Duration::from_millis(10));
+        runner.wait_for_jobs().unwrap();
+
+        let events = events.lock().unwrap();
+        assert!(events.contains(&format!("job {} failed", job_id)));
+        assert!(events.contains(&"maintenance".to_string()));
+    }
+
+    #[test]
+    fn debug_mode_is_enabled_per_job_type_and_cached() {
+        let _guard = TestGuard::lock();
+
+        let conn = runner().connection().unwrap();
+        crate::admin::enable_debug_logging(&conn, "Foo", Duration::from_secs(60)).unwrap();
+        let debug_job_types = DebugJobTypes::default();
+
+        assert!(debug_job_types.contains(&conn, "Foo"));
+        assert!(!debug_job_types.contains(&conn, "Bar"));
+
+        assert_eq!(Ok(true), crate::admin::disable_debug_logging(&conn, "Foo"));
+        assert!(debug_job_types.contains(&conn, "Foo"));
+        assert!(!DebugJobTypes::default().contains(&conn, "Foo"));
+    }
+
     lazy_static::lazy_static! {
         // Since these tests deal with behavior concerning multiple connections
         // running concurrently, they have to run outside of a transaction.
@@ -464,28 +2768,34 @@ mod tests {
 
     impl<'a> Drop for TestGuard<'a> {
         fn drop(&mut self) {
-            ::diesel::sql_query("TRUNCATE TABLE background_jobs")
-                .execute(&*runner().connection().unwrap())
-                .unwrap();
+            ::diesel::sql_query(
+                "TRUNCATE TABLE background_jobs, background_job_checkpoints, \
+                 swirl_debug_job_types, swirl_supersede_keys, swirl_job_dependencies",
+            )
+            .execute(&*runner().connection().unwrap())
+            .unwrap();
         }
     }
 
     type Runner<Env> = crate::Runner<Env, r2d2::Pool<r2d2::ConnectionManager<PgConnection>>>;
 
     fn runner() -> Runner<()> {
+        builder().build()
+    }
+
+    fn builder() -> crate::Builder<(), R2d2Builder> {
         let database_url =
             dotenv::var("TEST_DATABASE_URL").expect("TEST_DATABASE_URL must be set to run tests");
 
         crate::Runner::builder(())
             .database_url(database_url)
             .thread_count(2)
-            .build()
     }
 
     fn create_dummy_job(runner: &Runner<()>) -> storage::BackgroundJob {
         ::diesel::insert_into(background_jobs)
             .values((job_type.eq("Foo"), data.eq(serde_json::json!(null))))
-            .returning((id, job_type, data))
+            .returning((id, job_type, data, priority, queue, metadata, retries))
             .get_result(&*runner.connection().unwrap())
             .unwrap()
     }
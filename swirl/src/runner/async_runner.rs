@@ -0,0 +1,177 @@
+use std::error::Error;
+use std::panic::{resume_unwind, RefUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+use tokio::task::{spawn_blocking, JoinError};
+use tokio::time::timeout;
+
+use super::channel;
+use super::event::*;
+use super::{Runner, ShutdownHandle};
+use crate::db::DieselPool;
+use crate::errors::*;
+use crate::storage;
+
+/// A runner which runs jobs as Tokio tasks, for applications which already
+/// have a runtime. Built with [`Builder::build_async`](crate::Builder::build_async).
+///
+/// Jobs and the queries which fetch them still block, so each one is run with
+/// `tokio::task::spawn_blocking`. At most
+/// [`thread_count`](crate::Builder::thread_count) jobs run at once. No
+/// threads are started besides Tokio's own.
+///
+/// Requires the `tokio` feature.
+#[allow(missing_debug_implementations)]
+pub struct AsyncRunner<Env: 'static, ConnectionPool> {
+    runner: Runner<Env, ConnectionPool>,
+    running_jobs: Arc<Semaphore>,
+    panic_count: Arc<AtomicUsize>,
+}
+
+impl<Env, ConnectionPool> AsyncRunner<Env, ConnectionPool> {
+    pub(super) fn new(runner: Runner<Env, ConnectionPool>) -> Self {
+        Self {
+            running_jobs: Arc::new(Semaphore::new(runner.thread_count)),
+            panic_count: Arc::default(),
+            runner,
+        }
+    }
+
+    #[doc(hidden)]
+    /// For use in integration tests
+    pub fn connection_pool(&self) -> &ConnectionPool {
+        &self.runner.connection_pool
+    }
+
+    /// A handle which can be used to stop this runner from another task
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.runner.shutdown_handle()
+    }
+}
+
+impl<Env, ConnectionPool> AsyncRunner<Env, ConnectionPool>
+where
+    Env: RefUnwindSafe + Send + Sync + 'static,
+    ConnectionPool: DieselPool + 'static,
+{
+    /// Runs all pending jobs in the queue
+    ///
+    /// This behaves like [`Runner::run_all_pending_jobs`]. It completes once
+    /// all jobs in the queue have begun running, but does not wait for them
+    /// to complete.
+    pub async fn run_all_pending_jobs(&self) -> Result<(), FetchError> {
+        use std::cmp::max;
+
+        let pool = self.runner.connection_pool.clone();
+        let worker = Arc::clone(&self.runner.worker);
+        let fetch_options = Arc::clone(&self.runner.fetch_options);
+        unwrap_or_resume(spawn_blocking(move || worker.heartbeat(&pool, &fetch_options)).await);
+
+        let max_tasks = self.runner.thread_count;
+        let (sender, mut receiver) = channel::new_async(max_tasks);
+        let mut pending_messages = 0;
+        loop {
+            if self.runner.shutdown.is_shutdown() {
+                return Ok(());
+            }
+            let available_tasks = self.running_jobs.available_permits();
+
+            let jobs_to_queue = if pending_messages == 0 {
+                // If we have no queued jobs talking to us, and there are no
+                // available tasks, we still need to queue at least one job
+                // or we'll never receive a message
+                max(available_tasks, 1)
+            } else {
+                available_tasks
+            };
+
+            for _ in 0..jobs_to_queue {
+                self.run_single_job(sender.clone()).await;
+            }
+
+            pending_messages += jobs_to_queue;
+            match timeout(self.runner.job_start_timeout, receiver.recv()).await {
+                Ok(Some(Event::Working)) => pending_messages -= 1,
+                Ok(Some(Event::NoJobAvailable)) => return Ok(()),
+                Ok(Some(Event::ErrorLoadingJob(e))) => {
+                    return Err(FetchError::FailedLoadingJob(e, self.fetch_context()));
+                }
+                Ok(Some(Event::FailedToAcquireConnection(e))) => {
+                    let context = self.fetch_context();
+                    return Err(FetchError::NoDatabaseConnection(e.into(), context));
+                }
+                Ok(None) | Err(_) => {
+                    return Err(FetchError::NoMessageReceived(self.fetch_context()))
+                }
+            }
+        }
+    }
+
+    /// Describes the runner for a [`FetchError`]
+    fn fetch_context(&self) -> FetchContext {
+        let available_tasks = self.running_jobs.available_permits();
+        let busy_tasks = self.runner.thread_count.saturating_sub(available_tasks);
+        self.runner.fetch_context(busy_tasks)
+    }
+
+    /// Waits until fewer than `thread_count` jobs are running, then starts a
+    /// task which runs the next job
+    async fn run_single_job(&self, sender: EventSender<ConnectionPool>) {
+        let permit = Arc::clone(&self.running_jobs)
+            .acquire_owned()
+            .await
+            .expect("The semaphore is never closed");
+        let task = self.runner.job_task(sender, self.runner.perform_job());
+        let panic_count = Arc::clone(&self.panic_count);
+        tokio::spawn(async move {
+            if spawn_blocking(task).await.is_err() {
+                panic_count.fetch_add(1, Ordering::SeqCst);
+            }
+            drop(permit);
+        });
+    }
+
+    /// Waits for all running jobs to complete, and returns an error if any
+    /// failed
+    ///
+    /// This behaves like [`Runner::check_for_failed_jobs`], and is intended
+    /// for use in tests.
+    pub async fn check_for_failed_jobs(&self) -> Result<(), FailedJobsError> {
+        self.wait_for_jobs().await?;
+        let pool = self.runner.connection_pool.clone();
+        let failed_jobs = unwrap_or_resume(
+            spawn_blocking(move || -> Result<_, Box<dyn Error + Send + Sync>> {
+                Ok(storage::failed_job_count(&*pool.get()?)?)
+            })
+            .await,
+        )?;
+        if failed_jobs == 0 {
+            Ok(())
+        } else {
+            Err(JobsFailed(failed_jobs))
+        }
+    }
+
+    async fn wait_for_jobs(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let _all_jobs = self
+            .running_jobs
+            .acquire_many(self.runner.thread_count as u32)
+            .await
+            .expect("The semaphore is never closed");
+        let panic_count = self.panic_count.load(Ordering::SeqCst);
+        if panic_count == 0 {
+            Ok(())
+        } else {
+            Err(format!("{} tasks panicked", panic_count).into())
+        }
+    }
+}
+
+/// Returns the result of a blocking task, or continues its panic
+fn unwrap_or_resume<T>(result: Result<T, JoinError>) -> T {
+    match result {
+        Ok(t) => t,
+        Err(e) => resume_unwind(e.into_panic()),
+    }
+}
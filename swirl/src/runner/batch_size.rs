@@ -0,0 +1,59 @@
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// How long a batch of jobs should take to run. Every job in a batch stays
+/// locked until the whole batch has finished, and is run again if the runner
+/// crashes before then.
+const TARGET_BATCH_DURATION: Duration = Duration::from_millis(100);
+
+/// How far each job's duration moves the average towards it
+const SMOOTHING: f64 = 0.2;
+
+/// Picks how many jobs a thread claims before committing, from the average
+/// duration of the jobs it has run recently
+pub(super) struct BatchSizer {
+    max: usize,
+    average_seconds: Mutex<Option<f64>>,
+}
+
+impl BatchSizer {
+    pub(super) fn new(max: usize) -> Self {
+        Self {
+            max: max.max(1),
+            average_seconds: Mutex::new(None),
+        }
+    }
+
+    /// Records how long a job took to run
+    pub(super) fn record(&self, duration: Duration) {
+        if self.max == 1 {
+            return;
+        }
+        let seconds = duration.as_secs_f64();
+        let mut average = self
+            .average_seconds
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        *average = Some(match *average {
+            Some(average) => average + SMOOTHING * (seconds - average),
+            None => seconds,
+        });
+    }
+
+    /// The number of jobs the next batch should claim. This is 1 until a job
+    /// has finished, since nothing is known about how long jobs take.
+    pub(super) fn batch_size(&self) -> usize {
+        let average = self
+            .average_seconds
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        match *average {
+            Some(average) if average > 0.0 => {
+                let size = TARGET_BATCH_DURATION.as_secs_f64() / average;
+                (size as usize).max(1).min(self.max)
+            }
+            Some(_) => self.max,
+            None => 1,
+        }
+    }
+}
@@ -1,12 +1,23 @@
 //! A wrapper around a `std::sync::mpsc::sync_channel` that allows easy creation
-//! of a dummy sender for tests, and doesn't error if the receiver hung up
+//! of a dummy sender for tests, and doesn't error if the receiver hung up.
+//!
+//! With the `tokio` feature the sender can also wrap a Tokio channel, so the
+//! same jobs can report to an `AsyncRunner`.
 
 pub use std::sync::mpsc::Receiver;
 use std::sync::mpsc::{sync_channel, SyncSender};
 
 pub fn new<T>(size: usize) -> (Sender<T>, Receiver<T>) {
     let (std_sender, std_receiver) = sync_channel(size);
-    (Sender(std_sender), std_receiver)
+    (Sender(Inner::Std(std_sender)), std_receiver)
+}
+
+/// A channel whose receiver can be awaited. The sender blocks, so it must
+/// only be used outside of the runtime, such as from `spawn_blocking`.
+#[cfg(feature = "tokio")]
+pub fn new_async<T>(size: usize) -> (Sender<T>, tokio::sync::mpsc::Receiver<T>) {
+    let (tokio_sender, tokio_receiver) = tokio::sync::mpsc::channel(size);
+    (Sender(Inner::Tokio(tokio_sender)), tokio_receiver)
 }
 
 #[cfg(test)]
@@ -14,19 +25,34 @@ pub fn dummy_sender<T>() -> Sender<T> {
     new(1).0
 }
 
-pub struct Sender<T>(SyncSender<T>);
+pub struct Sender<T>(Inner<T>);
+
+enum Inner<T> {
+    Std(SyncSender<T>),
+    #[cfg(feature = "tokio")]
+    Tokio(tokio::sync::mpsc::Sender<T>),
+}
 
 impl<T> Sender<T> {
     pub fn send(&self, t: T) {
-        let _ = self.0.send(t);
+        match &self.0 {
+            Inner::Std(sender) => {
+                let _ = sender.send(t);
+            }
+            #[cfg(feature = "tokio")]
+            Inner::Tokio(sender) => {
+                let _ = sender.blocking_send(t);
+            }
+        }
     }
 }
 
-impl<T> Clone for Sender<T>
-where
-    SyncSender<T>: Clone,
-{
+impl<T> Clone for Sender<T> {
     fn clone(&self) -> Self {
-        Self(self.0.clone())
+        match &self.0 {
+            Inner::Std(sender) => Self(Inner::Std(sender.clone())),
+            #[cfg(feature = "tokio")]
+            Inner::Tokio(sender) => Self(Inner::Tokio(sender.clone())),
+        }
     }
 }
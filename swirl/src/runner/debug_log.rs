@@ -0,0 +1,92 @@
+use diesel::prelude::*;
+use std::collections::HashSet;
+use std::fmt::Display;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::storage::{self, BackgroundJob};
+
+/// How long the set of job types in debug mode is cached before it is loaded
+/// from the database again
+const REFRESH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The job types which have been put into debug mode with
+/// [`admin::enable_debug_logging`](crate::admin::enable_debug_logging).
+///
+/// This is cached so that `swirl_debug_job_types` isn't queried for every job,
+/// which means changes take up to [`REFRESH_INTERVAL`] to be noticed.
+#[derive(Default)]
+pub(super) struct DebugJobTypes {
+    cached: Mutex<Option<(Instant, HashSet<String>)>>,
+}
+
+impl DebugJobTypes {
+    /// Whether jobs of type `job_type` should be logged verbosely. If the job
+    /// types can't be loaded, the previously loaded set is used.
+    pub(super) fn contains(&self, conn: &PgConnection, job_type: &str) -> bool {
+        let mut cached = self.cached.lock().unwrap_or_else(|e| e.into_inner());
+        let is_stale = cached.as_ref().map_or(true, |(loaded_at, _)| {
+            loaded_at.elapsed() >= REFRESH_INTERVAL
+        });
+        if is_stale {
+            // Loaded in a savepoint, so that an error here doesn't abort the
+            // transaction the job is locked in
+            match conn.transaction(|| storage::debug_job_types(conn)) {
+                Ok(types) => *cached = Some((Instant::now(), types.into_iter().collect())),
+                Err(e) => eprintln!("Failed to load job types in debug mode: {}", e),
+            }
+        }
+        cached
+            .as_ref()
+            .map_or(false, |(_, types)| types.contains(job_type))
+    }
+}
+
+/// Writes verbose information about a run of a job in debug mode to stderr,
+/// including its arguments and metadata
+pub(super) struct DebugLog {
+    job_id: i64,
+    job_type: String,
+    started_at: Instant,
+}
+
+impl DebugLog {
+    pub(super) fn start(job: &BackgroundJob) -> Self {
+        eprintln!(
+            "[debug] Job {} ({}) started on queue {}, attempt {}",
+            job.id,
+            job.job_type,
+            job.queue,
+            job.retries + 1,
+        );
+        eprintln!("[debug] Job {} arguments: {}", job.id, job.data);
+        eprintln!("[debug] Job {} metadata: {}", job.id, job.metadata);
+        Self {
+            job_id: job.id,
+            job_type: job.job_type.clone(),
+            started_at: Instant::now(),
+        }
+    }
+
+    pub(super) fn succeeded(&self) {
+        self.finished("succeeded");
+    }
+
+    pub(super) fn yielded(&self) {
+        self.finished("yielded");
+    }
+
+    pub(super) fn failed(&self, error: &dyn Display) {
+        self.finished(&format!("failed with {:?}", error.to_string()));
+    }
+
+    fn finished(&self, outcome: &str) {
+        eprintln!(
+            "[debug] Job {} ({}) {} after {}ms",
+            self.job_id,
+            self.job_type,
+            outcome,
+            self.started_at.elapsed().as_millis(),
+        );
+    }
+}
@@ -0,0 +1,72 @@
+use std::fmt::Display;
+use std::time::Instant;
+use tracing::span::Entered;
+use tracing::{field, Span};
+
+use crate::storage::BackgroundJob;
+
+/// Wraps the run of a job in a `tracing` span named `swirl_job`.
+///
+/// The span has the fields `job_id`, `job_type`, `queue` and `retries`, and
+/// `owner` for jobs with an [`OWNER`](crate::Job::OWNER). An event is emitted
+/// in the span when the job succeeds, fails or yields, with `duration_ms`.
+/// Failures are emitted at the `ERROR` level with the `error` field, and the
+/// other events at the `INFO` level.
+pub(super) struct JobSpan {
+    span: Span,
+    started_at: Instant,
+}
+
+impl JobSpan {
+    pub(super) fn start(job: &BackgroundJob, owner: Option<&'static str>) -> Self {
+        let span = tracing::info_span!(
+            "swirl_job",
+            job_id = job.id,
+            job_type = %job.job_type,
+            queue = %job.queue,
+            retries = job.retries,
+            owner = field::Empty,
+        );
+        if let Some(owner) = owner {
+            span.record("owner", &owner);
+        }
+        Self {
+            span,
+            started_at: Instant::now(),
+        }
+    }
+
+    /// Enters the span, so that events emitted by the job are recorded in it
+    pub(super) fn enter(&self) -> Entered<'_> {
+        self.span.enter()
+    }
+
+    pub(super) fn succeeded(&self) {
+        tracing::info!(
+            parent: &self.span,
+            duration_ms = self.duration_ms(),
+            "job succeeded"
+        );
+    }
+
+    pub(super) fn yielded(&self) {
+        tracing::info!(
+            parent: &self.span,
+            duration_ms = self.duration_ms(),
+            "job yielded"
+        );
+    }
+
+    pub(super) fn failed(&self, error: &dyn Display) {
+        tracing::error!(
+            parent: &self.span,
+            duration_ms = self.duration_ms(),
+            error = %error,
+            "job failed"
+        );
+    }
+
+    fn duration_ms(&self) -> u64 {
+        self.started_at.elapsed().as_millis() as u64
+    }
+}
@@ -0,0 +1,70 @@
+use serde_json::{json, Value};
+use std::fmt::Display;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use crate::storage::BackgroundJob;
+
+/// Writes the lifecycle events of a job to stdout, as one JSON object per line.
+///
+/// Every line has the fields `timestamp` (in seconds since the Unix epoch),
+/// `event`, `job_id`, `job_type` and `queue`. The events are `job_started`,
+/// `job_succeeded`, `job_failed` and `job_yielded`. All events other than
+/// `job_started` also have `duration_ms`, and `job_failed` has `error`. Jobs
+/// with an [`OWNER`](crate::Job::OWNER) have `owner` on every line.
+pub(super) struct JsonLog {
+    job_id: i64,
+    job_type: String,
+    queue: String,
+    owner: Option<&'static str>,
+    started_at: Instant,
+}
+
+impl JsonLog {
+    pub(super) fn start(job: &BackgroundJob, owner: Option<&'static str>) -> Self {
+        let log = Self {
+            job_id: job.id,
+            job_type: job.job_type.clone(),
+            queue: job.queue.clone(),
+            owner,
+            started_at: Instant::now(),
+        };
+        log.emit("job_started", None);
+        log
+    }
+
+    pub(super) fn succeeded(&self) {
+        self.emit("job_succeeded", None);
+    }
+
+    pub(super) fn yielded(&self) {
+        self.emit("job_yielded", None);
+    }
+
+    pub(super) fn failed(&self, error: &dyn Display) {
+        self.emit("job_failed", Some(error.to_string()));
+    }
+
+    fn emit(&self, event: &str, error: Option<String>) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs_f64())
+            .unwrap_or_default();
+        let mut line = json!({
+            "timestamp": timestamp,
+            "event": event,
+            "job_id": self.job_id,
+            "job_type": self.job_type,
+            "queue": self.queue,
+        });
+        if let Some(owner) = self.owner {
+            line["owner"] = Value::from(owner);
+        }
+        if event != "job_started" {
+            line["duration_ms"] = Value::from(self.started_at.elapsed().as_millis() as u64);
+        }
+        if let Some(error) = error {
+            line["error"] = Value::from(error);
+        }
+        println!("{}", line);
+    }
+}
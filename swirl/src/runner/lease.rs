@@ -0,0 +1,177 @@
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::SHUTDOWN_CHECK_INTERVAL;
+use crate::db::DieselPool;
+use crate::storage;
+
+#[derive(Default)]
+struct State {
+    /// The ids of the jobs this runner holds a lease on, and of the worker
+    /// holding each lease
+    held: HashMap<i64, i64>,
+    /// Jobs whose lease was reclaimed by another runner while they ran
+    lost: HashSet<i64>,
+    started: bool,
+}
+
+/// The leases on jobs being run by this runner, which are renewed until the
+/// jobs finish.
+///
+/// A job's lease is kept in `swirl_job_leases`, rather than on its row, since
+/// the row is locked by the transaction the job runs in. Another runner which
+/// finds an expired lease terminates the connection holding the job's lock, so
+/// the job can be run again.
+pub(super) struct Leases {
+    duration: Duration,
+    state: Mutex<State>,
+}
+
+impl Leases {
+    pub(super) fn new(duration: Duration) -> Self {
+        Self {
+            duration,
+            state: Mutex::default(),
+        }
+    }
+
+    /// Takes out a lease on the job with the id `job_id` for the worker with
+    /// the id `worker_id`, whose lock is held by the backend `backend_pid`.
+    /// The thread renewing leases is started the first time this is called.
+    ///
+    /// The lease is written from another connection, since the job's own
+    /// transaction isn't visible to other runners. If that fails, the job is
+    /// run without a lease.
+    pub(super) fn acquire<Pool>(
+        self: &Arc<Self>,
+        pool: &Pool,
+        worker_id: i64,
+        job_id: i64,
+        backend_pid: i32,
+    ) where
+        Pool: DieselPool + 'static,
+    {
+        {
+            let mut state = self.lock();
+            if !state.started {
+                state.started = true;
+                let leases = Arc::downgrade(self);
+                let pool = pool.clone();
+                thread::spawn(move || run(leases, pool));
+            }
+        }
+        let acquire = || -> Result<(), Box<dyn Error>> {
+            let conn = pool.get()?;
+            storage::acquire_lease(&conn, job_id, worker_id, backend_pid, self.duration)?;
+            Ok(())
+        };
+        match acquire() {
+            Ok(()) => {
+                self.lock().held.insert(job_id, worker_id);
+            }
+            Err(e) => eprintln!("Failed to acquire a lease on job {}: {}", job_id, e),
+        }
+    }
+
+    /// Releases the leases on `job_ids` once their transaction has ended.
+    /// Returns `true` if any of them were reclaimed by another runner, in
+    /// which case their connection has been terminated.
+    pub(super) fn release<Pool: DieselPool>(&self, pool: &Pool, job_ids: &[i64]) -> bool {
+        let mut held = Vec::new();
+        let mut lost = false;
+        {
+            let mut state = self.lock();
+            for &job_id in job_ids {
+                lost |= state.lost.remove(&job_id);
+                if let Some(worker_id) = state.held.remove(&job_id) {
+                    held.push((job_id, worker_id));
+                }
+            }
+        }
+        if held.is_empty() {
+            return lost;
+        }
+        let conn = match pool.get() {
+            Ok(conn) => conn,
+            // The leases will expire, but the jobs are no longer locked
+            Err(_) => return lost,
+        };
+        for (worker_id, job_ids) in group_by_worker(&held) {
+            match storage::release_leases(&conn, &job_ids, worker_id) {
+                Ok(released) => lost |= released.len() < job_ids.len(),
+                Err(e) => eprintln!("Failed to release job leases: {}", e),
+            }
+        }
+        lost
+    }
+
+    /// Renews the leases held by this runner, and reclaims the jobs of any
+    /// other runner whose leases have expired
+    fn renew<Pool: DieselPool>(&self, pool: &Pool) -> Result<(), Box<dyn Error>> {
+        let held = self.lock().held.clone().into_iter().collect::<Vec<_>>();
+        let conn = pool.get()?;
+        for (worker_id, job_ids) in group_by_worker(&held) {
+            let renewed = storage::renew_leases(&conn, &job_ids, worker_id, self.duration)?;
+            let mut state = self.lock();
+            for job_id in job_ids.into_iter().filter(|id| !renewed.contains(id)) {
+                // The job may have finished since the leases were read
+                if state.held.get(&job_id) == Some(&worker_id) {
+                    state.held.remove(&job_id);
+                    eprintln!(
+                        "The lease on job {} was reclaimed by another runner",
+                        job_id
+                    );
+                    state.lost.insert(job_id);
+                }
+            }
+        }
+
+        let reclaimed = storage::reclaim_expired_leases(&conn)?;
+        if reclaimed > 0 {
+            eprintln!(
+                "Terminated {} connections holding jobs whose lease expired",
+                reclaimed
+            );
+        }
+        Ok(())
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Groups leases by the worker holding them. A runner only changes its
+/// worker id if it has to register again, so there is almost always one group.
+fn group_by_worker(leases: &[(i64, i64)]) -> Vec<(i64, Vec<i64>)> {
+    let mut groups = Vec::<(i64, Vec<i64>)>::new();
+    for &(job_id, worker_id) in leases {
+        match groups.iter_mut().find(|(id, _)| *id == worker_id) {
+            Some((_, job_ids)) => job_ids.push(job_id),
+            None => groups.push((worker_id, vec![job_id])),
+        }
+    }
+    groups
+}
+
+/// Renews leases a few times per lease duration until the runner is dropped
+fn run<Pool: DieselPool>(leases: Weak<Leases>, pool: Pool) {
+    let mut last_renewed = Instant::now();
+    loop {
+        thread::sleep(SHUTDOWN_CHECK_INTERVAL);
+        let leases = match leases.upgrade() {
+            Some(leases) => leases,
+            None => return,
+        };
+        if last_renewed.elapsed() < leases.duration / 3 {
+            continue;
+        }
+        last_renewed = Instant::now();
+        if let Err(e) = leases.renew(&pool) {
+            eprintln!("Failed to renew job leases: {}", e);
+        }
+    }
+}
@@ -0,0 +1,86 @@
+use postgres::fallible_iterator::FallibleIterator;
+use postgres::{Client, NoTls};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// The channel a notification is sent on whenever a job is enqueued. The
+/// payload is the queue the job was placed in.
+const CHANNEL: &str = "swirl_jobs";
+
+/// A connection which is listening for jobs to be enqueued.
+///
+/// Diesel has no way to receive notifications, so this uses its own
+/// connection. It is established the first time it is needed, and again after
+/// any error.
+pub(super) struct Listener {
+    database_url: String,
+    client: Mutex<Option<Client>>,
+}
+
+impl Listener {
+    pub(super) fn new(database_url: String) -> Self {
+        Self {
+            database_url,
+            client: Mutex::new(None),
+        }
+    }
+
+    /// Blocks until a job is enqueued in one of `queues`, or in any queue if
+    /// `queues` is `None`, or until `timeout` has passed. Returns whether a
+    /// job was enqueued.
+    pub(super) fn wait(
+        &self,
+        timeout: Duration,
+        queues: Option<&[String]>,
+    ) -> Result<bool, postgres::Error> {
+        let mut client = self.client.lock().unwrap_or_else(|e| e.into_inner());
+        let result = Self::wait_with(&mut client, &self.database_url, timeout, queues);
+        if result.is_err() {
+            *client = None;
+        }
+        result
+    }
+
+    fn wait_with(
+        client: &mut Option<Client>,
+        database_url: &str,
+        timeout: Duration,
+        queues: Option<&[String]>,
+    ) -> Result<bool, postgres::Error> {
+        let deadline = Instant::now() + timeout;
+        if client.is_none() {
+            let mut new_client = Client::connect(database_url, NoTls)?;
+            new_client.batch_execute(&format!("LISTEN {}", CHANNEL))?;
+            *client = Some(new_client);
+        }
+        let client = client.as_mut().unwrap();
+        // Notifications carry the queue the job was enqueued in, which may be
+        // an alias of a queue we're running. Aliases are only looked up once a
+        // notification for some other queue arrives, rather than every wait.
+        let mut queue_names = None;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let payload = match client.notifications().timeout_iter(remaining).next()? {
+                Some(n) => n.payload().to_owned(),
+                None => return Ok(false),
+            };
+            let wanted = match queues {
+                None => true,
+                Some(queues) if queues.contains(&payload) => true,
+                Some(queues) => {
+                    if queue_names.is_none() {
+                        let row = client.query_one("SELECT swirl_queue_names($1)", &[&queues])?;
+                        queue_names = Some(row.get::<_, Vec<String>>(0));
+                    }
+                    queue_names.as_ref().map_or(false, |q| q.contains(&payload))
+                }
+            };
+            if wanted {
+                // Any other notifications are for jobs the next fetch will
+                // find anyway
+                while client.notifications().iter().next()?.is_some() {}
+                return Ok(true);
+            }
+        }
+    }
+}
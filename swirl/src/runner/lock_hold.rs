@@ -0,0 +1,52 @@
+use std::time::Duration;
+
+/// How long jobs held their row locks, as recorded when
+/// [`Builder::measure_lock_hold_times`] is enabled.
+///
+/// A job's lock is held from when it is fetched until its transaction
+/// commits, so this covers the time spent running the job as well as the
+/// bookkeeping afterwards. Since other runners can't pick up a job while it is
+/// locked, this is the key measure of how well the queue scales.
+///
+/// [`Builder::measure_lock_hold_times`]: crate::Builder::measure_lock_hold_times
+#[derive(Debug, Clone, Default)]
+pub struct LockHoldTimes {
+    /// Always sorted
+    samples: Vec<Duration>,
+}
+
+impl LockHoldTimes {
+    pub(super) fn new(mut samples: Vec<Duration>) -> Self {
+        samples.sort();
+        Self { samples }
+    }
+
+    /// The number of jobs which were measured
+    pub fn count(&self) -> usize {
+        self.samples.len()
+    }
+
+    /// The mean time a lock was held
+    pub fn mean(&self) -> Option<Duration> {
+        if self.samples.is_empty() {
+            return None;
+        }
+        let total = self.samples.iter().sum::<Duration>();
+        Some(total / self.samples.len() as u32)
+    }
+
+    /// The time which `percentile` percent of locks were released within.
+    /// `percentile` must be between 0 and 100.
+    pub fn percentile(&self, percentile: f64) -> Option<Duration> {
+        if self.samples.is_empty() {
+            return None;
+        }
+        let rank = (percentile / 100.0 * (self.samples.len() - 1) as f64).round() as usize;
+        self.samples.get(rank).copied()
+    }
+
+    /// The longest time a lock was held
+    pub fn max(&self) -> Option<Duration> {
+        self.samples.last().copied()
+    }
+}
@@ -0,0 +1,295 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::storage::BackgroundJob;
+
+/// The upper bounds of the buckets of [`DurationHistogram`], in seconds
+const BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 300.0,
+];
+
+/// A histogram of how long jobs took to run or to enqueue, with buckets from
+/// 5ms to 5 minutes
+#[derive(Debug, Clone, PartialEq)]
+pub struct DurationHistogram {
+    /// The number of runs which fell into each bucket, not including earlier
+    /// buckets
+    counts: Vec<u64>,
+    sum: Duration,
+    count: u64,
+}
+
+impl Default for DurationHistogram {
+    fn default() -> Self {
+        Self {
+            counts: vec![0; BUCKETS.len()],
+            sum: Duration::from_secs(0),
+            count: 0,
+        }
+    }
+}
+
+impl DurationHistogram {
+    pub(crate) fn record(&mut self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        if let Some(bucket) = BUCKETS.iter().position(|&le| seconds <= le) {
+            self.counts[bucket] += 1;
+        }
+        self.sum += duration;
+        self.count += 1;
+    }
+
+    /// The upper bound of each bucket in seconds, and the number of runs which
+    /// took at most that long
+    pub fn buckets(&self) -> impl Iterator<Item = (f64, u64)> + '_ {
+        BUCKETS
+            .iter()
+            .zip(&self.counts)
+            .scan(0, |total, (&le, &count)| {
+                *total += count;
+                Some((le, *total))
+            })
+    }
+
+    /// The total time spent running jobs
+    pub fn sum(&self) -> Duration {
+        self.sum
+    }
+
+    /// The number of runs which were measured
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+}
+
+/// The metrics of one job type, as returned by [`Runner::metrics_snapshot`].
+///
+/// The counters and durations only cover jobs run by this runner since it was
+/// built. The queue depth and oldest job cover every runner.
+///
+/// [`Runner::metrics_snapshot`]: crate::Runner::metrics_snapshot
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct JobTypeMetrics {
+    /// The [`OWNER`](crate::Job::OWNER) of the job type, if it is registered
+    /// with this runner
+    pub owner: Option<&'static str>,
+
+    /// The number of jobs which began running
+    pub started: u64,
+
+    /// The number of jobs which succeeded
+    pub succeeded: u64,
+
+    /// The number of jobs which failed, including jobs which will be retried
+    pub failed: u64,
+
+    /// The number of jobs which began running after failing at least once
+    pub retried: u64,
+
+    /// How long jobs took to run, whether they succeeded or failed
+    pub duration: DurationHistogram,
+
+    /// The number of jobs in the queue, including running jobs
+    pub queue_depth: i64,
+
+    /// How long ago the oldest job in the queue was enqueued
+    pub oldest_job_age: Option<Duration>,
+}
+
+/// The metrics of every job type, as returned by
+/// [`Runner::metrics_snapshot`](crate::Runner::metrics_snapshot)
+///
+/// Its `Display` implementation renders the metrics in Prometheus' text
+/// format, so it can be served as is from a `/metrics` endpoint.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MetricsSnapshot {
+    /// The metrics of each job type, by job type
+    pub job_types: BTreeMap<String, JobTypeMetrics>,
+}
+
+impl MetricsSnapshot {
+    fn write_metric<F>(
+        &self,
+        f: &mut fmt::Formatter,
+        name: &str,
+        kind: &str,
+        help: &str,
+        value: F,
+    ) -> fmt::Result
+    where
+        F: Fn(&JobTypeMetrics) -> String,
+    {
+        writeln!(f, "# HELP {} {}", name, help)?;
+        writeln!(f, "# TYPE {} {}", name, kind)?;
+        for (job_type, metrics) in &self.job_types {
+            writeln!(
+                f,
+                "{}{{{}}} {}",
+                name,
+                labels(job_type, metrics),
+                value(metrics)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for MetricsSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.write_metric(
+            f,
+            "swirl_jobs_started_total",
+            "counter",
+            "Jobs which began running",
+            |m| m.started.to_string(),
+        )?;
+        self.write_metric(
+            f,
+            "swirl_jobs_succeeded_total",
+            "counter",
+            "Jobs which succeeded",
+            |m| m.succeeded.to_string(),
+        )?;
+        self.write_metric(
+            f,
+            "swirl_jobs_failed_total",
+            "counter",
+            "Jobs which failed",
+            |m| m.failed.to_string(),
+        )?;
+        self.write_metric(
+            f,
+            "swirl_jobs_retried_total",
+            "counter",
+            "Jobs which began running after failing",
+            |m| m.retried.to_string(),
+        )?;
+
+        let name = "swirl_job_duration_seconds";
+        writeln!(f, "# HELP {} How long jobs took to run", name)?;
+        writeln!(f, "# TYPE {} histogram", name)?;
+        for (job_type, metrics) in &self.job_types {
+            let labels = labels(job_type, metrics);
+            for (le, count) in metrics.duration.buckets() {
+                writeln!(f, "{}_bucket{{{},le=\"{}\"}} {}", name, labels, le, count)?;
+            }
+            let count = metrics.duration.count();
+            writeln!(f, "{}_bucket{{{},le=\"+Inf\"}} {}", name, labels, count)?;
+            let sum = metrics.duration.sum().as_secs_f64();
+            writeln!(f, "{}_sum{{{}}} {}", name, labels, sum)?;
+            writeln!(f, "{}_count{{{}}} {}", name, labels, count)?;
+        }
+
+        self.write_metric(f, "swirl_queue_depth", "gauge", "Jobs in the queue", |m| {
+            m.queue_depth.to_string()
+        })?;
+        self.write_metric(
+            f,
+            "swirl_oldest_job_age_seconds",
+            "gauge",
+            "How long ago the oldest job in the queue was enqueued",
+            |m| {
+                m.oldest_job_age
+                    .unwrap_or_default()
+                    .as_secs_f64()
+                    .to_string()
+            },
+        )
+    }
+}
+
+/// The labels of a job type's metrics
+fn labels(job_type: &str, metrics: &JobTypeMetrics) -> String {
+    let mut labels = format!("job_type=\"{}\"", escape(job_type));
+    if let Some(owner) = metrics.owner {
+        labels += &format!(",owner=\"{}\"", escape(owner));
+    }
+    labels
+}
+
+/// Escapes a label value for Prometheus' text format
+pub(crate) fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// The counters and durations recorded by a runner
+#[derive(Default)]
+pub(super) struct Metrics {
+    job_types: Mutex<HashMap<String, JobTypeMetrics>>,
+}
+
+impl Metrics {
+    /// Records that `job` began running, and returns a guard which records how
+    /// it finished
+    pub(super) fn start<'a>(
+        &'a self,
+        job: &BackgroundJob,
+        owner: Option<&'static str>,
+    ) -> JobRun<'a> {
+        self.update(&job.job_type, |metrics| {
+            metrics.owner = owner;
+            metrics.started += 1;
+            if job.retries > 0 {
+                metrics.retried += 1;
+            }
+        });
+        JobRun {
+            metrics: self,
+            job_type: job.job_type.clone(),
+            started_at: Instant::now(),
+        }
+    }
+
+    /// The metrics recorded so far
+    pub(super) fn snapshot(&self) -> MetricsSnapshot {
+        let job_types = self.job_types.lock().unwrap_or_else(|e| e.into_inner());
+        MetricsSnapshot {
+            job_types: job_types
+                .iter()
+                .map(|(job_type, metrics)| (job_type.clone(), metrics.clone()))
+                .collect(),
+        }
+    }
+
+    fn update(&self, job_type: &str, f: impl FnOnce(&mut JobTypeMetrics)) {
+        let mut job_types = self.job_types.lock().unwrap_or_else(|e| e.into_inner());
+        match job_types.get_mut(job_type) {
+            Some(metrics) => f(metrics),
+            None => f(job_types.entry(job_type.into()).or_default()),
+        }
+    }
+}
+
+/// A job which is being run, created by [`Metrics::start`]
+pub(super) struct JobRun<'a> {
+    metrics: &'a Metrics,
+    job_type: String,
+    started_at: Instant,
+}
+
+impl JobRun<'_> {
+    pub(super) fn succeeded(&self) {
+        self.finished(|metrics| metrics.succeeded += 1);
+    }
+
+    pub(super) fn failed(&self) {
+        self.finished(|metrics| metrics.failed += 1);
+    }
+
+    pub(super) fn yielded(&self) {
+        self.finished(|_| {});
+    }
+
+    fn finished(&self, f: impl FnOnce(&mut JobTypeMetrics)) {
+        let duration = self.started_at.elapsed();
+        self.metrics.update(&self.job_type, |metrics| {
+            metrics.duration.record(duration);
+            f(metrics);
+        });
+    }
+}
@@ -0,0 +1,80 @@
+use std::any::Any;
+use std::panic::PanicInfo;
+use std::sync::Arc;
+use std::thread;
+
+type PayloadFormatter = Arc<dyn Fn(&(dyn Any + Send)) -> Option<String> + Send + Sync>;
+
+/// How the payload of a panicking job is turned into the job's error message
+#[derive(Clone, Default)]
+pub(super) struct PanicFormat {
+    pub(super) max_length: Option<usize>,
+    pub(super) include_thread_name: bool,
+    payload_formatters: Vec<PayloadFormatter>,
+}
+
+impl PanicFormat {
+    /// Format payloads of type `T` with `f`. Formatters added later take
+    /// precedence.
+    pub(super) fn add_payload_formatter<T, F>(&mut self, f: F)
+    where
+        T: Any,
+        F: Fn(&T) -> String + Send + Sync + 'static,
+    {
+        let formatter = move |payload: &(dyn Any + Send)| payload.downcast_ref::<T>().map(&f);
+        self.payload_formatters.insert(0, Arc::new(formatter));
+    }
+
+    /// Try to figure out what's in the box, and describe it if we can.
+    ///
+    /// The actual error type we will get from `panic::catch_unwind` is really
+    /// poorly documented. However, the `panic::set_hook` functions deal with a
+    /// `PanicInfo` type, and its payload is documented as "commonly but not
+    /// always `&'static str` or `String`". So we try any types registered with
+    /// [`Builder::panic_payload`](crate::Builder::panic_payload) followed by
+    /// those three, and give up if we didn't get one of them.
+    ///
+    /// This is called on the thread which ran the job, so that its name can be
+    /// included.
+    pub(super) fn format(&self, payload: &(dyn Any + Send + 'static)) -> String {
+        let message = self
+            .payload_formatters
+            .iter()
+            .find_map(|f| f(payload))
+            .or_else(|| {
+                if let Some(x) = payload.downcast_ref::<PanicInfo>() {
+                    Some(x.to_string())
+                } else if let Some(x) = payload.downcast_ref::<&'static str>() {
+                    Some(x.to_string())
+                } else {
+                    payload.downcast_ref::<String>().cloned()
+                }
+            });
+
+        let mut formatted = String::from("job panicked");
+        if self.include_thread_name {
+            let thread = thread::current();
+            formatted += &format!(" on thread '{}'", thread.name().unwrap_or("<unnamed>"));
+        }
+        if let Some(message) = message {
+            formatted += ": ";
+            formatted += &truncate(&message, self.max_length);
+        }
+        formatted
+    }
+}
+
+/// Shortens `message` to at most `max_length` bytes, marking that it was cut
+/// off with an ellipsis
+fn truncate(message: &str, max_length: Option<usize>) -> String {
+    match max_length {
+        Some(max_length) if message.len() > max_length => {
+            let mut end = max_length;
+            while !message.is_char_boundary(end) {
+                end -= 1;
+            }
+            format!("{}...", &message[..end])
+        }
+        _ => message.into(),
+    }
+}
@@ -0,0 +1,70 @@
+use std::time::Duration;
+
+use super::Builder;
+use crate::db::DieselPoolObj;
+use crate::errors::PerformError;
+#[cfg(feature = "metrics")]
+use crate::MetricsSnapshot;
+
+/// An extension to a runner, added with [`Builder::plugin`].
+///
+/// This lets a crate which integrates swirl with something else, such as an
+/// error tracker, a metrics system or a scheduler, set up everything it needs
+/// from a single call, rather than asking users to call several builder
+/// methods in the right way:
+///
+/// ```ignore
+/// let runner = Runner::builder(env)
+///     .connection_pool(pool)
+///     .plugin(SentryPlugin::new(dsn))
+///     .build();
+/// ```
+///
+/// All of the hooks default to doing nothing.
+pub trait RunnerPlugin: Send + Sync + 'static {
+    /// The name of the plugin, which errors from its hooks are reported with
+    fn name(&self) -> &str;
+
+    /// Configures the runner, such as by adding middleware with
+    /// [`Builder::middleware`], registering jobs with
+    /// [`Builder::register_with`], or setting an
+    /// [`on_job_error`](Builder::on_job_error) handler.
+    ///
+    /// This is called once by [`Builder::plugin`], so settings made by
+    /// builder methods called afterwards take precedence.
+    fn configure<Env, Pool>(&self, builder: Builder<Env, Pool>) -> Builder<Env, Pool>
+    where
+        Self: Sized,
+    {
+        builder
+    }
+
+    /// How often [`maintenance`](Self::maintenance) is called while
+    /// [`Runner::run_forever`](crate::Runner::run_forever) is running.
+    ///
+    /// Defaults to `None`, which never calls it
+    fn maintenance_interval(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Periodic work which runs alongside the runner, such as enqueuing jobs
+    /// which are due or pruning old data.
+    ///
+    /// This is called on a thread of its own, once every
+    /// [`maintenance_interval`](Self::maintenance_interval) until the runner
+    /// is shut down, with the runner's connection pool. Errors are logged to
+    /// stderr, and the next call happens as usual.
+    fn maintenance(&self, _pool: &dyn DieselPoolObj) -> Result<(), PerformError> {
+        Ok(())
+    }
+
+    /// Called with the counters and durations of the jobs the runner has run
+    /// after each call to [`maintenance`](Self::maintenance), such as to
+    /// export them to a metrics system. The queue depths are not included,
+    /// use [`Runner::metrics_snapshot`](crate::Runner::metrics_snapshot) for
+    /// those.
+    ///
+    /// Requires the `metrics` feature.
+    #[cfg(feature = "metrics")]
+    fn record_metrics(&self, _snapshot: &MetricsSnapshot) {}
+}
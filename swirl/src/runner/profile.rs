@@ -0,0 +1,99 @@
+use std::time::Duration;
+
+/// Defaults for a runner which are suited to a kind of workload, applied with
+/// [`Builder::profile`](crate::Builder::profile).
+///
+/// Picking good values for the thread count and timeouts requires knowing how
+/// long jobs take and how quickly they need to start. If you don't know, pick
+/// the profile which sounds most like your application, and adjust from there.
+///
+/// The poll intervals bound how long a job waits to start without
+/// [`Builder::listen_for_jobs`](crate::Builder::listen_for_jobs), and the
+/// thread counts how many jobs can wait on I/O at once. The `swirl-profiles`
+/// example measures the throughput and start latency of each profile against
+/// your own database. On a single core with a local database, claiming 1ms
+/// jobs in batches was no faster than claiming them one at a time, so each
+/// profile sets [`Builder::max_batch_size`](crate::Builder::max_batch_size)
+/// to 1. Measure with the example before raising it for jobs which take well
+/// under a millisecond.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Profile {
+    /// Short jobs which should start as soon as possible after they are
+    /// enqueued, such as sending an email in response to a web request.
+    ///
+    /// Uses 10 threads, claims one job at a time, has a job start timeout of 5
+    /// seconds, runs scheduled jobs up to 1 second early, polls every 100
+    /// milliseconds, and waits at most 5 seconds between attempts to fetch
+    /// jobs after an error.
+    LowLatency,
+
+    /// Large numbers of jobs which should be worked through as quickly as
+    /// possible, where it doesn't matter exactly when each one starts.
+    ///
+    /// Uses 20 threads, claims one job at a time, has a job start timeout of 30
+    /// seconds, polls every second, and waits at most 30 seconds between
+    /// attempts to fetch jobs after an error. Make sure the connection pool
+    /// has room for every thread.
+    HighThroughput,
+
+    /// Infrequent, long running jobs, such as nightly reports.
+    ///
+    /// Uses 2 threads, claims one job at a time, has a job start timeout of 60
+    /// seconds, polls every 30 seconds, and waits at most 5 minutes between
+    /// attempts to fetch jobs after an error.
+    Batch,
+
+    #[doc(hidden)]
+    /// Match on `_` instead, more variants may be added in the future
+    __NonExhaustive,
+}
+
+/// The settings a [`Profile`] applies to a builder
+pub(super) struct ProfileSettings {
+    pub(super) thread_count: usize,
+    pub(super) max_batch_size: usize,
+    pub(super) job_start_timeout: Duration,
+    pub(super) early_execution_slack: Duration,
+    pub(super) poll_interval: Duration,
+    pub(super) max_fetch_error_backoff: Duration,
+}
+
+impl Profile {
+    /// The interval [`Runner::run_forever`](crate::Runner::run_forever) polls
+    /// at for this kind of workload, which
+    /// [`Builder::profile`](crate::Builder::profile) sets as the
+    /// [`Builder::poll_interval`](crate::Builder::poll_interval)
+    pub fn poll_interval(self) -> Duration {
+        self.settings().poll_interval
+    }
+
+    pub(super) fn settings(self) -> ProfileSettings {
+        match self {
+            Profile::LowLatency => ProfileSettings {
+                thread_count: 10,
+                max_batch_size: 1,
+                job_start_timeout: Duration::from_secs(5),
+                early_execution_slack: Duration::from_secs(1),
+                poll_interval: Duration::from_millis(100),
+                max_fetch_error_backoff: Duration::from_secs(5),
+            },
+            Profile::HighThroughput => ProfileSettings {
+                thread_count: 20,
+                max_batch_size: 1,
+                job_start_timeout: Duration::from_secs(30),
+                early_execution_slack: Duration::from_secs(0),
+                poll_interval: Duration::from_secs(1),
+                max_fetch_error_backoff: Duration::from_secs(30),
+            },
+            Profile::Batch => ProfileSettings {
+                thread_count: 2,
+                max_batch_size: 1,
+                job_start_timeout: Duration::from_secs(60),
+                early_execution_slack: Duration::from_secs(0),
+                poll_interval: Duration::from_secs(30),
+                max_fetch_error_backoff: Duration::from_secs(5 * 60),
+            },
+            Profile::__NonExhaustive => unreachable!(),
+        }
+    }
+}
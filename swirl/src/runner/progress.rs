@@ -0,0 +1,85 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+
+use super::SHUTDOWN_CHECK_INTERVAL;
+use crate::db::DieselPool;
+use crate::storage;
+
+#[derive(Default)]
+struct State {
+    /// The latest progress of each job which hasn't been written yet
+    pending: HashMap<i64, (u64, u64)>,
+    started: bool,
+}
+
+/// Progress reported by running jobs, which is written to
+/// `swirl_job_progress` by a thread of its own.
+///
+/// A job's row is locked by the transaction it runs in, so its progress is
+/// written from another connection, where other sessions can see it right
+/// away. Only the latest progress of each job is kept between writes, so
+/// reporting progress never waits for the database or for a connection.
+#[derive(Default)]
+pub(crate) struct ProgressWriter {
+    state: Mutex<State>,
+}
+
+impl ProgressWriter {
+    /// Starts the thread which writes progress, unless it is already running
+    pub(super) fn start<Pool>(self: &Arc<Self>, pool: &Pool)
+    where
+        Pool: DieselPool + 'static,
+    {
+        let mut state = self.lock();
+        if !state.started {
+            state.started = true;
+            let writer = Arc::downgrade(self);
+            let pool = pool.clone();
+            thread::spawn(move || run(writer, pool));
+        }
+    }
+
+    /// Records that the job with the id `job_id` has done `current` out of
+    /// `total` units of work
+    pub(crate) fn report(&self, job_id: i64, current: u64, total: u64) {
+        self.lock().pending.insert(job_id, (current, total));
+    }
+
+    /// Discards progress which hasn't been written yet for a job which has
+    /// finished running
+    pub(super) fn finished(&self, job_id: i64) {
+        self.lock().pending.remove(&job_id);
+    }
+
+    fn write<Pool: DieselPool>(&self, pool: &Pool) -> Result<(), Box<dyn Error>> {
+        let pending = std::mem::take(&mut self.lock().pending);
+        if pending.is_empty() {
+            return Ok(());
+        }
+        let conn = pool.get()?;
+        for (job_id, (current, total)) in pending {
+            storage::save_progress(&conn, job_id, current, total)?;
+        }
+        Ok(())
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Writes reported progress every so often until the runner is dropped
+fn run<Pool: DieselPool>(writer: Weak<ProgressWriter>, pool: Pool) {
+    loop {
+        thread::sleep(SHUTDOWN_CHECK_INTERVAL);
+        let writer = match writer.upgrade() {
+            Some(writer) => writer,
+            None => return,
+        };
+        if let Err(e) = writer.write(&pool) {
+            eprintln!("Failed to save job progress: {}", e);
+        }
+    }
+}
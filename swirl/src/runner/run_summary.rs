@@ -0,0 +1,53 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// What happened during a call to
+/// [`Runner::run_until_empty`](crate::Runner::run_until_empty)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RunSummary {
+    /// The number of jobs which were run, including jobs which failed or
+    /// yielded
+    pub jobs_run: u64,
+
+    /// The number of jobs which failed, including jobs which will be retried
+    pub failures: u64,
+
+    /// How long the run took, including waiting for the last jobs to finish
+    pub duration: Duration,
+}
+
+/// The number of jobs a runner has run since it was built
+#[derive(Debug, Default)]
+pub(super) struct RunCounts {
+    jobs_run: AtomicU64,
+    failures: AtomicU64,
+}
+
+impl RunCounts {
+    pub(super) fn finished(&self) {
+        self.jobs_run.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub(super) fn failed(&self) {
+        self.failures.fetch_add(1, Ordering::SeqCst);
+        self.finished();
+    }
+
+    pub(super) fn jobs_run(&self) -> u64 {
+        self.jobs_run.load(Ordering::SeqCst)
+    }
+
+    /// A summary of the jobs which were run after `before` was taken
+    pub(super) fn since(&self, before: &RunSummary, duration: Duration) -> RunSummary {
+        RunSummary {
+            jobs_run: self.jobs_run() - before.jobs_run,
+            failures: self.failures.load(Ordering::SeqCst) - before.failures,
+            duration,
+        }
+    }
+
+    /// The totals so far, to be passed to [`since`](Self::since) later
+    pub(super) fn totals(&self) -> RunSummary {
+        self.since(&RunSummary::default(), Duration::default())
+    }
+}
@@ -0,0 +1,153 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::storage::BackgroundJob;
+
+#[cfg(all(feature = "signals", unix))]
+use std::io;
+
+/// Stops a runner from starting new jobs, as returned by
+/// [`Runner::shutdown_handle`](crate::Runner::shutdown_handle).
+///
+/// Handles can be cloned and sent to other threads. All handles for a runner
+/// share the same state.
+#[derive(Debug, Clone, Default)]
+pub struct ShutdownHandle(Arc<AtomicBool>);
+
+impl ShutdownHandle {
+    /// Ask the runner to stop.
+    ///
+    /// The runner doesn't start any jobs after this, and
+    /// [`Runner::run_forever`](crate::Runner::run_forever) returns once the
+    /// jobs which were already running have finished. Jobs which were never
+    /// started are left in the queue for other runners.
+    pub fn shutdown(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    /// Whether the runner has been asked to stop
+    pub fn is_shutdown(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    /// Shut the runner down when the process receives `SIGTERM` or `SIGINT`,
+    /// such as when a container is being stopped.
+    ///
+    /// If a second signal is received while the runner is draining, the
+    /// process exits immediately with status 1.
+    ///
+    /// Requires the `signals` feature, and is only available on Unix.
+    #[cfg(all(feature = "signals", unix))]
+    pub fn shutdown_on_signals(&self) -> io::Result<()> {
+        use signal_hook::consts::{SIGINT, SIGTERM};
+        use signal_hook::flag;
+
+        for &signal in &[SIGTERM, SIGINT] {
+            // Registered first, so it only sees the flag set by an earlier
+            // signal
+            flag::register_conditional_shutdown(signal, 1, Arc::clone(&self.0))?;
+            flag::register(signal, Arc::clone(&self.0))?;
+        }
+        Ok(())
+    }
+}
+
+/// How long [`Runner::run_forever`](crate::Runner::run_forever) waits for
+/// running jobs once the runner has been shut down
+#[derive(Debug, Clone, Copy, Default)]
+pub(super) struct ShutdownTimeouts {
+    pub(super) soft: Option<Duration>,
+    pub(super) hard: Option<Duration>,
+}
+
+/// What happened to the jobs which were running when a runner was shut down,
+/// as returned by [`Runner::run_forever`](crate::Runner::run_forever)
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ShutdownReport {
+    /// The ids of jobs which were still running when the
+    /// [soft shutdown timeout](crate::Builder::soft_shutdown_timeout) passed.
+    ///
+    /// These jobs are still locked, and are retried by another runner once
+    /// this process exits and its transactions are rolled back. They have not
+    /// been marked as failed, so their retry count is unchanged.
+    pub abandoned_jobs: Vec<i64>,
+}
+
+impl ShutdownReport {
+    /// Returns `true` if every running job finished before the runner
+    /// stopped
+    pub fn is_clean(&self) -> bool {
+        self.abandoned_jobs.is_empty()
+    }
+}
+
+/// A job which is being run by this process, as returned by
+/// [`Runner::in_flight`](crate::Runner::in_flight)
+#[derive(Debug, Clone)]
+pub struct InFlightJob {
+    /// The id of the job
+    pub id: i64,
+    /// The type of the job
+    pub job_type: String,
+    /// The queue the job was placed in
+    pub queue: String,
+    /// When the job started running
+    pub started_at: SystemTime,
+    started: Instant,
+}
+
+impl InFlightJob {
+    /// How long the job has been running
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+}
+
+/// The jobs which a runner has locked. Jobs which have returned but are still
+/// locked, because the rest of their batch is running, map to `None`.
+#[derive(Debug, Clone, Default)]
+pub(super) struct RunningJobs(Arc<Mutex<HashMap<i64, Option<InFlightJob>>>>);
+
+impl RunningJobs {
+    pub(super) fn insert(&self, job: &BackgroundJob) {
+        let in_flight = InFlightJob {
+            id: job.id,
+            job_type: job.job_type.clone(),
+            queue: job.queue.clone(),
+            started_at: SystemTime::now(),
+            started: Instant::now(),
+        };
+        self.lock().insert(job.id, Some(in_flight));
+    }
+
+    /// Records that the job has returned, although it is still locked
+    pub(super) fn finished(&self, job_id: i64) {
+        if let Some(job) = self.lock().get_mut(&job_id) {
+            *job = None;
+        }
+    }
+
+    pub(super) fn remove(&self, job_id: i64) {
+        self.lock().remove(&job_id);
+    }
+
+    /// The jobs which are running, in order of id
+    pub(super) fn in_flight(&self) -> Vec<InFlightJob> {
+        let mut jobs = self.lock().values().flatten().cloned().collect::<Vec<_>>();
+        jobs.sort_unstable_by_key(|job| job.id);
+        jobs
+    }
+
+    /// The ids of the running jobs, in ascending order
+    pub(super) fn ids(&self) -> Vec<i64> {
+        let mut ids = self.lock().keys().copied().collect::<Vec<_>>();
+        ids.sort_unstable();
+        ids
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<i64, Option<InFlightJob>>> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
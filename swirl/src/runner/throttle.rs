@@ -0,0 +1,111 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// The number of fingerprints tracked before those whose window has passed
+/// are forgotten
+const MAX_FINGERPRINTS: usize = 1000;
+
+struct Window {
+    started: Instant,
+    suppressed: u64,
+}
+
+/// Limits notifications about failed jobs to one per kind of failure per time
+/// window, so that an incident which fails thousands of jobs in the same way
+/// doesn't flood whoever is being notified.
+///
+/// Failures are grouped by their fingerprint, which is the job type and the
+/// error message with any numbers removed, so that failures which only differ
+/// by an id or a duration count as the same failure. This is the same grouping
+/// used for `swirl_failure_samples` and a job's retry history.
+///
+/// [`Builder::throttle_job_errors`] uses this to throttle
+/// [`Builder::on_job_error`]. It can also be used directly, such as from
+/// [`JobMiddleware::on_failure`](crate::JobMiddleware::on_failure), to
+/// throttle notifications sent some other way. Failures are only counted
+/// within the process, so each runner notifies separately.
+///
+/// [`Builder::throttle_job_errors`]: crate::Builder::throttle_job_errors
+/// [`Builder::on_job_error`]: crate::Builder::on_job_error
+pub struct FailureThrottle {
+    window: Duration,
+    windows: Mutex<HashMap<String, Window>>,
+}
+
+impl fmt::Debug for FailureThrottle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("FailureThrottle")
+            .field("window", &self.window)
+            .finish()
+    }
+}
+
+impl FailureThrottle {
+    /// Creates a throttle which allows one notification per fingerprint per
+    /// `window`
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            windows: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Records a failure of a job of type `job_type` with `error`, and returns
+    /// whether a notification should be sent about it.
+    ///
+    /// Returns `Some` with the number of failures with the same fingerprint
+    /// which were suppressed since the last notification, or `None` if one
+    /// has already been sent in the current window.
+    pub fn check(&self, job_type: &str, error: &dyn Error) -> Option<u64> {
+        let fingerprint = fingerprint(job_type, &error.to_string());
+        let now = Instant::now();
+        let mut windows = self.windows.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(window) = windows.get_mut(&fingerprint) {
+            if now.duration_since(window.started) < self.window {
+                window.suppressed += 1;
+                return None;
+            }
+            let suppressed = window.suppressed;
+            *window = Window {
+                started: now,
+                suppressed: 0,
+            };
+            return Some(suppressed);
+        }
+
+        if windows.len() >= MAX_FINGERPRINTS {
+            let window = self.window;
+            windows.retain(|_, w| now.duration_since(w.started) < window);
+        }
+        windows.insert(
+            fingerprint,
+            Window {
+                started: now,
+                suppressed: 0,
+            },
+        );
+        Some(0)
+    }
+}
+
+/// The fingerprint of a failure of a job of type `job_type` with `error`. This
+/// matches what `storage::error_fingerprint` hashes.
+fn fingerprint(job_type: &str, error: &str) -> String {
+    let mut fingerprint = format!("{}:", job_type);
+    let mut in_number = false;
+    for c in error.chars() {
+        if c.is_ascii_digit() {
+            if !in_number {
+                fingerprint.push('N');
+            }
+            in_number = true;
+        } else {
+            fingerprint.push(c);
+            in_number = false;
+        }
+    }
+    fingerprint
+}
@@ -0,0 +1,123 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::SHUTDOWN_CHECK_INTERVAL;
+use crate::db::DieselPool;
+use crate::errors::JobTimedOut;
+use crate::retry::NextRun;
+use crate::storage;
+
+/// A job with a timeout, registered with [`Watchdog::watch`]
+pub(super) struct WatchedJob {
+    pub(super) job_id: i64,
+    /// The Postgres backend holding the job's lock
+    pub(super) backend_pid: i32,
+    pub(super) timeout: Duration,
+    /// When the job is run next if it times out
+    pub(super) next_run: NextRun,
+    pub(super) environment: serde_json::Value,
+    pub(super) owner: Option<&'static str>,
+}
+
+#[derive(Default)]
+struct State {
+    watched: HashMap<i64, (Instant, WatchedJob)>,
+    timed_out: HashSet<i64>,
+    started: bool,
+}
+
+/// Fails jobs which run for longer than their timeout.
+///
+/// Threads can't be stopped from the outside, so a job which times out is
+/// rolled back by terminating the connection holding its lock. It is then
+/// marked as failed from another connection, like any other failure.
+#[derive(Default)]
+pub(super) struct Watchdog(Mutex<State>);
+
+impl Watchdog {
+    /// Starts watching `job`, which must finish before its timeout passes.
+    /// The thread checking for jobs which timed out is started the first
+    /// time this is called.
+    pub(super) fn watch<Pool>(self: &Arc<Self>, pool: &Pool, job: WatchedJob)
+    where
+        Pool: DieselPool + 'static,
+    {
+        let mut state = self.lock();
+        if !state.started {
+            state.started = true;
+            let watchdog = Arc::downgrade(self);
+            let pool = pool.clone();
+            thread::spawn(move || run(watchdog, pool));
+        }
+        let deadline = Instant::now() + job.timeout;
+        state.watched.insert(job.job_id, (deadline, job));
+    }
+
+    /// Stops watching the job with the id `job_id`, once it has returned.
+    /// Returns `true` if it had already timed out, in which case its
+    /// connection has been or is about to be terminated.
+    pub(super) fn unwatch(&self, job_id: i64) -> bool {
+        let mut state = self.lock();
+        state.watched.remove(&job_id);
+        state.timed_out.remove(&job_id)
+    }
+
+    /// Removes the jobs whose deadline has passed
+    fn take_timed_out(&self) -> Vec<WatchedJob> {
+        let now = Instant::now();
+        let mut state = self.lock();
+        let ids = state
+            .watched
+            .iter()
+            .filter(|(_, (deadline, _))| *deadline <= now)
+            .map(|(&id, _)| id)
+            .collect::<Vec<_>>();
+        ids.into_iter()
+            .filter_map(|id| {
+                state.timed_out.insert(id);
+                state.watched.remove(&id).map(|(_, job)| job)
+            })
+            .collect()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, State> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Fails jobs which have timed out until the runner is dropped
+fn run<Pool: DieselPool>(watchdog: Weak<Watchdog>, pool: Pool) {
+    loop {
+        thread::sleep(SHUTDOWN_CHECK_INTERVAL);
+        let timed_out = match watchdog.upgrade() {
+            Some(watchdog) => watchdog.take_timed_out(),
+            None => return,
+        };
+        for job in timed_out {
+            eprintln!("Job {} did not finish within {:?}", job.job_id, job.timeout);
+            if let Err(e) = fail(&pool, &job) {
+                eprintln!(
+                    "Failed to fail job {} after it timed out: {}",
+                    job.job_id, e
+                );
+            }
+        }
+    }
+}
+
+/// Rolls back the job's transaction, then updates it as a failure
+fn fail<Pool: DieselPool>(pool: &Pool, job: &WatchedJob) -> Result<(), Box<dyn std::error::Error>> {
+    let conn = pool.get()?;
+    storage::terminate_backend(&conn, job.backend_pid)?;
+    storage::update_failed_job(
+        &conn,
+        job.job_id,
+        job.next_run,
+        &JobTimedOut(job.timeout).to_string(),
+        &job.environment,
+        job.owner,
+    );
+    Ok(())
+}
@@ -1,3 +1,11 @@
+table! {
+    background_job_checkpoints (job_id) {
+        job_id -> Int8,
+        state -> Jsonb,
+        updated_at -> Timestamp,
+    }
+}
+
 table! {
     background_jobs (id) {
         id -> Int8,
@@ -6,5 +14,183 @@ table! {
         retries -> Int4,
         last_retry -> Timestamp,
         created_at -> Timestamp,
+        priority -> Int2,
+        queue -> Text,
+        metadata -> Jsonb,
+        min_worker_version -> Nullable<Array<Int4>>,
+        scheduled_at -> Timestamp,
+        retry_at -> Nullable<Timestamp>,
+        first_failure -> Nullable<Jsonb>,
+        unique_key -> Nullable<Text>,
+        retry_history -> Jsonb,
+        locality -> Nullable<Text>,
+    }
+}
+
+table! {
+    swirl_debug_job_types (job_type) {
+        job_type -> Text,
+        enabled_until -> Timestamp,
+    }
+}
+
+table! {
+    swirl_failed_jobs (id) {
+        id -> Int8,
+        job_type -> Text,
+        data -> Jsonb,
+        priority -> Int2,
+        queue -> Text,
+        metadata -> Jsonb,
+        min_worker_version -> Nullable<Array<Int4>>,
+        retries -> Int4,
+        created_at -> Timestamp,
+        error -> Text,
+        failed_at -> Timestamp,
+        first_failure -> Nullable<Jsonb>,
+        retry_history -> Jsonb,
+        owner -> Nullable<Text>,
+        scheduled_at -> Timestamp,
+        unique_key -> Nullable<Text>,
+        locality -> Nullable<Text>,
+    }
+}
+
+table! {
+    swirl_failure_samples (id) {
+        id -> Int8,
+        job_id -> Int8,
+        job_type -> Text,
+        fingerprint -> Text,
+        data -> Jsonb,
+        error -> Text,
+        created_at -> Timestamp,
+        owner -> Nullable<Text>,
+    }
+}
+
+table! {
+    swirl_heartbeats (queue) {
+        queue -> Text,
+        enqueued_at -> Timestamp,
+        completed_at -> Timestamp,
+    }
+}
+
+table! {
+    swirl_job_batches (batch_id, job_id) {
+        batch_id -> Int8,
+        job_id -> Int8,
+    }
+}
+
+table! {
+    swirl_job_dependencies (job_id, depends_on) {
+        job_id -> Int8,
+        depends_on -> Int8,
+    }
+}
+
+table! {
+    swirl_job_leases (job_id) {
+        job_id -> Int8,
+        locked_by -> Int8,
+        backend_pid -> Int4,
+        locked_at -> Timestamp,
+        locked_until -> Timestamp,
+    }
+}
+
+table! {
+    swirl_job_progress (job_id) {
+        job_id -> Int8,
+        current -> Int8,
+        total -> Int8,
+        updated_at -> Timestamp,
+    }
+}
+
+table! {
+    swirl_job_results (job_id) {
+        job_id -> Int8,
+        job_type -> Text,
+        result -> Jsonb,
+        completed_at -> Timestamp,
+        expires_at -> Timestamp,
+    }
+}
+
+table! {
+    swirl_paused_queues (queue) {
+        queue -> Text,
+        paused_at -> Timestamp,
+    }
+}
+
+table! {
+    swirl_queue_aliases (alias) {
+        alias -> Text,
+        queue -> Text,
+    }
+}
+
+table! {
+    swirl_runs (id) {
+        id -> Int8,
+        hostname -> Nullable<Text>,
+        started_at -> Timestamp,
+        finished_at -> Timestamp,
+        jobs_run -> Int8,
+        failures -> Int8,
+        error -> Nullable<Text>,
+    }
+}
+
+table! {
+    swirl_supersede_keys (job_type, supersede_key) {
+        job_type -> Text,
+        supersede_key -> Text,
+        job_id -> Int8,
+    }
+}
+
+table! {
+    swirl_tenants (tenant) {
+        tenant -> Text,
+        pending_jobs -> Int8,
+        max_pending_jobs -> Nullable<Int8>,
+    }
+}
+
+table! {
+    swirl_workers (id) {
+        id -> Int8,
+        hostname -> Text,
+        pid -> Int4,
+        version -> Nullable<Text>,
+        queues -> Nullable<Array<Text>>,
+        thread_count -> Int4,
+        started_at -> Timestamp,
+        last_heartbeat_at -> Timestamp,
     }
 }
+
+allow_tables_to_appear_in_same_query!(
+    background_job_checkpoints,
+    background_jobs,
+    swirl_debug_job_types,
+    swirl_failed_jobs,
+    swirl_failure_samples,
+    swirl_heartbeats,
+    swirl_job_batches,
+    swirl_job_dependencies,
+    swirl_job_leases,
+    swirl_job_progress,
+    swirl_job_results,
+    swirl_paused_queues,
+    swirl_queue_aliases,
+    swirl_runs,
+    swirl_supersede_keys,
+    swirl_tenants,
+    swirl_workers,
+);
@@ -0,0 +1,119 @@
+use std::panic::resume_unwind;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+/// The threads a runner may use, shared between the jobs it runs and their
+/// sub-tasks
+pub(crate) struct ThreadBudget {
+    size: usize,
+    in_use: AtomicUsize,
+}
+
+impl ThreadBudget {
+    pub(crate) fn new(size: usize) -> Self {
+        Self {
+            size,
+            in_use: AtomicUsize::new(0),
+        }
+    }
+
+    /// The number of threads which are running a job or a sub-task
+    pub(crate) fn in_use(&self) -> usize {
+        self.in_use.load(Ordering::SeqCst)
+    }
+
+    /// Counts a thread which is running a job. Jobs are always counted, since
+    /// they are already running on one of the runner's threads.
+    pub(crate) fn claim(&self) -> BudgetSlot<'_> {
+        self.in_use.fetch_add(1, Ordering::SeqCst);
+        BudgetSlot(self)
+    }
+
+    /// Claims a thread for a sub-task, unless every thread is in use
+    fn try_claim(&self) -> Option<BudgetSlot<'_>> {
+        let mut in_use = self.in_use();
+        while in_use < self.size {
+            match self.in_use.compare_exchange(
+                in_use,
+                in_use + 1,
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            ) {
+                Ok(_) => return Some(BudgetSlot(self)),
+                Err(actual) => in_use = actual,
+            }
+        }
+        None
+    }
+}
+
+/// A thread counted against a [`ThreadBudget`], which is released when this
+/// is dropped
+pub(crate) struct BudgetSlot<'a>(&'a ThreadBudget);
+
+impl Drop for BudgetSlot<'_> {
+    fn drop(&mut self) {
+        self.0.in_use.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[allow(missing_debug_implementations)]
+/// Runs the sub-tasks of a job in parallel, on threads the runner isn't using.
+/// Created by [`JobContext::scope`](crate::JobContext::scope).
+pub struct Scope<'scope, 'env: 'scope> {
+    scope: &'scope thread::Scope<'scope, 'env>,
+    budget: &'env ThreadBudget,
+}
+
+impl<'scope, 'env> Scope<'scope, 'env> {
+    /// Runs `f` as a sub-task of the job.
+    ///
+    /// If the runner has fewer jobs and sub-tasks running than its
+    /// [`thread_count`](crate::Builder::thread_count), `f` is run on a new
+    /// thread, which is joined before [`JobContext::scope`] returns. Otherwise
+    /// `f` is run on the job's own thread before this returns, so a job never
+    /// uses more threads than the runner has to spare.
+    ///
+    /// [`JobContext::scope`]: crate::JobContext::scope
+    pub fn spawn<F, T>(&self, f: F) -> SubTask<'scope, T>
+    where
+        F: FnOnce() -> T + Send + 'scope,
+        T: Send + 'scope,
+    {
+        match self.budget.try_claim() {
+            Some(slot) => SubTask(SubTaskState::Running(self.scope.spawn(move || {
+                let _slot = slot;
+                f()
+            }))),
+            None => SubTask(SubTaskState::Finished(f())),
+        }
+    }
+}
+
+/// A sub-task started with [`Scope::spawn`]
+#[allow(missing_debug_implementations)]
+pub struct SubTask<'scope, T>(SubTaskState<'scope, T>);
+
+enum SubTaskState<'scope, T> {
+    Running(thread::ScopedJoinHandle<'scope, T>),
+    Finished(T),
+}
+
+impl<T> SubTask<'_, T> {
+    /// Waits for the sub-task to finish, and returns its result. If the
+    /// sub-task panicked, the panic is resumed on the current thread.
+    pub fn join(self) -> T {
+        match self.0 {
+            SubTaskState::Running(handle) => handle.join().unwrap_or_else(|e| resume_unwind(e)),
+            SubTaskState::Finished(result) => result,
+        }
+    }
+}
+
+/// Runs `f` with a [`Scope`] whose sub-tasks are counted against `budget`
+pub(crate) fn scope<'env, F, T>(budget: &'env ThreadBudget, f: F) -> T
+where
+    F: for<'scope> FnOnce(&Scope<'scope, 'env>) -> T,
+{
+    thread::scope(|scope| f(&Scope { scope, budget }))
+}
@@ -0,0 +1,172 @@
+use diesel::prelude::*;
+use diesel::result::Error::DeserializationError;
+use serde::de::DeserializeOwned;
+use std::time::SystemTime;
+
+use crate::enqueue::JobHandle;
+use crate::storage;
+
+/// Where a job is in its lifecycle, as returned by [`job_status`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JobStatus {
+    /// The job is waiting to be run for the first time
+    Pending,
+
+    /// A runner is running the job right now
+    Running,
+
+    /// The job has failed, and is waiting to be retried
+    Retrying {
+        /// The number of times the job has failed
+        retries: u32,
+        /// When the job last failed
+        last_retry: SystemTime,
+    },
+
+    /// The job ran out of retries, and was moved to `swirl_failed_jobs`
+    Failed,
+
+    /// There is no job with this id. Jobs are deleted once they succeed, so
+    /// this usually means the job has completed, but it is also returned for
+    /// jobs which were cancelled or purged.
+    NotFound,
+
+    #[doc(hidden)]
+    /// Match on `_` instead, more variants may be added in the future
+    __NonExhaustive,
+}
+
+/// Looks up the status of the job with the given id, such as to report the
+/// progress of work a web request enqueued.
+///
+/// Running jobs are detected by their lock. This briefly takes a share lock
+/// on the job's row, which runners skip over rather than wait for, so this
+/// should not be called in a long running transaction.
+pub fn job_status(conn: &PgConnection, job_id: i64) -> QueryResult<JobStatus> {
+    use crate::schema::{background_jobs, swirl_failed_jobs};
+
+    let unlocked = background_jobs::table
+        .find(job_id)
+        .select((background_jobs::retries, background_jobs::last_retry))
+        .for_key_share()
+        .skip_locked()
+        .first::<(i32, SystemTime)>(conn)
+        .optional()?;
+    if let Some((retries, last_retry)) = unlocked {
+        return Ok(if retries == 0 {
+            JobStatus::Pending
+        } else {
+            JobStatus::Retrying {
+                retries: retries as u32,
+                last_retry,
+            }
+        });
+    }
+
+    let queued = background_jobs::table
+        .find(job_id)
+        .select(background_jobs::id)
+        .first::<i64>(conn)
+        .optional()?;
+    if queued.is_some() {
+        return Ok(JobStatus::Running);
+    }
+    let failed = swirl_failed_jobs::table
+        .find(job_id)
+        .select(swirl_failed_jobs::id)
+        .first::<i64>(conn)
+        .optional()?;
+    if failed.is_some() {
+        Ok(JobStatus::Failed)
+    } else {
+        Ok(JobStatus::NotFound)
+    }
+}
+
+/// Looks up the value returned by the job with the given id, such as the
+/// report a web request enqueued a job to generate.
+///
+/// Returns `None` until the job has succeeded, and once its result has
+/// expired. Results are only saved by jobs defined with
+/// `#[swirl::background_job]` which return `Result<T, PerformError>`, or which
+/// call [`JobContext::save_result`](crate::JobContext::save_result). `T` must
+/// be able to deserialize what the job returned, or this fails with a
+/// deserialization error.
+pub fn job_result<T: DeserializeOwned>(conn: &PgConnection, job_id: i64) -> QueryResult<Option<T>> {
+    match storage::load_result(conn, job_id)? {
+        Some(result) => serde_json::from_value(result)
+            .map(Some)
+            .map_err(|e| DeserializationError(Box::new(e))),
+        None => Ok(None),
+    }
+}
+
+/// The result of [`cancel_job`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cancellation {
+    /// The job was removed from the queue, and will not be run
+    Cancelled,
+
+    /// A runner is already running the job, so it could not be cancelled
+    Running,
+
+    /// There is no job with this id in the queue. It may have already
+    /// completed, or run out of retries.
+    NotFound,
+
+    #[doc(hidden)]
+    /// Match on `_` instead, more variants may be added in the future
+    __NonExhaustive,
+}
+
+/// Removes the job with the given id from the queue, unless a runner has
+/// already started running it. Jobs which are waiting to be retried can be
+/// cancelled too.
+///
+/// This is useful when the record a job would operate on has been deleted.
+pub fn cancel_job(conn: &PgConnection, job_id: i64) -> QueryResult<Cancellation> {
+    use crate::schema::background_jobs;
+
+    conn.transaction(|| {
+        let unlocked = background_jobs::table
+            .find(job_id)
+            .select(background_jobs::id)
+            .for_update()
+            .skip_locked()
+            .first::<i64>(conn)
+            .optional()?;
+        if unlocked.is_some() {
+            storage::delete_job(conn, job_id)?;
+            return Ok(Cancellation::Cancelled);
+        }
+
+        let queued = background_jobs::table
+            .find(job_id)
+            .select(background_jobs::id)
+            .first::<i64>(conn)
+            .optional()?;
+        if queued.is_some() {
+            Ok(Cancellation::Running)
+        } else {
+            Ok(Cancellation::NotFound)
+        }
+    })
+}
+
+impl JobHandle {
+    /// The status of this job. See [`job_status`].
+    pub fn status(&self, conn: &PgConnection) -> QueryResult<JobStatus> {
+        job_status(conn, self.id())
+    }
+
+    /// The value this job returned, once it has succeeded. See
+    /// [`job_result`].
+    pub fn result<T: DeserializeOwned>(&self, conn: &PgConnection) -> QueryResult<Option<T>> {
+        job_result(conn, self.id())
+    }
+
+    /// Cancels this job, unless it is already running. See [`cancel_job`].
+    pub fn cancel(&self, conn: &PgConnection) -> QueryResult<Cancellation> {
+        cancel_job(conn, self.id())
+    }
+}
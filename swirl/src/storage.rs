@@ -1,53 +1,610 @@
 use diesel::dsl::now;
+use diesel::pg::data_types::PgInterval;
 use diesel::pg::Pg;
 use diesel::prelude::*;
-use diesel::sql_types::{Bool, Integer, Interval};
-use diesel::{delete, insert_into, update};
+use diesel::sql_types::{Array, BigInt, Bool, Integer, Interval, Jsonb, Nullable, Text, Timestamp};
+use diesel::{delete, insert_into, sql_query, update};
 use serde_json;
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime};
 
+use crate::completion::{self, JobOutcome};
+use crate::enqueue::{self, EnqueueOptions, Schedule};
 use crate::errors::EnqueueError;
+use crate::retry::NextRun;
 use crate::schema::background_jobs;
-use crate::Job;
+use crate::{Job, JobMeta, RunSummary};
 
-#[derive(Queryable, Identifiable, Debug, Clone)]
+/// The number of failures kept in each job's `retry_history`
+const RETRY_HISTORY_LENGTH: usize = 10;
+
+/// A job which has been locked by a runner, as returned by
+/// [`FetchQuery::fetch`](crate::FetchQuery::fetch).
+///
+/// This can be loaded by selecting `(id, job_type, data, priority, queue,
+/// metadata, retries)` from `background_jobs`, or by name with `sql_query`.
+#[derive(Queryable, QueryableByName, Identifiable, Debug, Clone)]
+#[table_name = "background_jobs"]
 pub struct BackgroundJob {
+    /// The id of the job
     pub id: i64,
+    /// The type of the job
     pub job_type: String,
+    /// The job's serialized arguments
     pub data: serde_json::Value,
+    /// The priority of the job
+    pub priority: i16,
+    /// The queue the job was placed in
+    pub queue: String,
+    /// The metadata the job was enqueued with
+    pub metadata: serde_json::Value,
+    /// The number of times the job has failed
+    pub retries: i32,
+}
+
+impl BackgroundJob {
+    pub(crate) fn meta(&self) -> JobMeta<'_> {
+        JobMeta {
+            id: self.id,
+            job_type: &self.job_type,
+            data: &self.data,
+            queue: &self.queue,
+            metadata: &self.metadata,
+        }
+    }
+}
+
+/// A job as it was stored when it was enqueued, returned by
+/// [`Job::enqueue_returning`]
+#[derive(Queryable, Debug, Clone, PartialEq, Eq)]
+pub struct EnqueuedJob {
+    /// The id of the job
+    pub id: i64,
+    /// The type of the job
+    pub job_type: String,
+    /// The queue the job was placed in
+    pub queue: String,
+    /// The priority of the job
+    pub priority: i16,
+    /// When the job was enqueued, according to the database's clock
+    pub enqueued_at: SystemTime,
+    /// When the job will first be run, according to the database's clock
+    pub run_at: SystemTime,
+}
+
+/// Restrictions on which jobs a runner will fetch
+#[derive(Debug, Clone, Default)]
+pub struct FetchOptions {
+    /// Only fetch jobs in these queues. Jobs in any queue are fetched if this
+    /// is `None`.
+    pub queues: Option<Vec<String>>,
+    /// Only fetch jobs of these types. Jobs of any type are fetched if this is
+    /// `None`.
+    pub job_types: Option<Vec<String>>,
+    /// Never fetch jobs of these types
+    pub except_job_types: Vec<String>,
+    /// The version of the application the runner is part of. Jobs which
+    /// require a newer version are not fetched.
+    pub worker_version: Option<String>,
+    /// How long before a job is due that it can be run
+    pub early_execution_slack: Duration,
+    /// The locality of the runner. Jobs in the same locality are fetched
+    /// first, unless another job has been due for longer than
+    /// `locality_window`.
+    pub locality: Option<String>,
+    /// How long a job in another locality can be passed over for jobs in the
+    /// runner's locality
+    pub locality_window: Duration,
+    /// The maximum number of jobs of each type which can run at once across
+    /// all runners
+    pub job_type_limits: HashMap<String, u32>,
+    /// The maximum number of jobs from each queue which can run at once across
+    /// all runners
+    pub queue_limits: HashMap<String, u32>,
+}
+
+/// Jobs which should be skipped when fetching the next job to run
+#[derive(Debug, Default)]
+pub struct Excluded {
+    pub ids: Vec<i64>,
+    pub job_types: Vec<String>,
+    pub queues: Vec<String>,
+    pub concurrency_keys: Vec<String>,
 }
 
+type BoxedCondition = Box<dyn BoxableExpression<background_jobs::table, Pg, SqlType = Bool>>;
+
 /// Enqueues a job to be run as soon as possible.
-pub fn enqueue_job<T: Job>(conn: &PgConnection, job: T) -> Result<(), EnqueueError> {
+///
+/// Returns `None` if the job was skipped because a job with the same unique
+/// key is already in the queue
+pub fn enqueue_job<T: Job>(
+    conn: &PgConnection,
+    job: T,
+    options: EnqueueOptions,
+) -> Result<Option<EnqueuedJob>, EnqueueError> {
+    let job_data = serde_json::to_value(job);
+    #[cfg(feature = "metrics")]
+    if job_data.is_err() {
+        enqueue::metrics::serialization_failed(T::JOB_TYPE);
+    }
+    insert_job(conn, T::JOB_TYPE, job_data?, options)
+}
+
+/// Enqueues a job of type `name` with the already serialized arguments
+/// `job_data`. See [`enqueue_job`].
+pub fn insert_job(
+    conn: &PgConnection,
+    name: &str,
+    job_data: serde_json::Value,
+    mut options: EnqueueOptions,
+) -> Result<Option<EnqueuedJob>, EnqueueError> {
     use crate::schema::background_jobs::dsl::*;
 
-    let job_data = serde_json::to_value(job)?;
-    insert_into(background_jobs)
-        .values((job_type.eq(T::JOB_TYPE), data.eq(job_data)))
-        .execute(conn)?;
+    sql_function!(fn coalesce(x: Nullable<Timestamp>, y: Timestamp) -> Timestamp);
+
+    enqueue::run_middleware(name, &job_data, &mut options)?;
+    if let Some(key) = options.concurrency_key.take() {
+        options
+            .metadata
+            .insert("concurrency_key".into(), key.into());
+    }
+    let required_version = match options.min_worker_version.as_deref() {
+        Some(v) => Some(parse_version(v).ok_or_else(|| EnqueueError::InvalidVersion(v.into()))?),
+        None => None,
+    };
+    // Delays are added to the database's clock, so they aren't affected by
+    // skew between the enqueueing machine and the database
+    let (run_at, delay) = match options.schedule {
+        Schedule::Now => (None, Duration::from_secs(0)),
+        Schedule::At(time) => (Some(time), Duration::from_secs(0)),
+        Schedule::In(delay) => (None, delay),
+    };
+    let delay = PgInterval::from_microseconds(delay.as_micros() as i64);
+    let tenant = options
+        .metadata
+        .get("tenant")
+        .and_then(|t| t.as_str())
+        .map(String::from);
+    let depends_on = std::mem::take(&mut options.depends_on);
+    #[cfg(feature = "metrics")]
+    let (payload_bytes, started_at) = (job_data.to_string().len(), std::time::Instant::now());
+    let result = conn.transaction(|| {
+        if let Some(tenant) = tenant {
+            check_tenant_quota(conn, &tenant)?;
+        }
+        let enqueued = insert_into(background_jobs)
+            .values((
+                job_type.eq(name),
+                data.eq(job_data),
+                priority.eq(options.priority),
+                queue.eq(options.queue),
+                metadata.eq(serde_json::Value::Object(options.metadata)),
+                min_worker_version.eq(required_version),
+                scheduled_at.eq(coalesce(run_at, now + delay.into_sql::<Interval>())),
+                unique_key.eq(options.unique_key),
+                locality.eq(options.locality),
+            ))
+            .on_conflict_do_nothing()
+            .returning((id, job_type, queue, priority, created_at, scheduled_at))
+            .get_result::<EnqueuedJob>(conn)
+            .optional()?;
+        if let Some(job) = &enqueued {
+            add_dependencies(conn, job.id, &depends_on)?;
+        }
+        Ok(enqueued)
+    });
+    #[cfg(feature = "metrics")]
+    enqueue::metrics::inserted(name, payload_bytes, started_at.elapsed(), &result);
+    result
+}
+
+/// Records that the job with the id `job_id` can't run until the jobs in
+/// `depends_on` have succeeded. Jobs which are no longer in `background_jobs`
+/// or `swirl_failed_jobs` have already succeeded, so they aren't recorded.
+fn add_dependencies(conn: &PgConnection, job_id: i64, depends_on: &[i64]) -> QueryResult<()> {
+    if depends_on.is_empty() {
+        return Ok(());
+    }
+    sql_query(
+        "INSERT INTO swirl_job_dependencies (job_id, depends_on) \
+         SELECT $1, id FROM background_jobs WHERE id = ANY($2) \
+         UNION SELECT $1, id FROM swirl_failed_jobs WHERE id = ANY($2)",
+    )
+    .bind::<BigInt, _>(job_id)
+    .bind::<Array<BigInt>, _>(depends_on)
+    .execute(conn)?;
     Ok(())
 }
 
-fn retriable() -> Box<dyn BoxableExpression<background_jobs::table, Pg, SqlType = Bool>> {
+/// Makes the job with the id `new_job_id` the latest job of type `name` with
+/// the supersede key `key`. The job it replaces is cancelled, unless a runner
+/// has already started it. Returns the id of the job which was cancelled.
+///
+/// The key's row is locked until the current transaction ends, so concurrent
+/// calls with the same key can't both miss the job they should cancel.
+pub fn supersede_job(
+    conn: &PgConnection,
+    name: &str,
+    key: &str,
+    new_job_id: i64,
+) -> QueryResult<Option<i64>> {
+    use crate::schema::swirl_supersede_keys::dsl::*;
+
+    conn.transaction(|| loop {
+        let previous = swirl_supersede_keys
+            .find((name, key))
+            .select(job_id)
+            .for_update()
+            .first::<i64>(conn)
+            .optional()?;
+        if let Some(previous) = previous {
+            update(swirl_supersede_keys.find((name, key)))
+                .set(job_id.eq(new_job_id))
+                .execute(conn)?;
+            let pending = background_jobs::table
+                .find(previous)
+                .select(background_jobs::id)
+                .for_update()
+                .skip_locked()
+                .first::<i64>(conn)
+                .optional()?;
+            if pending.is_some() {
+                delete_job(conn, previous)?;
+            }
+            return Ok(pending);
+        }
+
+        // If another transaction inserts the key first, nothing is inserted
+        // here, and its row is locked on the next attempt instead
+        let inserted = insert_into(swirl_supersede_keys)
+            .values((
+                job_type.eq(name),
+                supersede_key.eq(key),
+                job_id.eq(new_job_id),
+            ))
+            .on_conflict_do_nothing()
+            .execute(conn)?;
+        if inserted > 0 {
+            return Ok(None);
+        }
+    })
+}
+
+/// Fails if `tenant` already has as many pending jobs as its quota allows.
+///
+/// The tenant's counter is maintained by triggers on `background_jobs`. Its
+/// row stays locked until the enqueueing transaction ends, so concurrent
+/// enqueues can't both take the last free slot.
+fn check_tenant_quota(conn: &PgConnection, name: &str) -> Result<(), EnqueueError> {
+    use crate::schema::swirl_tenants::dsl::*;
+
+    let counts = swirl_tenants
+        .find(name)
+        .select((pending_jobs, max_pending_jobs))
+        .for_update()
+        .first::<(i64, Option<i64>)>(conn)
+        .optional()?;
+    match counts {
+        Some((pending, Some(max))) if pending >= max => {
+            Err(EnqueueError::QuotaExceeded(name.into()))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Jobs which are due to be run, because they were scheduled to run in the
+/// past, and any retry backoff has passed. This is always computed using the
+/// database's clock, so runners with skewed clocks still agree on which jobs
+/// are due.
+///
+/// Jobs which will become due within `slack` are included as well.
+fn retriable(slack: Duration) -> BoxedCondition {
     use crate::schema::background_jobs::dsl::*;
     use diesel::dsl::*;
 
-    sql_function!(fn power(x: Integer, y: Integer) -> Integer);
+    // Jobs without an explicit `retry_at` back off exponentially
+    let next_attempt = sql::<Timestamp>(
+        "COALESCE(retry_at, last_retry + INTERVAL '1 minute' * power(2, retries))",
+    );
+    let slack = PgInterval::from_microseconds(slack.as_micros() as i64);
+    Box::new(
+        scheduled_at
+            .le(now + slack.into_sql::<Interval>())
+            .and(next_attempt.lt(now + slack.into_sql::<Interval>())),
+    )
+}
+
+/// Jobs which are ready to be run, and allowed by `options`
+fn fetchable(options: &FetchOptions) -> BoxedCondition {
+    use crate::schema::background_jobs::dsl::*;
+
+    let mut condition: BoxedCondition = Box::new(
+        retriable(options.early_execution_slack)
+            .and(not_paused())
+            .and(not_blocked())
+            .and(not_waiting_for_batch()),
+    );
+    if let Some(queues) = &options.queues {
+        condition = Box::new(condition.and(in_queues(queues)));
+    }
+    if let Some(job_types) = &options.job_types {
+        condition = Box::new(condition.and(job_type.eq_any(job_types.clone())));
+    }
+    if !options.except_job_types.is_empty() {
+        condition = Box::new(condition.and(job_type.ne_all(options.except_job_types.clone())));
+    }
+    match options
+        .worker_version
+        .as_ref()
+        .and_then(|v| parse_version(v))
+    {
+        Some(version) => {
+            let compatible = min_worker_version
+                .is_null()
+                .or(min_worker_version.le(version));
+            Box::new(condition.and(compatible))
+        }
+        None => Box::new(condition.and(min_worker_version.is_null())),
+    }
+}
+
+/// Jobs in one of `queues`, or in a queue which is treated as the same queue
+/// by `swirl_queue_aliases`
+fn in_queues(queues: &[String]) -> BoxedCondition {
+    use crate::schema::background_jobs::dsl::*;
+    use diesel::dsl::any;
+
+    sql_function!(fn swirl_queue_names(names: Array<Text>) -> Array<Text>);
+
+    Box::new(queue.eq(any(swirl_queue_names(queues.to_vec()))))
+}
+
+/// Jobs which aren't in a queue in `swirl_paused_queues`
+fn not_paused() -> BoxedCondition {
+    use crate::schema::background_jobs::dsl::*;
+    use crate::schema::swirl_paused_queues;
+
+    Box::new(queue.ne_all(swirl_paused_queues::table.select(swirl_paused_queues::queue)))
+}
+
+/// Jobs which don't depend on a job that is still in `background_jobs` or
+/// `swirl_failed_jobs`
+fn not_blocked() -> BoxedCondition {
+    use crate::schema::background_jobs::dsl::*;
+    use crate::schema::{swirl_failed_jobs, swirl_job_dependencies};
 
-    Box::new(last_retry.lt(now - 1.minute().into_sql::<Interval>() * power(2, retries)))
+    let blocked = swirl_job_dependencies::table
+        .select(swirl_job_dependencies::job_id)
+        .filter(
+            swirl_job_dependencies::depends_on
+                .eq_any(background_jobs.select(id))
+                .or(swirl_job_dependencies::depends_on
+                    .eq_any(swirl_failed_jobs::table.select(swirl_failed_jobs::id))),
+        );
+    Box::new(id.ne_all(blocked))
+}
+
+/// Jobs which aren't the completion job of a batch which still has jobs in
+/// `background_jobs`
+fn not_waiting_for_batch() -> BoxedCondition {
+    use crate::schema::background_jobs::dsl::*;
+    use crate::schema::swirl_job_batches;
+
+    let waiting = swirl_job_batches::table
+        .select(swirl_job_batches::batch_id)
+        .filter(swirl_job_batches::job_id.eq_any(background_jobs.select(id)));
+    Box::new(id.ne_all(waiting))
+}
+
+/// Parses a version made up of numbers separated by dots, such as `1.4.2`,
+/// into an array which Postgres compares the same way.
+pub fn parse_version(version: &str) -> Option<Vec<i32>> {
+    version.split('.').map(|part| part.parse().ok()).collect()
 }
 
 /// Finds the next job that is unlocked, and ready to be retried. If a row is
 /// found, it will be locked.
-pub fn find_next_unlocked_job(conn: &PgConnection) -> QueryResult<BackgroundJob> {
+///
+/// Jobs matched by `excluded` will not be returned.
+pub fn find_next_unlocked_job(
+    conn: &PgConnection,
+    options: &FetchOptions,
+    excluded: &Excluded,
+) -> QueryResult<BackgroundJob> {
+    use crate::schema::background_jobs::dsl::*;
+
+    let query = background_jobs
+        .select((id, job_type, data, priority, queue, metadata, retries))
+        .filter(fetch_filter(options, excluded));
+    match &options.locality {
+        Some(runner_locality) => {
+            let preferred = preferred_locality(runner_locality, options.locality_window);
+            query
+                .order((priority.desc(), preferred.desc(), id))
+                .for_update()
+                .skip_locked()
+                .first::<BackgroundJob>(conn)
+        }
+        None => query
+            .order((priority.desc(), id))
+            .for_update()
+            .skip_locked()
+            .first::<BackgroundJob>(conn),
+    }
+}
+
+/// Finds the oldest unlocked job of the highest priority among the jobs
+/// allowed by `options` and `excluded`, in the first job type after `after`,
+/// or the first queue after it if `by_queue` is `true`. Types and queues are
+/// compared by name. If `after` is `None`, the first type or queue is used.
+///
+/// Returns `None` if there is no such job, including when every job of the
+/// highest priority is already running.
+pub fn find_next_job_in_turn(
+    conn: &PgConnection,
+    options: &FetchOptions,
+    excluded: &Excluded,
+    by_queue: bool,
+    after: Option<&str>,
+) -> QueryResult<Option<BackgroundJob>> {
+    use crate::schema::background_jobs::dsl::*;
+
+    let top_priority = background_jobs
+        .select(diesel::dsl::max(priority))
+        .filter(fetch_filter(options, excluded))
+        .first::<Option<i16>>(conn)?;
+    let top_priority = match top_priority {
+        Some(top_priority) => top_priority,
+        None => return Ok(None),
+    };
+
+    let in_turn: BoxedCondition = match (by_queue, after) {
+        (false, Some(after)) => Box::new(job_type.gt(after.to_string())),
+        (true, Some(after)) => Box::new(queue.gt(after.to_string())),
+        (_, None) => Box::new(true.into_sql::<Bool>()),
+    };
+    let query = background_jobs
+        .select((id, job_type, data, priority, queue, metadata, retries))
+        .filter(fetch_filter(options, excluded))
+        .filter(priority.eq(top_priority))
+        .filter(in_turn);
+    if by_queue {
+        query
+            .order((queue, id))
+            .for_update()
+            .skip_locked()
+            .first(conn)
+            .optional()
+    } else {
+        query
+            .order((job_type, id))
+            .for_update()
+            .skip_locked()
+            .first(conn)
+            .optional()
+    }
+}
+
+/// Jobs in `runner_locality`, and jobs which have been due for longer than
+/// `window`. Jobs elsewhere are only passed over for a while, so they can't be
+/// starved by a busy locality.
+fn preferred_locality(runner_locality: &str, window: Duration) -> BoxedCondition {
+    use crate::schema::background_jobs::dsl::*;
+
+    sql_function!(fn coalesce(x: Nullable<Bool>, y: Bool) -> Bool);
+
+    let window = PgInterval::from_microseconds(window.as_micros() as i64);
+    Box::new(
+        coalesce(locality.eq(runner_locality.to_string()).nullable(), false)
+            .or(scheduled_at.lt(now - window.into_sql::<Interval>())),
+    )
+}
+
+/// Jobs which are ready to be run, allowed by `options`, and not matched by
+/// `excluded`
+pub fn fetch_filter(options: &FetchOptions, excluded: &Excluded) -> BoxedCondition {
+    use crate::schema::background_jobs::dsl::*;
+
+    sql_function!(
+        fn swirl_concurrency_key_allowed(job_metadata: Jsonb, excluded_keys: Array<Text>) -> Bool
+    );
+
+    let condition = fetchable(options)
+        .and(id.ne_all(excluded.ids.clone()))
+        .and(job_type.ne_all(excluded.job_types.clone()))
+        .and(queue.ne_all(excluded.queues.clone()));
+    if excluded.concurrency_keys.is_empty() {
+        Box::new(condition)
+    } else {
+        let keys = excluded.concurrency_keys.clone();
+        Box::new(condition.and(swirl_concurrency_key_allowed(metadata, keys)))
+    }
+}
+
+/// Returns whether there is a job with a priority higher than
+/// `than_priority` which is ready to run, and not already running
+pub fn higher_priority_job_waiting(
+    conn: &PgConnection,
+    options: &FetchOptions,
+    than_priority: i16,
+) -> QueryResult<bool> {
     use crate::schema::background_jobs::dsl::*;
 
     background_jobs
-        .select((id, job_type, data))
-        .filter(retriable())
-        .order(id)
+        .select(id)
+        .filter(fetchable(options))
+        .filter(priority.gt(than_priority))
         .for_update()
         .skip_locked()
-        .first::<BackgroundJob>(conn)
+        .first::<i64>(conn)
+        .optional()
+        .map(|job| job.is_some())
+}
+
+#[derive(QueryableByName)]
+struct SlotTaken {
+    #[sql_type = "Bool"]
+    taken: bool,
+}
+
+/// Takes one of `limit` slots which are shared by every runner for `key`.
+/// Returns `false` if all of the slots are already taken.
+///
+/// Slots are transaction level advisory locks, so a slot is held until the
+/// job which took it finishes, and is released if the savepoint it was taken
+/// in is rolled back.
+pub fn try_take_concurrency_slot(conn: &PgConnection, key: &str, limit: u32) -> QueryResult<bool> {
+    sql_query(
+        "SELECT EXISTS ( \
+             SELECT 1 FROM generate_series(1, $2) slot \
+             WHERE pg_try_advisory_xact_lock(hashtext($1), slot) \
+         ) AS taken",
+    )
+    .bind::<Text, _>(format!("swirl:{}", key))
+    .bind::<Integer, _>(limit as i32)
+    .get_result::<SlotTaken>(conn)
+    .map(|s| s.taken)
+}
+
+/// Sets the `application_name` of `conn` to name the job it is running and
+/// the worker running it, until the current transaction ends. This is what
+/// [`admin::running_jobs`](crate::admin::running_jobs) reads.
+pub fn label_running_job(
+    conn: &PgConnection,
+    job_id: i64,
+    worker_id: Option<i64>,
+) -> QueryResult<()> {
+    let label = match worker_id {
+        Some(worker_id) => format!("swirl job {} worker {}", job_id, worker_id),
+        None => format!("swirl job {}", job_id),
+    };
+    sql_query("SELECT set_config('application_name', $1, true)")
+        .bind::<Text, _>(label)
+        .execute(conn)?;
+    Ok(())
+}
+
+#[derive(QueryableByName)]
+struct BackendPid {
+    #[sql_type = "Integer"]
+    pid: i32,
+}
+
+/// The process id of the Postgres backend `conn` is connected to
+pub fn backend_pid(conn: &PgConnection) -> QueryResult<i32> {
+    sql_query("SELECT pg_backend_pid() AS pid")
+        .get_result::<BackendPid>(conn)
+        .map(|b| b.pid)
+}
+
+/// Terminates the Postgres backend with the process id `pid`, which rolls back
+/// its transaction and releases its locks
+pub fn terminate_backend(conn: &PgConnection, pid: i32) -> QueryResult<()> {
+    sql_query("SELECT pg_terminate_backend($1)")
+        .bind::<Integer, _>(pid)
+        .execute(conn)
+        .map(|_| ())
 }
 
 /// The number of jobs that have failed at least once
@@ -60,22 +617,531 @@ pub fn failed_job_count(conn: &PgConnection) -> QueryResult<i64> {
         .get_result(conn)
 }
 
-/// Deletes a job that has successfully completed running
-pub fn delete_successful_job(conn: &PgConnection, job_id: i64) -> QueryResult<()> {
+/// The types of the jobs in the queue which a runner with `options` would run
+/// once they are due
+pub fn queued_job_types(conn: &PgConnection, options: &FetchOptions) -> QueryResult<Vec<String>> {
+    use crate::schema::background_jobs::dsl::*;
+
+    let mut query = background_jobs.select(job_type).distinct().into_boxed();
+    if let Some(queues) = &options.queues {
+        query = query.filter(in_queues(queues));
+    }
+    if let Some(job_types) = &options.job_types {
+        query = query.filter(job_type.eq_any(job_types));
+    }
+    if !options.except_job_types.is_empty() {
+        query = query.filter(job_type.ne_all(&options.except_job_types));
+    }
+    query.load(conn)
+}
+
+#[cfg(feature = "metrics")]
+#[derive(QueryableByName)]
+struct QueueDepth {
+    #[sql_type = "Text"]
+    job_type: String,
+    #[sql_type = "BigInt"]
+    depth: i64,
+    #[sql_type = "diesel::sql_types::Double"]
+    oldest_job_age: f64,
+}
+
+/// The number of queued jobs of each type, and how long ago the oldest one was
+/// enqueued
+#[cfg(feature = "metrics")]
+pub fn queue_depths(conn: &PgConnection) -> QueryResult<Vec<(String, i64, Duration)>> {
+    let depths = sql_query(
+        "SELECT job_type, COUNT(*) AS depth, \
+         EXTRACT(epoch FROM now() - MIN(created_at))::float8 AS oldest_job_age \
+         FROM background_jobs GROUP BY job_type",
+    )
+    .load::<QueueDepth>(conn)?;
+    Ok(depths
+        .into_iter()
+        .map(|d| {
+            let age = Duration::from_secs_f64(d.oldest_job_age.max(0.0));
+            (d.job_type, d.depth, age)
+        })
+        .collect())
+}
+
+/// The job types which are currently in debug mode
+pub fn debug_job_types(conn: &PgConnection) -> QueryResult<Vec<String>> {
+    use crate::schema::swirl_debug_job_types::dsl::*;
+
+    swirl_debug_job_types
+        .select(job_type)
+        .filter(enabled_until.gt(now))
+        .load(conn)
+}
+
+/// Deletes a job that has successfully completed running or was cancelled,
+/// along with its checkpoint, progress and dependencies, and the batch it
+/// completes if it has one
+pub fn delete_job(conn: &PgConnection, job_id: i64) -> QueryResult<()> {
     use crate::schema::background_jobs::dsl::*;
+    use crate::schema::{
+        background_job_checkpoints, swirl_job_batches, swirl_job_dependencies, swirl_job_progress,
+    };
 
     delete(background_jobs.find(job_id)).execute(conn)?;
+    delete(background_job_checkpoints::table.find(job_id)).execute(conn)?;
+    delete(swirl_job_progress::table.find(job_id)).execute(conn)?;
+    delete(swirl_job_dependencies::table.filter(swirl_job_dependencies::job_id.eq(job_id)))
+        .execute(conn)?;
+    delete(swirl_job_batches::table.filter(swirl_job_batches::batch_id.eq(job_id)))
+        .execute(conn)?;
+    Ok(())
+}
+
+/// Adds the job with the id `job_id` to the batch with the id `batch`
+pub fn add_to_batch(conn: &PgConnection, batch: i64, job_id: i64) -> QueryResult<()> {
+    use crate::schema::swirl_job_batches;
+
+    insert_into(swirl_job_batches::table)
+        .values((
+            swirl_job_batches::batch_id.eq(batch),
+            swirl_job_batches::job_id.eq(job_id),
+        ))
+        .execute(conn)?;
+    Ok(())
+}
+
+/// The ids of the jobs in the batch with the id `batch` which are in
+/// `swirl_failed_jobs`
+pub fn failed_batch_jobs(conn: &PgConnection, batch: i64) -> QueryResult<Vec<i64>> {
+    use crate::schema::{swirl_failed_jobs, swirl_job_batches};
+
+    swirl_job_batches::table
+        .select(swirl_job_batches::job_id)
+        .filter(swirl_job_batches::batch_id.eq(batch))
+        .filter(
+            swirl_job_batches::job_id
+                .eq_any(swirl_failed_jobs::table.select(swirl_failed_jobs::id)),
+        )
+        .order(swirl_job_batches::job_id)
+        .load(conn)
+}
+
+/// Notifies anyone waiting on a job that it has finished running. The
+/// notification is only delivered once the current transaction commits.
+pub fn notify_completion(conn: &PgConnection, job_id: i64, outcome: JobOutcome) -> QueryResult<()> {
+    sql_query("SELECT pg_notify($1, $2)")
+        .bind::<Text, _>(completion::channel(job_id))
+        .bind::<Text, _>(outcome.as_str())
+        .execute(conn)?;
     Ok(())
 }
 
-/// Marks that we just tried and failed to run a job.
+/// Records a call to `Runner::run_until_empty` in `swirl_runs`. The run is
+/// recorded as having started `summary.duration` ago by the database's clock.
+pub fn record_run(
+    conn: &PgConnection,
+    summary: &RunSummary,
+    error: Option<&str>,
+) -> QueryResult<()> {
+    use crate::schema::swirl_runs;
+
+    let host = ::hostname::get().map(|h| h.to_string_lossy().into_owned());
+    let duration = PgInterval::from_microseconds(summary.duration.as_micros() as i64);
+    insert_into(swirl_runs::table)
+        .values((
+            swirl_runs::hostname.eq(host.ok()),
+            swirl_runs::started_at.eq(now - duration.into_sql::<Interval>()),
+            swirl_runs::jobs_run.eq(summary.jobs_run as i64),
+            swirl_runs::failures.eq(summary.failures as i64),
+            swirl_runs::error.eq(error),
+        ))
+        .execute(conn)?;
+    Ok(())
+}
+
+/// Marks that we just tried and failed to run a job, and sets when it is next
+/// run. Jobs which won't be run again are moved to `swirl_failed_jobs`.
+///
+/// If this is the job's first failure, its arguments and error are recorded
+/// in `first_failure`, along with `environment`, which describes the runner.
 ///
 /// Ignores any database errors that may have occurred. If the DB has gone away,
 /// we assume that just trying again with a new connection will succeed.
-pub fn update_failed_job(conn: &PgConnection, job_id: i64) {
-    use crate::schema::background_jobs::dsl::*;
+pub fn update_failed_job(
+    conn: &PgConnection,
+    job_id: i64,
+    next_run: NextRun,
+    error: &str,
+    environment: &serde_json::Value,
+    owner: Option<&str>,
+) {
+    // Moved in a savepoint, so the job is still updated if this fails
+    if next_run == NextRun::Never
+        && conn
+            .transaction(|| move_to_failed_jobs(conn, job_id, error, environment, owner))
+            .is_ok()
+    {
+        return;
+    }
+
+    let delay = match next_run {
+        NextRun::After(delay) => Some(PgInterval::from_microseconds(delay.as_micros() as i64)),
+        NextRun::DefaultBackoff | NextRun::Never => None,
+    };
+    let _ = sql_query(format!(
+        "UPDATE background_jobs SET retries = retries + 1, last_retry = now(), \
+         retry_at = now() + $2, first_failure = COALESCE(first_failure, {}), \
+         retry_history = {} \
+         WHERE id = $1",
+        first_failure_snapshot("$3", "$4"),
+        appended_retry_history("$3"),
+    ))
+    .bind::<BigInt, _>(job_id)
+    .bind::<Nullable<Interval>, _>(delay)
+    .bind::<Text, _>(error)
+    .bind::<Jsonb, _>(environment)
+    .execute(conn);
+}
+
+/// The value `first_failure` is set to when a job first fails, given the
+/// placeholders of the error and the runner's environment
+fn first_failure_snapshot(error: &str, environment: &str) -> String {
+    format!(
+        "jsonb_build_object('data', data, 'error', {}::text, 'failed_at', now()::timestamp) || {}",
+        error, environment
+    )
+}
+
+/// The value `retry_history` is set to when a job fails, given the placeholder
+/// of the error. The new attempt is appended, and only the most recent
+/// [`RETRY_HISTORY_LENGTH`] attempts are kept.
+fn appended_retry_history(error: &str) -> String {
+    format!(
+        "(SELECT COALESCE(jsonb_agg(attempt ORDER BY n), '[]') \
+         FROM jsonb_array_elements(retry_history || jsonb_build_object(\
+             'failed_at', extract(epoch FROM now()), \
+             'fingerprint', {})) WITH ORDINALITY AS attempts (attempt, n) \
+         WHERE n > jsonb_array_length(retry_history) + 1 - {})",
+        error_fingerprint(error),
+        RETRY_HISTORY_LENGTH
+    )
+}
+
+/// A fingerprint of an error and the job type it came from, given the
+/// placeholder of the error. Numbers are removed from the error first, so that
+/// failures which only differ by an id or a duration are grouped together.
+fn error_fingerprint(error: &str) -> String {
+    format!(
+        "md5(job_type || ':' || regexp_replace({}, '[0-9]+', 'N', 'g'))",
+        error
+    )
+}
+
+/// Moves a job which has failed for the last time to `swirl_failed_jobs`
+fn move_to_failed_jobs(
+    conn: &PgConnection,
+    job_id: i64,
+    error: &str,
+    environment: &serde_json::Value,
+    owner: Option<&str>,
+) -> QueryResult<()> {
+    sql_query(format!(
+        "WITH failed AS (DELETE FROM background_jobs WHERE id = $1 RETURNING *) \
+         INSERT INTO swirl_failed_jobs (id, job_type, data, priority, queue, metadata, \
+             min_worker_version, retries, created_at, error, first_failure, retry_history, \
+             owner, scheduled_at, unique_key, locality) \
+         SELECT id, job_type, data, priority, queue, metadata, \
+             min_worker_version, retries + 1, created_at, $2, \
+             COALESCE(first_failure, {}), {}, $4, scheduled_at, unique_key, locality \
+         FROM failed",
+        first_failure_snapshot("$2", "$3"),
+        appended_retry_history("$2"),
+    ))
+    .bind::<BigInt, _>(job_id)
+    .bind::<Text, _>(error)
+    .bind::<Jsonb, _>(environment)
+    .bind::<Nullable<Text>, _>(owner)
+    .execute(conn)?;
+    Ok(())
+}
+
+/// Loads the most recently saved checkpoint for a job
+pub fn load_checkpoint(
+    conn: &PgConnection,
+    checkpoint_job_id: i64,
+) -> QueryResult<Option<serde_json::Value>> {
+    use crate::schema::background_job_checkpoints::dsl::*;
+
+    background_job_checkpoints
+        .find(checkpoint_job_id)
+        .select(state)
+        .first(conn)
+        .optional()
+}
+
+/// Saves a checkpoint for a job, replacing any previous checkpoint
+pub fn save_checkpoint(
+    conn: &PgConnection,
+    checkpoint_job_id: i64,
+    checkpoint_state: serde_json::Value,
+) -> QueryResult<()> {
+    use crate::schema::background_job_checkpoints::dsl::*;
+
+    insert_into(background_job_checkpoints)
+        .values((job_id.eq(checkpoint_job_id), state.eq(&checkpoint_state)))
+        .on_conflict(job_id)
+        .do_update()
+        .set((state.eq(&checkpoint_state), updated_at.eq(now)))
+        .execute(conn)?;
+    Ok(())
+}
 
-    let _ = update(background_jobs.find(job_id))
-        .set((retries.eq(retries + 1), last_retry.eq(now)))
-        .execute(conn);
+/// Saves the progress reported by a running job. Nothing is saved once the
+/// job is no longer in the queue, so progress which was written late isn't
+/// left behind.
+pub fn save_progress(
+    conn: &PgConnection,
+    job_id: i64,
+    current: u64,
+    total: u64,
+) -> QueryResult<()> {
+    sql_query(
+        "INSERT INTO swirl_job_progress (job_id, current, total, updated_at) \
+         SELECT $1, $2, $3, now() WHERE EXISTS (SELECT 1 FROM background_jobs WHERE id = $1) \
+         ON CONFLICT (job_id) DO UPDATE \
+         SET current = excluded.current, total = excluded.total, updated_at = excluded.updated_at",
+    )
+    .bind::<BigInt, _>(job_id)
+    .bind::<BigInt, _>(current as i64)
+    .bind::<BigInt, _>(total as i64)
+    .execute(conn)?;
+    Ok(())
+}
+
+/// Records `result` as the value returned by the job with the id `job_id`,
+/// to be kept for `retention`
+pub fn save_result(
+    conn: &PgConnection,
+    job_id: i64,
+    result: &serde_json::Value,
+    retention: Duration,
+) -> QueryResult<()> {
+    let retention = PgInterval::from_microseconds(retention.as_micros() as i64);
+    sql_query(
+        "INSERT INTO swirl_job_results (job_id, job_type, result, completed_at, expires_at) \
+         SELECT id, job_type, $2, now(), now() + $3 FROM background_jobs WHERE id = $1 \
+         ON CONFLICT (job_id) DO UPDATE \
+         SET result = excluded.result, completed_at = excluded.completed_at, \
+         expires_at = excluded.expires_at",
+    )
+    .bind::<BigInt, _>(job_id)
+    .bind::<Jsonb, _>(result)
+    .bind::<Interval, _>(&retention)
+    .execute(conn)?;
+    Ok(())
+}
+
+/// The result saved by the job with the id `job_id`, unless it has expired
+pub fn load_result(conn: &PgConnection, job_id: i64) -> QueryResult<Option<serde_json::Value>> {
+    use crate::schema::swirl_job_results;
+
+    swirl_job_results::table
+        .find(job_id)
+        .filter(swirl_job_results::expires_at.gt(now))
+        .select(swirl_job_results::result)
+        .first(conn)
+        .optional()
+}
+
+/// Allows the current transaction to commit without waiting for it to be
+/// flushed to disk
+pub fn disable_synchronous_commit(conn: &PgConnection) -> QueryResult<()> {
+    sql_query("SET LOCAL synchronous_commit TO OFF").execute(conn)?;
+    Ok(())
+}
+
+/// Copies the arguments of a failed job and its error into
+/// `swirl_failure_samples`, keeping only the `limit` most recent samples with
+/// the same fingerprint.
+///
+/// See [`error_fingerprint`] for how the fingerprint is derived.
+pub fn record_failure_sample(
+    conn: &PgConnection,
+    job_id: i64,
+    error: &str,
+    limit: u32,
+    owner: Option<&str>,
+) -> QueryResult<()> {
+    #[derive(QueryableByName)]
+    struct Fingerprint {
+        #[sql_type = "Text"]
+        fingerprint: String,
+    }
+
+    let sample = sql_query(format!(
+        "INSERT INTO swirl_failure_samples (job_id, job_type, fingerprint, data, error, owner) \
+         SELECT id, job_type, {}, data, $2, $3 \
+         FROM background_jobs WHERE id = $1 \
+         RETURNING fingerprint",
+        error_fingerprint("$2"),
+    ))
+    .bind::<BigInt, _>(job_id)
+    .bind::<Text, _>(error)
+    .bind::<Nullable<Text>, _>(owner)
+    .get_result::<Fingerprint>(conn)?;
+
+    sql_query(
+        "DELETE FROM swirl_failure_samples WHERE fingerprint = $1 AND id NOT IN ( \
+             SELECT id FROM swirl_failure_samples WHERE fingerprint = $1 \
+             ORDER BY id DESC LIMIT $2 \
+         )",
+    )
+    .bind::<Text, _>(sample.fingerprint)
+    .bind::<BigInt, _>(i64::from(limit))
+    .execute(conn)?;
+    Ok(())
+}
+
+/// Records a runner in `swirl_workers`, returning its id.
+///
+/// Workers which haven't sent a heartbeat within `stale_after` are assumed to
+/// have died without deregistering, and are removed.
+pub fn register_worker(
+    conn: &PgConnection,
+    options: &FetchOptions,
+    threads: i32,
+    stale_after: Duration,
+) -> QueryResult<i64> {
+    use crate::schema::swirl_workers::dsl::*;
+    use diesel::dsl::IntervalDsl;
+
+    let stale_after = (stale_after.as_secs() as i32).seconds();
+    delete(swirl_workers.filter(last_heartbeat_at.lt(now - stale_after))).execute(conn)?;
+
+    let host = ::hostname::get()
+        .map(|h| h.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    insert_into(swirl_workers)
+        .values((
+            hostname.eq(host),
+            pid.eq(std::process::id() as i32),
+            version.eq(&options.worker_version),
+            queues.eq(&options.queues),
+            thread_count.eq(threads),
+        ))
+        .returning(id)
+        .get_result(conn)
+}
+
+/// Updates the heartbeat of a worker. Returns `false` if the worker is no
+/// longer registered.
+pub fn worker_heartbeat(conn: &PgConnection, worker_id: i64) -> QueryResult<bool> {
+    use crate::schema::swirl_workers::dsl::*;
+
+    let updated = update(swirl_workers.find(worker_id))
+        .set(last_heartbeat_at.eq(now))
+        .execute(conn)?;
+    Ok(updated > 0)
+}
+
+pub fn deregister_worker(conn: &PgConnection, worker_id: i64) -> QueryResult<()> {
+    use crate::schema::swirl_workers::dsl::*;
+
+    delete(swirl_workers.find(worker_id)).execute(conn)?;
+    Ok(())
+}
+
+/// Records that the job with the id `lease_job_id` is locked by the worker
+/// with the id `worker_id`, through the connection to the backend with the
+/// process id `pid`. The lease expires after `duration` unless it is renewed.
+pub fn acquire_lease(
+    conn: &PgConnection,
+    lease_job_id: i64,
+    worker_id: i64,
+    pid: i32,
+    duration: Duration,
+) -> QueryResult<()> {
+    use crate::schema::swirl_job_leases::dsl::*;
+
+    let duration = PgInterval::from_microseconds(duration.as_micros() as i64);
+    insert_into(swirl_job_leases)
+        .values((
+            job_id.eq(lease_job_id),
+            locked_by.eq(worker_id),
+            backend_pid.eq(pid),
+            locked_until.eq(now + duration),
+        ))
+        .on_conflict(job_id)
+        .do_update()
+        .set((
+            locked_by.eq(worker_id),
+            backend_pid.eq(pid),
+            locked_at.eq(now),
+            locked_until.eq(now + duration),
+        ))
+        .execute(conn)?;
+    Ok(())
+}
+
+/// Extends the leases of `job_ids` held by the worker with the id
+/// `worker_id` until `duration` from now. Returns the ids of the jobs whose
+/// lease was renewed. Any others were reclaimed by another runner.
+pub fn renew_leases(
+    conn: &PgConnection,
+    job_ids: &[i64],
+    worker_id: i64,
+    duration: Duration,
+) -> QueryResult<Vec<i64>> {
+    use crate::schema::swirl_job_leases::dsl::*;
+
+    let duration = PgInterval::from_microseconds(duration.as_micros() as i64);
+    let leases = swirl_job_leases
+        .filter(job_id.eq_any(job_ids))
+        .filter(locked_by.eq(worker_id));
+    update(leases)
+        .set(locked_until.eq(now + duration))
+        .returning(job_id)
+        .get_results(conn)
+}
+
+/// Removes the leases of `job_ids` held by the worker with the id
+/// `worker_id`. Returns the ids of the jobs whose lease was removed. Any
+/// others were reclaimed by another runner.
+pub fn release_leases(
+    conn: &PgConnection,
+    job_ids: &[i64],
+    worker_id: i64,
+) -> QueryResult<Vec<i64>> {
+    use crate::schema::swirl_job_leases::dsl::*;
+
+    let leases = swirl_job_leases
+        .filter(job_id.eq_any(job_ids))
+        .filter(locked_by.eq(worker_id));
+    delete(leases).returning(job_id).get_results(conn)
+}
+
+#[derive(QueryableByName)]
+struct Reclaimed {
+    #[sql_type = "BigInt"]
+    count: i64,
+}
+
+/// Removes the leases which have expired, and terminates the connections
+/// still holding their jobs' locks. Returns the number of connections which
+/// were terminated.
+///
+/// A backend's process id can be reused once it exits, so only a backend
+/// whose transaction began before the lease was taken is terminated.
+pub fn reclaim_expired_leases(conn: &PgConnection) -> QueryResult<i64> {
+    sql_query(
+        "WITH expired AS ( \
+             DELETE FROM swirl_job_leases WHERE locked_until < now() \
+             RETURNING backend_pid, locked_at \
+         ) \
+         SELECT COUNT(pg_terminate_backend(pid)) AS count FROM ( \
+             SELECT DISTINCT a.pid FROM expired e \
+             INNER JOIN pg_stat_activity a ON a.pid = e.backend_pid \
+             WHERE a.xact_start <= e.locked_at \
+             AND a.pid <> pg_backend_pid() \
+         ) stuck",
+    )
+    .get_result::<Reclaimed>(conn)
+    .map(|r| r.count)
 }
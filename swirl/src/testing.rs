@@ -0,0 +1,104 @@
+//! Utilities for testing applications which use swirl
+//!
+//! Tests which run jobs can't be wrapped in a transaction, since jobs are run
+//! on other connections. [`TestSchema`] instead gives each test its own
+//! Postgres schema with swirl's tables in it, so that tests can run in
+//! parallel without seeing each other's jobs.
+//!
+//! [`sync`] has primitives for making jobs run in lockstep or in a fixed
+//! order, for deterministic tests of how jobs behave when they run at the
+//! same time.
+//!
+//! Requires the `testing` feature.
+
+use diesel::prelude::*;
+use diesel::sql_query;
+use std::collections::hash_map::RandomState;
+use std::error::Error;
+use std::hash::{BuildHasher, Hasher};
+
+pub mod sync;
+
+/// A temporary schema with swirl's migrations run in it, which is dropped
+/// along with everything in it when this is dropped.
+///
+/// ```no_run
+/// # use swirl::testing::TestSchema;
+/// # fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+/// let schema = TestSchema::new("postgres://localhost/my_app_test")?;
+/// let runner = swirl::Runner::builder(())
+///     .database_url(schema.database_url())
+///     .build();
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct TestSchema {
+    name: String,
+    base_url: String,
+    schema_url: String,
+}
+
+impl TestSchema {
+    /// Creates a schema named `swirl_test_` followed by a random suffix in the
+    /// database at `database_url`, and runs swirl's migrations in it.
+    ///
+    /// Any tables your jobs use need to be created in the schema as well, for
+    /// example by running your own migrations with [`TestSchema::connection`].
+    pub fn new(database_url: &str) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let name = format!("swirl_test_{:016x}", random_suffix());
+        let conn = PgConnection::establish(database_url)?;
+        sql_query(format!("CREATE SCHEMA {}", name)).execute(&conn)?;
+
+        let separator = if database_url.contains('?') { '&' } else { '?' };
+        let schema = Self {
+            schema_url: format!(
+                "{}{}options=-csearch_path%3D{}",
+                database_url, separator, name
+            ),
+            base_url: database_url.into(),
+            name,
+        };
+        crate::run_migrations(&schema.connection()?)?;
+        Ok(schema)
+    }
+
+    /// The name of the schema
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// A URL for the database which sets the schema as the search path.
+    /// Give this to [`Builder::database_url`](crate::Builder::database_url),
+    /// or use it to create your own connection pool, and all connections will
+    /// use the schema's tables.
+    pub fn database_url(&self) -> &str {
+        &self.schema_url
+    }
+
+    /// Opens a new connection which uses the schema's tables
+    pub fn connection(&self) -> ConnectionResult<PgConnection> {
+        PgConnection::establish(&self.schema_url)
+    }
+}
+
+impl Drop for TestSchema {
+    fn drop(&mut self) {
+        let result = PgConnection::establish(&self.base_url)
+            .map_err(Into::<Box<dyn Error>>::into)
+            .and_then(|conn| {
+                sql_query(format!("DROP SCHEMA {} CASCADE", self.name))
+                    .execute(&conn)
+                    .map_err(Into::into)
+            });
+        if let Err(e) = result {
+            eprintln!("Failed to drop schema {}: {}", self.name, e);
+        }
+    }
+}
+
+/// Schema names only need to differ between tests which run at the same time,
+/// so the randomly keyed hasher from std is random enough.
+fn random_suffix() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
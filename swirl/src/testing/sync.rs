@@ -0,0 +1,89 @@
+//! Synchronization primitives for testing how jobs behave when they run
+//! concurrently
+//!
+//! Job environments must be `RefUnwindSafe`, which the types in `std::sync`
+//! can't always be shared as. These can be cloned into a job's environment
+//! directly.
+
+use std::panic::{RefUnwindSafe, UnwindSafe};
+use std::sync::{Arc, Barrier as StdBarrier, BarrierWaitResult, Condvar, Mutex};
+use std::time::Duration;
+
+/// A `std::sync::Barrier` which can be cloned into a job's environment.
+///
+/// Jobs which wait on a barrier run in lockstep. For example, if `n` jobs and
+/// the test wait on a barrier for `n + 1` threads, every job is held at the
+/// same point until the test waits on it, such as to check which rows are
+/// locked while the jobs are running.
+#[derive(Debug, Clone)]
+pub struct Barrier {
+    inner: Arc<StdBarrier>,
+}
+
+impl Barrier {
+    /// A barrier which releases every thread once `n` threads are waiting
+    pub fn new(n: usize) -> Self {
+        Self {
+            inner: Arc::new(StdBarrier::new(n)),
+        }
+    }
+
+    /// Blocks until `n` threads are waiting on this barrier
+    pub fn wait(&self) -> BarrierWaitResult {
+        self.inner.wait()
+    }
+}
+
+impl UnwindSafe for Barrier {}
+impl RefUnwindSafe for Barrier {}
+
+/// Forces steps which run on different threads to happen in a fixed order.
+///
+/// Steps are numbered from 0, and each one waits for every step before it to
+/// finish. This allows a specific interleaving of jobs to be tested, such as
+/// one job committing between two queries made by another.
+#[derive(Debug, Clone, Default)]
+pub struct Sequence {
+    inner: Arc<(Mutex<usize>, Condvar)>,
+}
+
+impl Sequence {
+    /// How long a step waits for the steps before it before panicking, so
+    /// that a test with a mistake in its ordering fails instead of hanging
+    const TIMEOUT: Duration = Duration::from_secs(30);
+
+    /// A sequence which starts at step 0
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Waits for every step before `step` to finish, then runs `f`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the steps before `step` don't finish within 30 seconds, or if
+    /// `step` has already run.
+    pub fn step<T, F: FnOnce() -> T>(&self, step: usize, f: F) -> T {
+        let (next_step, changed) = &*self.inner;
+        let guard = next_step.lock().unwrap_or_else(|e| e.into_inner());
+        let (guard, timeout) = changed
+            .wait_timeout_while(guard, Self::TIMEOUT, |next| *next < step)
+            .unwrap_or_else(|e| e.into_inner());
+        assert!(
+            !timeout.timed_out(),
+            "Timed out waiting to run step {}",
+            step
+        );
+        assert_eq!(step, *guard, "Step {} ran more than once", step);
+        drop(guard);
+
+        let result = f();
+
+        *next_step.lock().unwrap_or_else(|e| e.into_inner()) += 1;
+        changed.notify_all();
+        result
+    }
+}
+
+impl UnwindSafe for Sequence {}
+impl RefUnwindSafe for Sequence {}
@@ -0,0 +1,114 @@
+use std::error::Error;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::db::{DieselPool, DieselPoolObj};
+use crate::storage::{self, FetchOptions};
+
+/// How often a runner updates its heartbeat in `swirl_workers`
+pub(crate) const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How long a worker can go without a heartbeat before it is considered dead
+pub(crate) const LIVE_WORKER_TIMEOUT: Duration = Duration::from_secs(3 * 30);
+
+/// Workers which haven't sent a heartbeat for this long are removed from
+/// `swirl_workers` the next time a runner registers
+const STALE_WORKER_TIMEOUT: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Keeps a runner's entry in `swirl_workers` up to date
+pub(crate) struct WorkerRegistration {
+    thread_count: i32,
+    registered: Mutex<Option<RegisteredWorker>>,
+}
+
+struct RegisteredWorker {
+    id: i64,
+    last_heartbeat: Instant,
+    pool: Box<dyn DieselPoolObj + Send>,
+}
+
+impl WorkerRegistration {
+    pub(crate) fn new(thread_count: usize) -> Self {
+        Self {
+            thread_count: thread_count as i32,
+            registered: Mutex::new(None),
+        }
+    }
+
+    /// Registers the runner if it hasn't been registered yet, or updates its
+    /// heartbeat if one is due.
+    ///
+    /// Runners still run jobs while they aren't registered, so errors are
+    /// only logged to stderr, and registering is tried again the next time
+    /// this is called.
+    pub(crate) fn heartbeat<Pool>(&self, pool: &Pool, options: &FetchOptions)
+    where
+        Pool: DieselPool + 'static,
+    {
+        if let Err(e) = self.try_heartbeat(pool, options) {
+            eprintln!("Failed to register the runner: {}", e);
+        }
+    }
+
+    fn try_heartbeat<Pool>(
+        &self,
+        pool: &Pool,
+        options: &FetchOptions,
+    ) -> Result<(), Box<dyn Error + Send + Sync>>
+    where
+        Pool: DieselPool + 'static,
+    {
+        let mut registered = self.registered.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(worker) = &*registered {
+            if worker.last_heartbeat.elapsed() < HEARTBEAT_INTERVAL {
+                return Ok(());
+            }
+        }
+
+        let conn = pool.get()?;
+        let register =
+            || storage::register_worker(&conn, options, self.thread_count, STALE_WORKER_TIMEOUT);
+        if let Some(worker) = &mut *registered {
+            // Workers which miss too many heartbeats are removed by other
+            // runners, so we need to register again if that happened
+            let still_registered = storage::worker_heartbeat(&conn, worker.id)?;
+            if !still_registered {
+                worker.id = register()?;
+            }
+            worker.last_heartbeat = Instant::now();
+        } else {
+            *registered = Some(RegisteredWorker {
+                id: register()?,
+                last_heartbeat: Instant::now(),
+                pool: Box::new(pool.clone()),
+            });
+        }
+        Ok(())
+    }
+
+    /// The runner's id in `swirl_workers`, or `None` if it isn't registered
+    pub(crate) fn id(&self) -> Option<i64> {
+        let registered = self.registered.lock().unwrap_or_else(|e| e.into_inner());
+        registered.as_ref().map(|worker| worker.id)
+    }
+
+    /// Describes the runner, for recording alongside a job's first failure.
+    /// `worker_id` is `None` if the runner isn't registered.
+    pub(crate) fn environment(&self, options: &FetchOptions) -> serde_json::Value {
+        let registered = self.registered.lock().unwrap_or_else(|e| e.into_inner());
+        let host = ::hostname::get().map(|h| h.to_string_lossy().into_owned());
+        serde_json::json!({
+            "worker_id": registered.as_ref().map(|worker| worker.id),
+            "worker_version": options.worker_version,
+            "hostname": host.ok(),
+        })
+    }
+}
+
+impl Drop for RegisteredWorker {
+    fn drop(&mut self) {
+        if let Ok(conn) = self.pool.get() {
+            storage::deregister_worker(&conn, self.id).ok();
+        }
+    }
+}
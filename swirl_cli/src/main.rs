@@ -0,0 +1,220 @@
+//! A command line tool for operators who need to look at or fix a swirl queue
+//! without writing Rust.
+//!
+//! It talks to the database directly, using the same functions as
+//! `swirl::admin`, so it doesn't need to know about the application's jobs.
+//! The database is read from `DATABASE_URL`, which can be set in a `.env`
+//! file.
+
+#![deny(warnings)]
+
+use diesel::prelude::*;
+use std::error::Error;
+use std::process;
+use std::time::Duration;
+use swirl::admin::{self, PreviewOptions};
+use swirl::{AdminError, Cancellation, Queue};
+
+const USAGE: &str = "\
+Usage: swirl <command> [arguments]
+
+Commands:
+    list [--failed] [--offset N] [--limit N]
+                          List queued jobs, or jobs which failed for the last time
+    stats                 Count the queued jobs of each type
+    running               List the jobs which are running, and where
+    retry <id>            Run a failed job again as soon as possible
+    cancel <id>           Remove a job which hasn't started from the queue
+    pause <queue>         Stop runners from starting jobs in a queue
+    resume <queue>        Let runners start jobs in a paused queue again
+    purge-failed <age>    Delete failed jobs older than <age>, such as 30d or 12h
+    doctor                Check the database for common problems
+
+The database is read from DATABASE_URL.";
+
+type CliResult = Result<(), Box<dyn Error>>;
+
+fn main() {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    let args = args.iter().map(String::as_str).collect::<Vec<_>>();
+    if let Err(e) = run(&args) {
+        eprintln!("Error: {}", e);
+        process::exit(1);
+    }
+}
+
+fn run(args: &[&str]) -> CliResult {
+    let (command, args) = match args.split_first() {
+        Some((&"help", _)) | Some((&"--help", _)) | None => {
+            println!("{}", USAGE);
+            return Ok(());
+        }
+        Some((&command, args)) => (command, args),
+    };
+    let database_url = dotenv::var("DATABASE_URL").map_err(|_| "DATABASE_URL must be set")?;
+    let conn = PgConnection::establish(&database_url)?;
+
+    match command {
+        "list" => list(&conn, args),
+        "stats" => stats(&conn),
+        "running" => running(&conn),
+        "retry" => retry(&conn, parse_id(args)?),
+        "cancel" => cancel(&conn, parse_id(args)?),
+        "pause" => pause(&conn, parse_queue(args)?),
+        "resume" => resume(&conn, parse_queue(args)?),
+        "purge-failed" => purge_failed(&conn, args),
+        "doctor" => doctor(&conn),
+        _ => Err(format!("unknown command `{}`\n\n{}", command, USAGE).into()),
+    }
+}
+
+fn list(conn: &PgConnection, args: &[&str]) -> CliResult {
+    let mut failed = false;
+    let mut offset = 0;
+    let mut limit = 50;
+    let mut args = args.iter();
+    while let Some(&arg) = args.next() {
+        match arg {
+            "--failed" => failed = true,
+            "--offset" => offset = parse_number(args.next(), "--offset")?,
+            "--limit" => limit = parse_number(args.next(), "--limit")?,
+            _ => return Err(format!("unexpected argument `{}`", arg).into()),
+        }
+    }
+
+    if failed {
+        println!("id\tjob_type\tretries\terror");
+        for job in admin::list_failed_jobs(conn, offset, limit)? {
+            println!(
+                "{}\t{}\t{}\t{}",
+                job.id, job.job_type, job.retries, job.error
+            );
+        }
+    } else {
+        println!("id\tjob_type\tqueue\tpriority\tretries\targuments");
+        for job in admin::list_jobs(conn, offset, limit, &PreviewOptions::default())? {
+            println!(
+                "{}\t{}\t{}\t{}\t{}\t{}",
+                job.id, job.job_type, job.queue, job.priority, job.retries, job.data_preview
+            );
+        }
+    }
+    Ok(())
+}
+
+fn stats(conn: &PgConnection) -> CliResult {
+    println!("job_type\tjobs\tfailing");
+    for count in admin::count_jobs_by_type(conn)? {
+        println!("{}\t{}\t{}", count.job_type, count.jobs, count.failing_jobs);
+    }
+    Ok(())
+}
+
+fn running(conn: &PgConnection) -> CliResult {
+    println!("job_id\tworker_id\thostname\tpid");
+    for job in admin::running_jobs(conn)? {
+        let or_unknown = |value: Option<String>| value.unwrap_or_else(|| "?".into());
+        println!(
+            "{}\t{}\t{}\t{}",
+            job.job_id,
+            or_unknown(job.worker_id.map(|id| id.to_string())),
+            or_unknown(job.hostname),
+            or_unknown(job.pid.map(|pid| pid.to_string())),
+        );
+    }
+    Ok(())
+}
+
+/// Retries a job which is waiting out its backoff, or one which has been
+/// moved to `swirl_failed_jobs`
+fn retry(conn: &PgConnection, id: i64) -> CliResult {
+    match admin::retry_job(conn, id) {
+        Err(AdminError::JobNotFound) => admin::retry_failed_job(conn, id, None)?,
+        result => result?,
+    }
+    println!("Job {} will be retried", id);
+    Ok(())
+}
+
+fn cancel(conn: &PgConnection, id: i64) -> CliResult {
+    match swirl::cancel_job(conn, id)? {
+        Cancellation::Cancelled => println!("Job {} was cancelled", id),
+        Cancellation::Running => return Err(format!("job {} is running", id).into()),
+        Cancellation::NotFound => return Err(format!("job {} was not found", id).into()),
+        _ => unreachable!(),
+    }
+    Ok(())
+}
+
+fn pause(conn: &PgConnection, queue: Queue<'_>) -> CliResult {
+    queue.pause(conn)?;
+    println!("Queue {} is paused", queue);
+    Ok(())
+}
+
+fn resume(conn: &PgConnection, queue: Queue<'_>) -> CliResult {
+    if !queue.resume(conn)? {
+        return Err(format!("queue {} is not paused", queue).into());
+    }
+    println!("Queue {} was resumed", queue);
+    Ok(())
+}
+
+fn purge_failed(conn: &PgConnection, args: &[&str]) -> CliResult {
+    let older_than = match args {
+        [age] => parse_age(age)?,
+        _ => return Err("expected the age of the failed jobs to purge, such as 30d".into()),
+    };
+    let purged = admin::purge_failed_jobs(conn, older_than)?;
+    println!("Purged {} failed jobs", purged);
+    Ok(())
+}
+
+fn doctor(conn: &PgConnection) -> CliResult {
+    let report = swirl::doctor(conn)?;
+    print!("{}", report);
+    if report.is_healthy() {
+        Ok(())
+    } else {
+        Err("problems were found".into())
+    }
+}
+
+fn parse_id(args: &[&str]) -> Result<i64, Box<dyn Error>> {
+    match args {
+        [id] => id
+            .parse()
+            .map_err(|_| format!("`{}` is not a job id", id).into()),
+        _ => Err("expected a job id".into()),
+    }
+}
+
+fn parse_queue<'a>(args: &[&'a str]) -> Result<Queue<'a>, Box<dyn Error>> {
+    match args {
+        [name] => Ok(swirl::queue(name)),
+        _ => Err("expected the name of a queue".into()),
+    }
+}
+
+fn parse_number(arg: Option<&&str>, name: &str) -> Result<i64, Box<dyn Error>> {
+    arg.and_then(|arg| arg.parse().ok())
+        .ok_or_else(|| format!("{} expects a number", name).into())
+}
+
+/// Parses an age such as `90s`, `30m`, `12h` or `7d`
+fn parse_age(age: &str) -> Result<Duration, Box<dyn Error>> {
+    let invalid = || format!("`{}` is not an age, such as 30d or 12h", age);
+    let unit_start = age
+        .trim_end_matches(|c: char| c.is_ascii_alphabetic())
+        .len();
+    let (number, unit) = age.split_at(unit_start);
+    let seconds_per_unit = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return Err(invalid().into()),
+    };
+    let number = number.parse::<u64>().map_err(|_| invalid())?;
+    Ok(Duration::from_secs(number * seconds_per_unit))
+}
@@ -2,11 +2,13 @@ use crate::diagnostic_shim::*;
 use proc_macro2::TokenStream;
 use quote::quote;
 use std::borrow::Cow;
+use syn::parse::{Parse, ParseStream};
 use syn::punctuated::Punctuated;
 use syn::spanned::Spanned;
 
-pub fn expand(item: syn::ItemFn) -> Result<TokenStream, Diagnostic> {
+pub fn expand(item: syn::ItemFn, options: JobOptions) -> Result<TokenStream, Diagnostic> {
     let job = BackgroundJob::try_from(item)?;
+    let returns_value = job.result_type().is_some();
 
     let attrs = job.attrs;
     let vis = job.visibility;
@@ -17,12 +19,125 @@ pub fn expand(item: syn::ItemFn) -> Result<TokenStream, Diagnostic> {
     let connection_arg = &job.args.connection_arg;
     let pool_pat = connection_arg.pool_pat();
     let pool_ty = connection_arg.pool_ty();
+    let context_binding = job.args.context_arg.as_ref().map(|(pat, ty)| {
+        quote! {
+            let #pat: &#ty = __swirl_context;
+        }
+    });
     let fn_args = job.args.iter();
     let struct_def = job.args.struct_def();
     let struct_assign = job.args.struct_assign();
-    let arg_names = job.args.names();
+    let arg_names = job.args.names().collect::<Vec<_>>();
     let return_type = job.return_type;
-    let body = connection_arg.wrap(job.body);
+    // Jobs which return a value have it saved by `perform`, which returns
+    // `Result<(), PerformError>` like any other job
+    let perform_return_type = if returns_value {
+        quote!(-> Result<(), swirl::PerformError>)
+    } else {
+        quote!(#return_type)
+    };
+    let save_result = |value: TokenStream| {
+        if !returns_value {
+            return value;
+        }
+        quote! {
+            let __swirl_result = #value?;
+            __swirl_context.save_result(
+                &__swirl_result,
+                <Self as swirl::Job>::RESULT_RETENTION,
+            )
+        }
+    };
+    let (env_param, perform_body) = if job.asyncness.is_some() {
+        // The body is run as an `async fn` with the job's arguments, which
+        // `swirl::block_on` drives to completion on the runner's thread
+        let async_args = job.args.iter();
+        let body = job.body;
+        let call = save_result(quote! {
+            swirl::block_on(__swirl_perform(__swirl_env, __swirl_context, #(#arg_names),*))
+        });
+        let perform_body = quote! {
+            async fn __swirl_perform(
+                #env_pat: &#env_type,
+                __swirl_context: &swirl::JobContext<'_>,
+                #(#async_args),*
+            ) #return_type {
+                let #pool_pat: &#pool_ty = __swirl_context.pool();
+                #context_binding
+                #(#body)*
+            }
+
+            let Self { #(#arg_names),* } = self;
+            #call
+        };
+        (quote!(__swirl_env), perform_body)
+    } else if returns_value {
+        // The body is run as a function of its own, so that `return` and `?`
+        // in it return from that function rather than from `perform`
+        let args = job.args.iter();
+        let body = job.body;
+        let call = save_result(quote! {
+            __swirl_perform(__swirl_env, __swirl_context, #(#arg_names),*)
+        });
+        let perform_body = quote! {
+            fn __swirl_perform(
+                #env_pat: &#env_type,
+                __swirl_context: &swirl::JobContext<'_>,
+                #(#args),*
+            ) #return_type {
+                let #pool_pat: &#pool_ty = __swirl_context.pool();
+                #context_binding
+                #(#body)*
+            }
+
+            let Self { #(#arg_names),* } = self;
+            #call
+        };
+        (quote!(__swirl_env), perform_body)
+    } else {
+        let body = connection_arg.wrap(job.body);
+        let perform_body = quote! {
+            let #pool_pat: &#pool_ty = __swirl_context.pool();
+            #context_binding
+            let Self { #(#arg_names),* } = self;
+            #body
+        };
+        (quote!(#env_pat), perform_body)
+    };
+    let max_retries = options.max_retries.map(|max_retries| {
+        quote! {
+            const MAX_RETRIES: Option<u32> = Some(#max_retries);
+        }
+    });
+    let owner = options.owner.map(|owner| {
+        quote! {
+            const OWNER: Option<&'static str> = Some(#owner);
+        }
+    });
+    let json_schema = options.json_schema.map(|json_schema| {
+        quote! {
+            const JSON_SCHEMA: Option<&'static str> = Some(#json_schema);
+        }
+    });
+    let timeout = options.timeout.map(|millis| {
+        quote! {
+            const TIMEOUT: Option<std::time::Duration> =
+                Some(std::time::Duration::from_millis(#millis));
+        }
+    });
+    let result_retention = options.result_retention.map(|millis| {
+        quote! {
+            const RESULT_RETENTION: std::time::Duration =
+                std::time::Duration::from_millis(#millis);
+        }
+    });
+    let retry_policy = options.retry_policy.map(|retry_policy| {
+        quote! {
+            fn retry_policy() -> Option<swirl::RetryPolicy> {
+                Some(#retry_policy)
+            }
+        }
+    });
 
     let res = quote! {
         #(#attrs)*
@@ -35,10 +150,16 @@ pub fn expand(item: syn::ItemFn) -> Result<TokenStream, Diagnostic> {
         impl swirl::Job for #name :: Job {
             type Environment = #env_type;
             const JOB_TYPE: &'static str = stringify!(#name);
+            #max_retries
+            #owner
+            #json_schema
+            #timeout
+            #result_retention
+
+            #retry_policy
 
-            #fn_token perform(self, #env_pat: &Self::Environment, #pool_pat: &#pool_ty) #return_type {
-                let Self { #(#arg_names),* } = self;
-                #body
+            #fn_token perform(self, #env_param: &Self::Environment, __swirl_context: &swirl::JobContext<'_>) #perform_return_type {
+                #perform_body
             }
         }
 
@@ -57,9 +178,95 @@ pub fn expand(item: syn::ItemFn) -> Result<TokenStream, Diagnostic> {
     Ok(res)
 }
 
+/// The arguments given to `#[swirl::background_job]`, such as
+/// `#[swirl::background_job(max_retries = 5)]`
+#[derive(Default)]
+pub struct JobOptions {
+    max_retries: Option<syn::LitInt>,
+    retry_policy: Option<syn::Expr>,
+    owner: Option<syn::LitStr>,
+    json_schema: Option<syn::Expr>,
+    /// The timeout in milliseconds
+    timeout: Option<u64>,
+    /// How long the job's result is kept for in milliseconds
+    result_retention: Option<u64>,
+}
+
+impl Parse for JobOptions {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let mut options = Self::default();
+        while !input.is_empty() {
+            let name = input.parse::<syn::Ident>()?;
+            input.parse::<syn::Token![=]>()?;
+            if name == "max_retries" && options.max_retries.is_none() {
+                options.max_retries = Some(input.parse()?);
+            } else if name == "retry_policy" && options.retry_policy.is_none() {
+                options.retry_policy = Some(input.parse()?);
+            } else if name == "owner" && options.owner.is_none() {
+                options.owner = Some(input.parse()?);
+            } else if name == "json_schema" && options.json_schema.is_none() {
+                options.json_schema = Some(input.parse()?);
+            } else if name == "timeout" && options.timeout.is_none() {
+                options.timeout = Some(parse_duration(&input.parse()?)?);
+            } else if name == "result_retention" && options.result_retention.is_none() {
+                options.result_retention = Some(parse_duration(&input.parse()?)?);
+            } else if name == "max_retries"
+                || name == "retry_policy"
+                || name == "owner"
+                || name == "json_schema"
+                || name == "timeout"
+                || name == "result_retention"
+            {
+                return Err(syn::Error::new(
+                    name.span(),
+                    format!("`{}` was given more than once", name),
+                ));
+            } else {
+                return Err(syn::Error::new(
+                    name.span(),
+                    "Unknown option, expected `max_retries`, `retry_policy`, `owner`, \
+                     `json_schema`, `timeout` or `result_retention`",
+                ));
+            }
+            if !input.is_empty() {
+                input.parse::<syn::Token![,]>()?;
+            }
+        }
+        Ok(options)
+    }
+}
+
+/// Parses a duration such as `"30s"` into milliseconds. The units `ms`, `s`,
+/// `m`, `h` and `d` are supported.
+fn parse_duration(lit: &syn::LitStr) -> syn::Result<u64> {
+    let value = lit.value();
+    let unit_start = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(unit_start);
+    let multiplier = match unit {
+        "ms" => Some(1),
+        "s" => Some(1000),
+        "m" => Some(60 * 1000),
+        "h" => Some(60 * 60 * 1000),
+        "d" => Some(24 * 60 * 60 * 1000),
+        _ => None,
+    };
+    match (number.parse::<u64>(), multiplier) {
+        (Ok(number), Some(multiplier)) => number
+            .checked_mul(multiplier)
+            .ok_or_else(|| syn::Error::new(lit.span(), "Duration is too long")),
+        _ => Err(syn::Error::new(
+            lit.span(),
+            "Expected a duration such as \"30s\", in `ms`, `s`, `m`, `h` or `d`",
+        )),
+    }
+}
+
 struct BackgroundJob {
     attrs: Vec<syn::Attribute>,
     visibility: syn::Visibility,
+    asyncness: Option<syn::Token![async]>,
     fn_token: syn::Token![fn],
     name: syn::Ident,
     args: JobArgs,
@@ -88,12 +295,6 @@ impl BackgroundJob {
                 .error("#[swirl::background_job] cannot be used on unsafe functions"));
         }
 
-        if let Some(asyncness) = sig.asyncness {
-            return Err(asyncness
-                .span
-                .error("#[swirl::background_job] cannot be used on async functions"));
-        }
-
         if let Some(abi) = sig.abi {
             return Err(abi
                 .span()
@@ -113,26 +314,73 @@ impl BackgroundJob {
             ));
         }
 
+        let asyncness = sig.asyncness;
         let fn_token = sig.fn_token;
         let return_type = sig.output.clone();
         let ident = sig.ident.clone();
         let job_args = JobArgs::try_from(sig)?;
 
-        Ok(Self {
+        if let (Some(asyncness), ConnectionArg::SingleConnection(_)) =
+            (asyncness, &job_args.connection_arg)
+        {
+            return Err(asyncness
+                .span
+                .error("Async background jobs cannot take a `&PgConnection` argument")
+                .help("Take a `&JobContext` argument and use `JobContext::connection` instead"));
+        }
+
+        let job = Self {
             attrs,
             visibility: vis,
+            asyncness,
             fn_token,
             name: ident,
             args: job_args,
             return_type,
             body: block.stmts,
-        })
+        };
+
+        if let (Some(result_type), ConnectionArg::SingleConnection(_)) =
+            (job.result_type(), &job.args.connection_arg)
+        {
+            return Err(result_type
+                .span()
+                .error(
+                    "Background jobs which take a `&PgConnection` argument cannot return a value",
+                )
+                .help("Take a `&JobContext` argument and use `JobContext::connection` instead"));
+        }
+
+        Ok(job)
+    }
+
+    /// The type of the value the job returns, if the job returns
+    /// `Result<T, _>` where `T` isn't `()`
+    fn result_type(&self) -> Option<&syn::Type> {
+        let ty = match &self.return_type {
+            syn::ReturnType::Type(_, ty) => ty,
+            syn::ReturnType::Default => return None,
+        };
+        let segment = match &**ty {
+            syn::Type::Path(syn::TypePath { path, .. }) => path.segments.last()?,
+            _ => return None,
+        };
+        let args = match &segment.arguments {
+            syn::PathArguments::AngleBracketed(args) if segment.ident == "Result" => args,
+            _ => return None,
+        };
+        match args.args.first()? {
+            syn::GenericArgument::Type(syn::Type::Tuple(tuple)) if tuple.elems.is_empty() => None,
+            syn::GenericArgument::Type(ty) => Some(ty),
+            _ => None,
+        }
     }
 }
 
 struct JobArgs {
     env_arg: EnvArg,
     connection_arg: ConnectionArg,
+    context_arg: Option<(Box<syn::Pat>, Box<syn::Type>)>,
     args: Punctuated<syn::PatType, syn::Token![,]>,
 }
 
@@ -144,6 +392,7 @@ impl JobArgs {
     fn try_from(decl: syn::Signature) -> Result<Self, Diagnostic> {
         let mut env_arg = None;
         let mut connection_arg = ConnectionArg::None;
+        let mut context_arg = None;
         let mut args = Punctuated::new();
 
         for fn_arg in decl.inputs {
@@ -184,6 +433,12 @@ impl JobArgs {
                             .help("To take a connection pool as an argument instead of a single connection, use the type `&dyn swirl::db::DieselPoolObj`")
                     );
                 }
+                (_, _, Arg::Context(pat, ty)) => {
+                    if context_arg.is_some() {
+                        return Err(span.error("Multiple `JobContext` arguments"));
+                    }
+                    context_arg = Some((pat, ty));
+                }
                 (_, _, Arg::Normal(pat_type)) => args.push(pat_type),
             }
         }
@@ -191,6 +446,7 @@ impl JobArgs {
         Ok(Self {
             env_arg: env_arg.unwrap_or_default(),
             connection_arg,
+            context_arg,
             args,
         })
     }
@@ -223,6 +479,7 @@ impl<'a> IntoIterator for &'a JobArgs {
 enum Arg {
     Env(EnvArg),
     Connection(ConnectionArg),
+    Context(Box<syn::Pat>, Box<syn::Type>),
     Normal(syn::PatType),
 }
 
@@ -236,6 +493,8 @@ impl Arg {
             let ty = type_ref.elem;
             if ConnectionArg::is_connection_arg(&ty) {
                 Ok(Arg::Connection(ConnectionArg::from_arg(pat, ty)))
+            } else if is_context_arg(&ty) {
+                Ok(Arg::Context(pat, ty))
             } else {
                 Ok(Arg::Env(EnvArg { pat, ty }))
             }
@@ -316,7 +575,7 @@ impl ConnectionArg {
         if let ConnectionArg::Pool(_, ty) = self {
             Cow::Borrowed(ty)
         } else {
-            Cow::Owned(syn::parse_quote!(swirl::db::DieselPoolObj))
+            Cow::Owned(syn::parse_quote!(dyn swirl::db::DieselPoolObj))
         }
     }
 
@@ -334,6 +593,17 @@ impl ConnectionArg {
     }
 }
 
+fn is_context_arg(ty: &syn::Type) -> bool {
+    if let syn::Type::Path(syn::TypePath { path, .. }) = ty {
+        path.segments
+            .last()
+            .map(|s| s.ident == "JobContext")
+            .unwrap_or(false)
+    } else {
+        false
+    }
+}
+
 fn path_ends_with(path: &syn::Path, needle: &str) -> bool {
     path.segments
         .last()
@@ -8,24 +8,15 @@ mod background_job;
 mod diagnostic_shim;
 
 use proc_macro::TokenStream;
-use proc_macro2::Span;
 use syn::{parse_macro_input, ItemFn};
 
 use diagnostic_shim::*;
 
 #[proc_macro_attribute]
 pub fn background_job(attr: TokenStream, item: TokenStream) -> TokenStream {
-    if !attr.is_empty() {
-        return syn::Error::new(
-            Span::call_site(),
-            "swirl::background_job does not take arguments",
-        )
-        .to_compile_error()
-        .into();
-    }
-
+    let options = parse_macro_input!(attr as background_job::JobOptions);
     let item = parse_macro_input!(item as ItemFn);
-    emit_errors(background_job::expand(item))
+    emit_errors(background_job::expand(item, options))
 }
 
 fn emit_errors(result: Result<proc_macro2::TokenStream, Diagnostic>) -> TokenStream {